@@ -1,4 +1,7 @@
 use cc::Build;
+use std::env;
+use std::fs;
+use std::path::Path;
 
 fn main() {
     println!("cargo:rerun-if-changed=./linear-malloc.c");
@@ -7,31 +10,374 @@ fn main() {
     println!("cargo:rerun-if-changed=./slab-malloc.h");
     println!("cargo:rerun-if-changed=./utils.h");
     println!("cargo:rerun-if-changed=./c-list.h");
+    println!("cargo:rerun-if-changed=./fixed_malloc.h");
+    println!("cargo:rerun-if-changed=./freestanding-mem.c");
+    println!("cargo:rerun-if-changed=./libc-symbols.c");
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_MEMORY_SIZE");
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_PAGE_SHIFT");
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_BUFFER_SECTION");
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_HEAP_START_SYMBOL");
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_HEAP_END_SYMBOL");
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_LIBC_PREFIX");
 
-    let mut build = Build::new();
+    // Page size is 1 << page shift, the same bounds as in linear-malloc.h
+    // apply
+    let page_shift: usize = match env::var("FIXED_MALLOC_PAGE_SHIFT") {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            panic!("FIXED_MALLOC_PAGE_SHIFT must be a number, got {:?}", value)
+        }),
+        Err(_) => 12,
+    };
+    if !(11..=14).contains(&page_shift) {
+        panic!(
+            "FIXED_MALLOC_PAGE_SHIFT must be between 11 and 14, got {}",
+            page_shift
+        );
+    }
+    let page_size = 1usize << page_shift;
+
+    // Size of the static memory buffer, checked against the same bounds as
+    // the C side so invalid values fail with a readable message
+    let memory_size: usize = match env::var("FIXED_MALLOC_MEMORY_SIZE") {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            panic!(
+                "FIXED_MALLOC_MEMORY_SIZE must be a number of bytes, got {:?}",
+                value
+            )
+        }),
+        Err(_) => 655360,
+    };
+    if !memory_size.is_multiple_of(page_size) {
+        panic!(
+            "FIXED_MALLOC_MEMORY_SIZE must be a multiple of {} bytes, got {}",
+            page_size, memory_size
+        );
+    }
+    let (min_size, max_size) = (2 * page_size, page_size * page_size - page_size);
+    if !(min_size..=max_size).contains(&memory_size) {
+        panic!(
+            "FIXED_MALLOC_MEMORY_SIZE must be between {} and {} bytes, got {}",
+            min_size, max_size, memory_size
+        );
+    }
+
+    // Linker section of the static buffer, e.g. to move it to external RAM.
+    // The default .bss placement applies when unset.
+    let buffer_section = env::var("FIXED_MALLOC_BUFFER_SECTION").ok();
+    if let Some(section) = &buffer_section {
+        if section.is_empty()
+            || !section
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._$".contains(c))
+        {
+            panic!(
+                "FIXED_MALLOC_BUFFER_SECTION must be a section name such as .ext_ram.bss, got {:?}",
+                section
+            );
+        }
+    }
+
+    // Symbols marking the heap bounds for FixedAlloc::from_linker_symbols,
+    // for linker scripts using other names than _heap_start and _heap_end
+    let heap_symbols: Vec<String> = [
+        ("FIXED_MALLOC_HEAP_START_SYMBOL", "_heap_start"),
+        ("FIXED_MALLOC_HEAP_END_SYMBOL", "_heap_end"),
+    ]
+    .iter()
+    .map(|(var, default)| {
+        let symbol = env::var(var).unwrap_or_else(|_| default.to_string());
+        if symbol.is_empty()
+            || symbol.starts_with(|c: char| c.is_ascii_digit())
+            || !symbol
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._$".contains(c))
+        {
+            panic!("{} must be a symbol name, got {:?}", var, symbol);
+        }
+        symbol
+    })
+    .collect();
+
+    // Prefix of the symbols exported by libc-symbols, which lets hosts link
+    // them next to their own C library. They keep the standard names when
+    // unset.
+    let libc_prefix = env::var("FIXED_MALLOC_LIBC_PREFIX").ok();
+    if let Some(prefix) = &libc_prefix {
+        if prefix.starts_with(|c: char| c.is_ascii_digit())
+            || !prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            panic!(
+                "FIXED_MALLOC_LIBC_PREFIX must be a symbol prefix, got {:?}",
+                prefix
+            );
+        }
+    }
+    if cfg!(feature = "libc-symbols") && cfg!(feature = "pure-rust") {
+        panic!("The libc-symbols feature requires the C allocator, not pure-rust");
+    }
+
+    // Version reported by fm_version, packed the same way as ffi::FM_VERSION
+    let version_part = |var: &str, limit: u32| -> u32 {
+        let value: u32 = env::var(var)
+            .expect(var)
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number", var));
+        if value >= limit {
+            panic!("{} must be less than {}, got {}", var, limit, value);
+        }
+        value
+    };
+    let version = version_part("CARGO_PKG_VERSION_MAJOR", 256) << 16
+        | version_part("CARGO_PKG_VERSION_MINOR", 256) << 8
+        | version_part("CARGO_PKG_VERSION_PATCH", 256);
+
+    // Smallest slab size class, the default of 32 applies when no feature
+    // is enabled
+    let slab_sizes: Vec<usize> = [
+        (cfg!(feature = "slab-size-16"), 16),
+        (cfg!(feature = "slab-size-32"), 32),
+        (cfg!(feature = "slab-size-64"), 64),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, size)| *size)
+    .collect();
+    if slab_sizes.len() > 1 {
+        panic!("Only one of the slab-size-* features can be enabled");
+    }
+    let slab_min_size = slab_sizes.first().copied().unwrap_or(32);
+    println!(
+        "cargo:rustc-env=FIXED_MALLOC_SLAB_MIN_SIZE={}",
+        slab_min_size
+    );
+
+    // Error codes from the C header are exported so the Rust side can check
+    // its own constants against them at compile time.
+    let header = fs::read_to_string("./linear-malloc.h").expect("read header");
+    let mut codes = String::new();
+    for line in header.lines() {
+        let mut parts = line.split_whitespace();
+        if let (Some("#define"), Some(name), Some(value)) =
+            (parts.next(), parts.next(), parts.next())
+        {
+            if name == "FM_OK"
+                || name == "FM_ABI_VERSION"
+                || name.starts_with("FM_ERR_")
+                || name.starts_with("FM_HEAP_")
+                || name.starts_with("FM_FEATURE_")
+            {
+                codes.push_str(&format!(
+                    "pub const {}: core::ffi::c_int = {};\n",
+                    name, value
+                ));
+            }
+        }
+    }
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR");
+    fs::write(Path::new(&out_dir).join("error_codes.rs"), codes).expect("write error codes");
+    fs::write(
+        Path::new(&out_dir).join("static_memory_size.rs"),
+        format!("pub const STATIC_MEMORY_SIZE: usize = {};\n", memory_size),
+    )
+    .expect("write static memory size");
+    fs::write(
+        Path::new(&out_dir).join("page_size.rs"),
+        format!(
+            "pub const FM_PAGE_SHIFT: usize = {};\npub const FM_PAGE_SIZE: usize = 1 << FM_PAGE_SHIFT;\n",
+            page_shift
+        ),
+    )
+    .expect("write page size");
+    // With manual-init the C sources leave out the static buffer, the one
+    // used by init_static is defined on the Rust side instead, so it is only
+    // linked in when actually used
+    let link_section = match &buffer_section {
+        Some(section) => format!("#[link_section = \"{}\"]\n", section),
+        None => String::new(),
+    };
+    fs::write(
+        Path::new(&out_dir).join("static_buffer.rs"),
+        format!(
+            "#[repr(C, align({}))]\nstruct StaticBuffer(core::cell::UnsafeCell<[u8; STATIC_MEMORY_SIZE]>);\n\
+             {}static BUFFER: StaticBuffer = StaticBuffer(core::cell::UnsafeCell::new([0; STATIC_MEMORY_SIZE]));\n",
+            page_size, link_section
+        ),
+    )
+    .expect("write static buffer");
+    fs::write(
+        Path::new(&out_dir).join("linker_heap.rs"),
+        format!(
+            "extern \"C\" {{\n    #[link_name = \"{}\"]\n    static mut HEAP_START: u8;\n    #[link_name = \"{}\"]\n    static HEAP_END: u8;\n}}\n",
+            heap_symbols[0], heap_symbols[1]
+        ),
+    )
+    .expect("write linker heap symbols");
+    // Lets dependents locate the C library, e.g. to inspect its symbols
+    println!("cargo:root={}", out_dir);
+
+    // Macros the C sources are built with, which also select the
+    // declarations of the installed header
+    let mut defines = vec![
+        ("FM_VERSION", Some(format!("0x{:06x}", version))),
+        ("FM_MEMORY_SIZE", Some(memory_size.to_string())),
+        ("FM_SLAB_MIN_SIZE", Some(slab_min_size.to_string())),
+        ("FM_PAGE_SHIFT", Some(page_shift.to_string())),
+    ];
     if cfg!(feature = "test-support") {
-        build.flag("-DFM_TEST_SUPPORT").flag("-DFM_GUARDS");
+        defines.push(("FM_TEST_SUPPORT", None));
+        defines.push(("FM_GUARDS", None));
+    }
+    if cfg!(feature = "fill-on-free") {
+        defines.push(("FM_FILL_ON_FREE", None));
+    }
+    if cfg!(feature = "hardening") {
+        defines.push(("FM_HARDENING", None));
     }
     if cfg!(feature = "manual-init") {
-        build.flag("-DFM_MANUAL_INIT");
-    }
-    build
-        .file("./linear-malloc.c")
-        .file("./slab-malloc.c")
-        .include(".")
-        .static_flag(true)
-        .flag("-O3")
-        .flag("-g")
-        .flag("-std=c99")
-        .flag("-nostdlib")
-        .flag("-Wall")
-        .flag("-Werror")
-        .flag("-Wextra")
-        .flag("-fno-builtin-printf")
-        .flag("-fno-builtin-memcmp")
-        .flag("-fdata-sections")
-        .flag("-ffunction-sections")
-        .flag("-DFM_MEMORY_SIZE=655360")
-        .flag("-DFM_DEBUG(...)=")
-        .compile("fixed-malloc");
+        defines.push(("FM_MANUAL_INIT", None));
+    }
+    if cfg!(feature = "trap-reentrant") {
+        defines.push(("FM_TRAP_REENTRANT", None));
+    }
+    // Debug messages are compiled out unless they can be passed to a hook
+    if cfg!(feature = "debug-hook") {
+        defines.push(("FM_DEBUG_CALLBACK", None));
+    } else {
+        defines.push(("FM_NO_DEBUG", None));
+    }
+
+    // fixed_malloc.h is installed with the configuration prepended, so C code
+    // of dependents sees the same declarations the library is built with.
+    // They find it via DEP_FIXED_MALLOC_INCLUDE.
+    let include_dir = Path::new(&out_dir).join("include");
+    fs::create_dir_all(&include_dir).expect("create include directory");
+    let mut public_header = String::from("/* Configuration of this build */\n");
+    for (name, value) in &defines {
+        match value {
+            Some(value) => public_header.push_str(&format!("#define {} {}\n", name, value)),
+            None => public_header.push_str(&format!("#define {}\n", name)),
+        }
+    }
+    public_header.push('\n');
+    public_header.push_str(&fs::read_to_string("./fixed_malloc.h").expect("read public header"));
+    fs::write(include_dir.join("fixed_malloc.h"), public_header).expect("write public header");
+    println!("cargo:include={}", include_dir.display());
+
+    // Bindings for the drift check of the tests crate, found via
+    // DEP_FIXED_MALLOC_BINDINGS
+    #[cfg(feature = "ffi-check")]
+    generate_bindings(&out_dir, page_shift);
+
+    // The allocator itself comes from src/rust_impl with pure-rust, only the
+    // mem functions are still built from C. Dependents packaging the C
+    // library see DEP_FIXED_MALLOC_PURE_RUST then.
+    if cfg!(feature = "pure-rust") {
+        println!("cargo:pure_rust=1");
+    }
+    if cfg!(feature = "pure-rust") && !cfg!(feature = "freestanding-mem") {
+        return;
+    }
+
+    let mut build = Build::new();
+    for (name, value) in &defines {
+        build.define(name, value.as_deref());
+    }
+    // cc passes the LLVM target triple matching the Rust target to clang, so
+    // cross compiling only requires clang to be installed. A compiler set via
+    // CC is still respected, e.g. CC=clang-15. GCC has no WebAssembly
+    // backend, so clang is always used there.
+    let wasm = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default() == "wasm32";
+    if (cfg!(feature = "clang") || wasm) && env::var_os("CC").is_none() {
+        build.compiler("clang");
+    }
+    // Defines are passed as -D, which MSVC understands as well. All other
+    // flags depend on the tool family of the compiler.
+    if build.get_compiler().is_like_msvc() {
+        if buffer_section.is_some() {
+            panic!("FIXED_MALLOC_BUFFER_SECTION is not supported with MSVC");
+        }
+        if cfg!(feature = "freestanding-mem") {
+            panic!("The freestanding-mem feature is not supported with MSVC");
+        }
+        // MSVC has no C99 mode, /Gy and /Gw are the equivalents of
+        // -ffunction-sections and -fdata-sections. cc already enables /W4.
+        build.flag("/std:c11").flag("/O2").flag("/Gy").flag("/Gw");
+    } else {
+        if let Some(section) = &buffer_section {
+            build.flag(format!("-DFM_BUFFER_SECTION=\"{}\"", section).as_str());
+        }
+        // CKB scripts are static binaries for this target, match the code
+        // model and relocation model Rust uses for it
+        if env::var("TARGET").as_deref() == Ok("riscv64imac-unknown-none-elf") {
+            build.pic(false).flag("-mcmodel=medany");
+        }
+        // The WebAssembly toolchain rejects -nostdlib, which only matters for
+        // linking anyway. There is no C library either, so the sources are
+        // built freestanding, with memcpy and memset coming from Rust.
+        if wasm {
+            build.flag("-ffreestanding");
+        } else {
+            build.flag("-nostdlib");
+        }
+        if cfg!(feature = "clang-lto") {
+            build.flag("-flto=thin");
+        }
+        build
+            .flag("-O3")
+            .flag("-g")
+            .flag("-std=c99")
+            .flag("-Wall")
+            .flag("-Werror")
+            .flag("-Wextra")
+            .flag("-fno-builtin-printf")
+            .flag("-fno-builtin-memcmp")
+            .flag("-fdata-sections")
+            .flag("-ffunction-sections")
+            // Only functions marked with FM_API are exported from shared
+            // libraries linked from the archive, such as the one of capi
+            .flag("-fvisibility=hidden");
+    }
+    // Kept in a separate object file, which is only linked in when the mem
+    // functions are not defined elsewhere
+    if cfg!(feature = "freestanding-mem") {
+        build.file("./freestanding-mem.c");
+    }
+    // Only compiled with the feature enabled, since the symbols are strong
+    if cfg!(feature = "libc-symbols") {
+        if let Some(prefix) = &libc_prefix {
+            build.define("FM_LIBC_PREFIX", prefix.as_str());
+        }
+        build.file("./libc-symbols.c");
+    }
+    if !cfg!(feature = "pure-rust") {
+        build.file("./linear-malloc.c").file("./slab-malloc.c");
+    }
+    build.include(".").compile("fixed-malloc");
+}
+
+// Every optional declaration is enabled, so all of src/ffi.rs is covered
+// whatever features are used for this build
+#[cfg(feature = "ffi-check")]
+fn generate_bindings(out_dir: &str, page_shift: usize) {
+    let bindings = bindgen::Builder::default()
+        .header("./fixed_malloc.h")
+        .clang_arg(format!("-DFM_PAGE_SHIFT={}", page_shift))
+        .clang_args([
+            "-DFM_TEST_SUPPORT",
+            "-DFM_FILL_ON_FREE",
+            "-DFM_HARDENING",
+            "-DFM_DEBUG_CALLBACK",
+        ])
+        .allowlist_function("fm_.*")
+        .allowlist_var("FM_.*")
+        .use_core()
+        .ctypes_prefix("core::ffi")
+        .layout_tests(false)
+        .generate()
+        .expect("generate bindings, which requires libclang");
+    let path = Path::new(out_dir).join("bindings.rs");
+    bindings.write_to_file(&path).expect("write bindings");
+    println!("cargo:bindings={}", path.display());
 }
@@ -0,0 +1,66 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Packages the C library built for fixed-malloc, so C projects can link the
+// very same allocator: lib holds the archive and a shared library linked
+// from it, include holds the header matching their configuration. The
+// sources are built with -fvisibility=hidden, only functions marked with
+// FM_API are exported from the shared library.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let root = env::var("DEP_FIXED_MALLOC_ROOT").expect("DEP_FIXED_MALLOC_ROOT");
+    let include = env::var("DEP_FIXED_MALLOC_INCLUDE").expect("DEP_FIXED_MALLOC_INCLUDE");
+    let archive = Path::new(&root).join("libfixed-malloc.a");
+    let compiler = cc::Build::new().get_compiler();
+    if compiler.is_like_msvc() {
+        panic!("fixed-malloc-capi is not supported with MSVC");
+    }
+    if env::var_os("DEP_FIXED_MALLOC_PURE_RUST").is_some() {
+        panic!("fixed-malloc-capi requires the C allocator, not pure-rust");
+    }
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR");
+    let lib_dir = Path::new(&out_dir).join("lib");
+    let include_dir = Path::new(&out_dir).join("include");
+    fs::create_dir_all(&lib_dir).expect("create lib directory");
+    fs::create_dir_all(&include_dir).expect("create include directory");
+    fs::copy(&archive, lib_dir.join("libfixed_malloc.a")).expect("copy archive");
+    fs::copy(
+        Path::new(&include).join("fixed_malloc.h"),
+        include_dir.join("fixed_malloc.h"),
+    )
+    .expect("copy header");
+
+    // All objects of the archive are linked in, the library itself has no
+    // references to them
+    let mut command = compiler.to_command();
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("macos") {
+        command
+            .arg("-dynamiclib")
+            .arg(format!("-Wl,-force_load,{}", archive.display()))
+            .arg("-o")
+            .arg(lib_dir.join("libfixed_malloc.dylib"));
+    } else {
+        command
+            .arg("-shared")
+            .arg("-Wl,--whole-archive")
+            .arg(&archive)
+            .arg("-Wl,--no-whole-archive")
+            .arg("-o")
+            .arg(lib_dir.join("libfixed_malloc.so"));
+    }
+    let status = command.status().expect("run C compiler");
+    if !status.success() {
+        panic!("linking the shared library failed: {}", status);
+    }
+
+    println!(
+        "cargo:rustc-env=FIXED_MALLOC_CAPI_LIB_DIR={}",
+        lib_dir.display()
+    );
+    println!(
+        "cargo:rustc-env=FIXED_MALLOC_CAPI_INCLUDE_DIR={}",
+        include_dir.display()
+    );
+}
@@ -0,0 +1,5 @@
+// Locations of the C libraries packaged by the build script. LIB_DIR holds
+// libfixed_malloc.a and the shared libfixed_malloc.so, or .dylib on macOS,
+// INCLUDE_DIR holds fixed_malloc.h for them.
+pub const LIB_DIR: &str = env!("FIXED_MALLOC_CAPI_LIB_DIR");
+pub const INCLUDE_DIR: &str = env!("FIXED_MALLOC_CAPI_INCLUDE_DIR");
@@ -0,0 +1,9 @@
+// Prints the directories of the libraries and the header, which C build
+// systems can pick up, e.g. via `cargo run -p fixed-malloc-capi --release`
+fn main() {
+    println!("FIXED_MALLOC_LIB_DIR={}", fixed_malloc_capi::LIB_DIR);
+    println!(
+        "FIXED_MALLOC_INCLUDE_DIR={}",
+        fixed_malloc_capi::INCLUDE_DIR
+    );
+}
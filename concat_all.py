@@ -15,7 +15,10 @@ o.write("\n")
 for header in HEADERS:
   with open(header, "r") as i:
     o.write("/* %s */\n" % (header))
-    o.writelines(i.readlines())
+    for line in i:
+      if line.startswith("#include \""):
+        line = "/* %s */\n" % ( line.strip() )
+      o.write(line)
     o.write("\n")
 
 o.write("#ifndef FIXED_MALLOC_DECLARATION_ONLY\n\n")
@@ -31,3 +34,29 @@ o.write("#endif /* FIXED_MALLOC_DECLARATION_ONLY */\n\n")
 
 o.write("#endif /* FIXED_MALLOC_ALL_H_ */\n")
 o.close()
+
+# Public header declaring all fm_* functions and constants, for C code linking
+# against the library built by cargo. build.rs installs it together with the
+# configuration the library is built with.
+PUBLIC_HEADERS = ["linear-malloc.h", "slab-malloc.h"]
+PUBLIC_OUTPUT = "fixed_malloc.h"
+
+o = open(PUBLIC_OUTPUT, "w")
+o.write("/*\n")
+o.write(" * Public declarations of fixed-malloc, generated by concat_all.py\n")
+o.write(" */\n")
+o.write("#ifndef FIXED_MALLOC_H_\n")
+o.write("#define FIXED_MALLOC_H_\n")
+o.write("\n")
+
+for header in PUBLIC_HEADERS:
+  with open(header, "r") as i:
+    o.write("/* %s */\n" % (header))
+    for line in i:
+      if line.startswith("#include \""):
+        line = "/* %s */\n" % ( line.strip() )
+      o.write(line)
+    o.write("\n")
+
+o.write("#endif /* FIXED_MALLOC_H_ */\n")
+o.close()
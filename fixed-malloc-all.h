@@ -520,6 +520,19 @@ static inline _Bool c_list_contains(const CList *list, const CList *what) {
 #define FIXED_MALLOC_UTILS_H_
 
 #include <stddef.h>
+#include <stdint.h>
+
+#if __STDC_HOSTED__
+#include <string.h>
+#else
+// Freestanding builds, such as for wasm32-unknown-unknown, come without C
+// library headers. The functions themselves are still needed, the Rust side
+// provides them.
+void *memcpy(void *dst, const void *src, size_t n);
+void *memset(void *dst, int c, size_t n);
+#endif
+
+/* #include "c-list.h" */
 
 static inline size_t __fm_roundup(size_t x, size_t round) {
   return x + (((~x) + 1) & (round - 1));
@@ -529,19 +542,160 @@ static inline size_t __fm_rounddown(size_t x, size_t round) {
   return x & (~(round - 1));
 }
 
+// GCC and clang builtins, other compilers such as MSVC get plain C versions
+#if defined(__GNUC__) || defined(__clang__)
+#define __fm_likely(x) __builtin_expect(!!(x), 1)
+#define __fm_mul_overflow(a, b, out) __builtin_mul_overflow(a, b, out)
+#define __fm_popcount64(x) __builtin_popcountll(x)
+#define __fm_ctz64(x) __builtin_ctzll(x)
+#else
+#define __fm_likely(x) (x)
+
+static inline int __fm_mul_overflow(size_t a, size_t b, size_t *out) {
+  *out = a * b;
+  return a != 0 && *out / a != b;
+}
+
+static inline int __fm_popcount64(uint64_t x) {
+  int count = 0;
+  for (; x != 0; x &= x - 1) {
+    count++;
+  }
+  return count;
+}
+
+// x must not be 0
+static inline int __fm_ctz64(uint64_t x) {
+  int count = 0;
+  for (; (x & 1) == 0; x >>= 1) {
+    count++;
+  }
+  return count;
+}
+#endif
+
+// Shift pointers of list nodes lying within [start, end) by delta, used when
+// the memory holding the nodes is copied elsewhere. head itself must not be
+// moved.
+static inline void __fm_relocate_list(CList *head, size_t start, size_t end,
+                                      size_t delta) {
+  CList *node = head;
+  do {
+    if ((size_t)node->next >= start && (size_t)node->next < end) {
+      node->next = (CList *)((size_t)node->next + delta);
+    }
+    if ((size_t)node->prev >= start && (size_t)node->prev < end) {
+      node->prev = (CList *)((size_t)node->prev + delta);
+    }
+    node = node->next;
+  } while (node != head);
+}
+
+// Append n bytes to a snapshot, bytes are only copied while they fit, but pos
+// always advances so the needed size is known in the end.
+static inline void __fm_snapshot_put(void *out, size_t len, size_t *pos,
+                                     const void *src, size_t n) {
+  if (*pos <= len && n <= len - *pos) {
+    memcpy((uint8_t *)out + *pos, src, n);
+  }
+  *pos += n;
+}
+
+// Read n bytes from a snapshot, returns 1 when the snapshot is too short
+static inline int __fm_snapshot_get(const void *in, size_t len, size_t *pos,
+                                    void *dst, size_t n) {
+  if (*pos > len || n > len - *pos) {
+    return 1;
+  }
+  if (dst != NULL) {
+    memcpy(dst, (const uint8_t *)in + *pos, n);
+  }
+  *pos += n;
+  return 0;
+}
+
+// Latch an error code, which can be queried via fm_last_error
+void __fm_set_error(int code);
+// Pass an internal error to the sink installed via fm_lm_set_error_sink
+void __fm_report(int code, const char *message);
+
+#ifdef FM_HARDENING
+// splitmix64, used by hardening mode to pick pseudo random locations
+static inline uint64_t __fm_random_next(uint64_t *state) {
+  uint64_t z = (*state += 0x9E3779B97F4A7C15ULL);
+  z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9ULL;
+  z = (z ^ (z >> 27)) * 0x94D049BB133111EBULL;
+  return z ^ (z >> 31);
+}
+#endif
+
+#ifdef FM_FILL_ON_FREE
+#define FM_FILL_PATTERN 0xAB
+
+// Returns 0 if all bytes still hold the fill pattern
+static inline int __fm_check_fill(const void *ptr, size_t size) {
+  const uint8_t *p = (const uint8_t *)ptr;
+  for (size_t i = 0; i < size; i++) {
+    if (p[i] != FM_FILL_PATTERN) {
+      return 1;
+    }
+  }
+  return 0;
+}
+#endif
+
+#ifndef FM_MEMORY_SIZE
+#define FM_MEMORY_SIZE (640 * 1024)
+#endif
+
+#ifdef FM_DEBUG_CALLBACK
+// Pass the format string of a debug message to the callback installed via
+// fm_set_debug_callback, arguments are dropped since no formatting is
+// available without the C library
+void __fm_debug(const char *format, ...);
+#define FM_DEBUG(...) __fm_debug(__VA_ARGS__)
+#endif
+
+// Debug messages can be compiled out via FM_NO_DEBUG as well, for compilers
+// which cannot define function-like macros on the command line
+#if defined(FM_NO_DEBUG) && !defined(FM_DEBUG)
+#define FM_DEBUG(...)
+#endif
+
+// Without a C library, output is dropped and aborting traps
 #ifndef FM_DEBUG
+#if __STDC_HOSTED__
 #include <stdio.h>
 #define FM_DEBUG(...) fprintf(stderr, __VA_ARGS__)
+#else
+#define FM_DEBUG(...)
+#endif
 #endif
 
+// Internal errors are printed as debug messages, and always passed to the
+// error sink
+#define FM_REPORT(code, message) \
+  do {                           \
+    FM_DEBUG(message);           \
+    __fm_report(code, message);  \
+  } while (0)
+
 #ifndef FM_PRINT
+#if __STDC_HOSTED__
 #include <stdio.h>
 #define FM_PRINT(...) printf(__VA_ARGS__)
+#else
+#define FM_PRINT(...)
+#endif
 #endif
 
 #ifndef FM_ABORT
+#if __STDC_HOSTED__
 #include <stdlib.h>
 #define FM_ABORT abort
+#else
+#define FM_ABORT __builtin_trap
+#endif
 #endif
 
 #endif /* FIXED_MALLOC_UTILS_H_ */
@@ -551,18 +705,270 @@ static inline size_t __fm_rounddown(size_t x, size_t round) {
 #define FIXED_MALLOC_LINEAR_MALLOC_H_
 
 #include <stddef.h>
+#include <stdint.h>
 
+// Pages of 4096 bytes unless configured otherwise
+#ifndef FM_PAGE_SHIFT
 #define FM_PAGE_SHIFT 12
-// 4096
+#endif
+#if (FM_PAGE_SHIFT < 11) || (FM_PAGE_SHIFT > 14)
+#error "Page shift must be between 11 and 14!"
+#endif
 #define FM_PAGE_SIZE (1 << FM_PAGE_SHIFT)
 
+// Marks the public API, which stays visible when the sources are built with
+// -fvisibility=hidden, e.g. for a shared library
+#ifndef FM_API
+#if defined(__GNUC__) || defined(__clang__)
+#define FM_API __attribute__((visibility("default")))
+#else
+#define FM_API
+#endif
+#endif
+
+// Bumped whenever the functions or structures declared here change
+// incompatibly, see fm_abi_version
+#define FM_ABI_VERSION 1
+
+// Version of fixed-malloc packed as major << 16 | minor << 8 | patch, see
+// fm_version. build.rs defines it from Cargo.toml, 0 is left when the sources
+// are built some other way.
+#ifndef FM_VERSION
+#define FM_VERSION 0
+#endif
+
+// Options the library is built with, as reported by fm_features
+#define FM_FEATURE_GUARDS 0x1
+#define FM_FEATURE_TEST_SUPPORT 0x2
+#define FM_FEATURE_MANUAL_INIT 0x4
+#define FM_FEATURE_HARDENING 0x8
+#define FM_FEATURE_FILL_ON_FREE 0x10
+#define FM_FEATURE_DEBUG_CALLBACK 0x20
+#define FM_FEATURE_TRAP_REENTRANT 0x40
+
+// At least 2 pages are required: one for accounting, one for allocation.
+#define FM_MIN_MEMORY_SIZE (2 * FM_PAGE_SIZE)
+// The bookkeeping page keeps one byte per page, which limits a buffer to as
+// many pages as the page has bytes, including the bookkeeping page itself.
+// That is 16MB with 4KB pages.
+#define FM_MAX_MEMORY_SIZE (FM_PAGE_SIZE * FM_PAGE_SIZE - FM_PAGE_SIZE)
+
 #define FM_LM_T_TRANSIENT 0x1
 #define FM_LM_T_PERSISTENT 0x2
 
-int fm_lm_reinit(void *buffer, size_t size, int zero_filled);
-void *fm_lm_malloc(size_t size, int t);
-void fm_lm_free(void *ptr);
-void *fm_lm_realloc(void *ptr, size_t size, int t);
+// Error codes latched by failing operations, see fm_last_error
+#define FM_OK 0
+// Requested size is larger than the whole heap
+#define FM_ERR_TOO_LARGE 1
+// Heap is exhausted or too fragmented to satisfy the request
+#define FM_ERR_NO_MEMORY 2
+#define FM_ERR_NOT_INITIALIZED 3
+// Pointer is not allocated by fixed-malloc, only checked with FM_HARDENING
+// except for slab objects freed twice
+#define FM_ERR_BAD_POINTER 4
+// Alignment is not a power of two
+#define FM_ERR_BAD_ALIGNMENT 5
+// Errors returned by reinit for invalid memory buffers
+#define FM_ERR_NULL_BUFFER 6
+#define FM_ERR_UNALIGNED_BUFFER 7
+#define FM_ERR_UNALIGNED_SIZE 8
+#define FM_ERR_BUFFER_TOO_SMALL 9
+#define FM_ERR_BUFFER_TOO_LARGE 10
+// Reinit is refused since there are still live allocations
+#define FM_ERR_LIVE_ALLOCATIONS 11
+// New memory buffer overlaps memory regions already in use
+#define FM_ERR_BUFFER_OVERLAP 12
+// All slots for extra memory regions are taken
+#define FM_ERR_TOO_MANY_REGIONS 13
+// Snapshot is truncated or taken from other memory regions
+#define FM_ERR_BAD_SNAPSHOT 14
+// Allocator called from a hook or callback it is running
+#define FM_ERR_REENTRANT 15
+// Bookkeeping data is found corrupted by an internal check, which aborts
+#define FM_ERR_CORRUPTED 16
+
+// Kinds of heap corruption reported by fm_lm_verify and fm_sm_verify
+#define FM_HEAP_OK 0
+// Bookkeeping data of a page block, free region or slab is inconsistent
+#define FM_HEAP_CORRUPTED_HEADER 1
+// A free list or slab list is broken or loops forever
+#define FM_HEAP_FREE_LIST_CYCLE 2
+// The end of a freed slab slot is modified, usually by an underflow of the
+// following object. Only detected with FM_FILL_ON_FREE.
+#define FM_HEAP_UNDERFLOW_GUARD 3
+// The start of a freed slab slot is modified, usually by an overflow of the
+// preceding object. Only detected with FM_FILL_ON_FREE.
+#define FM_HEAP_OVERFLOW_GUARD 4
+
+typedef struct fm_heap_error_t {
+  int kind;
+  // Start of the corrupted structure, NULL when it cannot be located
+  void *address;
+} fm_heap_error_t;
+
+// Returns FM_ABI_VERSION of the library, which can differ from the header when
+// linking against a prebuilt library
+FM_API int fm_abi_version();
+// Returns FM_VERSION of the library
+FM_API uint32_t fm_version();
+// Returns the FM_FEATURE_* bits of the options the library is built with
+FM_API uint32_t fm_features();
+
+// Returns the error code of the last failing operation, or FM_OK
+FM_API int fm_last_error();
+FM_API void fm_clear_error();
+
+typedef void (*fm_error_sink_t)(int code, const char *message, void *ctx);
+// Install a sink receiving internal errors, such as invalid buffers passed to
+// reinit or failed guard checks, along with their error codes. Unlike debug
+// messages they are never compiled out. The sink is called before aborting,
+// it must not call into the allocator. Pass NULL to remove the sink.
+FM_API void fm_lm_set_error_sink(fm_error_sink_t sink, void *ctx);
+
+#ifdef FM_DEBUG_CALLBACK
+typedef void (*fm_debug_cb_t)(const char *message, void *ctx);
+// Install a callback receiving debug messages, such as the reason why reinit
+// rejects a buffer. Only the format strings are passed, so values such as
+// pointers are not filled in. Pass NULL to remove the callback.
+FM_API void fm_set_debug_callback(fm_debug_cb_t callback, void *ctx);
+#endif
+
+// Validate a memory buffer the same way reinit does, returns 0 if valid
+FM_API int fm_lm_check_buffer(void *buffer, size_t size);
+// Extra regions added earlier are dropped
+FM_API int fm_lm_reinit(void *buffer, size_t size, int zero_filled);
+// Drop all regions, allocations then fail with FM_ERR_NOT_INITIALIZED until
+// the next reinit
+FM_API void fm_lm_deinit();
+// Add a memory region not contiguous with the existing ones, up to
+// FM_MAX_EXTRA_REGIONS regions can be added after reinit. A single
+// allocation never spans regions.
+FM_API int fm_lm_add_region(void *buffer, size_t size, int zero_filled);
+FM_API int fm_lm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                             void **old_buffer, size_t *old_size);
+// Same as fm_lm_reinit, but buffer is added as the second region, and the
+// first region is then reserved for fm_lm_malloc_reserved. All other
+// allocations are served from the remaining regions.
+FM_API int fm_lm_reinit_split(void *reserved_buffer, size_t reserved_size,
+                              void *buffer, size_t size, int zero_filled);
+FM_API void *fm_lm_malloc(size_t size, int t);
+// Allocate from the reserved first region, or the same as fm_lm_malloc when
+// no region is reserved
+FM_API void *fm_lm_malloc_reserved(size_t size, int t);
+// Largest size fm_lm_malloc, or fm_lm_malloc_reserved, can satisfy right now.
+// Freed pages not yet merged back are merged first.
+FM_API size_t fm_lm_max_alloc_size();
+FM_API size_t fm_lm_max_alloc_size_reserved();
+// Allocate zero-filled pages for n values of size bytes each
+FM_API void *fm_lm_calloc(size_t n, size_t size, int t);
+// Allocate pages aligned on align bytes, which must be a power of two
+FM_API void *fm_lm_aligned_malloc(size_t size, size_t align, int t);
+FM_API void fm_lm_free(void *ptr);
+// Resize ptr, t is the type of the returned block and need not match the one
+// ptr was allocated with. The block is kept in place whenever the pages it
+// needs are free there, whatever its type. Otherwise it is moved to new
+// pages taken like fm_lm_malloc does for t, which migrates a persistent block
+// to transient pages or vice versa.
+FM_API void *fm_lm_realloc(void *ptr, size_t size, int t);
+// Same as fm_lm_realloc, but NULL is returned when the pages following ptr
+// are taken, in which case ptr is left untouched.
+FM_API void *fm_lm_realloc_in_place(void *ptr, size_t size);
+// Shrink ptr to the pages needed for size, but at least one, and free the
+// pages following them. NULL is returned when size exceeds the block, which
+// is then left untouched.
+FM_API void *fm_lm_shrink_release(void *ptr, size_t size);
+// Count pages available for allocation, as well as pages that are free now.
+// Freed pages that are not yet merged back are also counted as free.
+FM_API void fm_lm_stats(size_t *total_pages, size_t *free_pages);
+// Number of allocated blocks of pages
+FM_API size_t fm_lm_live_blocks();
+// Number of pages in those blocks, which is kept up to date on each call so
+// unlike fm_lm_stats no free list is walked
+FM_API size_t fm_lm_used_pages();
+// Number of memory regions including the first one
+FM_API size_t fm_lm_regions();
+// Start and size of the first memory region, which is the buffer passed to
+// reinit, including its bookkeeping page. NULL and 0 before initialization.
+FM_API void fm_lm_buffer_range(void **start, size_t *size);
+// Returns 1 if the pointer lies within pages available for allocations
+FM_API int fm_lm_contains(const void *ptr);
+// Size in bytes of the allocated block starting at ptr, or 0 if ptr is not
+// the start of an allocated block
+FM_API size_t fm_lm_block_size(const void *ptr);
+
+typedef void (*fm_walk_cb_t)(void *ptr, size_t size, void *user);
+
+// Invoke callback for each allocated block of pages in address order, regions
+// are visited in the order they are added
+FM_API void fm_lm_walk(fm_walk_cb_t callback, void *user);
+// Check the bookkeeping data of all regions, returns FM_HEAP_OK when intact.
+// Otherwise the first problem found is also written to error if not NULL.
+FM_API int fm_lm_verify(fm_heap_error_t *error);
+// Copy the first region into a larger buffer, which must not overlap any
+// region. Pages beyond the current size become free pages. Nothing is
+// changed when an error is returned.
+FM_API int fm_lm_migrate(void *new_buffer, size_t new_size, void **old_buffer,
+                         size_t *old_size);
+// Grow the first region in place, the additional bytes must directly follow
+// the end of the current buffer and be owned by the allocator afterwards.
+FM_API int fm_lm_extend(size_t additional_bytes);
+
+// Serialize the bookkeeping data of all regions into out, which are the
+// state, bookkeeping pages and free region headers. Returns the number of
+// bytes written, or the negated size needed when out_len is too small.
+FM_API ptrdiff_t fm_lm_snapshot(void *out, size_t out_len);
+// Write a snapshot of the same regions back, allocations made after the
+// snapshot are dropped while freed ones become live again. Nothing is
+// changed when an error is returned.
+FM_API int fm_lm_restore(const void *in, size_t len);
+
+// All linear malloc state of an allocator instance. Functions above operate
+// on the default state, the ones below on the given state, where NULL stands
+// for the default state. A new state has to be initialized via
+// fm_lm_state_reinit.
+typedef struct fm_lm_state_t fm_lm_state_t;
+FM_API size_t fm_lm_state_size();
+FM_API int fm_lm_state_reinit(fm_lm_state_t *lm, void *buffer, size_t size,
+                              int zero_filled);
+FM_API void *fm_lm_state_malloc(fm_lm_state_t *lm, size_t size, int t);
+FM_API void *fm_lm_state_malloc_reserved(fm_lm_state_t *lm, size_t size,
+                                         int t);
+FM_API void fm_lm_state_free(fm_lm_state_t *lm, void *ptr);
+FM_API void *fm_lm_state_realloc(fm_lm_state_t *lm, void *ptr, size_t size,
+                                 int t);
+FM_API void *fm_lm_state_realloc_in_place(fm_lm_state_t *lm, void *ptr,
+                                          size_t size);
+FM_API void *fm_lm_state_shrink_release(fm_lm_state_t *lm, void *ptr,
+                                        size_t size);
+FM_API int fm_lm_state_contains(fm_lm_state_t *lm, const void *ptr);
+FM_API size_t fm_lm_state_block_size(fm_lm_state_t *lm, const void *ptr);
+
+#ifdef FM_TEST_SUPPORT
+// Same as the start and size reported by fm_lm_buffer_range
+FM_API void *fm_lm_test_buffer_pointer();
+// Address of the static buffer, or NULL with FM_MANUAL_INIT
+FM_API void *fm_lm_test_static_buffer();
+FM_API size_t fm_lm_test_total_buffer_size();
+FM_API void fm_lm_test_region(size_t index, void **buffer, size_t *size);
+#endif
+
+#ifdef FM_FILL_ON_FREE
+// Fill an allocated block with FM_FILL_PATTERN, which is done by fm_lm_free
+FM_API void fm_lm_fill(void *ptr);
+// Check if a freed block still holds the fill pattern, returns 0 if intact
+FM_API int fm_lm_check_fill(void *ptr);
+#endif
+
+// Return freed blocks to the free pages right away instead of queueing them,
+// and ignore the random seed with FM_HARDENING. Allocated addresses then only
+// depend on the sequence of calls since the heap was last empty. Blocks
+// queued so far are merged when enabling.
+FM_API void fm_lm_set_deterministic(int enabled);
+
+#ifdef FM_HARDENING
+// Enable randomized placement for large allocations using the given seed
+FM_API void fm_lm_set_random_seed(uint64_t seed);
+#endif
 
 #endif /* FIXED_MALLOC_LINEAR_MALLOC_H_ */
 
@@ -571,11 +977,237 @@ void *fm_lm_realloc(void *ptr, size_t size, int t);
 #define FIXED_MALLOC_SLAB_MALLOC_H_
 
 #include <stddef.h>
+#include <stdint.h>
+
+/* #include "linear-malloc.h" */
+
+typedef void (*fm_oom_hook_t)(size_t requested, void *ctx);
+typedef void (*fm_lock_cb_t)(void *ctx);
+typedef void (*fm_relocate_cb_t)(void *old_ptr, void *new_ptr, size_t size,
+                                 void *ctx);
+typedef void (*fm_class_stats_cb_t)(size_t class_size, size_t slabs,
+                                    size_t used_slots, size_t free_slots,
+                                    void *user);
+
+typedef struct fm_stats_t {
+  // Size of all memory regions, including their bookkeeping pages
+  size_t total_bytes;
+  // Bytes held by live allocations, rounded up to size classes or pages
+  size_t used_bytes;
+  // Bytes in free pages, which are available for new allocations
+  size_t free_bytes;
+  // Pages available for allocations, excluding bookkeeping pages
+  size_t total_pages;
+  // Allocated pages, including pages used by slabs
+  size_t used_pages;
+  size_t free_pages;
+} fm_stats_t;
+
+// Fails with FM_ERR_LIVE_ALLOCATIONS when allocations are not all freed.
+// buffer must be aligned to FM_PAGE_SIZE and size must be a multiple of it,
+// otherwise FM_ERR_UNALIGNED_BUFFER or FM_ERR_UNALIGNED_SIZE is returned.
+FM_API int fm_sm_reinit(void *buffer, size_t size, int zero_filled);
+// Same as fm_sm_reinit, but slabs are only created in slab_buffer, while
+// allocations larger than slab objects are served from linear_buffer. Regions
+// added later also serve large allocations.
+FM_API int fm_sm_reinit_split(void *slab_buffer, size_t slab_size,
+                              void *linear_buffer, size_t linear_size,
+                              int zero_filled);
+// Feed the heap with one more memory region, see fm_lm_add_region
+FM_API int fm_sm_add_region(void *buffer, size_t size, int zero_filled);
+// Hand a block obtained elsewhere, such as a DMA buffer, over to the heap as
+// free memory. ptr must be 16-byte aligned, the whole pages within the block
+// are then added as a memory region, which needs at least FM_MIN_MEMORY_SIZE
+// bytes of them. The block belongs to the heap until the next reinit.
+FM_API int fm_sm_adopt(void *ptr, size_t size);
+// Same as fm_sm_reinit, but all live allocations are discarded
+FM_API int fm_sm_reinit_forced(void *buffer, size_t size, int zero_filled);
+// Same as fm_sm_reinit, but the previously installed buffer and its size are
+// written to old_buffer and old_size first, so the caller can release them.
+FM_API int fm_sm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                             void **old_buffer, size_t *old_size);
+// Detach the heap from all memory regions, discarding live allocations, so
+// the memory can be released. All functions then fail with
+// FM_ERR_NOT_INITIALIZED until the next reinit.
+FM_API void fm_sm_deinit();
+FM_API void *fm_sm_malloc(size_t size);
+// Like free, passing NULL does nothing
+FM_API void fm_sm_free(void *ptr);
+// Free count blocks at once, taking the lock only once. NULL entries are
+// skipped like in fm_sm_free.
+FM_API void fm_sm_free_many(void *const *ptrs, size_t count);
+FM_API void *fm_sm_realloc(void *ptr, size_t size);
+// Only resize ptr where it is, NULL is returned without freeing ptr when the
+// block would have to move. For slab objects this means size must fit in
+// their size classes, while large blocks can take the free pages following
+// them.
+#define FM_REALLOC_NO_MOVE 0x1
+// Same as fm_sm_realloc, but with FM_REALLOC_* flags
+FM_API void *fm_sm_realloc_flags(void *ptr, size_t size, int flags);
+// Shrink ptr in place, the pages of a large block beyond size are returned to
+// the free pages right away, while the block keeps at least one page. Slab
+// objects keep their size classes. NULL is returned when size does not fit
+// in the block, which is then left untouched.
+FM_API void *fm_sm_shrink_release(void *ptr, size_t size);
+FM_API void *fm_sm_calloc(size_t n, size_t size);
+// Allocate pages contiguous pages aligned to FM_PAGE_SIZE, which unlike
+// fm_sm_malloc holds for any size. NULL is returned when pages is 0.
+FM_API void *fm_sm_page_alloc(size_t pages);
+// Free pages allocated by fm_sm_page_alloc, pointers that are not page
+// aligned are rejected with FM_ERR_BAD_POINTER.
+FM_API void fm_sm_page_free(void *ptr);
+FM_API void fm_sm_stats(fm_stats_t *stats);
+// Number of allocations that are not yet freed
+FM_API size_t fm_sm_live_allocations();
+// Bytes held by live allocations in O(1), which is the same as used_bytes of
+// fm_sm_stats: slab objects count with the size of their size classes, and
+// larger blocks with whole pages. Blocks kept in quarantine with
+// FM_TEST_SUPPORT also count until they are evicted.
+FM_API size_t fm_sm_allocated_bytes();
+// Size of the static memory buffer, which is FM_MEMORY_SIZE at compile time.
+// FM_MANUAL_INIT leaves the buffer out, all functions then fail with
+// FM_ERR_NOT_INITIALIZED until a buffer is installed via fm_sm_reinit.
+FM_API size_t fm_sm_default_memory_size();
+// Smallest buffer size accepted by fm_sm_reinit, which holds one bookkeeping
+// page and one page for allocations
+FM_API size_t fm_sm_min_buffer_size();
+// Usable size of the live allocation starting at ptr, or 0 if ptr is not
+// allocated from the current heap. Interior pointers are also rejected.
+FM_API size_t fm_sm_usable_size(const void *ptr);
+// Move the first memory region into a larger buffer, keeping all live
+// allocations at the same offsets, allocations in extra regions stay put.
+// callback, if not NULL, is then invoked once for each moved allocation so
+// the caller can fix up its pointers, slab objects are reported with the size
+// of their size classes. The current buffer is left untouched when an error
+// is returned.
+FM_API int fm_sm_migrate(void *new_buffer, size_t new_size,
+                         fm_relocate_cb_t callback, void *ctx);
+// Grow the heap with additional_bytes of memory directly following the end
+// of the current buffer, live allocations are kept in place.
+FM_API int fm_sm_extend(size_t additional_bytes);
+// Move slab objects out of sparsely used slabs into denser ones, and free the
+// slabs emptied this way. callback, if not NULL, is invoked once for each
+// moved object, all other pointers to moved objects become dangling. Returns
+// the number of pages reclaimed.
+FM_API size_t fm_sm_compact(fm_relocate_cb_t callback, void *ctx);
+// Return slabs without any live objects to the free pages, unlike
+// fm_sm_compact no object is moved. Returns the number of bytes recovered.
+FM_API size_t fm_sm_trim();
+// Largest size malloc can satisfy given the current buffers and what is
+// allocated from them. Empty slabs are returned to the free pages first, the
+// same way a failing malloc does.
+FM_API size_t fm_sm_max_alloc_size();
+// Make allocated addresses a pure function of the calls made since the heap
+// was last empty: freed blocks are merged into the free pages right away,
+// empty slabs are released as soon as their last object is freed, and the
+// random seed set with FM_HARDENING is ignored. Enabling it also releases
+// empty slabs and merges queued blocks, affecting all heap instances.
+FM_API void fm_sm_set_deterministic(int enabled);
+// Check the bookkeeping data of linear malloc and all slabs, as well as fill
+// patterns of free slab slots with FM_FILL_ON_FREE. Returns FM_HEAP_OK when
+// intact, otherwise the first problem found is also written to error if not
+// NULL.
+FM_API int fm_sm_verify(fm_heap_error_t *error);
+// Free all live allocations through the regular free path, so guards and
+// fill patterns are still checked. Returns the number of freed allocations.
+FM_API size_t fm_sm_free_all();
+// Serialize the bookkeeping data of linear malloc and all slabs, as well as
+// the quarantine with FM_TEST_SUPPORT, into out. Allocated memory itself is
+// not copied. Returns the number of bytes written, or the negated size needed
+// when out_len is too small.
+FM_API ptrdiff_t fm_sm_snapshot(void *out, size_t out_len);
+// Bring the heap back to the state of a snapshot taken from the same memory
+// regions, fails with FM_ERR_BAD_SNAPSHOT otherwise. Allocations made after
+// the snapshot are dropped while freed ones become live again, their
+// contents are left as is. Tags are not restored.
+FM_API int fm_sm_restore(const void *in, size_t len);
+
+// Independent heap instance, the global functions above operate on a default
+// instance. Hooks, error state and test support features are still shared.
+typedef struct fm_heap_t fm_heap_t;
+// Create a heap in buffer, its control block is also kept in the buffer so
+// no other memory is needed. NULL is returned on errors, which can then be
+// retrieved via fm_last_error.
+FM_API fm_heap_t *fm_sm_create(void *buffer, size_t size, int zero_filled);
+FM_API void *fm_sm_heap_malloc(fm_heap_t *heap, size_t size);
+FM_API void fm_sm_heap_free(fm_heap_t *heap, void *ptr);
+FM_API void *fm_sm_heap_realloc(fm_heap_t *heap, void *ptr, size_t size);
+// Invalidate the heap along with all its allocations, the buffer can then be
+// reused by the caller.
+FM_API void fm_sm_destroy(fm_heap_t *heap);
+
+// Serialize all fm_sm_* functions with lock and unlock, which are invoked
+// around each call once installed. The lock need not be reentrant, hence
+// other callbacks such as walk or relocation callbacks must not call into the
+// allocator. The OOM hook is the only exception, it runs with the lock
+// released. fm_lm_* functions are never locked, since slab malloc calls them
+// with the lock held. Pass NULL callbacks to disable locking, which must not
+// be changed while other threads use the allocator.
+FM_API void fm_set_lock_callbacks(fm_lock_cb_t lock, fm_lock_cb_t unlock,
+                                  void *ctx);
+
+// Same as fm_lm_set_error_sink, which also covers errors of slab malloc
+FM_API void fm_sm_set_error_sink(fm_error_sink_t sink, void *ctx);
+
+// Install a hook called exactly once for each failed allocation, including
+// failed reallocs. The hook can inspect the heap but must not allocate. Pass
+// NULL to remove the hook.
+//
+// Without lock callbacks, allocation functions called from the OOM hook or
+// the callbacks of fm_sm_migrate, fm_sm_compact, fm_sm_class_stats and
+// fm_sm_test_walk are detected. They fail with FM_ERR_REENTRANT on the heap
+// running the hook, or abort when built with FM_TRAP_REENTRANT.
+FM_API void fm_sm_set_oom_hook(fm_oom_hook_t hook, void *ctx);
+
+#ifdef FM_FILL_ON_FREE
+// Check if a freed block still holds the fill pattern, returns 0 if intact
+FM_API int fm_sm_check_fill(void *ptr);
+#endif
+
+#ifdef FM_HARDENING
+// Enable randomized placement for both slab objects and large allocations.
+// The same seed always produces the same sequence of allocated addresses.
+FM_API void fm_sm_set_random_seed(uint64_t seed);
+#endif
 
-int fm_sm_reinit(void *buffer, size_t size, int zero_filled);
-void *fm_sm_malloc(size_t size);
-void fm_sm_free(void *ptr);
-void *fm_sm_realloc(void *ptr, size_t size);
+#ifdef FM_TEST_SUPPORT
+// Keep the last n freed blocks out of circulation to help detecting
+// use-after-free bugs, n is capped at FM_SM_MAX_QUARANTINE.
+FM_API void fm_sm_set_quarantine(size_t n);
+// Fill the bytes a block gains in fm_sm_realloc and fm_sm_heap_realloc, from
+// its previous usable size up to its new one, with FM_REALLOC_FILL_PATTERN.
+// Preserved bytes can then be told apart from fresh ones.
+#define FM_REALLOC_FILL_PATTERN 0xCD
+FM_API void fm_sm_set_realloc_fill(int enabled);
+// Usable size of the block passed to the last successful realloc, 0 for NULL.
+// The first min(old size, new size) bytes of the new block must match it.
+FM_API size_t fm_sm_test_realloc_old_size();
+// Print the failed allocation size together with heap stats
+FM_API void fm_sm_test_report_oom(size_t requested);
+// Invoke callback once for each size class, from the smallest to the largest
+FM_API void fm_sm_class_stats(fm_class_stats_cb_t callback, void *user);
+typedef void (*fm_sm_walk_cb_t)(void *ptr, size_t size, uint32_t tag,
+                                void *user);
+// Invoke callback for each live allocation in address order, region by
+// region. Quarantined
+// blocks are also reported. Slab objects are reported with the size of
+// their size classes, untagged allocations have tag 0.
+FM_API void fm_sm_test_walk(fm_sm_walk_cb_t callback, void *user);
+// Count live allocations by size, bucket i holds blocks of [2^i, 2^(i+1))
+// bytes, with larger ones counted in the last bucket. Sizes are the ones
+// fm_sm_test_walk reports.
+#define FM_SM_HISTOGRAM_BUCKETS 16
+FM_API void fm_sm_test_size_histogram(uint64_t *buckets);
+// Allocate and tag a block for leak attribution, a tag of 0 means untagged.
+// Tags are kept when the block is reallocated.
+FM_API void *fm_sm_malloc_tagged(size_t size, uint32_t tag);
+// Same as fm_sm_free, but size is first checked against the block ptr
+// points to, which is rounded up to its size class or pages. Nonzero is
+// returned without freeing the block when size does not fit in the block, or
+// ptr is not a live allocation. Smaller sizes are accepted, since realloc
+// keeps shrunk blocks in place.
+FM_API int fm_sm_free_sized_checked(void *ptr, size_t size);
+#endif
 
 #endif /* FIXED_MALLOC_SLAB_MALLOC_H_ */
 
@@ -584,61 +1216,181 @@ void *fm_sm_realloc(void *ptr, size_t size);
 /* linear-malloc.c */
 /* #include "linear-malloc.h" */
 
-#include <string.h>
-
 /* #include "c-list.h" */
 /* #include "utils.h" */
 
 typedef struct region_t {
   CList link;
-  uint64_t start_page;
-  uint64_t pages;
+  size_t start_page;
+  size_t pages;
 } region_t;
 
 typedef struct meta_t {
-  // Linear malloc supports a maximum of 4096 pages now, which is 16MB.
-  uint8_t pages[4096];
+  // One entry per page, which fills the whole bookkeeping page
+  uint8_t pages[FM_PAGE_SIZE];
 } meta_t;
 
-#ifndef FM_MANUAL_INIT
-#ifndef FM_MEMORY_SIZE
-#define FM_MEMORY_SIZE (640 * 1024)
-#endif
 #if (FM_MEMORY_SIZE & (FM_PAGE_SIZE - 1)) != 0
-#error "Linear malloc memory size must be aligned on 4KB!"
+#error "Linear malloc memory size must be aligned on pages!"
+#endif
+#if (FM_MEMORY_SIZE < FM_MIN_MEMORY_SIZE) || (FM_MEMORY_SIZE > FM_MAX_MEMORY_SIZE)
+#error "Linear malloc memory size must be between 2 pages and the maximum!"
 #endif
-#if (FM_MEMORY_SIZE < 128 * 1024) || (FM_MEMORY_SIZE >= 16 * 1024 * 1024)
-#error "Linear malloc memory size must be between 128KB and 16MB!"
+
+// State of a memory region, regions added via fm_lm_add_region are managed
+// separately and never merged with each other.
+typedef struct heap_t {
+  uint8_t *buffer_start;
+  size_t buffer_size;
+  meta_t *meta;
+  CList free_regions;
+  CList freed_memories;
+} heap_t;
+
+#ifndef FM_MAX_EXTRA_REGIONS
+#define FM_MAX_EXTRA_REGIONS 3
 #endif
 
-static uint8_t __sbuffer[FM_MEMORY_SIZE]
-    __attribute__((aligned(FM_PAGE_SIZE))) = {0};
+struct fm_lm_state_t {
+  heap_t heaps[1 + FM_MAX_EXTRA_REGIONS];
+  size_t heap_count;
+  // 1 when the first region is reserved for fm_lm_malloc_reserved
+  size_t first_region;
+  // Number of allocated blocks, including pages used by slabs
+  size_t live_blocks;
+  // Number of pages held by those blocks
+  size_t used_pages;
+};
+
+#ifndef FM_MANUAL_INIT
+// The alignment also holds in the section set via FM_BUFFER_SECTION, as long
+// as the linker script does not place it at an unaligned address explicitly
+#if defined(FM_BUFFER_SECTION) && defined(_MSC_VER)
+#error "FM_BUFFER_SECTION is not supported with MSVC"
+#elif defined(FM_BUFFER_SECTION)
+#define FM_BUFFER_ATTRIBUTES \
+  __attribute__((section(FM_BUFFER_SECTION), aligned(FM_PAGE_SIZE)))
+#elif defined(_MSC_VER)
+#define FM_BUFFER_ATTRIBUTES _Alignas(FM_PAGE_SIZE)
+#else
+#define FM_BUFFER_ATTRIBUTES __attribute__((aligned(FM_PAGE_SIZE)))
+#endif
+static FM_BUFFER_ATTRIBUTES uint8_t __sbuffer[FM_MEMORY_SIZE] = {0};
 // Forward declaration
-static CList __free_regions;
+static fm_lm_state_t __default_state;
 // Here we employ a slight hack so we can initialize everything at compile time.
 static region_t __initial_region = {
-    .link = {&__free_regions, &__free_regions},
+    .link = {&__default_state.heaps[0].free_regions,
+             &__default_state.heaps[0].free_regions},
     // The first page is set aside for accounting purposes
     .start_page = 1,
     .pages = FM_MEMORY_SIZE / FM_PAGE_SIZE - 1,
 };
-static uint8_t *__buffer_start = __sbuffer;
-static CList __free_regions = {
-    .next = &__initial_region.link,
-    .prev = &__initial_region.link,
+static fm_lm_state_t __default_state = {
+    .heaps = {{
+        .buffer_start = __sbuffer,
+        .buffer_size = FM_MEMORY_SIZE,
+        .meta = (meta_t *)__sbuffer,
+        .free_regions = {&__initial_region.link, &__initial_region.link},
+        .freed_memories = C_LIST_INIT(__default_state.heaps[0].freed_memories),
+    }},
+    .heap_count = 1,
+    .first_region = 0,
+    .live_blocks = 0,
+    .used_pages = 0,
 };
-static meta_t *__meta = (meta_t *)__sbuffer;
 #else
-static uint8_t *__buffer_start = NULL;
-static CList __free_regions = C_LIST_INIT(__free_regions);
-static meta_t *__meta = NULL;
+static fm_lm_state_t __default_state = {
+    .heaps = {{
+        .buffer_start = NULL,
+        .buffer_size = 0,
+        .meta = NULL,
+        .free_regions = C_LIST_INIT(__default_state.heaps[0].free_regions),
+        .freed_memories = C_LIST_INIT(__default_state.heaps[0].freed_memories),
+    }},
+    .heap_count = 1,
+    .first_region = 0,
+    .live_blocks = 0,
+    .used_pages = 0,
+};
 #endif
 
-static CList __freed_memories = C_LIST_INIT(__freed_memories);
+static int __last_error = FM_OK;
+
+void __fm_set_error(int code) { __last_error = code; }
 
+int fm_abi_version() { return FM_ABI_VERSION; }
+
+uint32_t fm_version() { return FM_VERSION; }
+
+uint32_t fm_features() {
+  uint32_t features = 0;
+#ifdef FM_GUARDS
+  features |= FM_FEATURE_GUARDS;
+#endif
 #ifdef FM_TEST_SUPPORT
-#include <stdio.h>
+  features |= FM_FEATURE_TEST_SUPPORT;
+#endif
+#ifdef FM_MANUAL_INIT
+  features |= FM_FEATURE_MANUAL_INIT;
+#endif
+#ifdef FM_HARDENING
+  features |= FM_FEATURE_HARDENING;
+#endif
+#ifdef FM_FILL_ON_FREE
+  features |= FM_FEATURE_FILL_ON_FREE;
+#endif
+#ifdef FM_DEBUG_CALLBACK
+  features |= FM_FEATURE_DEBUG_CALLBACK;
+#endif
+#ifdef FM_TRAP_REENTRANT
+  features |= FM_FEATURE_TRAP_REENTRANT;
+#endif
+  return features;
+}
+
+int fm_last_error() { return __last_error; }
+
+size_t fm_lm_state_size() { return sizeof(fm_lm_state_t); }
+
+// NULL stands for the default state
+static fm_lm_state_t *state_of(fm_lm_state_t *lm) {
+  return (lm != NULL) ? lm : &__default_state;
+}
+
+void fm_clear_error() { __last_error = FM_OK; }
+
+static fm_error_sink_t __error_sink = NULL;
+static void *__error_sink_ctx = NULL;
+
+void fm_lm_set_error_sink(fm_error_sink_t sink, void *ctx) {
+  __error_sink = sink;
+  __error_sink_ctx = ctx;
+}
+
+void __fm_report(int code, const char *message) {
+  if (__error_sink != NULL) {
+    __error_sink(code, message, __error_sink_ctx);
+  }
+}
+
+#ifdef FM_DEBUG_CALLBACK
+static fm_debug_cb_t __debug_callback = NULL;
+static void *__debug_ctx = NULL;
+
+void fm_set_debug_callback(fm_debug_cb_t callback, void *ctx) {
+  __debug_callback = callback;
+  __debug_ctx = ctx;
+}
+
+void __fm_debug(const char *format, ...) {
+  if (__debug_callback != NULL) {
+    __debug_callback(format, __debug_ctx);
+  }
+}
+#endif
 
+#ifdef FM_TEST_SUPPORT
 void fm_lm_test_dump_regions(CList *first, const char *name, int max) {
   (void)name;
   FM_PRINT("### Region %s list first: %p\n", name, first);
@@ -648,8 +1400,8 @@ void fm_lm_test_dump_regions(CList *first, const char *name, int max) {
     region_t *region = c_list_entry(iter, region_t, link);
     (void)region;
     FM_PRINT(
-        "  Entry %d iter pointer: %p, actual pointer %p, start: %ld, pages: "
-        "%ld\n",
+        "  Entry %d iter pointer: %p, actual pointer %p, start: %zu, pages: "
+        "%zu\n",
         i, iter, region, region->start_page, region->pages);
     iter = iter->next;
     i++;
@@ -662,77 +1414,222 @@ void fm_lm_test_dump_regions(CList *first, const char *name, int max) {
   FM_PRINT("### Region %s ends.\n", name);
 }
 
-#ifndef FM_MANUAL_INIT
-static size_t __buffer_size = FM_MEMORY_SIZE;
+void *fm_lm_test_buffer_pointer() {
+  void *start;
+  size_t size;
+  fm_lm_buffer_range(&start, &size);
+  return start;
+}
+
+void *fm_lm_test_static_buffer() {
+#ifdef FM_MANUAL_INIT
+  return NULL;
 #else
-static size_t __buffer_size = 0;
+  return __sbuffer;
 #endif
+}
 
-void *fm_lm_test_buffer_pointer() { return __buffer_start; }
+size_t fm_lm_test_total_buffer_size() {
+  void *start;
+  size_t size;
+  fm_lm_buffer_range(&start, &size);
+  return size;
+}
 
-size_t fm_lm_test_total_buffer_size() { return __buffer_size; }
+void fm_lm_test_region(size_t index, void **buffer, size_t *size) {
+  *buffer = __default_state.heaps[index].buffer_start;
+  *size = __default_state.heaps[index].buffer_size;
+}
 #endif
 
-int fm_lm_reinit(void *buffer, size_t size, int zero_filled) {
+int fm_lm_check_buffer(void *buffer, size_t size) {
+  if (buffer == NULL) {
+    FM_REPORT(FM_ERR_NULL_BUFFER, "Memory buffer must not be NULL!");
+    return FM_ERR_NULL_BUFFER;
+  }
   if ((((size_t)buffer) & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG("Memory buffer must be aligned at 4K boundary!");
-    FM_ABORT();
+    FM_REPORT(FM_ERR_UNALIGNED_BUFFER,
+              "Memory buffer must be aligned at page boundary!");
+    return FM_ERR_UNALIGNED_BUFFER;
   }
   if ((size & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG("Memory size must be aligned to 4K!");
-    FM_ABORT();
+    FM_REPORT(FM_ERR_UNALIGNED_SIZE, "Memory size must be aligned to pages!");
+    return FM_ERR_UNALIGNED_SIZE;
   }
-  if ((size < 128 * 1024) || (size >= 16 * 1024 * 1024)) {
-    FM_DEBUG("Memory size must be between 128KB and 16MB!");
-    FM_ABORT();
+  if (size < FM_MIN_MEMORY_SIZE) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_SMALL, "Memory size must be at least 2 pages!");
+    return FM_ERR_BUFFER_TOO_SMALL;
+  }
+  if (size > FM_MAX_MEMORY_SIZE) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_LARGE,
+              "Memory size must be less than FM_MAX_MEMORY_SIZE!");
+    return FM_ERR_BUFFER_TOO_LARGE;
   }
 
-  __buffer_start = buffer;
-#ifdef FM_TEST_SUPPORT
-  __buffer_size = size;
-#endif
-  __meta = buffer;
+  return 0;
+}
+
+static void init_heap(heap_t *heap, void *buffer, size_t size,
+                      int zero_filled) {
+  heap->buffer_start = buffer;
+  heap->buffer_size = size;
+  heap->meta = buffer;
   if (!zero_filled) {
     memset(buffer, 0, FM_PAGE_SIZE);
   }
   region_t *region = (region_t *)(((uint8_t *)buffer) + FM_PAGE_SIZE);
   region->start_page = 1;
   region->pages = size / FM_PAGE_SIZE - 1;
-  c_list_init(&__free_regions);
-  c_list_link_after(&__free_regions, &region->link);
-  c_list_init(&__freed_memories);
+  c_list_init(&heap->free_regions);
+  c_list_link_after(&heap->free_regions, &region->link);
+  c_list_init(&heap->freed_memories);
+}
+
+int fm_lm_state_reinit(fm_lm_state_t *lm, void *buffer, size_t size,
+                       int zero_filled) {
+  int ret = fm_lm_check_buffer(buffer, size);
+  if (ret != 0) {
+    return ret;
+  }
+  lm = state_of(lm);
+  lm->live_blocks = 0;
+  lm->used_pages = 0;
+  // Extra regions are dropped as well
+  lm->heap_count = 1;
+  lm->first_region = 0;
+  init_heap(&lm->heaps[0], buffer, size, zero_filled);
+  return 0;
+}
+
+int fm_lm_reinit(void *buffer, size_t size, int zero_filled) {
+  return fm_lm_state_reinit(NULL, buffer, size, zero_filled);
+}
+
+void fm_lm_deinit() {
+  fm_lm_state_t *lm = &__default_state;
+  lm->live_blocks = 0;
+  lm->used_pages = 0;
+  lm->heap_count = 1;
+  lm->first_region = 0;
+  heap_t *heap = &lm->heaps[0];
+  heap->buffer_start = NULL;
+  heap->buffer_size = 0;
+  heap->meta = NULL;
+  c_list_init(&heap->free_regions);
+  c_list_init(&heap->freed_memories);
+}
+
+// Returns 1 if [start, start + size) overlaps any region other than skipped
+static int overlaps_heaps(const fm_lm_state_t *lm, size_t start, size_t size,
+                          const heap_t *skipped) {
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    const heap_t *heap = &lm->heaps[i];
+    size_t heap_start = (size_t)heap->buffer_start;
+    if (heap != skipped && start < heap_start + heap->buffer_size &&
+        heap_start < start + size) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+int fm_lm_add_region(void *buffer, size_t size, int zero_filled) {
+  fm_lm_state_t *lm = &__default_state;
+  if (lm->heaps[0].buffer_start == NULL) {
+    return FM_ERR_NOT_INITIALIZED;
+  }
+  int ret = fm_lm_check_buffer(buffer, size);
+  if (ret != 0) {
+    return ret;
+  }
+  if (lm->heap_count >= 1 + FM_MAX_EXTRA_REGIONS) {
+    FM_REPORT(FM_ERR_TOO_MANY_REGIONS, "Too many memory regions!");
+    return FM_ERR_TOO_MANY_REGIONS;
+  }
+  if (overlaps_heaps(lm, (size_t)buffer, size, NULL)) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "Memory region must not overlap existing ones!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+  init_heap(&lm->heaps[lm->heap_count], buffer, size, zero_filled);
+  lm->heap_count++;
+  return 0;
+}
+
+int fm_lm_reinit_split(void *reserved_buffer, size_t reserved_size,
+                       void *buffer, size_t size, int zero_filled) {
+  // Validate the second buffer first so reinit is never half done
+  int ret = fm_lm_check_buffer(buffer, size);
+  if (ret != 0) {
+    return ret;
+  }
+  if (FM_MAX_EXTRA_REGIONS == 0) {
+    FM_REPORT(FM_ERR_TOO_MANY_REGIONS, "Too many memory regions!");
+    return FM_ERR_TOO_MANY_REGIONS;
+  }
+  if ((size_t)reserved_buffer < (size_t)buffer + size &&
+      (size_t)buffer < (size_t)reserved_buffer + reserved_size) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "Memory region must not overlap existing ones!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+  ret = fm_lm_reinit(reserved_buffer, reserved_size, zero_filled);
+  if (ret != 0) {
+    return ret;
+  }
+  fm_lm_add_region(buffer, size, zero_filled);
+  __default_state.first_region = 1;
   return 0;
 }
 
-static void mark_alloced_pages(size_t first_page, size_t pages) {
+// Find the region containing ptr, the first region is picked when none does
+static heap_t *heap_of(fm_lm_state_t *lm, const void *ptr) {
+  size_t p = (size_t)ptr;
+  for (size_t i = 1; i < lm->heap_count; i++) {
+    size_t start = (size_t)lm->heaps[i].buffer_start;
+    if (p >= start && p < start + lm->heaps[i].buffer_size) {
+      return &lm->heaps[i];
+    }
+  }
+  return &lm->heaps[0];
+}
+
+int fm_lm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                      void **old_buffer, size_t *old_size) {
+  *old_buffer = __default_state.heaps[0].buffer_start;
+  *old_size = __default_state.heaps[0].buffer_size;
+  return fm_lm_reinit(new_buffer, new_size, zero_filled);
+}
+
+static void mark_alloced_pages(heap_t *heap, size_t first_page, size_t pages) {
   if (pages < 0xFF) {
-    __meta->pages[first_page] = (uint8_t)pages;
+    heap->meta->pages[first_page] = (uint8_t)pages;
   } else {
-    __meta->pages[first_page] = 0xFF;
+    heap->meta->pages[first_page] = 0xFF;
     size_t aligned_page = __fm_roundup(first_page + 1, 4);
-    *((uint32_t *)(&__meta->pages[aligned_page])) = (uint32_t)pages;
+    *((uint32_t *)(&heap->meta->pages[aligned_page])) = (uint32_t)pages;
   }
 }
 
-static size_t fetch_alloced_pages(size_t first_page) {
-  uint8_t pages = __meta->pages[first_page];
-  if (__builtin_expect(pages < 0xFF, 1)) {
+static size_t fetch_alloced_pages(const heap_t *heap, size_t first_page) {
+  uint8_t pages = heap->meta->pages[first_page];
+  if (__fm_likely(pages < 0xFF)) {
     return pages;
   }
   size_t aligned_page = __fm_roundup(first_page + 1, 4);
-  return *((uint32_t *)(&__meta->pages[aligned_page]));
+  return *((uint32_t *)(&heap->meta->pages[aligned_page]));
 }
 
-static inline size_t ptr_to_page(void *ptr) {
-  return (((size_t)ptr) - ((size_t)__buffer_start)) / FM_PAGE_SIZE;
+static inline size_t ptr_to_page(const heap_t *heap, const void *ptr) {
+  return (((size_t)ptr) - ((size_t)heap->buffer_start)) / FM_PAGE_SIZE;
 }
 
-static inline void *page_to_ptr(size_t page) {
-  return (void *)(__buffer_start + (page * FM_PAGE_SIZE));
+static inline void *page_to_ptr(const heap_t *heap, size_t page) {
+  return (void *)(heap->buffer_start + (page * FM_PAGE_SIZE));
 }
 
-static inline region_t *move_region(const region_t *src) {
-  region_t *dst = (region_t *)page_to_ptr(src->start_page);
+static inline region_t *move_region(const heap_t *heap, const region_t *src) {
+  region_t *dst = (region_t *)page_to_ptr(heap, src->start_page);
   if (dst == src) {
     return dst;
   }
@@ -744,106 +1641,254 @@ static inline region_t *move_region(const region_t *src) {
   return dst;
 }
 
-void fm_lm_free(void *ptr) {
-#ifdef FM_GUARDS
-  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG(
-        "Pointer passed to free is not aligned, which might be tampered with!");
-    FM_ABORT();
+static size_t count_pages(const CList *list) {
+  size_t pages = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    pages += c_list_entry(iter, region_t, link)->pages;
   }
-#endif
-  size_t first_page = ptr_to_page(ptr);
-  size_t pages = fetch_alloced_pages(first_page);
-  region_t *region = (region_t *)ptr;
-  region->start_page = first_page;
-  region->pages = pages;
-  c_list_link_tail(&__freed_memories, &region->link);
+  return pages;
 }
 
-static size_t alloc_designated_free_pages(size_t start_page,
-                                          size_t requested_pages) {
-  for (CList *iter = __free_regions.next; iter != &__free_regions;
-       iter = iter->next) {
-    region_t *region = c_list_entry(iter, region_t, link);
-    if (region->start_page == start_page && region->pages >= requested_pages) {
-      size_t result = region->start_page;
-      region->start_page += requested_pages;
-      region->pages -= requested_pages;
-      if (region->pages == 0) {
-        c_list_unlink(iter);
-      } else {
-        move_region(region);
-      }
-      return result;
+void fm_lm_stats(size_t *total_pages, size_t *free_pages) {
+  *total_pages = 0;
+  *free_pages = 0;
+  for (size_t i = 0; i < __default_state.heap_count; i++) {
+    heap_t *heap = &__default_state.heaps[i];
+    if (heap->buffer_size > 0) {
+      // The first page is set aside for accounting purposes
+      *total_pages += heap->buffer_size / FM_PAGE_SIZE - 1;
     }
+    *free_pages +=
+        count_pages(&heap->free_regions) + count_pages(&heap->freed_memories);
   }
-  return 0;
 }
 
-static size_t alloc_free_pages(size_t requested_pages) {
-  for (CList *iter = __free_regions.next; iter != &__free_regions;
-       iter = iter->next) {
-    region_t *region = c_list_entry(iter, region_t, link);
-    if (region->pages >= requested_pages) {
-      size_t result = region->start_page;
-      region->start_page += requested_pages;
-      region->pages -= requested_pages;
-      if (region->pages == 0) {
-        c_list_unlink(iter);
-      } else {
-        // we need to move region to a new location, since the old location
-        // has been allocated
-        move_region(region);
+#ifdef FM_FILL_ON_FREE
+// The region header used by freed memory is kept intact
+static void fill(const heap_t *heap, void *ptr) {
+  size_t pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+  memset(((uint8_t *)ptr) + sizeof(region_t), FM_FILL_PATTERN,
+         pages * FM_PAGE_SIZE - sizeof(region_t));
+}
+
+static int check_fill(const heap_t *heap, void *ptr) {
+  size_t pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+  return __fm_check_fill(((uint8_t *)ptr) + sizeof(region_t),
+                         pages * FM_PAGE_SIZE - sizeof(region_t));
+}
+
+void fm_lm_fill(void *ptr) { fill(heap_of(&__default_state, ptr), ptr); }
+
+int fm_lm_check_fill(void *ptr) {
+  return check_fill(heap_of(&__default_state, ptr), ptr);
+}
+#endif
+
+// Number of pages in the free region starting at page, or 0 if there is none
+static size_t free_pages_at(const heap_t *heap, size_t page) {
+  const CList *lists[] = {&heap->free_regions, &heap->freed_memories};
+  for (size_t i = 0; i < 2; i++) {
+    for (CList *iter = lists[i]->next; iter != lists[i]; iter = iter->next) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      if (region->start_page == page) {
+        return region->pages;
       }
-      return result;
     }
   }
   return 0;
 }
 
-void *fm_lm_realloc(void *ptr, size_t size, int t) {
-  if (ptr == NULL) {
-    return fm_lm_malloc(size, t);
-  }
-#ifdef FM_GUARDS
-  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG(
-        "Pointer passed to free is not aligned, which might be tampered with!");
-    FM_ABORT();
-  }
-#endif
-  size = __fm_roundup(size, FM_PAGE_SIZE);
-  size_t new_pages = size / FM_PAGE_SIZE;
-  size_t first_page = ptr_to_page(ptr);
-  size_t pages = fetch_alloced_pages(first_page);
-  if (new_pages <= pages) {
-    return ptr;
-  }
-  size_t succeeding_pages =
-      alloc_designated_free_pages(first_page + pages, new_pages - pages);
-  if (succeeding_pages != 0) {
-    // If there are enough free pages that are immediately after current
-    // allocated memory, we won't need to move the pages elsewhere
-    return ptr;
+void fm_lm_walk(fm_walk_cb_t callback, void *user) {
+  for (size_t i = 0; i < __default_state.heap_count; i++) {
+    const heap_t *heap = &__default_state.heaps[i];
+    size_t total_pages = heap->buffer_size / FM_PAGE_SIZE;
+    size_t page = 1;
+    while (page < total_pages) {
+      size_t pages = free_pages_at(heap, page);
+      if (pages == 0) {
+        pages = fetch_alloced_pages(heap, page);
+        if (pages == 0) {
+          FM_REPORT(FM_ERR_CORRUPTED, "Page is neither free nor allocated!");
+          FM_ABORT();
+        }
+        callback(page_to_ptr(heap, page), pages * FM_PAGE_SIZE, user);
+      }
+      page += pages;
+    }
   }
-  void *p = fm_lm_malloc(size, t);
-  if (p != NULL) {
-    memcpy(p, ptr, pages * FM_PAGE_SIZE);
-    fm_lm_free(ptr);
+}
+
+static int report(fm_heap_error_t *error, int kind, void *address) {
+  if (error != NULL) {
+    error->kind = kind;
+    error->address = address;
   }
-  return p;
+  return kind;
 }
 
-static size_t alloc_free_pages_reverse(size_t requested_pages) {
-  for (CList *iter = __free_regions.prev; iter != &__free_regions;
-       iter = iter->prev) {
+// Free lists are bounded by the number of pages in the region
+static int verify_list(const heap_t *heap, const CList *list,
+                       size_t total_pages, fm_heap_error_t *error) {
+  size_t steps = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    if (iter->next->prev != iter || ++steps >= total_pages) {
+      return report(error, FM_HEAP_FREE_LIST_CYCLE, iter);
+    }
     region_t *region = c_list_entry(iter, region_t, link);
-    if (region->pages >= requested_pages) {
-      // The first page is untouched, there is no need to move region struct.
-      size_t result = region->start_page + region->pages - requested_pages;
+    int misplaced = (void *)region != page_to_ptr(heap, region->start_page);
+#ifndef FM_MANUAL_INIT
+    // The initial region of the static buffer lives outside of the buffer
+    misplaced = misplaced && region != &__initial_region;
+#endif
+    if (region->start_page == 0 || region->pages == 0 ||
+        region->start_page + region->pages > total_pages || misplaced) {
+      return report(error, FM_HEAP_CORRUPTED_HEADER, region);
+    }
+  }
+  return FM_HEAP_OK;
+}
+
+int fm_lm_verify(fm_heap_error_t *error) {
+  fm_lm_state_t *lm = &__default_state;
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    heap_t *heap = &lm->heaps[i];
+    size_t total_pages = heap->buffer_size / FM_PAGE_SIZE;
+    // Lists are walked below, so they must be checked first
+    int ret = verify_list(heap, &heap->free_regions, total_pages, error);
+    if (ret == FM_HEAP_OK) {
+      ret = verify_list(heap, &heap->freed_memories, total_pages, error);
+    }
+    if (ret != FM_HEAP_OK) {
+      return ret;
+    }
+    size_t page = 1;
+    while (page < total_pages) {
+      size_t pages = free_pages_at(heap, page);
+      if (pages == 0) {
+        pages = fetch_alloced_pages(heap, page);
+      }
+      if (pages == 0 || pages > total_pages - page) {
+        return report(error, FM_HEAP_CORRUPTED_HEADER, page_to_ptr(heap, page));
+      }
+      page += pages;
+    }
+  }
+  return FM_HEAP_OK;
+}
+
+size_t fm_lm_state_block_size(fm_lm_state_t *lm, const void *ptr) {
+  lm = state_of(lm);
+  if (!fm_lm_state_contains(lm, ptr) ||
+      ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0)) {
+    return 0;
+  }
+  // Page counts are left behind when blocks are freed, so the pages have to
+  // be walked from the start to tell whether ptr is a live block.
+  const heap_t *heap = heap_of(lm, ptr);
+  size_t target = ptr_to_page(heap, ptr);
+  size_t page = 1;
+  while (page <= target) {
+    size_t pages = free_pages_at(heap, page);
+    if (pages > 0) {
+      page += pages;
+      continue;
+    }
+    pages = fetch_alloced_pages(heap, page);
+    if (pages == 0) {
+      return 0;
+    }
+    if (page == target) {
+      return pages * FM_PAGE_SIZE;
+    }
+    page += pages;
+  }
+  return 0;
+}
+
+size_t fm_lm_block_size(const void *ptr) {
+  return fm_lm_state_block_size(NULL, ptr);
+}
+
+size_t fm_lm_live_blocks() { return __default_state.live_blocks; }
+
+size_t fm_lm_used_pages() { return __default_state.used_pages; }
+
+size_t fm_lm_regions() { return __default_state.heap_count; }
+
+void fm_lm_buffer_range(void **start, size_t *size) {
+  *start = __default_state.heaps[0].buffer_start;
+  *size = __default_state.heaps[0].buffer_size;
+}
+
+int fm_lm_state_contains(fm_lm_state_t *lm, const void *ptr) {
+  lm = state_of(lm);
+  size_t p = (size_t)ptr;
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    size_t start = (size_t)lm->heaps[i].buffer_start;
+    if ((lm->heaps[i].buffer_start != NULL) && (p >= start + FM_PAGE_SIZE) &&
+        (p < start + lm->heaps[i].buffer_size)) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+int fm_lm_contains(const void *ptr) { return fm_lm_state_contains(NULL, ptr); }
+
+static int __deterministic = 0;
+static void restore_freed_region(heap_t *heap, region_t *free_region);
+
+void fm_lm_state_free(fm_lm_state_t *lm, void *ptr) {
+#ifdef FM_GUARDS
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
+    FM_REPORT(
+        FM_ERR_BAD_POINTER,
+        "Pointer passed to free is not aligned, which might be tampered with!");
+    FM_ABORT();
+  }
+#endif
+  lm = state_of(lm);
+  heap_t *heap = heap_of(lm, ptr);
+  size_t first_page = ptr_to_page(heap, ptr);
+  size_t pages = fetch_alloced_pages(heap, first_page);
+#ifdef FM_FILL_ON_FREE
+  fill(heap, ptr);
+#ifdef FM_TEST_SUPPORT
+  // Checked before the pages are merged into the free ones
+  if (check_fill(heap, ptr) != 0) {
+    FM_REPORT(FM_ERR_CORRUPTED, "Memory is not filled after being freed!");
+    FM_ABORT();
+  }
+#endif
+#endif
+  region_t *region = (region_t *)ptr;
+  region->start_page = first_page;
+  region->pages = pages;
+  if (__deterministic) {
+    // Merging right away leaves no trace of the order blocks are freed in
+    restore_freed_region(heap, region);
+  } else {
+    c_list_link_tail(&heap->freed_memories, &region->link);
+  }
+  lm->live_blocks--;
+  lm->used_pages -= pages;
+}
+
+void fm_lm_free(void *ptr) { fm_lm_state_free(NULL, ptr); }
+
+static size_t alloc_designated_free_pages(heap_t *heap, size_t start_page,
+                                          size_t requested_pages) {
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+       iter = iter->next) {
+    region_t *region = c_list_entry(iter, region_t, link);
+    if (region->start_page == start_page && region->pages >= requested_pages) {
+      size_t result = region->start_page;
+      region->start_page += requested_pages;
       region->pages -= requested_pages;
       if (region->pages == 0) {
         c_list_unlink(iter);
+      } else {
+        move_region(heap, region);
       }
       return result;
     }
@@ -851,10 +1896,194 @@ static size_t alloc_free_pages_reverse(size_t requested_pages) {
   return 0;
 }
 
-static void merged_consecutive_pages() {
-  CList *prev_item = __free_regions.next;
+static size_t take_front_pages(const heap_t *heap, region_t *region,
+                               size_t requested_pages) {
+  size_t result = region->start_page;
+  region->start_page += requested_pages;
+  region->pages -= requested_pages;
+  if (region->pages == 0) {
+    c_list_unlink(&region->link);
+  } else {
+    // we need to move region to a new location, since the old location
+    // has been allocated
+    move_region(heap, region);
+  }
+  return result;
+}
+
+static size_t take_back_pages(region_t *region, size_t requested_pages) {
+  // The first page is untouched, there is no need to move region struct.
+  size_t result = region->start_page + region->pages - requested_pages;
+  region->pages -= requested_pages;
+  if (region->pages == 0) {
+    c_list_unlink(&region->link);
+  }
+  return result;
+}
+
+#ifdef FM_HARDENING
+static int __random_enabled = 0;
+static uint64_t __random_state = 0;
+
+void fm_lm_set_random_seed(uint64_t seed) {
+  __random_enabled = 1;
+  __random_state = seed;
+}
+
+// Reservoir sampling picks one of all fitting regions with equal possibility
+// in a single pass.
+static region_t *pick_random_region(const heap_t *heap,
+                                    size_t requested_pages) {
+  region_t *picked = NULL;
+  uint64_t fitting = 0;
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+       iter = iter->next) {
+    region_t *region = c_list_entry(iter, region_t, link);
+    if (region->pages >= requested_pages) {
+      fitting++;
+      if (__fm_random_next(&__random_state) % fitting == 0) {
+        picked = region;
+      }
+    }
+  }
+  return picked;
+}
+#endif
+
+static size_t alloc_free_pages(heap_t *heap, size_t requested_pages) {
+#ifdef FM_HARDENING
+  if (__random_enabled && !__deterministic) {
+    region_t *region = pick_random_region(heap, requested_pages);
+    return (region != NULL) ? take_front_pages(heap, region, requested_pages)
+                            : 0;
+  }
+#endif
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+       iter = iter->next) {
+    region_t *region = c_list_entry(iter, region_t, link);
+    if (region->pages >= requested_pages) {
+      return take_front_pages(heap, region, requested_pages);
+    }
+  }
+  return 0;
+}
+
+void *fm_lm_state_realloc_in_place(fm_lm_state_t *lm, void *ptr, size_t size) {
+#ifdef FM_GUARDS
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
+    FM_REPORT(
+        FM_ERR_BAD_POINTER,
+        "Pointer passed to free is not aligned, which might be tampered with!");
+    FM_ABORT();
+  }
+#endif
+  lm = state_of(lm);
+  heap_t *heap = heap_of(lm, ptr);
+  // Rounding huge sizes up would wrap around to 0 pages
+  if (size > heap->buffer_size - FM_PAGE_SIZE) {
+    return NULL;
+  }
+  size_t new_pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+  size_t first_page = ptr_to_page(heap, ptr);
+  size_t pages = fetch_alloced_pages(heap, first_page);
+  if (new_pages <= pages) {
+    return ptr;
+  }
+  size_t succeeding_pages = alloc_designated_free_pages(
+      heap, first_page + pages, new_pages - pages);
+  if (succeeding_pages != 0) {
+    // If there are enough free pages that are immediately after current
+    // allocated memory, we won't need to move the pages elsewhere
+    mark_alloced_pages(heap, first_page, new_pages);
+    lm->used_pages += new_pages - pages;
+    return ptr;
+  }
+  return NULL;
+}
+
+void *fm_lm_realloc_in_place(void *ptr, size_t size) {
+  return fm_lm_state_realloc_in_place(NULL, ptr, size);
+}
+
+void *fm_lm_state_shrink_release(fm_lm_state_t *lm, void *ptr, size_t size) {
+#ifdef FM_GUARDS
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
+    FM_REPORT(
+        FM_ERR_BAD_POINTER,
+        "Pointer passed to free is not aligned, which might be tampered with!");
+    FM_ABORT();
+  }
+#endif
+  lm = state_of(lm);
+  heap_t *heap = heap_of(lm, ptr);
+  size_t first_page = ptr_to_page(heap, ptr);
+  size_t pages = fetch_alloced_pages(heap, first_page);
+  if (size > pages * FM_PAGE_SIZE) {
+    return NULL;
+  }
+  // At least one page is kept
+  size_t new_pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+  if (new_pages == 0) {
+    new_pages = 1;
+  }
+  if (new_pages < pages) {
+    // Split the tail off as a block of its own, which is then freed like any
+    // other block
+    mark_alloced_pages(heap, first_page, new_pages);
+    mark_alloced_pages(heap, first_page + new_pages, pages - new_pages);
+    lm->live_blocks++;
+    fm_lm_state_free(lm, page_to_ptr(heap, first_page + new_pages));
+  }
+  return ptr;
+}
+
+void *fm_lm_shrink_release(void *ptr, size_t size) {
+  return fm_lm_state_shrink_release(NULL, ptr, size);
+}
+
+void *fm_lm_state_realloc(fm_lm_state_t *lm, void *ptr, size_t size, int t) {
+  if (ptr == NULL) {
+    return fm_lm_state_malloc(lm, size, t);
+  }
+  if (fm_lm_state_realloc_in_place(lm, ptr, size) != NULL) {
+    return ptr;
+  }
+  heap_t *heap = heap_of(state_of(lm), ptr);
+  size_t pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+  void *p = fm_lm_state_malloc(lm, size, t);
+  if (p != NULL) {
+    memcpy(p, ptr, pages * FM_PAGE_SIZE);
+    fm_lm_state_free(lm, ptr);
+  }
+  return p;
+}
+
+void *fm_lm_realloc(void *ptr, size_t size, int t) {
+  return fm_lm_state_realloc(NULL, ptr, size, t);
+}
+
+static size_t alloc_free_pages_reverse(heap_t *heap, size_t requested_pages) {
+#ifdef FM_HARDENING
+  if (__random_enabled && !__deterministic) {
+    region_t *region = pick_random_region(heap, requested_pages);
+    return (region != NULL) ? take_back_pages(region, requested_pages) : 0;
+  }
+#endif
+  for (CList *iter = heap->free_regions.prev; iter != &heap->free_regions;
+       iter = iter->prev) {
+    region_t *region = c_list_entry(iter, region_t, link);
+    if (region->pages >= requested_pages) {
+      return take_back_pages(region, requested_pages);
+    }
+  }
+  return 0;
+}
+
+static void merged_consecutive_pages(heap_t *heap) {
+  CList *prev_item = heap->free_regions.next;
   CList *current_item = prev_item->next;
-  while (prev_item != &__free_regions && current_item != &__free_regions) {
+  while (prev_item != &heap->free_regions &&
+         current_item != &heap->free_regions) {
     region_t *prev_region = c_list_entry(prev_item, region_t, link);
     region_t *current_region = c_list_entry(current_item, region_t, link);
 
@@ -870,15 +2099,15 @@ static void merged_consecutive_pages() {
   }
 }
 
-static void restore_freed_region(region_t *free_region) {
-  CList *prev_item = &__free_regions;
-  for (CList *iter = __free_regions.next; iter != &__free_regions;
+static void restore_freed_region(heap_t *heap, region_t *free_region) {
+  CList *prev_item = &heap->free_regions;
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
        iter = iter->next) {
     region_t *region = c_list_entry(iter, region_t, link);
     if (free_region->start_page < region->start_page) {
       // Insert pages between prev_item and iter
       int inserted = 0;
-      if (prev_item != &__free_regions) {
+      if (prev_item != &heap->free_regions) {
         region_t *prev_region = c_list_entry(prev_item, region_t, link);
         if (prev_region->start_page + prev_region->pages ==
             free_region->start_page) {
@@ -892,12 +2121,12 @@ static void restore_freed_region(region_t *free_region) {
         region->start_page = free_region->start_page;
         region->pages += free_region->pages;
         // Attach free pages to current item, we need to move region here
-        region_t *new_region = move_region(region);
+        region_t *new_region = move_region(heap, region);
         iter = &new_region->link;
         inserted = 1;
       }
       if (inserted) {
-        merged_consecutive_pages();
+        merged_consecutive_pages(heap);
       } else {
         // Attach free pages as a new region
         c_list_link_before(iter, &free_region->link);
@@ -908,244 +2137,2185 @@ static void restore_freed_region(region_t *free_region) {
   }
   // Insert pages at the very end of the page. Notice at this stage, prev_item
   // contains the last item(if available)
-  c_list_link_tail(&__free_regions, &free_region->link);
-  merged_consecutive_pages();
+  c_list_link_tail(&heap->free_regions, &free_region->link);
+  merged_consecutive_pages(heap);
 }
 
-static void restore_all_freed_memories() {
-  CList *iter = __freed_memories.next;
-  while (iter != &__freed_memories) {
+static void restore_all_freed_memories(heap_t *heap) {
+  CList *iter = heap->freed_memories.next;
+  while (iter != &heap->freed_memories) {
     region_t *region = c_list_entry(iter, region_t, link);
     iter = iter->next;
-    restore_freed_region(region);
+    restore_freed_region(heap, region);
   }
-  c_list_init(&__freed_memories);
+  c_list_init(&heap->freed_memories);
 }
 
-static inline size_t alloc(size_t pages, int t) {
-  if (t == FM_LM_T_TRANSIENT) {
-    return alloc_free_pages(pages);
-  } else {
-    return alloc_free_pages_reverse(pages);
+void fm_lm_set_deterministic(int enabled) {
+  __deterministic = enabled;
+  if (enabled) {
+    for (size_t i = 0; i < __default_state.heap_count; i++) {
+      restore_all_freed_memories(&__default_state.heaps[i]);
+    }
   }
 }
 
-void *fm_lm_malloc(size_t size, int t) {
-  size = __fm_roundup(size, FM_PAGE_SIZE);
-  size_t pages = size / FM_PAGE_SIZE;
-
-  size_t page = alloc(pages, t);
-  if (page == 0) {
-    restore_all_freed_memories();
-    page = alloc(pages, t);
+// Find the first(or last when reverse is set) page in region, that is aligned
+// on align bytes and followed by enough pages. 0 is returned if none exists.
+static size_t find_aligned_page(const heap_t *heap, const region_t *region,
+                                size_t pages, size_t align, int reverse) {
+  if (region->pages < pages) {
+    return 0;
   }
-  if (page == 0) {
-    return NULL;
+  size_t first = (size_t)page_to_ptr(heap, region->start_page);
+  size_t last =
+      (size_t)page_to_ptr(heap, region->start_page + region->pages - pages);
+  size_t p = reverse ? __fm_rounddown(last, align) : __fm_roundup(first, align);
+  if (p < first || p > last) {
+    return 0;
   }
-  mark_alloced_pages(page, pages);
-  return page_to_ptr(page);
+  return ptr_to_page(heap, (void *)p);
 }
 
-/* slab-malloc.c */
-/* #include "slab-malloc.h" */
-
-#include <string.h>
-
-/* #include "c-list.h" */
-/* #include "linear-malloc.h" */
-/* #include "utils.h" */
-
-#define FM_SM_INVALID_SLAB 0xFFFFFFFF
-
-static size_t slab_sizes[] = {32, 64, 128, 512, 1024};
-static CList slab_lists[] = {
-    C_LIST_INIT(slab_lists[0]), C_LIST_INIT(slab_lists[1]),
-    C_LIST_INIT(slab_lists[2]), C_LIST_INIT(slab_lists[3]),
-    C_LIST_INIT(slab_lists[4]),
-};
-
-int fm_sm_reinit(void *buffer, size_t size, int zero_filled) {
-  int ret = fm_lm_reinit(buffer, size, zero_filled);
-  if (ret != 0) {
-    return ret;
+// Take pages from the middle of a region, the region might be split into two.
+static size_t take_middle_pages(const heap_t *heap, region_t *region,
+                                size_t page, size_t requested_pages) {
+  if (page == region->start_page) {
+    return take_front_pages(heap, region, requested_pages);
   }
-  for (size_t i = 0; i < sizeof(slab_lists) / sizeof(CList); i++) {
-    c_list_init(&slab_lists[i]);
+  size_t tail_pages =
+      region->start_page + region->pages - page - requested_pages;
+  region->pages = page - region->start_page;
+  if (tail_pages > 0) {
+    region_t *tail = (region_t *)page_to_ptr(heap, page + requested_pages);
+    tail->start_page = page + requested_pages;
+    tail->pages = tail_pages;
+    c_list_link_after(&region->link, &tail->link);
   }
-  return 0;
+  return page;
 }
 
-static size_t slab_index(size_t size) {
-  // Right now we only have 5 slabs, a linear search shall be enough,
-  // a binary search might be needed once we have more slabs.
-  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
-    if (size <= slab_sizes[i]) {
-      return i;
+static size_t alloc_aligned(heap_t *heap, size_t pages, size_t align, int t) {
+  if (t == FM_LM_T_TRANSIENT) {
+    for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+         iter = iter->next) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      size_t page = find_aligned_page(heap, region, pages, align, 0);
+      if (page != 0) {
+        return take_middle_pages(heap, region, page, pages);
+      }
+    }
+  } else {
+    for (CList *iter = heap->free_regions.prev; iter != &heap->free_regions;
+         iter = iter->prev) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      size_t page = find_aligned_page(heap, region, pages, align, 1);
+      if (page != 0) {
+        return take_middle_pages(heap, region, page, pages);
+      }
     }
   }
-  return FM_SM_INVALID_SLAB;
+  return 0;
 }
 
-typedef struct page_meta_t {
-  CList link;
-  uint64_t bitmap[2];
-  size_t size;
-  size_t count;
-  size_t slab_index;
-  size_t _padding;
-} page_meta_t;
-
-#define PAGE_META_RESERVED_SIZE 64
+static inline size_t alloc(heap_t *heap, size_t pages, int t) {
+  if (t == FM_LM_T_TRANSIENT) {
+    return alloc_free_pages(heap, pages);
+  } else {
+    return alloc_free_pages_reverse(heap, pages);
+  }
+}
 
-static size_t ptr_to_index(const page_meta_t *meta, const void *ptr) {
-  size_t p = (size_t)ptr;
-  size_t base = ((size_t)meta) + PAGE_META_RESERVED_SIZE;
-#ifdef FM_GUARDS
-  if ((p - base) % meta->size != 0) {
-    FM_DEBUG("Pointer does not lie on the boundary of slab allocated value!");
-    FM_ABORT();
+// Allocate from regions [first, end), which are tried in the order they are
+// added
+static void *malloc_in(fm_lm_state_t *lm, size_t first, size_t end,
+                       size_t size, int t) {
+  if (lm->heaps[0].buffer_start == NULL) {
+    __fm_set_error(FM_ERR_NOT_INITIALIZED);
+    return NULL;
   }
-  if ((p - base) / meta->size >= meta->count) {
-    FM_DEBUG("Pointer exceeds slab count!");
-    FM_ABORT();
+  int too_large = 1;
+  for (size_t i = first; i < end; i++) {
+    heap_t *heap = &lm->heaps[i];
+    // This also prevents overflows when rounding up
+    if (size > heap->buffer_size - FM_PAGE_SIZE) {
+      continue;
+    }
+    too_large = 0;
+    size_t pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+
+    size_t page = alloc(heap, pages, t);
+    if (page == 0) {
+      restore_all_freed_memories(heap);
+      page = alloc(heap, pages, t);
+    }
+    if (page != 0) {
+      mark_alloced_pages(heap, page, pages);
+      lm->live_blocks++;
+      lm->used_pages += pages;
+      return page_to_ptr(heap, page);
+    }
   }
-#endif
-  return (p - base) / meta->size;
+  __fm_set_error(too_large ? FM_ERR_TOO_LARGE : FM_ERR_NO_MEMORY);
+  return NULL;
 }
 
-static void *index_to_ptr(const page_meta_t *meta, size_t index) {
-#ifdef FM_GUARDS
-  if (index >= meta->count) {
-    FM_DEBUG("Invalid index in slab!");
-    FM_ABORT();
+void *fm_lm_state_malloc(fm_lm_state_t *lm, size_t size, int t) {
+  lm = state_of(lm);
+  return malloc_in(lm, lm->first_region, lm->heap_count, size, t);
+}
+
+void *fm_lm_state_malloc_reserved(fm_lm_state_t *lm, size_t size, int t) {
+  lm = state_of(lm);
+  if (lm->first_region == 0) {
+    return fm_lm_state_malloc(lm, size, t);
   }
-#endif
-  return (void *)(((size_t)meta) + PAGE_META_RESERVED_SIZE +
-                  index * meta->size);
+  return malloc_in(lm, 0, 1, size, t);
 }
 
-static int bitmap_all_cleared(const page_meta_t *meta) {
-  return (meta->bitmap[0] == 0) && (meta->bitmap[1] == 0);
+void *fm_lm_malloc(size_t size, int t) {
+  return fm_lm_state_malloc(NULL, size, t);
 }
 
-static int bitmap_all_used(const page_meta_t *meta) {
-  return (size_t)(__builtin_popcountl(meta->bitmap[0]) +
-                  __builtin_popcountl(meta->bitmap[1])) == meta->count;
+void *fm_lm_malloc_reserved(size_t size, int t) {
+  return fm_lm_state_malloc_reserved(NULL, size, t);
 }
 
-static size_t bitmap_next_free(const page_meta_t *meta) {
-  size_t zeros = FM_SM_INVALID_SLAB;
-  if (meta->bitmap[0] != (uint64_t)-1) {
-    zeros = __builtin_ctzl(~(meta->bitmap[0]));
-  } else if (meta->bitmap[1] != (uint64_t)-1) {
-    zeros = 64 + __builtin_ctzl(~(meta->bitmap[1]));
+static size_t max_alloc_size_in(fm_lm_state_t *lm, size_t first, size_t end) {
+  if (lm->heaps[0].buffer_start == NULL) {
+    return 0;
   }
-  if (zeros >= meta->count) {
-    return FM_SM_INVALID_SLAB;
+  size_t max_pages = 0;
+  for (size_t i = first; i < end; i++) {
+    heap_t *heap = &lm->heaps[i];
+    // A failing allocation would merge freed memory before giving up
+    restore_all_freed_memories(heap);
+    for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+         iter = iter->next) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      if (region->pages > max_pages) {
+        max_pages = region->pages;
+      }
+    }
   }
-  return zeros;
-}
-
-static void bitmap_set(page_meta_t *meta, size_t index) {
-  meta->bitmap[index / 64] |= ((uint64_t)1) << (index % 64);
+  return max_pages * FM_PAGE_SIZE;
 }
 
-static void bitmap_clear(page_meta_t *meta, size_t index) {
-  meta->bitmap[index / 64] &= (~(((uint64_t)1) << (index % 64)));
+size_t fm_lm_max_alloc_size() {
+  return max_alloc_size_in(&__default_state, __default_state.first_region,
+                           __default_state.heap_count);
 }
 
-void fm_sm_free(void *ptr) {
-  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
-    fm_lm_free(ptr);
-    return;
-  }
-  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
-  size_t element_index = ptr_to_index(meta, ptr);
-  int all_used = bitmap_all_used(meta);
-  bitmap_clear(meta, element_index);
-  if (all_used) {
-    c_list_link_tail(&slab_lists[meta->slab_index], &meta->link);
-    FM_DEBUG("Retrieving previously fully used slab: %p %ld\n", meta,
-             meta->size);
+size_t fm_lm_max_alloc_size_reserved() {
+  if (__default_state.first_region == 0) {
+    return fm_lm_max_alloc_size();
   }
+  return max_alloc_size_in(&__default_state, 0, 1);
 }
 
-void *fm_sm_realloc(void *ptr, size_t size) {
-  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
-    return fm_lm_realloc(ptr, size, FM_LM_T_TRANSIENT);
+void *fm_lm_aligned_malloc(size_t size, size_t align, int t) {
+  if (align == 0 || (align & (align - 1)) != 0) {
+    __fm_set_error(FM_ERR_BAD_ALIGNMENT);
+    return NULL;
   }
-  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
-  if (size <= meta->size) {
-    return ptr;
+  // All pages are aligned on page boundary already
+  if (align <= FM_PAGE_SIZE) {
+    return fm_lm_malloc(size, t);
   }
-  void *p = fm_sm_malloc(size);
+  fm_lm_state_t *lm = &__default_state;
+  if (lm->heaps[0].buffer_start == NULL) {
+    __fm_set_error(FM_ERR_NOT_INITIALIZED);
+    return NULL;
+  }
+  int too_large = 1;
+  for (size_t i = lm->first_region; i < lm->heap_count; i++) {
+    heap_t *heap = &lm->heaps[i];
+    if (size > heap->buffer_size - FM_PAGE_SIZE || align > heap->buffer_size) {
+      continue;
+    }
+    too_large = 0;
+    size_t pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+    if (pages == 0) {
+      pages = 1;
+    }
+
+    size_t page = alloc_aligned(heap, pages, align, t);
+    if (page == 0) {
+      restore_all_freed_memories(heap);
+      page = alloc_aligned(heap, pages, align, t);
+    }
+    if (page != 0) {
+      mark_alloced_pages(heap, page, pages);
+      lm->live_blocks++;
+      lm->used_pages += pages;
+      return page_to_ptr(heap, page);
+    }
+  }
+  __fm_set_error(too_large ? FM_ERR_TOO_LARGE : FM_ERR_NO_MEMORY);
+  return NULL;
+}
+
+void *fm_lm_calloc(size_t n, size_t size, int t) {
+  size_t total;
+  if (__fm_mul_overflow(n, size, &total)) {
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    return NULL;
+  }
+  void *p = fm_lm_malloc(total, t);
+  if (p != NULL) {
+    // Freed pages are never cleared, the whole block is zeroed here
+    memset(p, 0, __fm_roundup(total, FM_PAGE_SIZE));
+  }
+  return p;
+}
+
+// Pages between old_size and new_size become a new free region
+static void grow(heap_t *heap, size_t old_size, size_t new_size) {
+  heap->buffer_size = new_size;
+  if (new_size > old_size) {
+    size_t old_pages = old_size / FM_PAGE_SIZE;
+    region_t *region = (region_t *)page_to_ptr(heap, old_pages);
+    region->start_page = old_pages;
+    region->pages = new_size / FM_PAGE_SIZE - old_pages;
+    restore_freed_region(heap, region);
+  }
+}
+
+int fm_lm_migrate(void *new_buffer, size_t new_size, void **old_buffer,
+                  size_t *old_size) {
+  // Only the first region is migrated
+  heap_t *heap = &__default_state.heaps[0];
+  if (heap->buffer_start == NULL) {
+    return FM_ERR_NOT_INITIALIZED;
+  }
+  int ret = fm_lm_check_buffer(new_buffer, new_size);
+  if (ret != 0) {
+    return ret;
+  }
+  if (new_size < heap->buffer_size) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_SMALL,
+              "New memory buffer must be larger than the current one!");
+    return FM_ERR_BUFFER_TOO_SMALL;
+  }
+  size_t start = (size_t)heap->buffer_start;
+  size_t end = start + heap->buffer_size;
+  size_t new_start = (size_t)new_buffer;
+  if (overlaps_heaps(&__default_state, new_start, new_size, NULL)) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "New memory buffer must not overlap existing regions!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+
+  *old_buffer = heap->buffer_start;
+  *old_size = heap->buffer_size;
+  // Page indices are relative to the buffer start, only pointers linking
+  // regions need to be adjusted.
+  memcpy(new_buffer, heap->buffer_start, heap->buffer_size);
+  size_t delta = new_start - start;
+  __fm_relocate_list(&heap->free_regions, start, end, delta);
+  __fm_relocate_list(&heap->freed_memories, start, end, delta);
+  heap->buffer_start = new_buffer;
+  heap->meta = new_buffer;
+  grow(heap, *old_size, new_size);
+  return 0;
+}
+
+int fm_lm_extend(size_t additional_bytes) {
+  heap_t *heap = &__default_state.heaps[0];
+  if (heap->buffer_start == NULL) {
+    return FM_ERR_NOT_INITIALIZED;
+  }
+  if ((additional_bytes & (FM_PAGE_SIZE - 1)) != 0) {
+    FM_REPORT(FM_ERR_UNALIGNED_SIZE, "Extended size must be aligned to pages!");
+    return FM_ERR_UNALIGNED_SIZE;
+  }
+  if (additional_bytes > FM_MAX_MEMORY_SIZE - heap->buffer_size) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_LARGE,
+              "Memory size must be less than FM_MAX_MEMORY_SIZE!");
+    return FM_ERR_BUFFER_TOO_LARGE;
+  }
+  size_t end = (size_t)heap->buffer_start + heap->buffer_size;
+  if (overlaps_heaps(&__default_state, end, additional_bytes, heap)) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "Extended memory must not overlap other regions!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+  grow(heap, heap->buffer_size, heap->buffer_size + additional_bytes);
+  return 0;
+}
+
+static size_t count_regions(const CList *list) {
+  size_t count = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    count++;
+  }
+  return count;
+}
+
+static void put_regions(void *out, size_t len, size_t *pos,
+                        const CList *list) {
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    region_t *region = c_list_entry(iter, region_t, link);
+    __fm_snapshot_put(out, len, pos, &region, sizeof(region_t *));
+    __fm_snapshot_put(out, len, pos, region, sizeof(region_t));
+  }
+}
+
+// Snapshot layout: address and copy of the state, bookkeeping pages of all
+// regions, then the number of free region headers followed by their
+// addresses and contents.
+ptrdiff_t fm_lm_snapshot(void *out, size_t out_len) {
+  fm_lm_state_t *lm = &__default_state;
+  size_t pos = 0;
+  __fm_snapshot_put(out, out_len, &pos, &lm, sizeof(fm_lm_state_t *));
+  __fm_snapshot_put(out, out_len, &pos, lm, sizeof(fm_lm_state_t));
+  size_t count = 0;
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    __fm_snapshot_put(out, out_len, &pos, lm->heaps[i].meta, FM_PAGE_SIZE);
+    count += count_regions(&lm->heaps[i].free_regions);
+    count += count_regions(&lm->heaps[i].freed_memories);
+  }
+  __fm_snapshot_put(out, out_len, &pos, &count, sizeof(size_t));
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    put_regions(out, out_len, &pos, &lm->heaps[i].free_regions);
+    put_regions(out, out_len, &pos, &lm->heaps[i].freed_memories);
+  }
+  return (pos <= out_len) ? (ptrdiff_t)pos : -(ptrdiff_t)pos;
+}
+
+static int valid_region_address(const region_t *region) {
+#ifndef FM_MANUAL_INIT
+  if (region == &__initial_region) {
+    return 1;
+  }
+#endif
+  return (((size_t)region) & (FM_PAGE_SIZE - 1)) == 0 &&
+         fm_lm_contains(region);
+}
+
+int fm_lm_restore(const void *in, size_t len) {
+  fm_lm_state_t *lm = &__default_state;
+  // Validate everything first, so nothing is written on errors
+  size_t pos = 0;
+  fm_lm_state_t *state;
+  fm_lm_state_t copy;
+  if (__fm_snapshot_get(in, len, &pos, &state, sizeof(fm_lm_state_t *)) ||
+      __fm_snapshot_get(in, len, &pos, &copy, sizeof(fm_lm_state_t)) ||
+      state != lm || copy.heap_count != lm->heap_count) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    if (copy.heaps[i].buffer_start != lm->heaps[i].buffer_start ||
+        copy.heaps[i].buffer_size != lm->heaps[i].buffer_size ||
+        __fm_snapshot_get(in, len, &pos, NULL, FM_PAGE_SIZE)) {
+      return FM_ERR_BAD_SNAPSHOT;
+    }
+  }
+  size_t count;
+  if (__fm_snapshot_get(in, len, &pos, &count, sizeof(size_t))) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  size_t regions_start = pos;
+  for (size_t i = 0; i < count; i++) {
+    region_t *region = NULL;
+    if (__fm_snapshot_get(in, len, &pos, &region, sizeof(region_t *)) ||
+        !valid_region_address(region) ||
+        __fm_snapshot_get(in, len, &pos, NULL, sizeof(region_t))) {
+      return FM_ERR_BAD_SNAPSHOT;
+    }
+  }
+  if (pos != len) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+
+  pos = sizeof(fm_lm_state_t *) + sizeof(fm_lm_state_t);
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    __fm_snapshot_get(in, len, &pos, lm->heaps[i].meta, FM_PAGE_SIZE);
+  }
+  pos = regions_start;
+  for (size_t i = 0; i < count; i++) {
+    region_t *region = NULL;
+    __fm_snapshot_get(in, len, &pos, &region, sizeof(region_t *));
+    __fm_snapshot_get(in, len, &pos, region, sizeof(region_t));
+  }
+  memcpy(lm, &copy, sizeof(fm_lm_state_t));
+  return 0;
+}
+
+/* slab-malloc.c */
+/* #include "slab-malloc.h" */
+
+/* #include "c-list.h" */
+/* #include "linear-malloc.h" */
+/* #include "utils.h" */
+
+#define FM_SM_INVALID_SLAB 0xFFFFFFFF
+
+#ifndef FM_SLAB_MIN_SIZE
+#define FM_SLAB_MIN_SIZE 32
+#endif
+
+// Smaller classes are only added below the default ones. The two largest
+// classes fit 8 and 4 objects in a page, which are 512 and 1024 bytes with 4KB
+// pages.
+#define LARGE_SLAB_SIZES (FM_PAGE_SIZE / 8), (FM_PAGE_SIZE / 4)
+#if FM_SLAB_MIN_SIZE == 16
+static size_t slab_sizes[] = {16, 32, 64, 128, LARGE_SLAB_SIZES};
+#elif FM_SLAB_MIN_SIZE == 32
+static size_t slab_sizes[] = {32, 64, 128, LARGE_SLAB_SIZES};
+#elif FM_SLAB_MIN_SIZE == 64
+static size_t slab_sizes[] = {64, 128, LARGE_SLAB_SIZES};
+#else
+#error "Minimal slab size must be 16, 32 or 64!"
+#endif
+
+struct fm_heap_t {
+  CList slab_lists[sizeof(slab_sizes) / sizeof(size_t)];
+  // Fully used slabs are never picked for allocations, they are only tracked
+  // so the heap can be walked.
+  CList full_slabs;
+  // Number of pages used as slabs, and total size of live slab objects
+  size_t slab_pages;
+  size_t slab_used_bytes;
+  // Number of slabs, and used slots in those slabs for each size class
+  size_t class_slabs[sizeof(slab_sizes) / sizeof(size_t)];
+  size_t class_used_slots[sizeof(slab_sizes) / sizeof(size_t)];
+  // NULL for the default linear malloc state
+  fm_lm_state_t *lm;
+  // Set while a hook or callback runs for this heap, see callback_begin
+  int in_callback;
+};
+
+// Instance used by all global functions
+static fm_heap_t __default_heap = {
+    .slab_lists =
+        {
+            C_LIST_INIT(__default_heap.slab_lists[0]),
+            C_LIST_INIT(__default_heap.slab_lists[1]),
+            C_LIST_INIT(__default_heap.slab_lists[2]),
+            C_LIST_INIT(__default_heap.slab_lists[3]),
+#if FM_SLAB_MIN_SIZE <= 32
+            C_LIST_INIT(__default_heap.slab_lists[4]),
+#endif
+#if FM_SLAB_MIN_SIZE <= 16
+            C_LIST_INIT(__default_heap.slab_lists[5]),
+#endif
+        },
+    .full_slabs = C_LIST_INIT(__default_heap.full_slabs),
+    .lm = NULL,
+};
+
+#ifdef FM_TEST_SUPPORT
+#ifndef FM_SM_MAX_QUARANTINE
+#define FM_SM_MAX_QUARANTINE 64
+#endif
+
+// Ring buffer of freed pointers that are not yet returned to allocators
+static void *__quarantine[FM_SM_MAX_QUARANTINE];
+static size_t __quarantine_limit = 0;
+static size_t __quarantine_start = 0;
+static size_t __quarantine_count = 0;
+
+// Set via fm_sm_set_realloc_fill, see fm_sm_test_realloc_old_size
+static int __realloc_fill = 0;
+static size_t __realloc_old_size = 0;
+
+#ifndef FM_SM_MAX_TAGS
+#define FM_SM_MAX_TAGS 4096
+#endif
+#define FM_SM_TAG_EMPTY 0
+#define FM_SM_TAG_TOMBSTONE ((size_t)-1)
+
+// Open addressing hash table keeping tags of live allocations, allocations
+// without an entry have tag 0. Allocations are keyed by their offsets in the
+// buffer, so the table stays valid when the heap is migrated. Offset 0 is the
+// accounting page, which can never be an allocation.
+typedef struct tag_entry_t {
+  size_t offset;
+  uint32_t tag;
+} tag_entry_t;
+static tag_entry_t __tags[FM_SM_MAX_TAGS];
+
+static size_t tag_offset(const void *ptr) {
+  return (size_t)ptr - (size_t)fm_lm_test_buffer_pointer();
+}
+
+static size_t tag_slot(size_t offset) {
+  return (size_t)(((uint64_t)offset >> 4) * 0x9E3779B97F4A7C15ULL %
+                  FM_SM_MAX_TAGS);
+}
+
+static tag_entry_t *tag_find(const void *ptr) {
+  size_t offset = tag_offset(ptr);
+  size_t start = tag_slot(offset);
+  for (size_t i = 0; i < FM_SM_MAX_TAGS; i++) {
+    tag_entry_t *entry = &__tags[(start + i) % FM_SM_MAX_TAGS];
+    if (entry->offset == offset) {
+      return entry;
+    }
+    if (entry->offset == FM_SM_TAG_EMPTY) {
+      return NULL;
+    }
+  }
+  return NULL;
+}
+
+// When the table is full the tag is simply dropped
+static void tag_set(void *ptr, uint32_t tag) {
+  if (tag == 0) {
+    return;
+  }
+  size_t offset = tag_offset(ptr);
+  size_t start = tag_slot(offset);
+  for (size_t i = 0; i < FM_SM_MAX_TAGS; i++) {
+    tag_entry_t *entry = &__tags[(start + i) % FM_SM_MAX_TAGS];
+    if (entry->offset == FM_SM_TAG_EMPTY ||
+        entry->offset == FM_SM_TAG_TOMBSTONE) {
+      entry->offset = offset;
+      entry->tag = tag;
+      return;
+    }
+  }
+}
+
+static uint32_t tag_remove(const void *ptr) {
+  tag_entry_t *entry = tag_find(ptr);
+  if (entry == NULL) {
+    return 0;
+  }
+  entry->offset = FM_SM_TAG_TOMBSTONE;
+  return entry->tag;
+}
+#endif
+
+
+static fm_lock_cb_t __lock = NULL;
+static fm_lock_cb_t __unlock = NULL;
+static void *__lock_ctx = NULL;
+
+void fm_set_lock_callbacks(fm_lock_cb_t lock, fm_lock_cb_t unlock,
+                           void *ctx) {
+  __lock = lock;
+  __unlock = unlock;
+  __lock_ctx = ctx;
+}
+
+static void lock() {
+  if (__lock != NULL) {
+    __lock(__lock_ctx);
+  }
+}
+
+static void unlock() {
+  if (__unlock != NULL) {
+    __unlock(__lock_ctx);
+  }
+}
+
+// Mark heap as running a hook or callback, so allocations made from there
+// are rejected instead of corrupting the heap. With lock callbacks
+// installed the lock already serializes calls, other threads entering the
+// heap meanwhile are legitimate, hence nothing is marked. Returns the
+// previous mark, which is put back by callback_end.
+static int callback_begin(fm_heap_t *heap) {
+  int previous = heap->in_callback;
+  if (__lock == NULL) {
+    heap->in_callback = 1;
+  }
+  return previous;
+}
+
+static void callback_end(fm_heap_t *heap, int previous) {
+  heap->in_callback = previous;
+}
+
+// Check for an allocation made from a hook or callback of heap, which fails
+// with FM_ERR_REENTRANT, or aborts with FM_TRAP_REENTRANT.
+static int reentered(const fm_heap_t *heap) {
+  if (heap->in_callback) {
+#ifdef FM_TRAP_REENTRANT
+    FM_ABORT();
+#endif
+    __fm_set_error(FM_ERR_REENTRANT);
+    return 1;
+  }
+  return 0;
+}
+
+static fm_oom_hook_t __oom_hook = NULL;
+static void *__oom_hook_ctx = NULL;
+
+static void set_oom_hook(fm_oom_hook_t hook, void *ctx) {
+  __oom_hook = hook;
+  __oom_hook_ctx = ctx;
+}
+
+void fm_sm_set_oom_hook(fm_oom_hook_t hook, void *ctx) {
+  lock();
+  set_oom_hook(hook, ctx);
+  unlock();
+}
+
+// Called with the lock held, which is released while the hook runs so the
+// hook can still inspect the heap
+static void notify_oom(fm_heap_t *heap, size_t requested) {
+  if (__oom_hook != NULL) {
+    fm_oom_hook_t hook = __oom_hook;
+    void *ctx = __oom_hook_ctx;
+    int previous = callback_begin(heap);
+    unlock();
+    hook(requested, ctx);
+    lock();
+    callback_end(heap, previous);
+  }
+}
+
+static void *sm_malloc(fm_heap_t *heap, size_t size);
+static size_t usable_size(fm_heap_t *heap, const void *ptr);
+
+static void init_slabs(fm_heap_t *heap) {
+  c_list_init(&heap->full_slabs);
+  for (size_t i = 0; i < sizeof(heap->slab_lists) / sizeof(CList); i++) {
+    c_list_init(&heap->slab_lists[i]);
+    heap->class_slabs[i] = 0;
+    heap->class_used_slots[i] = 0;
+  }
+  heap->slab_pages = 0;
+  heap->slab_used_bytes = 0;
+}
+
+static void reset_slabs() {
+#ifdef FM_TEST_SUPPORT
+  // Quarantined pointers belong to the previous buffer
+  __quarantine_start = 0;
+  __quarantine_count = 0;
+  memset(__tags, 0, sizeof(__tags));
+#endif
+  init_slabs(&__default_heap);
+}
+
+static size_t live_allocations() {
+  fm_heap_t *heap = &__default_heap;
+  size_t live = fm_lm_live_blocks() - heap->slab_pages;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    live += heap->class_used_slots[i];
+  }
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks are already freed by the caller
+  live -= __quarantine_count;
+#endif
+  return live;
+}
+
+size_t fm_sm_live_allocations() {
+  lock();
+  size_t result = live_allocations();
+  unlock();
+  return result;
+}
+
+static size_t allocated_bytes() {
+  fm_heap_t *heap = &__default_heap;
+  return (fm_lm_used_pages() - heap->slab_pages) * FM_PAGE_SIZE +
+         heap->slab_used_bytes;
+}
+
+size_t fm_sm_allocated_bytes() {
+  lock();
+  size_t result = allocated_bytes();
+  unlock();
+  return result;
+}
+
+size_t fm_sm_default_memory_size() { return FM_MEMORY_SIZE; }
+
+size_t fm_sm_min_buffer_size() { return FM_MIN_MEMORY_SIZE; }
+
+static int reinit_forced(void *buffer, size_t size, int zero_filled) {
+  int ret = fm_lm_reinit(buffer, size, zero_filled);
+  if (ret != 0) {
+    return ret;
+  }
+  reset_slabs();
+  return 0;
+}
+
+static void deinit() {
+  fm_lm_deinit();
+  reset_slabs();
+}
+
+void fm_sm_deinit() {
+  lock();
+  deinit();
+  unlock();
+}
+
+static int reinit(void *buffer, size_t size, int zero_filled) {
+  if (live_allocations() > 0) {
+    return FM_ERR_LIVE_ALLOCATIONS;
+  }
+  return reinit_forced(buffer, size, zero_filled);
+}
+
+int fm_sm_reinit(void *buffer, size_t size, int zero_filled) {
+  lock();
+  int result = reinit(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+int fm_sm_reinit_forced(void *buffer, size_t size, int zero_filled) {
+  lock();
+  int result = reinit_forced(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+static int reinit_split(void *slab_buffer, size_t slab_size,
+                        void *linear_buffer, size_t linear_size,
+                        int zero_filled) {
+  if (live_allocations() > 0) {
+    return FM_ERR_LIVE_ALLOCATIONS;
+  }
+  int ret = fm_lm_reinit_split(slab_buffer, slab_size, linear_buffer,
+                               linear_size, zero_filled);
+  if (ret != 0) {
+    return ret;
+  }
+  reset_slabs();
+  return 0;
+}
+
+int fm_sm_reinit_split(void *slab_buffer, size_t slab_size,
+                       void *linear_buffer, size_t linear_size,
+                       int zero_filled) {
+  lock();
+  int result = reinit_split(slab_buffer, slab_size, linear_buffer,
+                            linear_size, zero_filled);
+  unlock();
+  return result;
+}
+
+static int add_region(void *buffer, size_t size, int zero_filled) {
+  return fm_lm_add_region(buffer, size, zero_filled);
+}
+
+int fm_sm_add_region(void *buffer, size_t size, int zero_filled) {
+  lock();
+  int result = add_region(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+static int adopt(void *ptr, size_t size) {
+  if ((((size_t)ptr) & 15) != 0) {
+    return FM_ERR_BAD_ALIGNMENT;
+  }
+  if (size > (size_t)-1 - (size_t)ptr) {
+    return FM_ERR_BUFFER_TOO_LARGE;
+  }
+  // Only whole pages within the block can be managed
+  size_t start = __fm_roundup((size_t)ptr, FM_PAGE_SIZE);
+  size_t end = __fm_rounddown((size_t)ptr + size, FM_PAGE_SIZE);
+  if (end < start + FM_MIN_MEMORY_SIZE) {
+    return FM_ERR_BUFFER_TOO_SMALL;
+  }
+  return add_region((void *)start, end - start, 0);
+}
+
+int fm_sm_adopt(void *ptr, size_t size) {
+  lock();
+  int result = adopt(ptr, size);
+  unlock();
+  return result;
+}
+
+static int reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                       void **old_buffer, size_t *old_size) {
+  if (live_allocations() > 0) {
+    return FM_ERR_LIVE_ALLOCATIONS;
+  }
+  int ret = fm_lm_reinit_swap(new_buffer, new_size, zero_filled, old_buffer,
+                              old_size);
+  if (ret != 0) {
+    return ret;
+  }
+  reset_slabs();
+  return 0;
+}
+
+int fm_sm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                      void **old_buffer, size_t *old_size) {
+  lock();
+  int result =
+      reinit_swap(new_buffer, new_size, zero_filled, old_buffer, old_size);
+  unlock();
+  return result;
+}
+
+static size_t slab_index(size_t size) {
+  // Right now we have at most 6 slabs, a linear search shall be enough,
+  // a binary search might be needed once we have more slabs.
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    if (size <= slab_sizes[i]) {
+      return i;
+    }
+  }
+  return FM_SM_INVALID_SLAB;
+}
+
+// Enough bits for all slots of the smallest size class
+#define BITMAP_WORDS ((FM_PAGE_SIZE / FM_SLAB_MIN_SIZE + 63) / 64)
+
+typedef struct page_meta_t {
+  CList link;
+  uint64_t bitmap[BITMAP_WORDS];
+  size_t size;
+  size_t count;
+  size_t slab_index;
+  size_t _padding;
+} page_meta_t;
+
+// Slots start after the page meta, rounded up to 64 bytes. Mirrored by
+// FM_SLAB_RESERVED_SIZE in src/ffi.rs.
+#define PAGE_META_RESERVED_SIZE ((sizeof(page_meta_t) + 63) / 64 * 64)
+
+static size_t ptr_to_index(const page_meta_t *meta, const void *ptr) {
+  size_t p = (size_t)ptr;
+  size_t base = ((size_t)meta) + PAGE_META_RESERVED_SIZE;
+#ifdef FM_GUARDS
+  if ((p - base) % meta->size != 0) {
+    FM_REPORT(FM_ERR_BAD_POINTER,
+              "Pointer does not lie on the boundary of slab allocated value!");
+    FM_ABORT();
+  }
+  if ((p - base) / meta->size >= meta->count) {
+    FM_REPORT(FM_ERR_BAD_POINTER, "Pointer exceeds slab count!");
+    FM_ABORT();
+  }
+#endif
+  return (p - base) / meta->size;
+}
+
+static void *index_to_ptr(const page_meta_t *meta, size_t index) {
+#ifdef FM_GUARDS
+  if (index >= meta->count) {
+    FM_REPORT(FM_ERR_CORRUPTED, "Invalid index in slab!");
+    FM_ABORT();
+  }
+#endif
+  return (void *)(((size_t)meta) + PAGE_META_RESERVED_SIZE +
+                  index * meta->size);
+}
+
+static int bitmap_all_cleared(const page_meta_t *meta) {
+  for (size_t i = 0; i < BITMAP_WORDS; i++) {
+    if (meta->bitmap[i] != 0) {
+      return 0;
+    }
+  }
+  return 1;
+}
+
+static size_t used_slots(const page_meta_t *meta) {
+  size_t used = 0;
+  for (size_t i = 0; i < BITMAP_WORDS; i++) {
+    used += __fm_popcount64(meta->bitmap[i]);
+  }
+  return used;
+}
+
+static int bitmap_all_used(const page_meta_t *meta) {
+  return used_slots(meta) == meta->count;
+}
+
+static size_t bitmap_next_free(const page_meta_t *meta) {
+  size_t zeros = FM_SM_INVALID_SLAB;
+  for (size_t i = 0; i < BITMAP_WORDS; i++) {
+    if (meta->bitmap[i] != (uint64_t)-1) {
+      zeros = i * 64 + __fm_ctz64(~(meta->bitmap[i]));
+      break;
+    }
+  }
+  if (zeros >= meta->count) {
+    return FM_SM_INVALID_SLAB;
+  }
+  return zeros;
+}
+
+static int bitmap_is_set(const page_meta_t *meta, size_t index) {
+  return (meta->bitmap[index / 64] >> (index % 64)) & 1;
+}
+
+static int __slab_deterministic = 0;
+
+#ifdef FM_HARDENING
+static int __slab_random_enabled = 0;
+static uint64_t __slab_random_state = 0;
+
+static void set_random_seed(uint64_t seed) {
+  __slab_random_enabled = 1;
+  __slab_random_state = seed;
+  fm_lm_set_random_seed(__fm_random_next(&__slab_random_state));
+}
+
+void fm_sm_set_random_seed(uint64_t seed) {
+  lock();
+  set_random_seed(seed);
+  unlock();
+}
+
+// Start from a random slot, then pick the first free slot, wrapping around
+// at the end of the page.
+static size_t bitmap_random_free(const page_meta_t *meta) {
+  size_t start =
+      (size_t)(__fm_random_next(&__slab_random_state) % meta->count);
+  for (size_t i = 0; i < meta->count; i++) {
+    size_t index = (start + i) % meta->count;
+    if (!bitmap_is_set(meta, index)) {
+      return index;
+    }
+  }
+  return FM_SM_INVALID_SLAB;
+}
+#endif
+
+static size_t pick_free(const page_meta_t *meta) {
+#ifdef FM_HARDENING
+  if (__slab_random_enabled && !__slab_deterministic) {
+    return bitmap_random_free(meta);
+  }
+#endif
+  return bitmap_next_free(meta);
+}
+
+static void bitmap_set(page_meta_t *meta, size_t index) {
+  meta->bitmap[index / 64] |= ((uint64_t)1) << (index % 64);
+}
+
+static void bitmap_clear(page_meta_t *meta, size_t index) {
+  meta->bitmap[index / 64] &= (~(((uint64_t)1) << (index % 64)));
+}
+
+static void release(fm_heap_t *heap, void *ptr) {
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    fm_lm_state_free(heap->lm, ptr);
+    return;
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  size_t element_index = ptr_to_index(meta, ptr);
+  // Freeing a slot twice would break the counts of the slab
+  if (!bitmap_is_set(meta, element_index)) {
+    FM_REPORT(FM_ERR_BAD_POINTER, "Slab object is freed twice!");
+    __fm_set_error(FM_ERR_BAD_POINTER);
+    return;
+  }
+  int all_used = bitmap_all_used(meta);
+#ifdef FM_FILL_ON_FREE
+  memset(ptr, FM_FILL_PATTERN, meta->size);
+#ifdef FM_TEST_SUPPORT
+  // Checked while the slot is still allocated, clearing it might release the
+  // whole slab
+  if (__fm_check_fill(ptr, meta->size) != 0) {
+    FM_REPORT(FM_ERR_CORRUPTED, "Memory is not filled after being freed!");
+    FM_ABORT();
+  }
+#endif
+#endif
+  bitmap_clear(meta, element_index);
+  heap->slab_used_bytes -= meta->size;
+  heap->class_used_slots[meta->slab_index]--;
+  if (all_used) {
+    c_list_unlink(&meta->link);
+    c_list_link_tail(&heap->slab_lists[meta->slab_index], &meta->link);
+    FM_DEBUG("Retrieving previously fully used slab: %p %zu\n", meta,
+             meta->size);
+  }
+  if (__slab_deterministic && bitmap_all_cleared(meta)) {
+    // Empty slabs are not kept around, which would depend on past frees
+    heap->slab_pages--;
+    heap->class_slabs[meta->slab_index]--;
+    c_list_unlink(&meta->link);
+    fm_lm_state_free(heap->lm, meta);
+  }
+}
+
+#ifdef FM_FILL_ON_FREE
+int fm_sm_check_fill(void *ptr) {
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return fm_lm_check_fill(ptr);
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  return __fm_check_fill(ptr, meta->size);
+}
+#endif
+
+#ifdef FM_TEST_SUPPORT
+#ifdef FM_FILL_ON_FREE
+static void fill_block(void *ptr) {
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    fm_lm_fill(ptr);
+    return;
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  memset(ptr, FM_FILL_PATTERN, meta->size);
+}
+#endif
+
+static void evict_quarantine(size_t limit) {
+  while (__quarantine_count > limit) {
+    void *oldest = __quarantine[__quarantine_start];
+    __quarantine_start = (__quarantine_start + 1) % FM_SM_MAX_QUARANTINE;
+    __quarantine_count--;
+#ifdef FM_FILL_ON_FREE
+    if (fm_sm_check_fill(oldest) != 0) {
+      FM_REPORT(FM_ERR_CORRUPTED,
+                "Quarantined memory is modified after being freed!");
+      FM_ABORT();
+    }
+#endif
+    release(&__default_heap, oldest);
+  }
+}
+
+static void set_quarantine(size_t n) {
+  if (n > FM_SM_MAX_QUARANTINE) {
+    n = FM_SM_MAX_QUARANTINE;
+  }
+  __quarantine_limit = n;
+  evict_quarantine(n);
+}
+
+void fm_sm_set_quarantine(size_t n) {
+  lock();
+  set_quarantine(n);
+  unlock();
+}
+
+void fm_sm_set_realloc_fill(int enabled) {
+  lock();
+  __realloc_fill = enabled;
+  unlock();
+}
+
+size_t fm_sm_test_realloc_old_size() {
+  lock();
+  size_t result = __realloc_old_size;
+  unlock();
+  return result;
+}
+#endif
+
+#ifdef FM_HARDENING
+static int valid_pointer(fm_heap_t *heap, void *ptr) {
+  if (!fm_lm_state_contains(heap->lm, ptr)) {
+    return 0;
+  }
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return 1;
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  if ((meta->slab_index >= sizeof(slab_sizes) / sizeof(size_t)) ||
+      (meta->size != slab_sizes[meta->slab_index])) {
+    return 0;
+  }
+  size_t p = (size_t)ptr;
+  size_t base = ((size_t)meta) + PAGE_META_RESERVED_SIZE;
+  if ((p < base) || ((p - base) % meta->size != 0) ||
+      ((p - base) / meta->size >= meta->count)) {
+    return 0;
+  }
+  // Double free is also rejected
+  return bitmap_is_set(meta, (p - base) / meta->size);
+}
+#endif
+
+static void sm_free(fm_heap_t *heap, void *ptr) {
+  if (ptr == NULL) {
+    return;
+  }
+#ifdef FM_HARDENING
+  if (!valid_pointer(heap, ptr)) {
+    FM_REPORT(FM_ERR_BAD_POINTER, "Pointer passed to free is not allocated!");
+    __fm_set_error(FM_ERR_BAD_POINTER);
+    return;
+  }
+#endif
+#ifdef FM_TEST_SUPPORT
+  // Tags and quarantine are only kept for the default instance
+  if (heap == &__default_heap) {
+    tag_remove(ptr);
+  }
+  if (__quarantine_limit > 0 && heap == &__default_heap) {
+#ifdef FM_FILL_ON_FREE
+    // Quarantined memory shall also be poisoned
+    fill_block(ptr);
+#endif
+    // Make room first so the ring buffer never overflows
+    evict_quarantine(__quarantine_limit - 1);
+    size_t end =
+        (__quarantine_start + __quarantine_count) % FM_SM_MAX_QUARANTINE;
+    __quarantine[end] = ptr;
+    __quarantine_count++;
+    return;
+  }
+#endif
+  release(heap, ptr);
+}
+
+void fm_sm_free(void *ptr) { fm_sm_heap_free(&__default_heap, ptr); }
+
+void fm_sm_heap_free(fm_heap_t *heap, void *ptr) {
+  if (reentered(heap)) {
+    return;
+  }
+  lock();
+  sm_free(heap, ptr);
+  unlock();
+}
+
+static void free_many(fm_heap_t *heap, void *const *ptrs, size_t count) {
+  for (size_t i = 0; i < count; i++) {
+    sm_free(heap, ptrs[i]);
+  }
+}
+
+void fm_sm_free_many(void *const *ptrs, size_t count) {
+  if (reentered(&__default_heap)) {
+    return;
+  }
+  lock();
+  free_many(&__default_heap, ptrs, count);
+  unlock();
+}
+
+// Requests larger than any buffer are rejected before size classes or pages
+// are computed, so no rounding can wrap around to a small block.
+static int too_large(size_t size) {
+  if (size > FM_MAX_MEMORY_SIZE) {
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    return 1;
+  }
+  return 0;
+}
+
+static void *sm_realloc(fm_heap_t *heap, void *ptr, size_t size) {
+  if (too_large(size)) {
+    notify_oom(heap, size);
+    return NULL;
+  }
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    void *p = fm_lm_state_realloc(heap->lm, ptr, size, FM_LM_T_TRANSIENT);
+    if (p == NULL) {
+      notify_oom(heap, size);
+    }
+    return p;
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  if (size <= meta->size) {
+    return ptr;
+  }
+  void *p = sm_malloc(heap, size);
+  if (p != NULL) {
+    memcpy(p, ptr, meta->size);
+    sm_free(heap, ptr);
+  } else {
+    notify_oom(heap, size);
+  }
+  return p;
+}
+
+static void *shrink_release(fm_heap_t *heap, void *ptr, size_t size) {
+  if (too_large(size)) {
+    return NULL;
+  }
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return fm_lm_state_shrink_release(heap->lm, ptr, size);
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  return (size <= meta->size) ? ptr : NULL;
+}
+
+void *fm_sm_shrink_release(void *ptr, size_t size) {
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = shrink_release(&__default_heap, ptr, size);
+  unlock();
+  return result;
+}
+
+#ifdef FM_TEST_SUPPORT
+// Record the size ptr had before being reallocated to p, and fill the bytes
+// p gained beyond it when enabled
+static void track_realloc(fm_heap_t *heap, void *p, size_t old_size) {
+  __realloc_old_size = old_size;
+  size_t new_size = usable_size(heap, p);
+  if (__realloc_fill && new_size > old_size) {
+    memset((uint8_t *)p + old_size, FM_REALLOC_FILL_PATTERN,
+           new_size - old_size);
+  }
+}
+#endif
+
+static void *heap_realloc(fm_heap_t *heap, void *ptr, size_t size) {
+#ifdef FM_TEST_SUPPORT
+  size_t old_size = (ptr != NULL) ? usable_size(heap, ptr) : 0;
+  void *p = sm_realloc(heap, ptr, size);
+  if (p != NULL) {
+    track_realloc(heap, p, old_size);
+  }
+#else
+  void *p = sm_realloc(heap, ptr, size);
+#endif
+  return p;
+}
+
+void *fm_sm_realloc(void *ptr, size_t size) {
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+#ifdef FM_TEST_SUPPORT
+  tag_entry_t *entry = (ptr != NULL) ? tag_find(ptr) : NULL;
+  uint32_t tag = (entry != NULL) ? entry->tag : 0;
+  void *p = heap_realloc(&__default_heap, ptr, size);
+  if (p != NULL && p != ptr) {
+    // Tags move together with reallocated blocks
+    tag_remove(ptr);
+    tag_set(p, tag);
+  }
+#else
+  void *p = heap_realloc(&__default_heap, ptr, size);
+#endif
+  unlock();
+  return p;
+}
+
+static void *resize_in_place(fm_heap_t *heap, void *ptr, size_t size) {
+  if (too_large(size)) {
+    return NULL;
+  }
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return fm_lm_state_realloc_in_place(heap->lm, ptr, size);
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  return (size <= meta->size) ? ptr : NULL;
+}
+
+void *fm_sm_realloc_flags(void *ptr, size_t size, int flags) {
+  if (ptr == NULL || !(flags & FM_REALLOC_NO_MOVE)) {
+    return fm_sm_realloc(ptr, size);
+  }
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = resize_in_place(&__default_heap, ptr, size);
+  unlock();
+  return result;
+}
+
+void *fm_sm_heap_realloc(fm_heap_t *heap, void *ptr, size_t size) {
+  if (reentered(heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = heap_realloc(heap, ptr, size);
+  unlock();
+  return result;
+}
+
+static size_t free_empty_slabs(fm_heap_t *heap) {
+  size_t freed = 0;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    CList *iter = heap->slab_lists[i].next;
+    while (iter != &heap->slab_lists[i]) {
+      page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+      CList *old = iter;
+      iter = iter->next;
+      if (bitmap_all_cleared(meta)) {
+        c_list_unlink(old);
+        fm_lm_state_free(heap->lm, meta);
+        heap->slab_pages--;
+        heap->class_slabs[i]--;
+        freed++;
+      }
+    }
+  }
+  return freed;
+}
+
+void fm_sm_set_error_sink(fm_error_sink_t sink, void *ctx) {
+  lock();
+  fm_lm_set_error_sink(sink, ctx);
+  unlock();
+}
+
+void fm_sm_set_deterministic(int enabled) {
+  lock();
+  __slab_deterministic = enabled;
+  if (enabled) {
+    free_empty_slabs(&__default_heap);
+  }
+  fm_lm_set_deterministic(enabled);
+  unlock();
+}
+
+size_t fm_sm_trim() {
+  if (reentered(&__default_heap)) {
+    return 0;
+  }
+  lock();
+  size_t result = free_empty_slabs(&__default_heap) * FM_PAGE_SIZE;
+  unlock();
+  return result;
+}
+
+size_t fm_sm_max_alloc_size() {
+  if (reentered(&__default_heap)) {
+    return 0;
+  }
+  lock();
+  fm_heap_t *heap = &__default_heap;
+  free_empty_slabs(heap);
+  size_t result = fm_lm_max_alloc_size();
+  size_t classes = sizeof(slab_sizes) / sizeof(size_t);
+  if (result < slab_sizes[classes - 1] &&
+      fm_lm_max_alloc_size_reserved() >= FM_PAGE_SIZE) {
+    // A new slab can still be created for any class
+    result = slab_sizes[classes - 1];
+  }
+  for (size_t i = classes; i > 0 && result < slab_sizes[i - 1]; i--) {
+    if (!c_list_is_empty(&heap->slab_lists[i - 1])) {
+      result = slab_sizes[i - 1];
+    }
+  }
+  unlock();
+  return result;
+}
+
+static void *lm_malloc(fm_heap_t *heap, size_t size, int t) {
+  void *p = fm_lm_state_malloc(heap->lm, size, t);
+  if (p == NULL) {
+    // When previous attempt fails, try freeing empty slabs, then retry
+    free_empty_slabs(heap);
+    p = fm_lm_state_malloc(heap->lm, size, t);
+  }
+  return p;
+}
+
+static void *sm_malloc(fm_heap_t *heap, size_t size) {
+  if (too_large(size)) {
+    return NULL;
+  }
+  size_t i = slab_index(size);
+  if (i == FM_SM_INVALID_SLAB) {
+    return lm_malloc(heap, size, FM_LM_T_TRANSIENT);
+  }
+  for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
+       iter = iter->next) {
+    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+    size_t index = pick_free(meta);
+    if (index != FM_SM_INVALID_SLAB) {
+      bitmap_set(meta, index);
+      heap->slab_used_bytes += meta->size;
+      heap->class_used_slots[i]++;
+      if (bitmap_all_used(meta)) {
+        c_list_unlink(iter);
+        c_list_link_tail(&heap->full_slabs, &meta->link);
+        FM_DEBUG("Unlinking fully utilized slab: %p %zu\n", meta, meta->size);
+      }
+      return index_to_ptr(meta, index);
+    }
+  }
+  // Create a new slab here
+  void *slab =
+      fm_lm_state_malloc_reserved(heap->lm, FM_PAGE_SIZE, FM_LM_T_PERSISTENT);
+  if (slab == NULL) {
+    return NULL;
+  }
+  page_meta_t *meta = (page_meta_t *)slab;
+#ifdef FM_FILL_ON_FREE
+  // Free slots always hold the fill pattern, so fm_sm_verify can check them
+  memset((uint8_t *)slab + PAGE_META_RESERVED_SIZE, FM_FILL_PATTERN,
+         FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE);
+#endif
+  memset(meta->bitmap, 0, sizeof(meta->bitmap));
+  meta->size = slab_sizes[i];
+  meta->slab_index = i;
+  meta->count = (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / meta->size;
+  c_list_link_front(&heap->slab_lists[i], &meta->link);
+  FM_DEBUG("Creating new slab: %p %zu\n", meta, meta->size);
+  heap->slab_pages++;
+  heap->slab_used_bytes += meta->size;
+  heap->class_slabs[i]++;
+  heap->class_used_slots[i]++;
+
+  size_t element_index = 0;
+#ifdef FM_HARDENING
+  if (__slab_random_enabled && !__slab_deterministic) {
+    // All slots are free in a new slab
+    element_index =
+        (size_t)(__fm_random_next(&__slab_random_state) % meta->count);
+  }
+#endif
+  bitmap_set(meta, element_index);
+  return index_to_ptr(meta, element_index);
+}
+
+void *fm_sm_malloc(size_t size) {
+  return fm_sm_heap_malloc(&__default_heap, size);
+}
+
+static void *heap_malloc(fm_heap_t *heap, size_t size) {
+  void *p = sm_malloc(heap, size);
+  if (p == NULL) {
+    notify_oom(heap, size);
+  }
+  return p;
+}
+
+void *fm_sm_heap_malloc(fm_heap_t *heap, size_t size) {
+  if (reentered(heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = heap_malloc(heap, size);
+  unlock();
+  return result;
+}
+
+// The control block lives at the end of the bookkeeping page
+static size_t control_size() {
+  return __fm_roundup(sizeof(fm_heap_t) + fm_lm_state_size(), 16);
+}
+
+static fm_heap_t *create(void *buffer, size_t size, int zero_filled) {
+  int ret = fm_lm_check_buffer(buffer, size);
+  // Enough room must be left for page counts in the bookkeeping page
+  if (ret == 0 && size / FM_PAGE_SIZE + 8 > FM_PAGE_SIZE - control_size()) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_LARGE,
+              "Memory size is too large to keep the control block!");
+    ret = FM_ERR_BUFFER_TOO_LARGE;
+  }
+  if (ret != 0) {
+    __fm_set_error(ret);
+    return NULL;
+  }
+  if (!zero_filled) {
+    memset(buffer, 0, FM_PAGE_SIZE);
+  }
+  fm_heap_t *heap =
+      (fm_heap_t *)((uint8_t *)buffer + FM_PAGE_SIZE - control_size());
+  heap->lm = (fm_lm_state_t *)(heap + 1);
+  fm_lm_state_reinit(heap->lm, buffer, size, 1);
+  init_slabs(heap);
+  return heap;
+}
+
+fm_heap_t *fm_sm_create(void *buffer, size_t size, int zero_filled) {
+  lock();
+  fm_heap_t *result = create(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+void fm_sm_destroy(fm_heap_t *heap) { memset(heap, 0, control_size()); }
+
+void *fm_sm_calloc(size_t n, size_t size) {
+  size_t total;
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  if (__fm_mul_overflow(n, size, &total)) {
+    lock();
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    // The actual size cannot be represented, use the largest value instead
+    notify_oom(&__default_heap, (size_t)-1);
+    unlock();
+    return NULL;
+  }
+  void *p = fm_sm_malloc(total);
   if (p != NULL) {
-    memcpy(p, ptr, meta->size);
-    fm_sm_free(ptr);
+    memset(p, 0, total);
+  }
+  return p;
+}
+
+static void *page_alloc(size_t pages) {
+  size_t size;
+  if (pages == 0) {
+    return NULL;
+  }
+  if (__fm_mul_overflow(pages, FM_PAGE_SIZE, &size)) {
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    notify_oom(&__default_heap, (size_t)-1);
+    return NULL;
+  }
+  // Blocks served by linear malloc always start on a page boundary
+  void *p = lm_malloc(&__default_heap, size, FM_LM_T_TRANSIENT);
+  if (p == NULL) {
+    notify_oom(&__default_heap, size);
+  }
+  return p;
+}
+
+void *fm_sm_page_alloc(size_t pages) {
+  if (reentered(&__default_heap)) {
+    return NULL;
   }
+  lock();
+  void *p = page_alloc(pages);
+  unlock();
   return p;
 }
 
-static void free_empty_slabs() {
+void fm_sm_page_free(void *ptr) {
+  if (ptr == NULL || reentered(&__default_heap)) {
+    return;
+  }
+  lock();
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
+    __fm_set_error(FM_ERR_BAD_POINTER);
+  } else {
+    sm_free(&__default_heap, ptr);
+  }
+  unlock();
+}
+
+static void collect_stats(fm_stats_t *stats) {
+  fm_heap_t *heap = &__default_heap;
+  fm_lm_stats(&stats->total_pages, &stats->free_pages);
+  stats->used_pages = stats->total_pages - stats->free_pages;
+  // Each region has its own bookkeeping page
+  size_t pages = stats->total_pages + fm_lm_regions();
+  stats->total_bytes = (stats->total_pages > 0) ? pages * FM_PAGE_SIZE : 0;
+  stats->used_bytes = (stats->used_pages - heap->slab_pages) * FM_PAGE_SIZE +
+                      heap->slab_used_bytes;
+  stats->free_bytes = stats->free_pages * FM_PAGE_SIZE;
+}
+
+void fm_sm_stats(fm_stats_t *stats) {
+  lock();
+  collect_stats(stats);
+  unlock();
+}
+
+static int is_slab(const fm_heap_t *heap, const void *page) {
   for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
-    CList *iter = slab_lists[i].next;
-    while (iter != &slab_lists[i]) {
-      page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
-      CList *old = iter;
-      iter = iter->next;
-      if (bitmap_all_cleared(meta)) {
-        c_list_unlink(old);
-        fm_lm_free(meta);
+    for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
+         iter = iter->next) {
+      if ((void *)c_list_entry(iter, page_meta_t, link) == page) {
+        return 1;
       }
     }
   }
+  for (CList *iter = heap->full_slabs.next; iter != &heap->full_slabs;
+       iter = iter->next) {
+    if ((void *)c_list_entry(iter, page_meta_t, link) == page) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+static size_t usable_size(fm_heap_t *heap, const void *ptr) {
+  if (!fm_lm_state_contains(heap->lm, ptr)) {
+    return 0;
+  }
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks are already freed by the caller
+  for (size_t i = 0; i < __quarantine_count; i++) {
+    if (__quarantine[(__quarantine_start + i) % FM_SM_MAX_QUARANTINE] == ptr) {
+      return 0;
+    }
+  }
+#endif
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return is_slab(heap, ptr) ? 0 : fm_lm_state_block_size(heap->lm, ptr);
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  if (!is_slab(heap, meta)) {
+    return 0;
+  }
+  size_t p = (size_t)ptr;
+  size_t base = ((size_t)meta) + PAGE_META_RESERVED_SIZE;
+  if ((p < base) || ((p - base) % meta->size != 0) ||
+      ((p - base) / meta->size >= meta->count)) {
+    return 0;
+  }
+  return bitmap_is_set(meta, (p - base) / meta->size) ? meta->size : 0;
 }
 
-static void *lm_malloc(size_t size, int t) {
-  void *p = fm_lm_malloc(size, t);
-  if (p == NULL) {
-    // When previous attempt fails, try freeing empty slabs, then retry
-    free_empty_slabs();
-    p = fm_lm_malloc(size, t);
+size_t fm_sm_usable_size(const void *ptr) {
+  lock();
+  size_t result = usable_size(&__default_heap, ptr);
+  unlock();
+  return result;
+}
+
+typedef struct walk_ctx_t {
+  fm_walk_cb_t callback;
+  void *user;
+} walk_ctx_t;
+
+static void walk_block(void *ptr, size_t size, void *user) {
+  walk_ctx_t *ctx = (walk_ctx_t *)user;
+  if (!is_slab(&__default_heap, ptr)) {
+    ctx->callback(ptr, size, ctx->user);
+    return;
+  }
+  page_meta_t *meta = (page_meta_t *)ptr;
+  for (size_t i = 0; i < meta->count; i++) {
+    if (meta->bitmap[i / 64] & (((uint64_t)1) << (i % 64))) {
+      ctx->callback(index_to_ptr(meta, i), meta->size, ctx->user);
+    }
+  }
+}
+
+// Invoke callback for each live allocation in address order, slab pages
+// themselves are not reported but the slab objects in them are.
+static void walk_allocations(fm_walk_cb_t callback, void *user) {
+  walk_ctx_t ctx = {callback, user};
+  fm_lm_walk(walk_block, &ctx);
+}
+
+#ifdef FM_TEST_SUPPORT
+static void class_stats(fm_class_stats_cb_t callback, void *user) {
+  fm_heap_t *heap = &__default_heap;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    size_t count = (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / slab_sizes[i];
+    size_t used = heap->class_used_slots[i];
+    callback(slab_sizes[i], heap->class_slabs[i], used,
+             heap->class_slabs[i] * count - used, user);
+  }
+}
+
+void fm_sm_class_stats(fm_class_stats_cb_t callback, void *user) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  class_stats(callback, user);
+  callback_end(&__default_heap, previous);
+  unlock();
+}
+
+void fm_sm_test_report_oom(size_t requested) {
+  (void)requested;
+  fm_stats_t stats;
+  fm_sm_stats(&stats);
+  FM_PRINT("Failed to allocate %zu bytes, used: %zu bytes, free: %zu bytes\n",
+           requested, stats.used_bytes, stats.free_bytes);
+}
+
+typedef struct test_walk_ctx_t {
+  fm_sm_walk_cb_t callback;
+  void *user;
+} test_walk_ctx_t;
+
+static uint32_t tag_of(const void *ptr) {
+  tag_entry_t *entry = tag_find(ptr);
+  return (entry != NULL) ? entry->tag : 0;
+}
+
+static void test_walk_block(void *ptr, size_t size, void *user) {
+  test_walk_ctx_t *ctx = (test_walk_ctx_t *)user;
+  ctx->callback(ptr, size, tag_of(ptr), ctx->user);
+}
+
+static void *malloc_tagged(size_t size, uint32_t tag) {
+  void *p = heap_malloc(&__default_heap, size);
+  if (p != NULL) {
+    tag_set(p, tag);
   }
   return p;
 }
 
-void *fm_sm_malloc(size_t size) {
-  size_t i = slab_index(size);
-  if (i == FM_SM_INVALID_SLAB) {
-    return lm_malloc(size, FM_LM_T_TRANSIENT);
+void *fm_sm_malloc_tagged(size_t size, uint32_t tag) {
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = malloc_tagged(size, tag);
+  unlock();
+  return result;
+}
+
+static int free_sized_checked(void *ptr, size_t size) {
+  if (ptr == NULL) {
+    return 0;
+  }
+  size_t usable = usable_size(&__default_heap, ptr);
+  int mismatch = (usable == 0) || (size > usable);
+  if (!mismatch) {
+    sm_free(&__default_heap, ptr);
+  }
+  return mismatch;
+}
+
+int fm_sm_free_sized_checked(void *ptr, size_t size) {
+  if (reentered(&__default_heap)) {
+    return 0;
+  }
+  lock();
+  int result = free_sized_checked(ptr, size);
+  unlock();
+  return result;
+}
+
+static void test_walk(fm_sm_walk_cb_t callback, void *user) {
+  test_walk_ctx_t ctx = {callback, user};
+  walk_allocations(test_walk_block, &ctx);
+}
+
+void fm_sm_test_walk(fm_sm_walk_cb_t callback, void *user) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  test_walk(callback, user);
+  callback_end(&__default_heap, previous);
+  unlock();
+}
+
+static void histogram_block(void *ptr, size_t size, void *user) {
+  (void)ptr;
+  uint64_t *buckets = (uint64_t *)user;
+  size_t i = 0;
+  while (i + 1 < FM_SM_HISTOGRAM_BUCKETS && (size >> (i + 1)) != 0) {
+    i++;
+  }
+  buckets[i]++;
+}
+
+void fm_sm_test_size_histogram(uint64_t *buckets) {
+  for (size_t i = 0; i < FM_SM_HISTOGRAM_BUCKETS; i++) {
+    buckets[i] = 0;
+  }
+  lock();
+  walk_allocations(histogram_block, buckets);
+  unlock();
+}
+#endif
+
+typedef struct migrate_ctx_t {
+  fm_relocate_cb_t callback;
+  void *ctx;
+  size_t delta;
+  // Range of the new buffer
+  size_t start;
+  size_t end;
+} migrate_ctx_t;
+
+static void migrate_block(void *ptr, size_t size, void *user) {
+  migrate_ctx_t *m = (migrate_ctx_t *)user;
+  if ((size_t)ptr >= m->start && (size_t)ptr < m->end) {
+    m->callback((void *)((size_t)ptr - m->delta), ptr, size, m->ctx);
+  }
+}
+
+static int migrate(void *new_buffer, size_t new_size,
+                   fm_relocate_cb_t callback, void *ctx) {
+  fm_heap_t *heap = &__default_heap;
+  void *old_buffer;
+  size_t old_size;
+  int ret = fm_lm_migrate(new_buffer, new_size, &old_buffer, &old_size);
+  if (ret != 0) {
+    return ret;
+  }
+  size_t start = (size_t)old_buffer;
+  size_t end = start + old_size;
+  size_t delta = (size_t)new_buffer - start;
+  for (size_t i = 0; i < sizeof(heap->slab_lists) / sizeof(CList); i++) {
+    __fm_relocate_list(&heap->slab_lists[i], start, end, delta);
+  }
+  __fm_relocate_list(&heap->full_slabs, start, end, delta);
+#ifdef FM_TEST_SUPPORT
+  for (size_t i = 0; i < __quarantine_count; i++) {
+    size_t index = (__quarantine_start + i) % FM_SM_MAX_QUARANTINE;
+    size_t p = (size_t)__quarantine[index];
+    if (p >= start && p < end) {
+      __quarantine[index] = (void *)(p + delta);
+    }
+  }
+#endif
+  if (callback != NULL) {
+    migrate_ctx_t m = {callback, ctx, delta, (size_t)new_buffer,
+                       (size_t)new_buffer + new_size};
+    walk_allocations(migrate_block, &m);
   }
-  for (CList *iter = slab_lists[i].next; iter != &slab_lists[i];
+  return 0;
+}
+
+int fm_sm_migrate(void *new_buffer, size_t new_size, fm_relocate_cb_t callback,
+                  void *ctx) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  int result = migrate(new_buffer, new_size, callback, ctx);
+  callback_end(&__default_heap, previous);
+  unlock();
+  return result;
+}
+
+static int extend(size_t additional_bytes) {
+  return fm_lm_extend(additional_bytes);
+}
+
+int fm_sm_extend(size_t additional_bytes) {
+  lock();
+  int result = extend(additional_bytes);
+  unlock();
+  return result;
+}
+
+// Partially used slab in class i with the most used slots, except skipped
+static page_meta_t *densest_slab(const fm_heap_t *heap, size_t i,
+                                 const page_meta_t *skipped) {
+  page_meta_t *densest = NULL;
+  for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
        iter = iter->next) {
     page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
-    size_t index = bitmap_next_free(meta);
-    if (index != FM_SM_INVALID_SLAB) {
-      bitmap_set(meta, index);
-      if (bitmap_all_used(meta)) {
-        c_list_unlink(iter);
-        FM_DEBUG("Unlinking fully utilized slab: %p %ld\n", meta, meta->size);
+    if (meta != skipped &&
+        (densest == NULL || used_slots(meta) > used_slots(densest))) {
+      densest = meta;
+    }
+  }
+  return densest;
+}
+
+static void move_object(fm_heap_t *heap, page_meta_t *source, size_t index,
+                        page_meta_t *target, fm_relocate_cb_t callback,
+                        void *ctx) {
+  void *old_ptr = index_to_ptr(source, index);
+  size_t slot = bitmap_next_free(target);
+  void *new_ptr = index_to_ptr(target, slot);
+  memcpy(new_ptr, old_ptr, source->size);
+  bitmap_set(target, slot);
+  bitmap_clear(source, index);
+  if (bitmap_all_used(target)) {
+    c_list_unlink(&target->link);
+    c_list_link_tail(&heap->full_slabs, &target->link);
+  }
+#ifdef FM_TEST_SUPPORT
+  uint32_t tag = tag_remove(old_ptr);
+  tag_set(new_ptr, tag);
+#endif
+  if (callback != NULL) {
+    callback(old_ptr, new_ptr, source->size, ctx);
+  }
+}
+
+static size_t compact(fm_relocate_cb_t callback, void *ctx) {
+  fm_heap_t *heap = &__default_heap;
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks are already freed, they shall never be moved
+  evict_quarantine(0);
+#endif
+  size_t reclaimed = 0;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    while (1) {
+      // Empty the sparsest slab, as long as its objects fit in other slabs
+      page_meta_t *source = NULL;
+      size_t free_slots = 0;
+      for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
+           iter = iter->next) {
+        page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+        free_slots += meta->count - used_slots(meta);
+        if (source == NULL || used_slots(meta) < used_slots(source)) {
+          source = meta;
+        }
       }
-      return index_to_ptr(meta, index);
+      if (source == NULL) {
+        break;
+      }
+      size_t used = used_slots(source);
+      if (free_slots - (source->count - used) < used) {
+        break;
+      }
+      for (size_t index = 0; index < source->count; index++) {
+        if (bitmap_is_set(source, index)) {
+          move_object(heap, source, index, densest_slab(heap, i, source),
+                      callback, ctx);
+        }
+      }
+      c_list_unlink(&source->link);
+      fm_lm_free(source);
+      heap->slab_pages--;
+      heap->class_slabs[i]--;
+      reclaimed++;
     }
   }
-  // Create a new slab here
-  void *slab = fm_lm_malloc(FM_PAGE_SIZE, FM_LM_T_PERSISTENT);
-  if (slab == NULL) {
-    return NULL;
+  return reclaimed;
+}
+
+size_t fm_sm_compact(fm_relocate_cb_t callback, void *ctx) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  size_t result = compact(callback, ctx);
+  callback_end(&__default_heap, previous);
+  unlock();
+  return result;
+}
+
+static int slab_report(fm_heap_error_t *error, int kind, void *address) {
+  if (error != NULL) {
+    error->kind = kind;
+    error->address = address;
   }
-  page_meta_t *meta = (page_meta_t *)slab;
-  meta->bitmap[0] = 0;
-  meta->bitmap[1] = 0;
-  meta->size = slab_sizes[i];
-  meta->slab_index = i;
-  meta->count = (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / meta->size;
-  c_list_link_front(&slab_lists[i], &meta->link);
-  FM_DEBUG("Creating new slab: %p %ld\n", meta, meta->size);
+  return kind;
+}
 
-  size_t element_index = 0;
-  bitmap_set(meta, element_index);
-  return index_to_ptr(meta, element_index);
+static int verify_slab(const page_meta_t *meta, fm_heap_error_t *error) {
+  size_t i = meta->slab_index;
+  if (i >= sizeof(slab_sizes) / sizeof(size_t) ||
+      meta->size != slab_sizes[i] ||
+      meta->count != (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / meta->size) {
+    return slab_report(error, FM_HEAP_CORRUPTED_HEADER, (void *)meta);
+  }
+  // Bits beyond the slot count are never set
+  for (size_t index = meta->count; index < BITMAP_WORDS * 64; index++) {
+    if (bitmap_is_set(meta, index)) {
+      return slab_report(error, FM_HEAP_CORRUPTED_HEADER, (void *)meta);
+    }
+  }
+#ifdef FM_FILL_ON_FREE
+  for (size_t index = 0; index < meta->count; index++) {
+    if (bitmap_is_set(meta, index)) {
+      continue;
+    }
+    const uint8_t *p = (const uint8_t *)index_to_ptr(meta, index);
+    for (size_t offset = 0; offset < meta->size; offset++) {
+      if (p[offset] != FM_FILL_PATTERN) {
+        return slab_report(error,
+                           (offset < meta->size / 2) ? FM_HEAP_OVERFLOW_GUARD
+                                                     : FM_HEAP_UNDERFLOW_GUARD,
+                           (void *)p);
+      }
+    }
+  }
+#endif
+  return FM_HEAP_OK;
+}
+
+// Walk a slab list, full is 1 for the list of fully used slabs, slabs count
+// the number of slabs seen so far.
+static int verify_slab_list(const fm_heap_t *heap, const CList *list,
+                            size_t class_index, int full, size_t *slabs,
+                            fm_heap_error_t *error) {
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    if (iter->next->prev != iter || ++(*slabs) > heap->slab_pages) {
+      return slab_report(error, FM_HEAP_FREE_LIST_CYCLE, iter);
+    }
+    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+    if ((((size_t)meta) & (FM_PAGE_SIZE - 1)) != 0 || !fm_lm_contains(meta)) {
+      return slab_report(error, FM_HEAP_CORRUPTED_HEADER, iter);
+    }
+    int ret = verify_slab(meta, error);
+    if (ret != FM_HEAP_OK) {
+      return ret;
+    }
+    if (bitmap_all_used(meta) != full ||
+        (!full && meta->slab_index != class_index)) {
+      return slab_report(error, FM_HEAP_CORRUPTED_HEADER, meta);
+    }
+  }
+  return FM_HEAP_OK;
+}
+
+static int verify(fm_heap_error_t *error) {
+  int ret = fm_lm_verify(error);
+  if (ret != FM_HEAP_OK) {
+    return ret;
+  }
+  const fm_heap_t *heap = &__default_heap;
+  size_t slabs = 0;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    ret = verify_slab_list(heap, &heap->slab_lists[i], i, 0, &slabs, error);
+    if (ret != FM_HEAP_OK) {
+      return ret;
+    }
+  }
+  ret = verify_slab_list(heap, &heap->full_slabs, 0, 1, &slabs, error);
+  if (ret != FM_HEAP_OK) {
+    return ret;
+  }
+  if (slabs != heap->slab_pages) {
+    // Some slab is no longer linked in any list
+    return slab_report(error, FM_HEAP_CORRUPTED_HEADER, NULL);
+  }
+  return FM_HEAP_OK;
+}
+
+int fm_sm_verify(fm_heap_error_t *error) {
+  lock();
+  int result = verify(error);
+  unlock();
+  return result;
+}
+
+// Pointers collected per heap walk, since blocks cannot be freed while the
+// heap is being walked
+#define FM_SM_FREE_BATCH 64
+
+typedef struct free_all_ctx_t {
+  void *ptrs[FM_SM_FREE_BATCH];
+  size_t count;
+} free_all_ctx_t;
+
+static void collect_block(void *ptr, size_t size, void *user) {
+  (void)size;
+  free_all_ctx_t *ctx = (free_all_ctx_t *)user;
+  if (ctx->count < FM_SM_FREE_BATCH) {
+    ctx->ptrs[ctx->count++] = ptr;
+  }
+}
+
+static size_t free_all() {
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks would otherwise stay live forever
+  size_t quarantine_limit = __quarantine_limit;
+  evict_quarantine(0);
+  __quarantine_limit = 0;
+#endif
+  size_t freed = 0;
+  free_all_ctx_t ctx;
+  do {
+    ctx.count = 0;
+    walk_allocations(collect_block, &ctx);
+    for (size_t i = 0; i < ctx.count; i++) {
+      sm_free(&__default_heap, ctx.ptrs[i]);
+    }
+    freed += ctx.count;
+  } while (ctx.count > 0);
+#ifdef FM_TEST_SUPPORT
+  __quarantine_limit = quarantine_limit;
+#endif
+  return freed;
+}
+
+size_t fm_sm_free_all() {
+  lock();
+  size_t result = free_all();
+  unlock();
+  return result;
+}
+
+static size_t count_slabs(const CList *list) {
+  size_t count = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    count++;
+  }
+  return count;
+}
+
+static void put_slabs(void *out, size_t len, size_t *pos, const CList *list) {
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+    __fm_snapshot_put(out, len, pos, &meta, sizeof(page_meta_t *));
+    __fm_snapshot_put(out, len, pos, meta, sizeof(page_meta_t));
+  }
+}
+
+// Snapshot layout: size of the linear malloc snapshot followed by itself,
+// a copy of the heap, the number of slabs followed by their addresses and
+// headers, then the quarantine with FM_TEST_SUPPORT.
+static ptrdiff_t snapshot(void *out, size_t out_len) {
+  fm_heap_t *heap = &__default_heap;
+  size_t pos = 0;
+  int fits = out_len >= sizeof(size_t);
+  ptrdiff_t lm = fm_lm_snapshot(fits ? (uint8_t *)out + sizeof(size_t) : NULL,
+                                fits ? out_len - sizeof(size_t) : 0);
+  size_t lm_size = (lm < 0) ? (size_t)(-lm) : (size_t)lm;
+  __fm_snapshot_put(out, out_len, &pos, &lm_size, sizeof(size_t));
+  pos += lm_size;
+  __fm_snapshot_put(out, out_len, &pos, heap, sizeof(fm_heap_t));
+  size_t count = count_slabs(&heap->full_slabs);
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    count += count_slabs(&heap->slab_lists[i]);
+  }
+  __fm_snapshot_put(out, out_len, &pos, &count, sizeof(size_t));
+  put_slabs(out, out_len, &pos, &heap->full_slabs);
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    put_slabs(out, out_len, &pos, &heap->slab_lists[i]);
+  }
+#ifdef FM_TEST_SUPPORT
+  __fm_snapshot_put(out, out_len, &pos, __quarantine, sizeof(__quarantine));
+  __fm_snapshot_put(out, out_len, &pos, &__quarantine_limit, sizeof(size_t));
+  __fm_snapshot_put(out, out_len, &pos, &__quarantine_start, sizeof(size_t));
+  __fm_snapshot_put(out, out_len, &pos, &__quarantine_count, sizeof(size_t));
+#endif
+  return (pos <= out_len) ? (ptrdiff_t)pos : -(ptrdiff_t)pos;
+}
+
+ptrdiff_t fm_sm_snapshot(void *out, size_t out_len) {
+  lock();
+  ptrdiff_t result = snapshot(out, out_len);
+  unlock();
+  return result;
+}
+
+static int restore(const void *in, size_t len) {
+  fm_heap_t *heap = &__default_heap;
+  // Validate the slab part first, linear malloc validates its own part
+  size_t pos = 0;
+  size_t lm_size;
+  if (__fm_snapshot_get(in, len, &pos, &lm_size, sizeof(size_t)) ||
+      __fm_snapshot_get(in, len, &pos, NULL, lm_size)) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  fm_heap_t copy;
+  size_t count;
+  if (__fm_snapshot_get(in, len, &pos, &copy, sizeof(fm_heap_t)) ||
+      copy.lm != heap->lm ||
+      __fm_snapshot_get(in, len, &pos, &count, sizeof(size_t))) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  size_t slabs_start = pos;
+  for (size_t i = 0; i < count; i++) {
+    page_meta_t *meta = NULL;
+    if (__fm_snapshot_get(in, len, &pos, &meta, sizeof(page_meta_t *)) ||
+        (((size_t)meta) & (FM_PAGE_SIZE - 1)) != 0 || !fm_lm_contains(meta) ||
+        __fm_snapshot_get(in, len, &pos, NULL, sizeof(page_meta_t))) {
+      return FM_ERR_BAD_SNAPSHOT;
+    }
+  }
+#ifdef FM_TEST_SUPPORT
+  size_t quarantine_start = pos;
+  if (__fm_snapshot_get(in, len, &pos, NULL,
+                        sizeof(__quarantine) + 3 * sizeof(size_t))) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+#endif
+  if (pos != len) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  int ret = fm_lm_restore((const uint8_t *)in + sizeof(size_t), lm_size);
+  if (ret != 0) {
+    return ret;
+  }
+
+  pos = slabs_start;
+  for (size_t i = 0; i < count; i++) {
+    page_meta_t *meta = NULL;
+    __fm_snapshot_get(in, len, &pos, &meta, sizeof(page_meta_t *));
+    __fm_snapshot_get(in, len, &pos, meta, sizeof(page_meta_t));
+  }
+  memcpy(heap, &copy, sizeof(fm_heap_t));
+#ifdef FM_TEST_SUPPORT
+  pos = quarantine_start;
+  __fm_snapshot_get(in, len, &pos, __quarantine, sizeof(__quarantine));
+  __fm_snapshot_get(in, len, &pos, &__quarantine_limit, sizeof(size_t));
+  __fm_snapshot_get(in, len, &pos, &__quarantine_start, sizeof(size_t));
+  __fm_snapshot_get(in, len, &pos, &__quarantine_count, sizeof(size_t));
+#endif
+  return 0;
+}
+
+int fm_sm_restore(const void *in, size_t len) {
+  lock();
+  int result = restore(in, len);
+  unlock();
+  return result;
 }
 
 #endif /* FIXED_MALLOC_DECLARATION_ONLY */
@@ -0,0 +1,518 @@
+/*
+ * Public declarations of fixed-malloc, generated by concat_all.py
+ */
+#ifndef FIXED_MALLOC_H_
+#define FIXED_MALLOC_H_
+
+/* linear-malloc.h */
+#ifndef FIXED_MALLOC_LINEAR_MALLOC_H_
+#define FIXED_MALLOC_LINEAR_MALLOC_H_
+
+#include <stddef.h>
+#include <stdint.h>
+
+// Pages of 4096 bytes unless configured otherwise
+#ifndef FM_PAGE_SHIFT
+#define FM_PAGE_SHIFT 12
+#endif
+#if (FM_PAGE_SHIFT < 11) || (FM_PAGE_SHIFT > 14)
+#error "Page shift must be between 11 and 14!"
+#endif
+#define FM_PAGE_SIZE (1 << FM_PAGE_SHIFT)
+
+// Marks the public API, which stays visible when the sources are built with
+// -fvisibility=hidden, e.g. for a shared library
+#ifndef FM_API
+#if defined(__GNUC__) || defined(__clang__)
+#define FM_API __attribute__((visibility("default")))
+#else
+#define FM_API
+#endif
+#endif
+
+// Bumped whenever the functions or structures declared here change
+// incompatibly, see fm_abi_version
+#define FM_ABI_VERSION 1
+
+// Version of fixed-malloc packed as major << 16 | minor << 8 | patch, see
+// fm_version. build.rs defines it from Cargo.toml, 0 is left when the sources
+// are built some other way.
+#ifndef FM_VERSION
+#define FM_VERSION 0
+#endif
+
+// Options the library is built with, as reported by fm_features
+#define FM_FEATURE_GUARDS 0x1
+#define FM_FEATURE_TEST_SUPPORT 0x2
+#define FM_FEATURE_MANUAL_INIT 0x4
+#define FM_FEATURE_HARDENING 0x8
+#define FM_FEATURE_FILL_ON_FREE 0x10
+#define FM_FEATURE_DEBUG_CALLBACK 0x20
+#define FM_FEATURE_TRAP_REENTRANT 0x40
+
+// At least 2 pages are required: one for accounting, one for allocation.
+#define FM_MIN_MEMORY_SIZE (2 * FM_PAGE_SIZE)
+// The bookkeeping page keeps one byte per page, which limits a buffer to as
+// many pages as the page has bytes, including the bookkeeping page itself.
+// That is 16MB with 4KB pages.
+#define FM_MAX_MEMORY_SIZE (FM_PAGE_SIZE * FM_PAGE_SIZE - FM_PAGE_SIZE)
+
+#define FM_LM_T_TRANSIENT 0x1
+#define FM_LM_T_PERSISTENT 0x2
+
+// Error codes latched by failing operations, see fm_last_error
+#define FM_OK 0
+// Requested size is larger than the whole heap
+#define FM_ERR_TOO_LARGE 1
+// Heap is exhausted or too fragmented to satisfy the request
+#define FM_ERR_NO_MEMORY 2
+#define FM_ERR_NOT_INITIALIZED 3
+// Pointer is not allocated by fixed-malloc, only checked with FM_HARDENING
+// except for slab objects freed twice
+#define FM_ERR_BAD_POINTER 4
+// Alignment is not a power of two
+#define FM_ERR_BAD_ALIGNMENT 5
+// Errors returned by reinit for invalid memory buffers
+#define FM_ERR_NULL_BUFFER 6
+#define FM_ERR_UNALIGNED_BUFFER 7
+#define FM_ERR_UNALIGNED_SIZE 8
+#define FM_ERR_BUFFER_TOO_SMALL 9
+#define FM_ERR_BUFFER_TOO_LARGE 10
+// Reinit is refused since there are still live allocations
+#define FM_ERR_LIVE_ALLOCATIONS 11
+// New memory buffer overlaps memory regions already in use
+#define FM_ERR_BUFFER_OVERLAP 12
+// All slots for extra memory regions are taken
+#define FM_ERR_TOO_MANY_REGIONS 13
+// Snapshot is truncated or taken from other memory regions
+#define FM_ERR_BAD_SNAPSHOT 14
+// Allocator called from a hook or callback it is running
+#define FM_ERR_REENTRANT 15
+// Bookkeeping data is found corrupted by an internal check, which aborts
+#define FM_ERR_CORRUPTED 16
+
+// Kinds of heap corruption reported by fm_lm_verify and fm_sm_verify
+#define FM_HEAP_OK 0
+// Bookkeeping data of a page block, free region or slab is inconsistent
+#define FM_HEAP_CORRUPTED_HEADER 1
+// A free list or slab list is broken or loops forever
+#define FM_HEAP_FREE_LIST_CYCLE 2
+// The end of a freed slab slot is modified, usually by an underflow of the
+// following object. Only detected with FM_FILL_ON_FREE.
+#define FM_HEAP_UNDERFLOW_GUARD 3
+// The start of a freed slab slot is modified, usually by an overflow of the
+// preceding object. Only detected with FM_FILL_ON_FREE.
+#define FM_HEAP_OVERFLOW_GUARD 4
+
+typedef struct fm_heap_error_t {
+  int kind;
+  // Start of the corrupted structure, NULL when it cannot be located
+  void *address;
+} fm_heap_error_t;
+
+// Returns FM_ABI_VERSION of the library, which can differ from the header when
+// linking against a prebuilt library
+FM_API int fm_abi_version();
+// Returns FM_VERSION of the library
+FM_API uint32_t fm_version();
+// Returns the FM_FEATURE_* bits of the options the library is built with
+FM_API uint32_t fm_features();
+
+// Returns the error code of the last failing operation, or FM_OK
+FM_API int fm_last_error();
+FM_API void fm_clear_error();
+
+typedef void (*fm_error_sink_t)(int code, const char *message, void *ctx);
+// Install a sink receiving internal errors, such as invalid buffers passed to
+// reinit or failed guard checks, along with their error codes. Unlike debug
+// messages they are never compiled out. The sink is called before aborting,
+// it must not call into the allocator. Pass NULL to remove the sink.
+FM_API void fm_lm_set_error_sink(fm_error_sink_t sink, void *ctx);
+
+#ifdef FM_DEBUG_CALLBACK
+typedef void (*fm_debug_cb_t)(const char *message, void *ctx);
+// Install a callback receiving debug messages, such as the reason why reinit
+// rejects a buffer. Only the format strings are passed, so values such as
+// pointers are not filled in. Pass NULL to remove the callback.
+FM_API void fm_set_debug_callback(fm_debug_cb_t callback, void *ctx);
+#endif
+
+// Validate a memory buffer the same way reinit does, returns 0 if valid
+FM_API int fm_lm_check_buffer(void *buffer, size_t size);
+// Extra regions added earlier are dropped
+FM_API int fm_lm_reinit(void *buffer, size_t size, int zero_filled);
+// Drop all regions, allocations then fail with FM_ERR_NOT_INITIALIZED until
+// the next reinit
+FM_API void fm_lm_deinit();
+// Add a memory region not contiguous with the existing ones, up to
+// FM_MAX_EXTRA_REGIONS regions can be added after reinit. A single
+// allocation never spans regions.
+FM_API int fm_lm_add_region(void *buffer, size_t size, int zero_filled);
+FM_API int fm_lm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                             void **old_buffer, size_t *old_size);
+// Same as fm_lm_reinit, but buffer is added as the second region, and the
+// first region is then reserved for fm_lm_malloc_reserved. All other
+// allocations are served from the remaining regions.
+FM_API int fm_lm_reinit_split(void *reserved_buffer, size_t reserved_size,
+                              void *buffer, size_t size, int zero_filled);
+FM_API void *fm_lm_malloc(size_t size, int t);
+// Allocate from the reserved first region, or the same as fm_lm_malloc when
+// no region is reserved
+FM_API void *fm_lm_malloc_reserved(size_t size, int t);
+// Largest size fm_lm_malloc, or fm_lm_malloc_reserved, can satisfy right now.
+// Freed pages not yet merged back are merged first.
+FM_API size_t fm_lm_max_alloc_size();
+FM_API size_t fm_lm_max_alloc_size_reserved();
+// Allocate zero-filled pages for n values of size bytes each
+FM_API void *fm_lm_calloc(size_t n, size_t size, int t);
+// Allocate pages aligned on align bytes, which must be a power of two
+FM_API void *fm_lm_aligned_malloc(size_t size, size_t align, int t);
+FM_API void fm_lm_free(void *ptr);
+// Resize ptr, t is the type of the returned block and need not match the one
+// ptr was allocated with. The block is kept in place whenever the pages it
+// needs are free there, whatever its type. Otherwise it is moved to new
+// pages taken like fm_lm_malloc does for t, which migrates a persistent block
+// to transient pages or vice versa.
+FM_API void *fm_lm_realloc(void *ptr, size_t size, int t);
+// Same as fm_lm_realloc, but NULL is returned when the pages following ptr
+// are taken, in which case ptr is left untouched.
+FM_API void *fm_lm_realloc_in_place(void *ptr, size_t size);
+// Shrink ptr to the pages needed for size, but at least one, and free the
+// pages following them. NULL is returned when size exceeds the block, which
+// is then left untouched.
+FM_API void *fm_lm_shrink_release(void *ptr, size_t size);
+// Count pages available for allocation, as well as pages that are free now.
+// Freed pages that are not yet merged back are also counted as free.
+FM_API void fm_lm_stats(size_t *total_pages, size_t *free_pages);
+// Number of allocated blocks of pages
+FM_API size_t fm_lm_live_blocks();
+// Number of pages in those blocks, which is kept up to date on each call so
+// unlike fm_lm_stats no free list is walked
+FM_API size_t fm_lm_used_pages();
+// Number of memory regions including the first one
+FM_API size_t fm_lm_regions();
+// Start and size of the first memory region, which is the buffer passed to
+// reinit, including its bookkeeping page. NULL and 0 before initialization.
+FM_API void fm_lm_buffer_range(void **start, size_t *size);
+// Returns 1 if the pointer lies within pages available for allocations
+FM_API int fm_lm_contains(const void *ptr);
+// Size in bytes of the allocated block starting at ptr, or 0 if ptr is not
+// the start of an allocated block
+FM_API size_t fm_lm_block_size(const void *ptr);
+
+typedef void (*fm_walk_cb_t)(void *ptr, size_t size, void *user);
+
+// Invoke callback for each allocated block of pages in address order, regions
+// are visited in the order they are added
+FM_API void fm_lm_walk(fm_walk_cb_t callback, void *user);
+// Check the bookkeeping data of all regions, returns FM_HEAP_OK when intact.
+// Otherwise the first problem found is also written to error if not NULL.
+FM_API int fm_lm_verify(fm_heap_error_t *error);
+// Copy the first region into a larger buffer, which must not overlap any
+// region. Pages beyond the current size become free pages. Nothing is
+// changed when an error is returned.
+FM_API int fm_lm_migrate(void *new_buffer, size_t new_size, void **old_buffer,
+                         size_t *old_size);
+// Grow the first region in place, the additional bytes must directly follow
+// the end of the current buffer and be owned by the allocator afterwards.
+FM_API int fm_lm_extend(size_t additional_bytes);
+
+// Serialize the bookkeeping data of all regions into out, which are the
+// state, bookkeeping pages and free region headers. Returns the number of
+// bytes written, or the negated size needed when out_len is too small.
+FM_API ptrdiff_t fm_lm_snapshot(void *out, size_t out_len);
+// Write a snapshot of the same regions back, allocations made after the
+// snapshot are dropped while freed ones become live again. Nothing is
+// changed when an error is returned.
+FM_API int fm_lm_restore(const void *in, size_t len);
+
+// All linear malloc state of an allocator instance. Functions above operate
+// on the default state, the ones below on the given state, where NULL stands
+// for the default state. A new state has to be initialized via
+// fm_lm_state_reinit.
+typedef struct fm_lm_state_t fm_lm_state_t;
+FM_API size_t fm_lm_state_size();
+FM_API int fm_lm_state_reinit(fm_lm_state_t *lm, void *buffer, size_t size,
+                              int zero_filled);
+FM_API void *fm_lm_state_malloc(fm_lm_state_t *lm, size_t size, int t);
+FM_API void *fm_lm_state_malloc_reserved(fm_lm_state_t *lm, size_t size,
+                                         int t);
+FM_API void fm_lm_state_free(fm_lm_state_t *lm, void *ptr);
+FM_API void *fm_lm_state_realloc(fm_lm_state_t *lm, void *ptr, size_t size,
+                                 int t);
+FM_API void *fm_lm_state_realloc_in_place(fm_lm_state_t *lm, void *ptr,
+                                          size_t size);
+FM_API void *fm_lm_state_shrink_release(fm_lm_state_t *lm, void *ptr,
+                                        size_t size);
+FM_API int fm_lm_state_contains(fm_lm_state_t *lm, const void *ptr);
+FM_API size_t fm_lm_state_block_size(fm_lm_state_t *lm, const void *ptr);
+
+#ifdef FM_TEST_SUPPORT
+// Same as the start and size reported by fm_lm_buffer_range
+FM_API void *fm_lm_test_buffer_pointer();
+// Address of the static buffer, or NULL with FM_MANUAL_INIT
+FM_API void *fm_lm_test_static_buffer();
+FM_API size_t fm_lm_test_total_buffer_size();
+FM_API void fm_lm_test_region(size_t index, void **buffer, size_t *size);
+#endif
+
+#ifdef FM_FILL_ON_FREE
+// Fill an allocated block with FM_FILL_PATTERN, which is done by fm_lm_free
+FM_API void fm_lm_fill(void *ptr);
+// Check if a freed block still holds the fill pattern, returns 0 if intact
+FM_API int fm_lm_check_fill(void *ptr);
+#endif
+
+// Return freed blocks to the free pages right away instead of queueing them,
+// and ignore the random seed with FM_HARDENING. Allocated addresses then only
+// depend on the sequence of calls since the heap was last empty. Blocks
+// queued so far are merged when enabling.
+FM_API void fm_lm_set_deterministic(int enabled);
+
+#ifdef FM_HARDENING
+// Enable randomized placement for large allocations using the given seed
+FM_API void fm_lm_set_random_seed(uint64_t seed);
+#endif
+
+#endif /* FIXED_MALLOC_LINEAR_MALLOC_H_ */
+
+/* slab-malloc.h */
+#ifndef FIXED_MALLOC_SLAB_MALLOC_H_
+#define FIXED_MALLOC_SLAB_MALLOC_H_
+
+#include <stddef.h>
+#include <stdint.h>
+
+/* #include "linear-malloc.h" */
+
+typedef void (*fm_oom_hook_t)(size_t requested, void *ctx);
+typedef void (*fm_lock_cb_t)(void *ctx);
+typedef void (*fm_relocate_cb_t)(void *old_ptr, void *new_ptr, size_t size,
+                                 void *ctx);
+typedef void (*fm_class_stats_cb_t)(size_t class_size, size_t slabs,
+                                    size_t used_slots, size_t free_slots,
+                                    void *user);
+
+typedef struct fm_stats_t {
+  // Size of all memory regions, including their bookkeeping pages
+  size_t total_bytes;
+  // Bytes held by live allocations, rounded up to size classes or pages
+  size_t used_bytes;
+  // Bytes in free pages, which are available for new allocations
+  size_t free_bytes;
+  // Pages available for allocations, excluding bookkeeping pages
+  size_t total_pages;
+  // Allocated pages, including pages used by slabs
+  size_t used_pages;
+  size_t free_pages;
+} fm_stats_t;
+
+// Fails with FM_ERR_LIVE_ALLOCATIONS when allocations are not all freed.
+// buffer must be aligned to FM_PAGE_SIZE and size must be a multiple of it,
+// otherwise FM_ERR_UNALIGNED_BUFFER or FM_ERR_UNALIGNED_SIZE is returned.
+FM_API int fm_sm_reinit(void *buffer, size_t size, int zero_filled);
+// Same as fm_sm_reinit, but slabs are only created in slab_buffer, while
+// allocations larger than slab objects are served from linear_buffer. Regions
+// added later also serve large allocations.
+FM_API int fm_sm_reinit_split(void *slab_buffer, size_t slab_size,
+                              void *linear_buffer, size_t linear_size,
+                              int zero_filled);
+// Feed the heap with one more memory region, see fm_lm_add_region
+FM_API int fm_sm_add_region(void *buffer, size_t size, int zero_filled);
+// Hand a block obtained elsewhere, such as a DMA buffer, over to the heap as
+// free memory. ptr must be 16-byte aligned, the whole pages within the block
+// are then added as a memory region, which needs at least FM_MIN_MEMORY_SIZE
+// bytes of them. The block belongs to the heap until the next reinit.
+FM_API int fm_sm_adopt(void *ptr, size_t size);
+// Same as fm_sm_reinit, but all live allocations are discarded
+FM_API int fm_sm_reinit_forced(void *buffer, size_t size, int zero_filled);
+// Same as fm_sm_reinit, but the previously installed buffer and its size are
+// written to old_buffer and old_size first, so the caller can release them.
+FM_API int fm_sm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                             void **old_buffer, size_t *old_size);
+// Detach the heap from all memory regions, discarding live allocations, so
+// the memory can be released. All functions then fail with
+// FM_ERR_NOT_INITIALIZED until the next reinit.
+FM_API void fm_sm_deinit();
+FM_API void *fm_sm_malloc(size_t size);
+// Like free, passing NULL does nothing
+FM_API void fm_sm_free(void *ptr);
+// Free count blocks at once, taking the lock only once. NULL entries are
+// skipped like in fm_sm_free.
+FM_API void fm_sm_free_many(void *const *ptrs, size_t count);
+FM_API void *fm_sm_realloc(void *ptr, size_t size);
+// Only resize ptr where it is, NULL is returned without freeing ptr when the
+// block would have to move. For slab objects this means size must fit in
+// their size classes, while large blocks can take the free pages following
+// them.
+#define FM_REALLOC_NO_MOVE 0x1
+// Same as fm_sm_realloc, but with FM_REALLOC_* flags
+FM_API void *fm_sm_realloc_flags(void *ptr, size_t size, int flags);
+// Shrink ptr in place, the pages of a large block beyond size are returned to
+// the free pages right away, while the block keeps at least one page. Slab
+// objects keep their size classes. NULL is returned when size does not fit
+// in the block, which is then left untouched.
+FM_API void *fm_sm_shrink_release(void *ptr, size_t size);
+FM_API void *fm_sm_calloc(size_t n, size_t size);
+// Allocate pages contiguous pages aligned to FM_PAGE_SIZE, which unlike
+// fm_sm_malloc holds for any size. NULL is returned when pages is 0.
+FM_API void *fm_sm_page_alloc(size_t pages);
+// Free pages allocated by fm_sm_page_alloc, pointers that are not page
+// aligned are rejected with FM_ERR_BAD_POINTER.
+FM_API void fm_sm_page_free(void *ptr);
+FM_API void fm_sm_stats(fm_stats_t *stats);
+// Number of allocations that are not yet freed
+FM_API size_t fm_sm_live_allocations();
+// Bytes held by live allocations in O(1), which is the same as used_bytes of
+// fm_sm_stats: slab objects count with the size of their size classes, and
+// larger blocks with whole pages. Blocks kept in quarantine with
+// FM_TEST_SUPPORT also count until they are evicted.
+FM_API size_t fm_sm_allocated_bytes();
+// Size of the static memory buffer, which is FM_MEMORY_SIZE at compile time.
+// FM_MANUAL_INIT leaves the buffer out, all functions then fail with
+// FM_ERR_NOT_INITIALIZED until a buffer is installed via fm_sm_reinit.
+FM_API size_t fm_sm_default_memory_size();
+// Smallest buffer size accepted by fm_sm_reinit, which holds one bookkeeping
+// page and one page for allocations
+FM_API size_t fm_sm_min_buffer_size();
+// Usable size of the live allocation starting at ptr, or 0 if ptr is not
+// allocated from the current heap. Interior pointers are also rejected.
+FM_API size_t fm_sm_usable_size(const void *ptr);
+// Move the first memory region into a larger buffer, keeping all live
+// allocations at the same offsets, allocations in extra regions stay put.
+// callback, if not NULL, is then invoked once for each moved allocation so
+// the caller can fix up its pointers, slab objects are reported with the size
+// of their size classes. The current buffer is left untouched when an error
+// is returned.
+FM_API int fm_sm_migrate(void *new_buffer, size_t new_size,
+                         fm_relocate_cb_t callback, void *ctx);
+// Grow the heap with additional_bytes of memory directly following the end
+// of the current buffer, live allocations are kept in place.
+FM_API int fm_sm_extend(size_t additional_bytes);
+// Move slab objects out of sparsely used slabs into denser ones, and free the
+// slabs emptied this way. callback, if not NULL, is invoked once for each
+// moved object, all other pointers to moved objects become dangling. Returns
+// the number of pages reclaimed.
+FM_API size_t fm_sm_compact(fm_relocate_cb_t callback, void *ctx);
+// Return slabs without any live objects to the free pages, unlike
+// fm_sm_compact no object is moved. Returns the number of bytes recovered.
+FM_API size_t fm_sm_trim();
+// Largest size malloc can satisfy given the current buffers and what is
+// allocated from them. Empty slabs are returned to the free pages first, the
+// same way a failing malloc does.
+FM_API size_t fm_sm_max_alloc_size();
+// Make allocated addresses a pure function of the calls made since the heap
+// was last empty: freed blocks are merged into the free pages right away,
+// empty slabs are released as soon as their last object is freed, and the
+// random seed set with FM_HARDENING is ignored. Enabling it also releases
+// empty slabs and merges queued blocks, affecting all heap instances.
+FM_API void fm_sm_set_deterministic(int enabled);
+// Check the bookkeeping data of linear malloc and all slabs, as well as fill
+// patterns of free slab slots with FM_FILL_ON_FREE. Returns FM_HEAP_OK when
+// intact, otherwise the first problem found is also written to error if not
+// NULL.
+FM_API int fm_sm_verify(fm_heap_error_t *error);
+// Free all live allocations through the regular free path, so guards and
+// fill patterns are still checked. Returns the number of freed allocations.
+FM_API size_t fm_sm_free_all();
+// Serialize the bookkeeping data of linear malloc and all slabs, as well as
+// the quarantine with FM_TEST_SUPPORT, into out. Allocated memory itself is
+// not copied. Returns the number of bytes written, or the negated size needed
+// when out_len is too small.
+FM_API ptrdiff_t fm_sm_snapshot(void *out, size_t out_len);
+// Bring the heap back to the state of a snapshot taken from the same memory
+// regions, fails with FM_ERR_BAD_SNAPSHOT otherwise. Allocations made after
+// the snapshot are dropped while freed ones become live again, their
+// contents are left as is. Tags are not restored.
+FM_API int fm_sm_restore(const void *in, size_t len);
+
+// Independent heap instance, the global functions above operate on a default
+// instance. Hooks, error state and test support features are still shared.
+typedef struct fm_heap_t fm_heap_t;
+// Create a heap in buffer, its control block is also kept in the buffer so
+// no other memory is needed. NULL is returned on errors, which can then be
+// retrieved via fm_last_error.
+FM_API fm_heap_t *fm_sm_create(void *buffer, size_t size, int zero_filled);
+FM_API void *fm_sm_heap_malloc(fm_heap_t *heap, size_t size);
+FM_API void fm_sm_heap_free(fm_heap_t *heap, void *ptr);
+FM_API void *fm_sm_heap_realloc(fm_heap_t *heap, void *ptr, size_t size);
+// Invalidate the heap along with all its allocations, the buffer can then be
+// reused by the caller.
+FM_API void fm_sm_destroy(fm_heap_t *heap);
+
+// Serialize all fm_sm_* functions with lock and unlock, which are invoked
+// around each call once installed. The lock need not be reentrant, hence
+// other callbacks such as walk or relocation callbacks must not call into the
+// allocator. The OOM hook is the only exception, it runs with the lock
+// released. fm_lm_* functions are never locked, since slab malloc calls them
+// with the lock held. Pass NULL callbacks to disable locking, which must not
+// be changed while other threads use the allocator.
+FM_API void fm_set_lock_callbacks(fm_lock_cb_t lock, fm_lock_cb_t unlock,
+                                  void *ctx);
+
+// Same as fm_lm_set_error_sink, which also covers errors of slab malloc
+FM_API void fm_sm_set_error_sink(fm_error_sink_t sink, void *ctx);
+
+// Install a hook called exactly once for each failed allocation, including
+// failed reallocs. The hook can inspect the heap but must not allocate. Pass
+// NULL to remove the hook.
+//
+// Without lock callbacks, allocation functions called from the OOM hook or
+// the callbacks of fm_sm_migrate, fm_sm_compact, fm_sm_class_stats and
+// fm_sm_test_walk are detected. They fail with FM_ERR_REENTRANT on the heap
+// running the hook, or abort when built with FM_TRAP_REENTRANT.
+FM_API void fm_sm_set_oom_hook(fm_oom_hook_t hook, void *ctx);
+
+#ifdef FM_FILL_ON_FREE
+// Check if a freed block still holds the fill pattern, returns 0 if intact
+FM_API int fm_sm_check_fill(void *ptr);
+#endif
+
+#ifdef FM_HARDENING
+// Enable randomized placement for both slab objects and large allocations.
+// The same seed always produces the same sequence of allocated addresses.
+FM_API void fm_sm_set_random_seed(uint64_t seed);
+#endif
+
+#ifdef FM_TEST_SUPPORT
+// Keep the last n freed blocks out of circulation to help detecting
+// use-after-free bugs, n is capped at FM_SM_MAX_QUARANTINE.
+FM_API void fm_sm_set_quarantine(size_t n);
+// Fill the bytes a block gains in fm_sm_realloc and fm_sm_heap_realloc, from
+// its previous usable size up to its new one, with FM_REALLOC_FILL_PATTERN.
+// Preserved bytes can then be told apart from fresh ones.
+#define FM_REALLOC_FILL_PATTERN 0xCD
+FM_API void fm_sm_set_realloc_fill(int enabled);
+// Usable size of the block passed to the last successful realloc, 0 for NULL.
+// The first min(old size, new size) bytes of the new block must match it.
+FM_API size_t fm_sm_test_realloc_old_size();
+// Print the failed allocation size together with heap stats
+FM_API void fm_sm_test_report_oom(size_t requested);
+// Invoke callback once for each size class, from the smallest to the largest
+FM_API void fm_sm_class_stats(fm_class_stats_cb_t callback, void *user);
+typedef void (*fm_sm_walk_cb_t)(void *ptr, size_t size, uint32_t tag,
+                                void *user);
+// Invoke callback for each live allocation in address order, region by
+// region. Quarantined
+// blocks are also reported. Slab objects are reported with the size of
+// their size classes, untagged allocations have tag 0.
+FM_API void fm_sm_test_walk(fm_sm_walk_cb_t callback, void *user);
+// Count live allocations by size, bucket i holds blocks of [2^i, 2^(i+1))
+// bytes, with larger ones counted in the last bucket. Sizes are the ones
+// fm_sm_test_walk reports.
+#define FM_SM_HISTOGRAM_BUCKETS 16
+FM_API void fm_sm_test_size_histogram(uint64_t *buckets);
+// Allocate and tag a block for leak attribution, a tag of 0 means untagged.
+// Tags are kept when the block is reallocated.
+FM_API void *fm_sm_malloc_tagged(size_t size, uint32_t tag);
+// Same as fm_sm_free, but size is first checked against the block ptr
+// points to, which is rounded up to its size class or pages. Nonzero is
+// returned without freeing the block when size does not fit in the block, or
+// ptr is not a live allocation. Smaller sizes are accepted, since realloc
+// keeps shrunk blocks in place.
+FM_API int fm_sm_free_sized_checked(void *ptr, size_t size);
+#endif
+
+#endif /* FIXED_MALLOC_SLAB_MALLOC_H_ */
+
+#endif /* FIXED_MALLOC_H_ */
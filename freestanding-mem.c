@@ -0,0 +1,49 @@
+// Byte-wise memcpy, memset and memmove for freestanding programs which get
+// them from neither a C library nor compiler-builtins. They are weak and live
+// in their own object file, so the linker only picks them up when nothing
+// else defines them.
+#include <stddef.h>
+
+#if defined(__GNUC__) && !defined(__clang__)
+// Keep GCC from turning the loops below back into calls to themselves
+#define FM_MEM_ATTRIBUTES \
+  __attribute__((weak, optimize("no-tree-loop-distribute-patterns")))
+#else
+#define FM_MEM_ATTRIBUTES __attribute__((weak))
+#endif
+
+FM_MEM_ATTRIBUTES void *memcpy(void *dst, const void *src, size_t n) {
+  unsigned char *d = (unsigned char *)dst;
+  const unsigned char *s = (const unsigned char *)src;
+  while (n > 0) {
+    *d++ = *s++;
+    n--;
+  }
+  return dst;
+}
+
+FM_MEM_ATTRIBUTES void *memset(void *dst, int c, size_t n) {
+  unsigned char *d = (unsigned char *)dst;
+  while (n > 0) {
+    *d++ = (unsigned char)c;
+    n--;
+  }
+  return dst;
+}
+
+FM_MEM_ATTRIBUTES void *memmove(void *dst, const void *src, size_t n) {
+  unsigned char *d = (unsigned char *)dst;
+  const unsigned char *s = (const unsigned char *)src;
+  if (d < s) {
+    while (n > 0) {
+      *d++ = *s++;
+      n--;
+    }
+  } else {
+    while (n > 0) {
+      n--;
+      d[n] = s[n];
+    }
+  }
+  return dst;
+}
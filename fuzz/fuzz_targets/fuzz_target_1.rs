@@ -0,0 +1,88 @@
+// Interleave malloc, free and realloc as directed by the input, verifying the
+// whole heap after each operation. Run it with `cargo fuzz run fuzz_target_1`
+// from the repository root.
+#![no_main]
+
+use core::ffi::c_void;
+use fixed_malloc::{ffi, FixedAlloc};
+use libfuzzer_sys::fuzz_target;
+use std::alloc::{alloc_zeroed, Layout};
+use std::sync::OnceLock;
+
+const MEMORY_SIZE: usize = 1024 * 1024;
+
+// Buffer shared by all runs, which is reinitialized for every input
+fn buffer() -> *mut u8 {
+    static BUFFER: OnceLock<usize> = OnceLock::new();
+    *BUFFER.get_or_init(|| {
+        let layout = Layout::from_size_align(MEMORY_SIZE, ffi::FM_PAGE_SIZE).unwrap();
+        unsafe { alloc_zeroed(layout) as usize }
+    }) as *mut u8
+}
+
+// Each live block is filled with a byte derived from the address it is first
+// allocated at, so blocks handed out twice or overwritten by the allocator are
+// noticed on free.
+fn fill(ptr: *mut c_void, size: usize) -> u8 {
+    let byte = (ptr as usize >> 4) as u8;
+    unsafe { (ptr as *mut u8).write_bytes(byte, size) };
+    byte
+}
+
+fn check(ptr: *mut c_void, size: usize, byte: u8) {
+    let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, size) };
+    assert!(
+        bytes.iter().all(|b| *b == byte),
+        "Block {:p} of {} bytes is corrupted!",
+        ptr,
+        size
+    );
+}
+
+fuzz_target!(|data: &[u8]| {
+    let alloc = unsafe { FixedAlloc::new(buffer(), MEMORY_SIZE, false) };
+    let mut live: Vec<(*mut c_void, usize, u8)> = vec![];
+
+    // Each operation takes 3 bytes: an op byte, whose lower bits select
+    // alloc/free/realloc and remaining bits the block to free or realloc,
+    // followed by a little endian u16 size.
+    for op in data.chunks_exact(3) {
+        let size = u16::from_le_bytes([op[1], op[2]]) as usize;
+        let index = (op[0] / 3) as usize;
+        match op[0] % 3 {
+            0 => {
+                let ptr = unsafe { ffi::fm_sm_malloc(size) };
+                if !ptr.is_null() {
+                    live.push((ptr, size, fill(ptr, size)));
+                }
+            }
+            1 if !live.is_empty() => {
+                let (ptr, size, byte) = live.swap_remove(index % live.len());
+                check(ptr, size, byte);
+                unsafe { ffi::fm_sm_free(ptr) };
+            }
+            2 if !live.is_empty() => {
+                let index = index % live.len();
+                let (ptr, old_size, byte) = live[index];
+                check(ptr, old_size, byte);
+                // Resizing to 0 is left to free
+                let size = size.max(1);
+                let new_ptr = unsafe { ffi::fm_sm_realloc(ptr, size) };
+                // The old block is kept when realloc fails
+                if !new_ptr.is_null() {
+                    check(new_ptr, old_size.min(size), byte);
+                    unsafe { (new_ptr as *mut u8).write_bytes(byte, size) };
+                    live[index] = (new_ptr, size, byte);
+                }
+            }
+            _ => (),
+        }
+        assert_eq!(alloc.verify_heap_integrity(), Ok(()));
+    }
+
+    for (ptr, size, byte) in live {
+        check(ptr, size, byte);
+        unsafe { ffi::fm_sm_free(ptr) };
+    }
+    assert_eq!(alloc.live_allocations(), 0);
+});
@@ -0,0 +1,29 @@
+// Standard allocation functions forwarding to slab malloc, so C libraries
+// linked into the same program share its heap. The symbols are strong and
+// would clash with a real C library, FM_LIBC_PREFIX can be set to prepend a
+// prefix to all of them, e.g. to test them on hosts.
+#include "slab-malloc.h"
+
+#ifndef FM_LIBC_PREFIX
+#define FM_LIBC_PREFIX
+#endif
+
+#define FM_LIBC_CONCAT(prefix, name) prefix##name
+#define FM_LIBC_EXPAND(prefix, name) FM_LIBC_CONCAT(prefix, name)
+#define FM_LIBC_NAME(name) FM_LIBC_EXPAND(FM_LIBC_PREFIX, name)
+
+FM_API void *FM_LIBC_NAME(malloc)(size_t size) { return fm_sm_malloc(size); }
+
+FM_API void FM_LIBC_NAME(free)(void *ptr) { fm_sm_free(ptr); }
+
+FM_API void *FM_LIBC_NAME(calloc)(size_t n, size_t size) {
+  return fm_sm_calloc(n, size);
+}
+
+FM_API void *FM_LIBC_NAME(realloc)(void *ptr, size_t size) {
+  return fm_sm_realloc(ptr, size);
+}
+
+FM_API size_t FM_LIBC_NAME(malloc_usable_size)(void *ptr) {
+  return fm_sm_usable_size(ptr);
+}
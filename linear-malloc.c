@@ -1,60 +1,180 @@
 #include "linear-malloc.h"
 
-#include <string.h>
-
 #include "c-list.h"
 #include "utils.h"
 
 typedef struct region_t {
   CList link;
-  uint64_t start_page;
-  uint64_t pages;
+  size_t start_page;
+  size_t pages;
 } region_t;
 
 typedef struct meta_t {
-  // Linear malloc supports a maximum of 4096 pages now, which is 16MB.
-  uint8_t pages[4096];
+  // One entry per page, which fills the whole bookkeeping page
+  uint8_t pages[FM_PAGE_SIZE];
 } meta_t;
 
-#ifndef FM_MANUAL_INIT
-#ifndef FM_MEMORY_SIZE
-#define FM_MEMORY_SIZE (640 * 1024)
-#endif
 #if (FM_MEMORY_SIZE & (FM_PAGE_SIZE - 1)) != 0
-#error "Linear malloc memory size must be aligned on 4KB!"
+#error "Linear malloc memory size must be aligned on pages!"
 #endif
-#if (FM_MEMORY_SIZE < 128 * 1024) || (FM_MEMORY_SIZE >= 16 * 1024 * 1024)
-#error "Linear malloc memory size must be between 128KB and 16MB!"
+#if (FM_MEMORY_SIZE < FM_MIN_MEMORY_SIZE) || (FM_MEMORY_SIZE > FM_MAX_MEMORY_SIZE)
+#error "Linear malloc memory size must be between 2 pages and the maximum!"
+#endif
+
+// State of a memory region, regions added via fm_lm_add_region are managed
+// separately and never merged with each other.
+typedef struct heap_t {
+  uint8_t *buffer_start;
+  size_t buffer_size;
+  meta_t *meta;
+  CList free_regions;
+  CList freed_memories;
+} heap_t;
+
+#ifndef FM_MAX_EXTRA_REGIONS
+#define FM_MAX_EXTRA_REGIONS 3
 #endif
 
-static uint8_t __sbuffer[FM_MEMORY_SIZE]
-    __attribute__((aligned(FM_PAGE_SIZE))) = {0};
+struct fm_lm_state_t {
+  heap_t heaps[1 + FM_MAX_EXTRA_REGIONS];
+  size_t heap_count;
+  // 1 when the first region is reserved for fm_lm_malloc_reserved
+  size_t first_region;
+  // Number of allocated blocks, including pages used by slabs
+  size_t live_blocks;
+  // Number of pages held by those blocks
+  size_t used_pages;
+};
+
+#ifndef FM_MANUAL_INIT
+// The alignment also holds in the section set via FM_BUFFER_SECTION, as long
+// as the linker script does not place it at an unaligned address explicitly
+#if defined(FM_BUFFER_SECTION) && defined(_MSC_VER)
+#error "FM_BUFFER_SECTION is not supported with MSVC"
+#elif defined(FM_BUFFER_SECTION)
+#define FM_BUFFER_ATTRIBUTES \
+  __attribute__((section(FM_BUFFER_SECTION), aligned(FM_PAGE_SIZE)))
+#elif defined(_MSC_VER)
+#define FM_BUFFER_ATTRIBUTES _Alignas(FM_PAGE_SIZE)
+#else
+#define FM_BUFFER_ATTRIBUTES __attribute__((aligned(FM_PAGE_SIZE)))
+#endif
+static FM_BUFFER_ATTRIBUTES uint8_t __sbuffer[FM_MEMORY_SIZE] = {0};
 // Forward declaration
-static CList __free_regions;
+static fm_lm_state_t __default_state;
 // Here we employ a slight hack so we can initialize everything at compile time.
 static region_t __initial_region = {
-    .link = {&__free_regions, &__free_regions},
+    .link = {&__default_state.heaps[0].free_regions,
+             &__default_state.heaps[0].free_regions},
     // The first page is set aside for accounting purposes
     .start_page = 1,
     .pages = FM_MEMORY_SIZE / FM_PAGE_SIZE - 1,
 };
-static uint8_t *__buffer_start = __sbuffer;
-static CList __free_regions = {
-    .next = &__initial_region.link,
-    .prev = &__initial_region.link,
+static fm_lm_state_t __default_state = {
+    .heaps = {{
+        .buffer_start = __sbuffer,
+        .buffer_size = FM_MEMORY_SIZE,
+        .meta = (meta_t *)__sbuffer,
+        .free_regions = {&__initial_region.link, &__initial_region.link},
+        .freed_memories = C_LIST_INIT(__default_state.heaps[0].freed_memories),
+    }},
+    .heap_count = 1,
+    .first_region = 0,
+    .live_blocks = 0,
+    .used_pages = 0,
 };
-static meta_t *__meta = (meta_t *)__sbuffer;
 #else
-static uint8_t *__buffer_start = NULL;
-static CList __free_regions = C_LIST_INIT(__free_regions);
-static meta_t *__meta = NULL;
+static fm_lm_state_t __default_state = {
+    .heaps = {{
+        .buffer_start = NULL,
+        .buffer_size = 0,
+        .meta = NULL,
+        .free_regions = C_LIST_INIT(__default_state.heaps[0].free_regions),
+        .freed_memories = C_LIST_INIT(__default_state.heaps[0].freed_memories),
+    }},
+    .heap_count = 1,
+    .first_region = 0,
+    .live_blocks = 0,
+    .used_pages = 0,
+};
 #endif
 
-static CList __freed_memories = C_LIST_INIT(__freed_memories);
+static int __last_error = FM_OK;
+
+void __fm_set_error(int code) { __last_error = code; }
+
+int fm_abi_version() { return FM_ABI_VERSION; }
 
+uint32_t fm_version() { return FM_VERSION; }
+
+uint32_t fm_features() {
+  uint32_t features = 0;
+#ifdef FM_GUARDS
+  features |= FM_FEATURE_GUARDS;
+#endif
 #ifdef FM_TEST_SUPPORT
-#include <stdio.h>
+  features |= FM_FEATURE_TEST_SUPPORT;
+#endif
+#ifdef FM_MANUAL_INIT
+  features |= FM_FEATURE_MANUAL_INIT;
+#endif
+#ifdef FM_HARDENING
+  features |= FM_FEATURE_HARDENING;
+#endif
+#ifdef FM_FILL_ON_FREE
+  features |= FM_FEATURE_FILL_ON_FREE;
+#endif
+#ifdef FM_DEBUG_CALLBACK
+  features |= FM_FEATURE_DEBUG_CALLBACK;
+#endif
+#ifdef FM_TRAP_REENTRANT
+  features |= FM_FEATURE_TRAP_REENTRANT;
+#endif
+  return features;
+}
+
+int fm_last_error() { return __last_error; }
+
+size_t fm_lm_state_size() { return sizeof(fm_lm_state_t); }
+
+// NULL stands for the default state
+static fm_lm_state_t *state_of(fm_lm_state_t *lm) {
+  return (lm != NULL) ? lm : &__default_state;
+}
+
+void fm_clear_error() { __last_error = FM_OK; }
+
+static fm_error_sink_t __error_sink = NULL;
+static void *__error_sink_ctx = NULL;
+
+void fm_lm_set_error_sink(fm_error_sink_t sink, void *ctx) {
+  __error_sink = sink;
+  __error_sink_ctx = ctx;
+}
+
+void __fm_report(int code, const char *message) {
+  if (__error_sink != NULL) {
+    __error_sink(code, message, __error_sink_ctx);
+  }
+}
+
+#ifdef FM_DEBUG_CALLBACK
+static fm_debug_cb_t __debug_callback = NULL;
+static void *__debug_ctx = NULL;
 
+void fm_set_debug_callback(fm_debug_cb_t callback, void *ctx) {
+  __debug_callback = callback;
+  __debug_ctx = ctx;
+}
+
+void __fm_debug(const char *format, ...) {
+  if (__debug_callback != NULL) {
+    __debug_callback(format, __debug_ctx);
+  }
+}
+#endif
+
+#ifdef FM_TEST_SUPPORT
 void fm_lm_test_dump_regions(CList *first, const char *name, int max) {
   (void)name;
   FM_PRINT("### Region %s list first: %p\n", name, first);
@@ -64,8 +184,8 @@ void fm_lm_test_dump_regions(CList *first, const char *name, int max) {
     region_t *region = c_list_entry(iter, region_t, link);
     (void)region;
     FM_PRINT(
-        "  Entry %d iter pointer: %p, actual pointer %p, start: %ld, pages: "
-        "%ld\n",
+        "  Entry %d iter pointer: %p, actual pointer %p, start: %zu, pages: "
+        "%zu\n",
         i, iter, region, region->start_page, region->pages);
     iter = iter->next;
     i++;
@@ -78,77 +198,222 @@ void fm_lm_test_dump_regions(CList *first, const char *name, int max) {
   FM_PRINT("### Region %s ends.\n", name);
 }
 
-#ifndef FM_MANUAL_INIT
-static size_t __buffer_size = FM_MEMORY_SIZE;
+void *fm_lm_test_buffer_pointer() {
+  void *start;
+  size_t size;
+  fm_lm_buffer_range(&start, &size);
+  return start;
+}
+
+void *fm_lm_test_static_buffer() {
+#ifdef FM_MANUAL_INIT
+  return NULL;
 #else
-static size_t __buffer_size = 0;
+  return __sbuffer;
 #endif
+}
 
-void *fm_lm_test_buffer_pointer() { return __buffer_start; }
+size_t fm_lm_test_total_buffer_size() {
+  void *start;
+  size_t size;
+  fm_lm_buffer_range(&start, &size);
+  return size;
+}
 
-size_t fm_lm_test_total_buffer_size() { return __buffer_size; }
+void fm_lm_test_region(size_t index, void **buffer, size_t *size) {
+  *buffer = __default_state.heaps[index].buffer_start;
+  *size = __default_state.heaps[index].buffer_size;
+}
 #endif
 
-int fm_lm_reinit(void *buffer, size_t size, int zero_filled) {
+int fm_lm_check_buffer(void *buffer, size_t size) {
+  if (buffer == NULL) {
+    FM_REPORT(FM_ERR_NULL_BUFFER, "Memory buffer must not be NULL!");
+    return FM_ERR_NULL_BUFFER;
+  }
   if ((((size_t)buffer) & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG("Memory buffer must be aligned at 4K boundary!");
-    FM_ABORT();
+    FM_REPORT(FM_ERR_UNALIGNED_BUFFER,
+              "Memory buffer must be aligned at page boundary!");
+    return FM_ERR_UNALIGNED_BUFFER;
   }
   if ((size & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG("Memory size must be aligned to 4K!");
-    FM_ABORT();
+    FM_REPORT(FM_ERR_UNALIGNED_SIZE, "Memory size must be aligned to pages!");
+    return FM_ERR_UNALIGNED_SIZE;
   }
-  if ((size < 128 * 1024) || (size >= 16 * 1024 * 1024)) {
-    FM_DEBUG("Memory size must be between 128KB and 16MB!");
-    FM_ABORT();
+  if (size < FM_MIN_MEMORY_SIZE) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_SMALL, "Memory size must be at least 2 pages!");
+    return FM_ERR_BUFFER_TOO_SMALL;
+  }
+  if (size > FM_MAX_MEMORY_SIZE) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_LARGE,
+              "Memory size must be less than FM_MAX_MEMORY_SIZE!");
+    return FM_ERR_BUFFER_TOO_LARGE;
   }
 
-  __buffer_start = buffer;
-#ifdef FM_TEST_SUPPORT
-  __buffer_size = size;
-#endif
-  __meta = buffer;
+  return 0;
+}
+
+static void init_heap(heap_t *heap, void *buffer, size_t size,
+                      int zero_filled) {
+  heap->buffer_start = buffer;
+  heap->buffer_size = size;
+  heap->meta = buffer;
   if (!zero_filled) {
     memset(buffer, 0, FM_PAGE_SIZE);
   }
   region_t *region = (region_t *)(((uint8_t *)buffer) + FM_PAGE_SIZE);
   region->start_page = 1;
   region->pages = size / FM_PAGE_SIZE - 1;
-  c_list_init(&__free_regions);
-  c_list_link_after(&__free_regions, &region->link);
-  c_list_init(&__freed_memories);
+  c_list_init(&heap->free_regions);
+  c_list_link_after(&heap->free_regions, &region->link);
+  c_list_init(&heap->freed_memories);
+}
+
+int fm_lm_state_reinit(fm_lm_state_t *lm, void *buffer, size_t size,
+                       int zero_filled) {
+  int ret = fm_lm_check_buffer(buffer, size);
+  if (ret != 0) {
+    return ret;
+  }
+  lm = state_of(lm);
+  lm->live_blocks = 0;
+  lm->used_pages = 0;
+  // Extra regions are dropped as well
+  lm->heap_count = 1;
+  lm->first_region = 0;
+  init_heap(&lm->heaps[0], buffer, size, zero_filled);
+  return 0;
+}
+
+int fm_lm_reinit(void *buffer, size_t size, int zero_filled) {
+  return fm_lm_state_reinit(NULL, buffer, size, zero_filled);
+}
+
+void fm_lm_deinit() {
+  fm_lm_state_t *lm = &__default_state;
+  lm->live_blocks = 0;
+  lm->used_pages = 0;
+  lm->heap_count = 1;
+  lm->first_region = 0;
+  heap_t *heap = &lm->heaps[0];
+  heap->buffer_start = NULL;
+  heap->buffer_size = 0;
+  heap->meta = NULL;
+  c_list_init(&heap->free_regions);
+  c_list_init(&heap->freed_memories);
+}
+
+// Returns 1 if [start, start + size) overlaps any region other than skipped
+static int overlaps_heaps(const fm_lm_state_t *lm, size_t start, size_t size,
+                          const heap_t *skipped) {
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    const heap_t *heap = &lm->heaps[i];
+    size_t heap_start = (size_t)heap->buffer_start;
+    if (heap != skipped && start < heap_start + heap->buffer_size &&
+        heap_start < start + size) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+int fm_lm_add_region(void *buffer, size_t size, int zero_filled) {
+  fm_lm_state_t *lm = &__default_state;
+  if (lm->heaps[0].buffer_start == NULL) {
+    return FM_ERR_NOT_INITIALIZED;
+  }
+  int ret = fm_lm_check_buffer(buffer, size);
+  if (ret != 0) {
+    return ret;
+  }
+  if (lm->heap_count >= 1 + FM_MAX_EXTRA_REGIONS) {
+    FM_REPORT(FM_ERR_TOO_MANY_REGIONS, "Too many memory regions!");
+    return FM_ERR_TOO_MANY_REGIONS;
+  }
+  if (overlaps_heaps(lm, (size_t)buffer, size, NULL)) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "Memory region must not overlap existing ones!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+  init_heap(&lm->heaps[lm->heap_count], buffer, size, zero_filled);
+  lm->heap_count++;
+  return 0;
+}
+
+int fm_lm_reinit_split(void *reserved_buffer, size_t reserved_size,
+                       void *buffer, size_t size, int zero_filled) {
+  // Validate the second buffer first so reinit is never half done
+  int ret = fm_lm_check_buffer(buffer, size);
+  if (ret != 0) {
+    return ret;
+  }
+  if (FM_MAX_EXTRA_REGIONS == 0) {
+    FM_REPORT(FM_ERR_TOO_MANY_REGIONS, "Too many memory regions!");
+    return FM_ERR_TOO_MANY_REGIONS;
+  }
+  if ((size_t)reserved_buffer < (size_t)buffer + size &&
+      (size_t)buffer < (size_t)reserved_buffer + reserved_size) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "Memory region must not overlap existing ones!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+  ret = fm_lm_reinit(reserved_buffer, reserved_size, zero_filled);
+  if (ret != 0) {
+    return ret;
+  }
+  fm_lm_add_region(buffer, size, zero_filled);
+  __default_state.first_region = 1;
   return 0;
 }
 
-static void mark_alloced_pages(size_t first_page, size_t pages) {
+// Find the region containing ptr, the first region is picked when none does
+static heap_t *heap_of(fm_lm_state_t *lm, const void *ptr) {
+  size_t p = (size_t)ptr;
+  for (size_t i = 1; i < lm->heap_count; i++) {
+    size_t start = (size_t)lm->heaps[i].buffer_start;
+    if (p >= start && p < start + lm->heaps[i].buffer_size) {
+      return &lm->heaps[i];
+    }
+  }
+  return &lm->heaps[0];
+}
+
+int fm_lm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                      void **old_buffer, size_t *old_size) {
+  *old_buffer = __default_state.heaps[0].buffer_start;
+  *old_size = __default_state.heaps[0].buffer_size;
+  return fm_lm_reinit(new_buffer, new_size, zero_filled);
+}
+
+static void mark_alloced_pages(heap_t *heap, size_t first_page, size_t pages) {
   if (pages < 0xFF) {
-    __meta->pages[first_page] = (uint8_t)pages;
+    heap->meta->pages[first_page] = (uint8_t)pages;
   } else {
-    __meta->pages[first_page] = 0xFF;
+    heap->meta->pages[first_page] = 0xFF;
     size_t aligned_page = __fm_roundup(first_page + 1, 4);
-    *((uint32_t *)(&__meta->pages[aligned_page])) = (uint32_t)pages;
+    *((uint32_t *)(&heap->meta->pages[aligned_page])) = (uint32_t)pages;
   }
 }
 
-static size_t fetch_alloced_pages(size_t first_page) {
-  uint8_t pages = __meta->pages[first_page];
-  if (__builtin_expect(pages < 0xFF, 1)) {
+static size_t fetch_alloced_pages(const heap_t *heap, size_t first_page) {
+  uint8_t pages = heap->meta->pages[first_page];
+  if (__fm_likely(pages < 0xFF)) {
     return pages;
   }
   size_t aligned_page = __fm_roundup(first_page + 1, 4);
-  return *((uint32_t *)(&__meta->pages[aligned_page]));
+  return *((uint32_t *)(&heap->meta->pages[aligned_page]));
 }
 
-static inline size_t ptr_to_page(void *ptr) {
-  return (((size_t)ptr) - ((size_t)__buffer_start)) / FM_PAGE_SIZE;
+static inline size_t ptr_to_page(const heap_t *heap, const void *ptr) {
+  return (((size_t)ptr) - ((size_t)heap->buffer_start)) / FM_PAGE_SIZE;
 }
 
-static inline void *page_to_ptr(size_t page) {
-  return (void *)(__buffer_start + (page * FM_PAGE_SIZE));
+static inline void *page_to_ptr(const heap_t *heap, size_t page) {
+  return (void *)(heap->buffer_start + (page * FM_PAGE_SIZE));
 }
 
-static inline region_t *move_region(const region_t *src) {
-  region_t *dst = (region_t *)page_to_ptr(src->start_page);
+static inline region_t *move_region(const heap_t *heap, const region_t *src) {
+  region_t *dst = (region_t *)page_to_ptr(heap, src->start_page);
   if (dst == src) {
     return dst;
   }
@@ -160,25 +425,244 @@ static inline region_t *move_region(const region_t *src) {
   return dst;
 }
 
-void fm_lm_free(void *ptr) {
+static size_t count_pages(const CList *list) {
+  size_t pages = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    pages += c_list_entry(iter, region_t, link)->pages;
+  }
+  return pages;
+}
+
+void fm_lm_stats(size_t *total_pages, size_t *free_pages) {
+  *total_pages = 0;
+  *free_pages = 0;
+  for (size_t i = 0; i < __default_state.heap_count; i++) {
+    heap_t *heap = &__default_state.heaps[i];
+    if (heap->buffer_size > 0) {
+      // The first page is set aside for accounting purposes
+      *total_pages += heap->buffer_size / FM_PAGE_SIZE - 1;
+    }
+    *free_pages +=
+        count_pages(&heap->free_regions) + count_pages(&heap->freed_memories);
+  }
+}
+
+#ifdef FM_FILL_ON_FREE
+// The region header used by freed memory is kept intact
+static void fill(const heap_t *heap, void *ptr) {
+  size_t pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+  memset(((uint8_t *)ptr) + sizeof(region_t), FM_FILL_PATTERN,
+         pages * FM_PAGE_SIZE - sizeof(region_t));
+}
+
+static int check_fill(const heap_t *heap, void *ptr) {
+  size_t pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+  return __fm_check_fill(((uint8_t *)ptr) + sizeof(region_t),
+                         pages * FM_PAGE_SIZE - sizeof(region_t));
+}
+
+void fm_lm_fill(void *ptr) { fill(heap_of(&__default_state, ptr), ptr); }
+
+int fm_lm_check_fill(void *ptr) {
+  return check_fill(heap_of(&__default_state, ptr), ptr);
+}
+#endif
+
+// Number of pages in the free region starting at page, or 0 if there is none
+static size_t free_pages_at(const heap_t *heap, size_t page) {
+  const CList *lists[] = {&heap->free_regions, &heap->freed_memories};
+  for (size_t i = 0; i < 2; i++) {
+    for (CList *iter = lists[i]->next; iter != lists[i]; iter = iter->next) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      if (region->start_page == page) {
+        return region->pages;
+      }
+    }
+  }
+  return 0;
+}
+
+void fm_lm_walk(fm_walk_cb_t callback, void *user) {
+  for (size_t i = 0; i < __default_state.heap_count; i++) {
+    const heap_t *heap = &__default_state.heaps[i];
+    size_t total_pages = heap->buffer_size / FM_PAGE_SIZE;
+    size_t page = 1;
+    while (page < total_pages) {
+      size_t pages = free_pages_at(heap, page);
+      if (pages == 0) {
+        pages = fetch_alloced_pages(heap, page);
+        if (pages == 0) {
+          FM_REPORT(FM_ERR_CORRUPTED, "Page is neither free nor allocated!");
+          FM_ABORT();
+        }
+        callback(page_to_ptr(heap, page), pages * FM_PAGE_SIZE, user);
+      }
+      page += pages;
+    }
+  }
+}
+
+static int report(fm_heap_error_t *error, int kind, void *address) {
+  if (error != NULL) {
+    error->kind = kind;
+    error->address = address;
+  }
+  return kind;
+}
+
+// Free lists are bounded by the number of pages in the region
+static int verify_list(const heap_t *heap, const CList *list,
+                       size_t total_pages, fm_heap_error_t *error) {
+  size_t steps = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    if (iter->next->prev != iter || ++steps >= total_pages) {
+      return report(error, FM_HEAP_FREE_LIST_CYCLE, iter);
+    }
+    region_t *region = c_list_entry(iter, region_t, link);
+    int misplaced = (void *)region != page_to_ptr(heap, region->start_page);
+#ifndef FM_MANUAL_INIT
+    // The initial region of the static buffer lives outside of the buffer
+    misplaced = misplaced && region != &__initial_region;
+#endif
+    if (region->start_page == 0 || region->pages == 0 ||
+        region->start_page + region->pages > total_pages || misplaced) {
+      return report(error, FM_HEAP_CORRUPTED_HEADER, region);
+    }
+  }
+  return FM_HEAP_OK;
+}
+
+int fm_lm_verify(fm_heap_error_t *error) {
+  fm_lm_state_t *lm = &__default_state;
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    heap_t *heap = &lm->heaps[i];
+    size_t total_pages = heap->buffer_size / FM_PAGE_SIZE;
+    // Lists are walked below, so they must be checked first
+    int ret = verify_list(heap, &heap->free_regions, total_pages, error);
+    if (ret == FM_HEAP_OK) {
+      ret = verify_list(heap, &heap->freed_memories, total_pages, error);
+    }
+    if (ret != FM_HEAP_OK) {
+      return ret;
+    }
+    size_t page = 1;
+    while (page < total_pages) {
+      size_t pages = free_pages_at(heap, page);
+      if (pages == 0) {
+        pages = fetch_alloced_pages(heap, page);
+      }
+      if (pages == 0 || pages > total_pages - page) {
+        return report(error, FM_HEAP_CORRUPTED_HEADER, page_to_ptr(heap, page));
+      }
+      page += pages;
+    }
+  }
+  return FM_HEAP_OK;
+}
+
+size_t fm_lm_state_block_size(fm_lm_state_t *lm, const void *ptr) {
+  lm = state_of(lm);
+  if (!fm_lm_state_contains(lm, ptr) ||
+      ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0)) {
+    return 0;
+  }
+  // Page counts are left behind when blocks are freed, so the pages have to
+  // be walked from the start to tell whether ptr is a live block.
+  const heap_t *heap = heap_of(lm, ptr);
+  size_t target = ptr_to_page(heap, ptr);
+  size_t page = 1;
+  while (page <= target) {
+    size_t pages = free_pages_at(heap, page);
+    if (pages > 0) {
+      page += pages;
+      continue;
+    }
+    pages = fetch_alloced_pages(heap, page);
+    if (pages == 0) {
+      return 0;
+    }
+    if (page == target) {
+      return pages * FM_PAGE_SIZE;
+    }
+    page += pages;
+  }
+  return 0;
+}
+
+size_t fm_lm_block_size(const void *ptr) {
+  return fm_lm_state_block_size(NULL, ptr);
+}
+
+size_t fm_lm_live_blocks() { return __default_state.live_blocks; }
+
+size_t fm_lm_used_pages() { return __default_state.used_pages; }
+
+size_t fm_lm_regions() { return __default_state.heap_count; }
+
+void fm_lm_buffer_range(void **start, size_t *size) {
+  *start = __default_state.heaps[0].buffer_start;
+  *size = __default_state.heaps[0].buffer_size;
+}
+
+int fm_lm_state_contains(fm_lm_state_t *lm, const void *ptr) {
+  lm = state_of(lm);
+  size_t p = (size_t)ptr;
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    size_t start = (size_t)lm->heaps[i].buffer_start;
+    if ((lm->heaps[i].buffer_start != NULL) && (p >= start + FM_PAGE_SIZE) &&
+        (p < start + lm->heaps[i].buffer_size)) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+int fm_lm_contains(const void *ptr) { return fm_lm_state_contains(NULL, ptr); }
+
+static int __deterministic = 0;
+static void restore_freed_region(heap_t *heap, region_t *free_region);
+
+void fm_lm_state_free(fm_lm_state_t *lm, void *ptr) {
 #ifdef FM_GUARDS
   if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG(
+    FM_REPORT(
+        FM_ERR_BAD_POINTER,
         "Pointer passed to free is not aligned, which might be tampered with!");
     FM_ABORT();
   }
 #endif
-  size_t first_page = ptr_to_page(ptr);
-  size_t pages = fetch_alloced_pages(first_page);
+  lm = state_of(lm);
+  heap_t *heap = heap_of(lm, ptr);
+  size_t first_page = ptr_to_page(heap, ptr);
+  size_t pages = fetch_alloced_pages(heap, first_page);
+#ifdef FM_FILL_ON_FREE
+  fill(heap, ptr);
+#ifdef FM_TEST_SUPPORT
+  // Checked before the pages are merged into the free ones
+  if (check_fill(heap, ptr) != 0) {
+    FM_REPORT(FM_ERR_CORRUPTED, "Memory is not filled after being freed!");
+    FM_ABORT();
+  }
+#endif
+#endif
   region_t *region = (region_t *)ptr;
   region->start_page = first_page;
   region->pages = pages;
-  c_list_link_tail(&__freed_memories, &region->link);
+  if (__deterministic) {
+    // Merging right away leaves no trace of the order blocks are freed in
+    restore_freed_region(heap, region);
+  } else {
+    c_list_link_tail(&heap->freed_memories, &region->link);
+  }
+  lm->live_blocks--;
+  lm->used_pages -= pages;
 }
 
-static size_t alloc_designated_free_pages(size_t start_page,
+void fm_lm_free(void *ptr) { fm_lm_state_free(NULL, ptr); }
+
+static size_t alloc_designated_free_pages(heap_t *heap, size_t start_page,
                                           size_t requested_pages) {
-  for (CList *iter = __free_regions.next; iter != &__free_regions;
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
        iter = iter->next) {
     region_t *region = c_list_entry(iter, region_t, link);
     if (region->start_page == start_page && region->pages >= requested_pages) {
@@ -188,7 +672,7 @@ static size_t alloc_designated_free_pages(size_t start_page,
       if (region->pages == 0) {
         c_list_unlink(iter);
       } else {
-        move_region(region);
+        move_region(heap, region);
       }
       return result;
     }
@@ -196,81 +680,194 @@ static size_t alloc_designated_free_pages(size_t start_page,
   return 0;
 }
 
-static size_t alloc_free_pages(size_t requested_pages) {
-  for (CList *iter = __free_regions.next; iter != &__free_regions;
+static size_t take_front_pages(const heap_t *heap, region_t *region,
+                               size_t requested_pages) {
+  size_t result = region->start_page;
+  region->start_page += requested_pages;
+  region->pages -= requested_pages;
+  if (region->pages == 0) {
+    c_list_unlink(&region->link);
+  } else {
+    // we need to move region to a new location, since the old location
+    // has been allocated
+    move_region(heap, region);
+  }
+  return result;
+}
+
+static size_t take_back_pages(region_t *region, size_t requested_pages) {
+  // The first page is untouched, there is no need to move region struct.
+  size_t result = region->start_page + region->pages - requested_pages;
+  region->pages -= requested_pages;
+  if (region->pages == 0) {
+    c_list_unlink(&region->link);
+  }
+  return result;
+}
+
+#ifdef FM_HARDENING
+static int __random_enabled = 0;
+static uint64_t __random_state = 0;
+
+void fm_lm_set_random_seed(uint64_t seed) {
+  __random_enabled = 1;
+  __random_state = seed;
+}
+
+// Reservoir sampling picks one of all fitting regions with equal possibility
+// in a single pass.
+static region_t *pick_random_region(const heap_t *heap,
+                                    size_t requested_pages) {
+  region_t *picked = NULL;
+  uint64_t fitting = 0;
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
        iter = iter->next) {
     region_t *region = c_list_entry(iter, region_t, link);
     if (region->pages >= requested_pages) {
-      size_t result = region->start_page;
-      region->start_page += requested_pages;
-      region->pages -= requested_pages;
-      if (region->pages == 0) {
-        c_list_unlink(iter);
-      } else {
-        // we need to move region to a new location, since the old location
-        // has been allocated
-        move_region(region);
+      fitting++;
+      if (__fm_random_next(&__random_state) % fitting == 0) {
+        picked = region;
       }
-      return result;
     }
   }
-  return 0;
+  return picked;
 }
+#endif
 
-void *fm_lm_realloc(void *ptr, size_t size, int t) {
-  if (ptr == NULL) {
-    return fm_lm_malloc(size, t);
+static size_t alloc_free_pages(heap_t *heap, size_t requested_pages) {
+#ifdef FM_HARDENING
+  if (__random_enabled && !__deterministic) {
+    region_t *region = pick_random_region(heap, requested_pages);
+    return (region != NULL) ? take_front_pages(heap, region, requested_pages)
+                            : 0;
+  }
+#endif
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+       iter = iter->next) {
+    region_t *region = c_list_entry(iter, region_t, link);
+    if (region->pages >= requested_pages) {
+      return take_front_pages(heap, region, requested_pages);
+    }
   }
+  return 0;
+}
+
+void *fm_lm_state_realloc_in_place(fm_lm_state_t *lm, void *ptr, size_t size) {
 #ifdef FM_GUARDS
   if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
-    FM_DEBUG(
+    FM_REPORT(
+        FM_ERR_BAD_POINTER,
         "Pointer passed to free is not aligned, which might be tampered with!");
     FM_ABORT();
   }
 #endif
-  size = __fm_roundup(size, FM_PAGE_SIZE);
-  size_t new_pages = size / FM_PAGE_SIZE;
-  size_t first_page = ptr_to_page(ptr);
-  size_t pages = fetch_alloced_pages(first_page);
+  lm = state_of(lm);
+  heap_t *heap = heap_of(lm, ptr);
+  // Rounding huge sizes up would wrap around to 0 pages
+  if (size > heap->buffer_size - FM_PAGE_SIZE) {
+    return NULL;
+  }
+  size_t new_pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+  size_t first_page = ptr_to_page(heap, ptr);
+  size_t pages = fetch_alloced_pages(heap, first_page);
   if (new_pages <= pages) {
     return ptr;
   }
-  size_t succeeding_pages =
-      alloc_designated_free_pages(first_page + pages, new_pages - pages);
+  size_t succeeding_pages = alloc_designated_free_pages(
+      heap, first_page + pages, new_pages - pages);
   if (succeeding_pages != 0) {
     // If there are enough free pages that are immediately after current
     // allocated memory, we won't need to move the pages elsewhere
+    mark_alloced_pages(heap, first_page, new_pages);
+    lm->used_pages += new_pages - pages;
     return ptr;
   }
-  void *p = fm_lm_malloc(size, t);
+  return NULL;
+}
+
+void *fm_lm_realloc_in_place(void *ptr, size_t size) {
+  return fm_lm_state_realloc_in_place(NULL, ptr, size);
+}
+
+void *fm_lm_state_shrink_release(fm_lm_state_t *lm, void *ptr, size_t size) {
+#ifdef FM_GUARDS
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
+    FM_REPORT(
+        FM_ERR_BAD_POINTER,
+        "Pointer passed to free is not aligned, which might be tampered with!");
+    FM_ABORT();
+  }
+#endif
+  lm = state_of(lm);
+  heap_t *heap = heap_of(lm, ptr);
+  size_t first_page = ptr_to_page(heap, ptr);
+  size_t pages = fetch_alloced_pages(heap, first_page);
+  if (size > pages * FM_PAGE_SIZE) {
+    return NULL;
+  }
+  // At least one page is kept
+  size_t new_pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+  if (new_pages == 0) {
+    new_pages = 1;
+  }
+  if (new_pages < pages) {
+    // Split the tail off as a block of its own, which is then freed like any
+    // other block
+    mark_alloced_pages(heap, first_page, new_pages);
+    mark_alloced_pages(heap, first_page + new_pages, pages - new_pages);
+    lm->live_blocks++;
+    fm_lm_state_free(lm, page_to_ptr(heap, first_page + new_pages));
+  }
+  return ptr;
+}
+
+void *fm_lm_shrink_release(void *ptr, size_t size) {
+  return fm_lm_state_shrink_release(NULL, ptr, size);
+}
+
+void *fm_lm_state_realloc(fm_lm_state_t *lm, void *ptr, size_t size, int t) {
+  if (ptr == NULL) {
+    return fm_lm_state_malloc(lm, size, t);
+  }
+  if (fm_lm_state_realloc_in_place(lm, ptr, size) != NULL) {
+    return ptr;
+  }
+  heap_t *heap = heap_of(state_of(lm), ptr);
+  size_t pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+  void *p = fm_lm_state_malloc(lm, size, t);
   if (p != NULL) {
     memcpy(p, ptr, pages * FM_PAGE_SIZE);
-    fm_lm_free(ptr);
+    fm_lm_state_free(lm, ptr);
   }
   return p;
 }
 
-static size_t alloc_free_pages_reverse(size_t requested_pages) {
-  for (CList *iter = __free_regions.prev; iter != &__free_regions;
+void *fm_lm_realloc(void *ptr, size_t size, int t) {
+  return fm_lm_state_realloc(NULL, ptr, size, t);
+}
+
+static size_t alloc_free_pages_reverse(heap_t *heap, size_t requested_pages) {
+#ifdef FM_HARDENING
+  if (__random_enabled && !__deterministic) {
+    region_t *region = pick_random_region(heap, requested_pages);
+    return (region != NULL) ? take_back_pages(region, requested_pages) : 0;
+  }
+#endif
+  for (CList *iter = heap->free_regions.prev; iter != &heap->free_regions;
        iter = iter->prev) {
     region_t *region = c_list_entry(iter, region_t, link);
     if (region->pages >= requested_pages) {
-      // The first page is untouched, there is no need to move region struct.
-      size_t result = region->start_page + region->pages - requested_pages;
-      region->pages -= requested_pages;
-      if (region->pages == 0) {
-        c_list_unlink(iter);
-      }
-      return result;
+      return take_back_pages(region, requested_pages);
     }
   }
   return 0;
 }
 
-static void merged_consecutive_pages() {
-  CList *prev_item = __free_regions.next;
+static void merged_consecutive_pages(heap_t *heap) {
+  CList *prev_item = heap->free_regions.next;
   CList *current_item = prev_item->next;
-  while (prev_item != &__free_regions && current_item != &__free_regions) {
+  while (prev_item != &heap->free_regions &&
+         current_item != &heap->free_regions) {
     region_t *prev_region = c_list_entry(prev_item, region_t, link);
     region_t *current_region = c_list_entry(current_item, region_t, link);
 
@@ -286,15 +883,15 @@ static void merged_consecutive_pages() {
   }
 }
 
-static void restore_freed_region(region_t *free_region) {
-  CList *prev_item = &__free_regions;
-  for (CList *iter = __free_regions.next; iter != &__free_regions;
+static void restore_freed_region(heap_t *heap, region_t *free_region) {
+  CList *prev_item = &heap->free_regions;
+  for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
        iter = iter->next) {
     region_t *region = c_list_entry(iter, region_t, link);
     if (free_region->start_page < region->start_page) {
       // Insert pages between prev_item and iter
       int inserted = 0;
-      if (prev_item != &__free_regions) {
+      if (prev_item != &heap->free_regions) {
         region_t *prev_region = c_list_entry(prev_item, region_t, link);
         if (prev_region->start_page + prev_region->pages ==
             free_region->start_page) {
@@ -308,12 +905,12 @@ static void restore_freed_region(region_t *free_region) {
         region->start_page = free_region->start_page;
         region->pages += free_region->pages;
         // Attach free pages to current item, we need to move region here
-        region_t *new_region = move_region(region);
+        region_t *new_region = move_region(heap, region);
         iter = &new_region->link;
         inserted = 1;
       }
       if (inserted) {
-        merged_consecutive_pages();
+        merged_consecutive_pages(heap);
       } else {
         // Attach free pages as a new region
         c_list_link_before(iter, &free_region->link);
@@ -324,40 +921,407 @@ static void restore_freed_region(region_t *free_region) {
   }
   // Insert pages at the very end of the page. Notice at this stage, prev_item
   // contains the last item(if available)
-  c_list_link_tail(&__free_regions, &free_region->link);
-  merged_consecutive_pages();
+  c_list_link_tail(&heap->free_regions, &free_region->link);
+  merged_consecutive_pages(heap);
 }
 
-static void restore_all_freed_memories() {
-  CList *iter = __freed_memories.next;
-  while (iter != &__freed_memories) {
+static void restore_all_freed_memories(heap_t *heap) {
+  CList *iter = heap->freed_memories.next;
+  while (iter != &heap->freed_memories) {
     region_t *region = c_list_entry(iter, region_t, link);
     iter = iter->next;
-    restore_freed_region(region);
+    restore_freed_region(heap, region);
   }
-  c_list_init(&__freed_memories);
+  c_list_init(&heap->freed_memories);
 }
 
-static inline size_t alloc(size_t pages, int t) {
+void fm_lm_set_deterministic(int enabled) {
+  __deterministic = enabled;
+  if (enabled) {
+    for (size_t i = 0; i < __default_state.heap_count; i++) {
+      restore_all_freed_memories(&__default_state.heaps[i]);
+    }
+  }
+}
+
+// Find the first(or last when reverse is set) page in region, that is aligned
+// on align bytes and followed by enough pages. 0 is returned if none exists.
+static size_t find_aligned_page(const heap_t *heap, const region_t *region,
+                                size_t pages, size_t align, int reverse) {
+  if (region->pages < pages) {
+    return 0;
+  }
+  size_t first = (size_t)page_to_ptr(heap, region->start_page);
+  size_t last =
+      (size_t)page_to_ptr(heap, region->start_page + region->pages - pages);
+  size_t p = reverse ? __fm_rounddown(last, align) : __fm_roundup(first, align);
+  if (p < first || p > last) {
+    return 0;
+  }
+  return ptr_to_page(heap, (void *)p);
+}
+
+// Take pages from the middle of a region, the region might be split into two.
+static size_t take_middle_pages(const heap_t *heap, region_t *region,
+                                size_t page, size_t requested_pages) {
+  if (page == region->start_page) {
+    return take_front_pages(heap, region, requested_pages);
+  }
+  size_t tail_pages =
+      region->start_page + region->pages - page - requested_pages;
+  region->pages = page - region->start_page;
+  if (tail_pages > 0) {
+    region_t *tail = (region_t *)page_to_ptr(heap, page + requested_pages);
+    tail->start_page = page + requested_pages;
+    tail->pages = tail_pages;
+    c_list_link_after(&region->link, &tail->link);
+  }
+  return page;
+}
+
+static size_t alloc_aligned(heap_t *heap, size_t pages, size_t align, int t) {
   if (t == FM_LM_T_TRANSIENT) {
-    return alloc_free_pages(pages);
+    for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+         iter = iter->next) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      size_t page = find_aligned_page(heap, region, pages, align, 0);
+      if (page != 0) {
+        return take_middle_pages(heap, region, page, pages);
+      }
+    }
   } else {
-    return alloc_free_pages_reverse(pages);
+    for (CList *iter = heap->free_regions.prev; iter != &heap->free_regions;
+         iter = iter->prev) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      size_t page = find_aligned_page(heap, region, pages, align, 1);
+      if (page != 0) {
+        return take_middle_pages(heap, region, page, pages);
+      }
+    }
   }
+  return 0;
+}
+
+static inline size_t alloc(heap_t *heap, size_t pages, int t) {
+  if (t == FM_LM_T_TRANSIENT) {
+    return alloc_free_pages(heap, pages);
+  } else {
+    return alloc_free_pages_reverse(heap, pages);
+  }
+}
+
+// Allocate from regions [first, end), which are tried in the order they are
+// added
+static void *malloc_in(fm_lm_state_t *lm, size_t first, size_t end,
+                       size_t size, int t) {
+  if (lm->heaps[0].buffer_start == NULL) {
+    __fm_set_error(FM_ERR_NOT_INITIALIZED);
+    return NULL;
+  }
+  int too_large = 1;
+  for (size_t i = first; i < end; i++) {
+    heap_t *heap = &lm->heaps[i];
+    // This also prevents overflows when rounding up
+    if (size > heap->buffer_size - FM_PAGE_SIZE) {
+      continue;
+    }
+    too_large = 0;
+    size_t pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+
+    size_t page = alloc(heap, pages, t);
+    if (page == 0) {
+      restore_all_freed_memories(heap);
+      page = alloc(heap, pages, t);
+    }
+    if (page != 0) {
+      mark_alloced_pages(heap, page, pages);
+      lm->live_blocks++;
+      lm->used_pages += pages;
+      return page_to_ptr(heap, page);
+    }
+  }
+  __fm_set_error(too_large ? FM_ERR_TOO_LARGE : FM_ERR_NO_MEMORY);
+  return NULL;
+}
+
+void *fm_lm_state_malloc(fm_lm_state_t *lm, size_t size, int t) {
+  lm = state_of(lm);
+  return malloc_in(lm, lm->first_region, lm->heap_count, size, t);
+}
+
+void *fm_lm_state_malloc_reserved(fm_lm_state_t *lm, size_t size, int t) {
+  lm = state_of(lm);
+  if (lm->first_region == 0) {
+    return fm_lm_state_malloc(lm, size, t);
+  }
+  return malloc_in(lm, 0, 1, size, t);
 }
 
 void *fm_lm_malloc(size_t size, int t) {
-  size = __fm_roundup(size, FM_PAGE_SIZE);
-  size_t pages = size / FM_PAGE_SIZE;
+  return fm_lm_state_malloc(NULL, size, t);
+}
+
+void *fm_lm_malloc_reserved(size_t size, int t) {
+  return fm_lm_state_malloc_reserved(NULL, size, t);
+}
 
-  size_t page = alloc(pages, t);
-  if (page == 0) {
-    restore_all_freed_memories();
-    page = alloc(pages, t);
+static size_t max_alloc_size_in(fm_lm_state_t *lm, size_t first, size_t end) {
+  if (lm->heaps[0].buffer_start == NULL) {
+    return 0;
+  }
+  size_t max_pages = 0;
+  for (size_t i = first; i < end; i++) {
+    heap_t *heap = &lm->heaps[i];
+    // A failing allocation would merge freed memory before giving up
+    restore_all_freed_memories(heap);
+    for (CList *iter = heap->free_regions.next; iter != &heap->free_regions;
+         iter = iter->next) {
+      region_t *region = c_list_entry(iter, region_t, link);
+      if (region->pages > max_pages) {
+        max_pages = region->pages;
+      }
+    }
   }
-  if (page == 0) {
+  return max_pages * FM_PAGE_SIZE;
+}
+
+size_t fm_lm_max_alloc_size() {
+  return max_alloc_size_in(&__default_state, __default_state.first_region,
+                           __default_state.heap_count);
+}
+
+size_t fm_lm_max_alloc_size_reserved() {
+  if (__default_state.first_region == 0) {
+    return fm_lm_max_alloc_size();
+  }
+  return max_alloc_size_in(&__default_state, 0, 1);
+}
+
+void *fm_lm_aligned_malloc(size_t size, size_t align, int t) {
+  if (align == 0 || (align & (align - 1)) != 0) {
+    __fm_set_error(FM_ERR_BAD_ALIGNMENT);
     return NULL;
   }
-  mark_alloced_pages(page, pages);
-  return page_to_ptr(page);
+  // All pages are aligned on page boundary already
+  if (align <= FM_PAGE_SIZE) {
+    return fm_lm_malloc(size, t);
+  }
+  fm_lm_state_t *lm = &__default_state;
+  if (lm->heaps[0].buffer_start == NULL) {
+    __fm_set_error(FM_ERR_NOT_INITIALIZED);
+    return NULL;
+  }
+  int too_large = 1;
+  for (size_t i = lm->first_region; i < lm->heap_count; i++) {
+    heap_t *heap = &lm->heaps[i];
+    if (size > heap->buffer_size - FM_PAGE_SIZE || align > heap->buffer_size) {
+      continue;
+    }
+    too_large = 0;
+    size_t pages = __fm_roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+    if (pages == 0) {
+      pages = 1;
+    }
+
+    size_t page = alloc_aligned(heap, pages, align, t);
+    if (page == 0) {
+      restore_all_freed_memories(heap);
+      page = alloc_aligned(heap, pages, align, t);
+    }
+    if (page != 0) {
+      mark_alloced_pages(heap, page, pages);
+      lm->live_blocks++;
+      lm->used_pages += pages;
+      return page_to_ptr(heap, page);
+    }
+  }
+  __fm_set_error(too_large ? FM_ERR_TOO_LARGE : FM_ERR_NO_MEMORY);
+  return NULL;
+}
+
+void *fm_lm_calloc(size_t n, size_t size, int t) {
+  size_t total;
+  if (__fm_mul_overflow(n, size, &total)) {
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    return NULL;
+  }
+  void *p = fm_lm_malloc(total, t);
+  if (p != NULL) {
+    // Freed pages are never cleared, the whole block is zeroed here
+    memset(p, 0, __fm_roundup(total, FM_PAGE_SIZE));
+  }
+  return p;
+}
+
+// Pages between old_size and new_size become a new free region
+static void grow(heap_t *heap, size_t old_size, size_t new_size) {
+  heap->buffer_size = new_size;
+  if (new_size > old_size) {
+    size_t old_pages = old_size / FM_PAGE_SIZE;
+    region_t *region = (region_t *)page_to_ptr(heap, old_pages);
+    region->start_page = old_pages;
+    region->pages = new_size / FM_PAGE_SIZE - old_pages;
+    restore_freed_region(heap, region);
+  }
+}
+
+int fm_lm_migrate(void *new_buffer, size_t new_size, void **old_buffer,
+                  size_t *old_size) {
+  // Only the first region is migrated
+  heap_t *heap = &__default_state.heaps[0];
+  if (heap->buffer_start == NULL) {
+    return FM_ERR_NOT_INITIALIZED;
+  }
+  int ret = fm_lm_check_buffer(new_buffer, new_size);
+  if (ret != 0) {
+    return ret;
+  }
+  if (new_size < heap->buffer_size) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_SMALL,
+              "New memory buffer must be larger than the current one!");
+    return FM_ERR_BUFFER_TOO_SMALL;
+  }
+  size_t start = (size_t)heap->buffer_start;
+  size_t end = start + heap->buffer_size;
+  size_t new_start = (size_t)new_buffer;
+  if (overlaps_heaps(&__default_state, new_start, new_size, NULL)) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "New memory buffer must not overlap existing regions!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+
+  *old_buffer = heap->buffer_start;
+  *old_size = heap->buffer_size;
+  // Page indices are relative to the buffer start, only pointers linking
+  // regions need to be adjusted.
+  memcpy(new_buffer, heap->buffer_start, heap->buffer_size);
+  size_t delta = new_start - start;
+  __fm_relocate_list(&heap->free_regions, start, end, delta);
+  __fm_relocate_list(&heap->freed_memories, start, end, delta);
+  heap->buffer_start = new_buffer;
+  heap->meta = new_buffer;
+  grow(heap, *old_size, new_size);
+  return 0;
+}
+
+int fm_lm_extend(size_t additional_bytes) {
+  heap_t *heap = &__default_state.heaps[0];
+  if (heap->buffer_start == NULL) {
+    return FM_ERR_NOT_INITIALIZED;
+  }
+  if ((additional_bytes & (FM_PAGE_SIZE - 1)) != 0) {
+    FM_REPORT(FM_ERR_UNALIGNED_SIZE, "Extended size must be aligned to pages!");
+    return FM_ERR_UNALIGNED_SIZE;
+  }
+  if (additional_bytes > FM_MAX_MEMORY_SIZE - heap->buffer_size) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_LARGE,
+              "Memory size must be less than FM_MAX_MEMORY_SIZE!");
+    return FM_ERR_BUFFER_TOO_LARGE;
+  }
+  size_t end = (size_t)heap->buffer_start + heap->buffer_size;
+  if (overlaps_heaps(&__default_state, end, additional_bytes, heap)) {
+    FM_REPORT(FM_ERR_BUFFER_OVERLAP,
+              "Extended memory must not overlap other regions!");
+    return FM_ERR_BUFFER_OVERLAP;
+  }
+  grow(heap, heap->buffer_size, heap->buffer_size + additional_bytes);
+  return 0;
+}
+
+static size_t count_regions(const CList *list) {
+  size_t count = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    count++;
+  }
+  return count;
+}
+
+static void put_regions(void *out, size_t len, size_t *pos,
+                        const CList *list) {
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    region_t *region = c_list_entry(iter, region_t, link);
+    __fm_snapshot_put(out, len, pos, &region, sizeof(region_t *));
+    __fm_snapshot_put(out, len, pos, region, sizeof(region_t));
+  }
+}
+
+// Snapshot layout: address and copy of the state, bookkeeping pages of all
+// regions, then the number of free region headers followed by their
+// addresses and contents.
+ptrdiff_t fm_lm_snapshot(void *out, size_t out_len) {
+  fm_lm_state_t *lm = &__default_state;
+  size_t pos = 0;
+  __fm_snapshot_put(out, out_len, &pos, &lm, sizeof(fm_lm_state_t *));
+  __fm_snapshot_put(out, out_len, &pos, lm, sizeof(fm_lm_state_t));
+  size_t count = 0;
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    __fm_snapshot_put(out, out_len, &pos, lm->heaps[i].meta, FM_PAGE_SIZE);
+    count += count_regions(&lm->heaps[i].free_regions);
+    count += count_regions(&lm->heaps[i].freed_memories);
+  }
+  __fm_snapshot_put(out, out_len, &pos, &count, sizeof(size_t));
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    put_regions(out, out_len, &pos, &lm->heaps[i].free_regions);
+    put_regions(out, out_len, &pos, &lm->heaps[i].freed_memories);
+  }
+  return (pos <= out_len) ? (ptrdiff_t)pos : -(ptrdiff_t)pos;
+}
+
+static int valid_region_address(const region_t *region) {
+#ifndef FM_MANUAL_INIT
+  if (region == &__initial_region) {
+    return 1;
+  }
+#endif
+  return (((size_t)region) & (FM_PAGE_SIZE - 1)) == 0 &&
+         fm_lm_contains(region);
+}
+
+int fm_lm_restore(const void *in, size_t len) {
+  fm_lm_state_t *lm = &__default_state;
+  // Validate everything first, so nothing is written on errors
+  size_t pos = 0;
+  fm_lm_state_t *state;
+  fm_lm_state_t copy;
+  if (__fm_snapshot_get(in, len, &pos, &state, sizeof(fm_lm_state_t *)) ||
+      __fm_snapshot_get(in, len, &pos, &copy, sizeof(fm_lm_state_t)) ||
+      state != lm || copy.heap_count != lm->heap_count) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    if (copy.heaps[i].buffer_start != lm->heaps[i].buffer_start ||
+        copy.heaps[i].buffer_size != lm->heaps[i].buffer_size ||
+        __fm_snapshot_get(in, len, &pos, NULL, FM_PAGE_SIZE)) {
+      return FM_ERR_BAD_SNAPSHOT;
+    }
+  }
+  size_t count;
+  if (__fm_snapshot_get(in, len, &pos, &count, sizeof(size_t))) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  size_t regions_start = pos;
+  for (size_t i = 0; i < count; i++) {
+    region_t *region = NULL;
+    if (__fm_snapshot_get(in, len, &pos, &region, sizeof(region_t *)) ||
+        !valid_region_address(region) ||
+        __fm_snapshot_get(in, len, &pos, NULL, sizeof(region_t))) {
+      return FM_ERR_BAD_SNAPSHOT;
+    }
+  }
+  if (pos != len) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+
+  pos = sizeof(fm_lm_state_t *) + sizeof(fm_lm_state_t);
+  for (size_t i = 0; i < lm->heap_count; i++) {
+    __fm_snapshot_get(in, len, &pos, lm->heaps[i].meta, FM_PAGE_SIZE);
+  }
+  pos = regions_start;
+  for (size_t i = 0; i < count; i++) {
+    region_t *region = NULL;
+    __fm_snapshot_get(in, len, &pos, &region, sizeof(region_t *));
+    __fm_snapshot_get(in, len, &pos, region, sizeof(region_t));
+  }
+  memcpy(lm, &copy, sizeof(fm_lm_state_t));
+  return 0;
 }
@@ -2,17 +2,269 @@
 #define FIXED_MALLOC_LINEAR_MALLOC_H_
 
 #include <stddef.h>
+#include <stdint.h>
 
+// Pages of 4096 bytes unless configured otherwise
+#ifndef FM_PAGE_SHIFT
 #define FM_PAGE_SHIFT 12
-// 4096
+#endif
+#if (FM_PAGE_SHIFT < 11) || (FM_PAGE_SHIFT > 14)
+#error "Page shift must be between 11 and 14!"
+#endif
 #define FM_PAGE_SIZE (1 << FM_PAGE_SHIFT)
 
+// Marks the public API, which stays visible when the sources are built with
+// -fvisibility=hidden, e.g. for a shared library
+#ifndef FM_API
+#if defined(__GNUC__) || defined(__clang__)
+#define FM_API __attribute__((visibility("default")))
+#else
+#define FM_API
+#endif
+#endif
+
+// Bumped whenever the functions or structures declared here change
+// incompatibly, see fm_abi_version
+#define FM_ABI_VERSION 1
+
+// Version of fixed-malloc packed as major << 16 | minor << 8 | patch, see
+// fm_version. build.rs defines it from Cargo.toml, 0 is left when the sources
+// are built some other way.
+#ifndef FM_VERSION
+#define FM_VERSION 0
+#endif
+
+// Options the library is built with, as reported by fm_features
+#define FM_FEATURE_GUARDS 0x1
+#define FM_FEATURE_TEST_SUPPORT 0x2
+#define FM_FEATURE_MANUAL_INIT 0x4
+#define FM_FEATURE_HARDENING 0x8
+#define FM_FEATURE_FILL_ON_FREE 0x10
+#define FM_FEATURE_DEBUG_CALLBACK 0x20
+#define FM_FEATURE_TRAP_REENTRANT 0x40
+
+// At least 2 pages are required: one for accounting, one for allocation.
+#define FM_MIN_MEMORY_SIZE (2 * FM_PAGE_SIZE)
+// The bookkeeping page keeps one byte per page, which limits a buffer to as
+// many pages as the page has bytes, including the bookkeeping page itself.
+// That is 16MB with 4KB pages.
+#define FM_MAX_MEMORY_SIZE (FM_PAGE_SIZE * FM_PAGE_SIZE - FM_PAGE_SIZE)
+
 #define FM_LM_T_TRANSIENT 0x1
 #define FM_LM_T_PERSISTENT 0x2
 
-int fm_lm_reinit(void *buffer, size_t size, int zero_filled);
-void *fm_lm_malloc(size_t size, int t);
-void fm_lm_free(void *ptr);
-void *fm_lm_realloc(void *ptr, size_t size, int t);
+// Error codes latched by failing operations, see fm_last_error
+#define FM_OK 0
+// Requested size is larger than the whole heap
+#define FM_ERR_TOO_LARGE 1
+// Heap is exhausted or too fragmented to satisfy the request
+#define FM_ERR_NO_MEMORY 2
+#define FM_ERR_NOT_INITIALIZED 3
+// Pointer is not allocated by fixed-malloc, only checked with FM_HARDENING
+// except for slab objects freed twice
+#define FM_ERR_BAD_POINTER 4
+// Alignment is not a power of two
+#define FM_ERR_BAD_ALIGNMENT 5
+// Errors returned by reinit for invalid memory buffers
+#define FM_ERR_NULL_BUFFER 6
+#define FM_ERR_UNALIGNED_BUFFER 7
+#define FM_ERR_UNALIGNED_SIZE 8
+#define FM_ERR_BUFFER_TOO_SMALL 9
+#define FM_ERR_BUFFER_TOO_LARGE 10
+// Reinit is refused since there are still live allocations
+#define FM_ERR_LIVE_ALLOCATIONS 11
+// New memory buffer overlaps memory regions already in use
+#define FM_ERR_BUFFER_OVERLAP 12
+// All slots for extra memory regions are taken
+#define FM_ERR_TOO_MANY_REGIONS 13
+// Snapshot is truncated or taken from other memory regions
+#define FM_ERR_BAD_SNAPSHOT 14
+// Allocator called from a hook or callback it is running
+#define FM_ERR_REENTRANT 15
+// Bookkeeping data is found corrupted by an internal check, which aborts
+#define FM_ERR_CORRUPTED 16
+
+// Kinds of heap corruption reported by fm_lm_verify and fm_sm_verify
+#define FM_HEAP_OK 0
+// Bookkeeping data of a page block, free region or slab is inconsistent
+#define FM_HEAP_CORRUPTED_HEADER 1
+// A free list or slab list is broken or loops forever
+#define FM_HEAP_FREE_LIST_CYCLE 2
+// The end of a freed slab slot is modified, usually by an underflow of the
+// following object. Only detected with FM_FILL_ON_FREE.
+#define FM_HEAP_UNDERFLOW_GUARD 3
+// The start of a freed slab slot is modified, usually by an overflow of the
+// preceding object. Only detected with FM_FILL_ON_FREE.
+#define FM_HEAP_OVERFLOW_GUARD 4
+
+typedef struct fm_heap_error_t {
+  int kind;
+  // Start of the corrupted structure, NULL when it cannot be located
+  void *address;
+} fm_heap_error_t;
+
+// Returns FM_ABI_VERSION of the library, which can differ from the header when
+// linking against a prebuilt library
+FM_API int fm_abi_version();
+// Returns FM_VERSION of the library
+FM_API uint32_t fm_version();
+// Returns the FM_FEATURE_* bits of the options the library is built with
+FM_API uint32_t fm_features();
+
+// Returns the error code of the last failing operation, or FM_OK
+FM_API int fm_last_error();
+FM_API void fm_clear_error();
+
+typedef void (*fm_error_sink_t)(int code, const char *message, void *ctx);
+// Install a sink receiving internal errors, such as invalid buffers passed to
+// reinit or failed guard checks, along with their error codes. Unlike debug
+// messages they are never compiled out. The sink is called before aborting,
+// it must not call into the allocator. Pass NULL to remove the sink.
+FM_API void fm_lm_set_error_sink(fm_error_sink_t sink, void *ctx);
+
+#ifdef FM_DEBUG_CALLBACK
+typedef void (*fm_debug_cb_t)(const char *message, void *ctx);
+// Install a callback receiving debug messages, such as the reason why reinit
+// rejects a buffer. Only the format strings are passed, so values such as
+// pointers are not filled in. Pass NULL to remove the callback.
+FM_API void fm_set_debug_callback(fm_debug_cb_t callback, void *ctx);
+#endif
+
+// Validate a memory buffer the same way reinit does, returns 0 if valid
+FM_API int fm_lm_check_buffer(void *buffer, size_t size);
+// Extra regions added earlier are dropped
+FM_API int fm_lm_reinit(void *buffer, size_t size, int zero_filled);
+// Drop all regions, allocations then fail with FM_ERR_NOT_INITIALIZED until
+// the next reinit
+FM_API void fm_lm_deinit();
+// Add a memory region not contiguous with the existing ones, up to
+// FM_MAX_EXTRA_REGIONS regions can be added after reinit. A single
+// allocation never spans regions.
+FM_API int fm_lm_add_region(void *buffer, size_t size, int zero_filled);
+FM_API int fm_lm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                             void **old_buffer, size_t *old_size);
+// Same as fm_lm_reinit, but buffer is added as the second region, and the
+// first region is then reserved for fm_lm_malloc_reserved. All other
+// allocations are served from the remaining regions.
+FM_API int fm_lm_reinit_split(void *reserved_buffer, size_t reserved_size,
+                              void *buffer, size_t size, int zero_filled);
+FM_API void *fm_lm_malloc(size_t size, int t);
+// Allocate from the reserved first region, or the same as fm_lm_malloc when
+// no region is reserved
+FM_API void *fm_lm_malloc_reserved(size_t size, int t);
+// Largest size fm_lm_malloc, or fm_lm_malloc_reserved, can satisfy right now.
+// Freed pages not yet merged back are merged first.
+FM_API size_t fm_lm_max_alloc_size();
+FM_API size_t fm_lm_max_alloc_size_reserved();
+// Allocate zero-filled pages for n values of size bytes each
+FM_API void *fm_lm_calloc(size_t n, size_t size, int t);
+// Allocate pages aligned on align bytes, which must be a power of two
+FM_API void *fm_lm_aligned_malloc(size_t size, size_t align, int t);
+FM_API void fm_lm_free(void *ptr);
+// Resize ptr, t is the type of the returned block and need not match the one
+// ptr was allocated with. The block is kept in place whenever the pages it
+// needs are free there, whatever its type. Otherwise it is moved to new
+// pages taken like fm_lm_malloc does for t, which migrates a persistent block
+// to transient pages or vice versa.
+FM_API void *fm_lm_realloc(void *ptr, size_t size, int t);
+// Same as fm_lm_realloc, but NULL is returned when the pages following ptr
+// are taken, in which case ptr is left untouched.
+FM_API void *fm_lm_realloc_in_place(void *ptr, size_t size);
+// Shrink ptr to the pages needed for size, but at least one, and free the
+// pages following them. NULL is returned when size exceeds the block, which
+// is then left untouched.
+FM_API void *fm_lm_shrink_release(void *ptr, size_t size);
+// Count pages available for allocation, as well as pages that are free now.
+// Freed pages that are not yet merged back are also counted as free.
+FM_API void fm_lm_stats(size_t *total_pages, size_t *free_pages);
+// Number of allocated blocks of pages
+FM_API size_t fm_lm_live_blocks();
+// Number of pages in those blocks, which is kept up to date on each call so
+// unlike fm_lm_stats no free list is walked
+FM_API size_t fm_lm_used_pages();
+// Number of memory regions including the first one
+FM_API size_t fm_lm_regions();
+// Start and size of the first memory region, which is the buffer passed to
+// reinit, including its bookkeeping page. NULL and 0 before initialization.
+FM_API void fm_lm_buffer_range(void **start, size_t *size);
+// Returns 1 if the pointer lies within pages available for allocations
+FM_API int fm_lm_contains(const void *ptr);
+// Size in bytes of the allocated block starting at ptr, or 0 if ptr is not
+// the start of an allocated block
+FM_API size_t fm_lm_block_size(const void *ptr);
+
+typedef void (*fm_walk_cb_t)(void *ptr, size_t size, void *user);
+
+// Invoke callback for each allocated block of pages in address order, regions
+// are visited in the order they are added
+FM_API void fm_lm_walk(fm_walk_cb_t callback, void *user);
+// Check the bookkeeping data of all regions, returns FM_HEAP_OK when intact.
+// Otherwise the first problem found is also written to error if not NULL.
+FM_API int fm_lm_verify(fm_heap_error_t *error);
+// Copy the first region into a larger buffer, which must not overlap any
+// region. Pages beyond the current size become free pages. Nothing is
+// changed when an error is returned.
+FM_API int fm_lm_migrate(void *new_buffer, size_t new_size, void **old_buffer,
+                         size_t *old_size);
+// Grow the first region in place, the additional bytes must directly follow
+// the end of the current buffer and be owned by the allocator afterwards.
+FM_API int fm_lm_extend(size_t additional_bytes);
+
+// Serialize the bookkeeping data of all regions into out, which are the
+// state, bookkeeping pages and free region headers. Returns the number of
+// bytes written, or the negated size needed when out_len is too small.
+FM_API ptrdiff_t fm_lm_snapshot(void *out, size_t out_len);
+// Write a snapshot of the same regions back, allocations made after the
+// snapshot are dropped while freed ones become live again. Nothing is
+// changed when an error is returned.
+FM_API int fm_lm_restore(const void *in, size_t len);
+
+// All linear malloc state of an allocator instance. Functions above operate
+// on the default state, the ones below on the given state, where NULL stands
+// for the default state. A new state has to be initialized via
+// fm_lm_state_reinit.
+typedef struct fm_lm_state_t fm_lm_state_t;
+FM_API size_t fm_lm_state_size();
+FM_API int fm_lm_state_reinit(fm_lm_state_t *lm, void *buffer, size_t size,
+                              int zero_filled);
+FM_API void *fm_lm_state_malloc(fm_lm_state_t *lm, size_t size, int t);
+FM_API void *fm_lm_state_malloc_reserved(fm_lm_state_t *lm, size_t size,
+                                         int t);
+FM_API void fm_lm_state_free(fm_lm_state_t *lm, void *ptr);
+FM_API void *fm_lm_state_realloc(fm_lm_state_t *lm, void *ptr, size_t size,
+                                 int t);
+FM_API void *fm_lm_state_realloc_in_place(fm_lm_state_t *lm, void *ptr,
+                                          size_t size);
+FM_API void *fm_lm_state_shrink_release(fm_lm_state_t *lm, void *ptr,
+                                        size_t size);
+FM_API int fm_lm_state_contains(fm_lm_state_t *lm, const void *ptr);
+FM_API size_t fm_lm_state_block_size(fm_lm_state_t *lm, const void *ptr);
+
+#ifdef FM_TEST_SUPPORT
+// Same as the start and size reported by fm_lm_buffer_range
+FM_API void *fm_lm_test_buffer_pointer();
+// Address of the static buffer, or NULL with FM_MANUAL_INIT
+FM_API void *fm_lm_test_static_buffer();
+FM_API size_t fm_lm_test_total_buffer_size();
+FM_API void fm_lm_test_region(size_t index, void **buffer, size_t *size);
+#endif
+
+#ifdef FM_FILL_ON_FREE
+// Fill an allocated block with FM_FILL_PATTERN, which is done by fm_lm_free
+FM_API void fm_lm_fill(void *ptr);
+// Check if a freed block still holds the fill pattern, returns 0 if intact
+FM_API int fm_lm_check_fill(void *ptr);
+#endif
+
+// Return freed blocks to the free pages right away instead of queueing them,
+// and ignore the random seed with FM_HARDENING. Allocated addresses then only
+// depend on the sequence of calls since the heap was last empty. Blocks
+// queued so far are merged when enabling.
+FM_API void fm_lm_set_deterministic(int enabled);
+
+#ifdef FM_HARDENING
+// Enable randomized placement for large allocations using the given seed
+FM_API void fm_lm_set_random_seed(uint64_t seed);
+#endif
 
 #endif /* FIXED_MALLOC_LINEAR_MALLOC_H_ */
@@ -1,33 +1,416 @@
 #include "slab-malloc.h"
 
-#include <string.h>
-
 #include "c-list.h"
 #include "linear-malloc.h"
 #include "utils.h"
 
 #define FM_SM_INVALID_SLAB 0xFFFFFFFF
 
-static size_t slab_sizes[] = {32, 64, 128, 512, 1024};
-static CList slab_lists[] = {
-    C_LIST_INIT(slab_lists[0]), C_LIST_INIT(slab_lists[1]),
-    C_LIST_INIT(slab_lists[2]), C_LIST_INIT(slab_lists[3]),
-    C_LIST_INIT(slab_lists[4]),
+#ifndef FM_SLAB_MIN_SIZE
+#define FM_SLAB_MIN_SIZE 32
+#endif
+
+// Smaller classes are only added below the default ones. The two largest
+// classes fit 8 and 4 objects in a page, which are 512 and 1024 bytes with 4KB
+// pages.
+#define LARGE_SLAB_SIZES (FM_PAGE_SIZE / 8), (FM_PAGE_SIZE / 4)
+#if FM_SLAB_MIN_SIZE == 16
+static size_t slab_sizes[] = {16, 32, 64, 128, LARGE_SLAB_SIZES};
+#elif FM_SLAB_MIN_SIZE == 32
+static size_t slab_sizes[] = {32, 64, 128, LARGE_SLAB_SIZES};
+#elif FM_SLAB_MIN_SIZE == 64
+static size_t slab_sizes[] = {64, 128, LARGE_SLAB_SIZES};
+#else
+#error "Minimal slab size must be 16, 32 or 64!"
+#endif
+
+struct fm_heap_t {
+  CList slab_lists[sizeof(slab_sizes) / sizeof(size_t)];
+  // Fully used slabs are never picked for allocations, they are only tracked
+  // so the heap can be walked.
+  CList full_slabs;
+  // Number of pages used as slabs, and total size of live slab objects
+  size_t slab_pages;
+  size_t slab_used_bytes;
+  // Number of slabs, and used slots in those slabs for each size class
+  size_t class_slabs[sizeof(slab_sizes) / sizeof(size_t)];
+  size_t class_used_slots[sizeof(slab_sizes) / sizeof(size_t)];
+  // NULL for the default linear malloc state
+  fm_lm_state_t *lm;
+  // Set while a hook or callback runs for this heap, see callback_begin
+  int in_callback;
 };
 
-int fm_sm_reinit(void *buffer, size_t size, int zero_filled) {
+// Instance used by all global functions
+static fm_heap_t __default_heap = {
+    .slab_lists =
+        {
+            C_LIST_INIT(__default_heap.slab_lists[0]),
+            C_LIST_INIT(__default_heap.slab_lists[1]),
+            C_LIST_INIT(__default_heap.slab_lists[2]),
+            C_LIST_INIT(__default_heap.slab_lists[3]),
+#if FM_SLAB_MIN_SIZE <= 32
+            C_LIST_INIT(__default_heap.slab_lists[4]),
+#endif
+#if FM_SLAB_MIN_SIZE <= 16
+            C_LIST_INIT(__default_heap.slab_lists[5]),
+#endif
+        },
+    .full_slabs = C_LIST_INIT(__default_heap.full_slabs),
+    .lm = NULL,
+};
+
+#ifdef FM_TEST_SUPPORT
+#ifndef FM_SM_MAX_QUARANTINE
+#define FM_SM_MAX_QUARANTINE 64
+#endif
+
+// Ring buffer of freed pointers that are not yet returned to allocators
+static void *__quarantine[FM_SM_MAX_QUARANTINE];
+static size_t __quarantine_limit = 0;
+static size_t __quarantine_start = 0;
+static size_t __quarantine_count = 0;
+
+// Set via fm_sm_set_realloc_fill, see fm_sm_test_realloc_old_size
+static int __realloc_fill = 0;
+static size_t __realloc_old_size = 0;
+
+#ifndef FM_SM_MAX_TAGS
+#define FM_SM_MAX_TAGS 4096
+#endif
+#define FM_SM_TAG_EMPTY 0
+#define FM_SM_TAG_TOMBSTONE ((size_t)-1)
+
+// Open addressing hash table keeping tags of live allocations, allocations
+// without an entry have tag 0. Allocations are keyed by their offsets in the
+// buffer, so the table stays valid when the heap is migrated. Offset 0 is the
+// accounting page, which can never be an allocation.
+typedef struct tag_entry_t {
+  size_t offset;
+  uint32_t tag;
+} tag_entry_t;
+static tag_entry_t __tags[FM_SM_MAX_TAGS];
+
+static size_t tag_offset(const void *ptr) {
+  return (size_t)ptr - (size_t)fm_lm_test_buffer_pointer();
+}
+
+static size_t tag_slot(size_t offset) {
+  return (size_t)(((uint64_t)offset >> 4) * 0x9E3779B97F4A7C15ULL %
+                  FM_SM_MAX_TAGS);
+}
+
+static tag_entry_t *tag_find(const void *ptr) {
+  size_t offset = tag_offset(ptr);
+  size_t start = tag_slot(offset);
+  for (size_t i = 0; i < FM_SM_MAX_TAGS; i++) {
+    tag_entry_t *entry = &__tags[(start + i) % FM_SM_MAX_TAGS];
+    if (entry->offset == offset) {
+      return entry;
+    }
+    if (entry->offset == FM_SM_TAG_EMPTY) {
+      return NULL;
+    }
+  }
+  return NULL;
+}
+
+// When the table is full the tag is simply dropped
+static void tag_set(void *ptr, uint32_t tag) {
+  if (tag == 0) {
+    return;
+  }
+  size_t offset = tag_offset(ptr);
+  size_t start = tag_slot(offset);
+  for (size_t i = 0; i < FM_SM_MAX_TAGS; i++) {
+    tag_entry_t *entry = &__tags[(start + i) % FM_SM_MAX_TAGS];
+    if (entry->offset == FM_SM_TAG_EMPTY ||
+        entry->offset == FM_SM_TAG_TOMBSTONE) {
+      entry->offset = offset;
+      entry->tag = tag;
+      return;
+    }
+  }
+}
+
+static uint32_t tag_remove(const void *ptr) {
+  tag_entry_t *entry = tag_find(ptr);
+  if (entry == NULL) {
+    return 0;
+  }
+  entry->offset = FM_SM_TAG_TOMBSTONE;
+  return entry->tag;
+}
+#endif
+
+
+static fm_lock_cb_t __lock = NULL;
+static fm_lock_cb_t __unlock = NULL;
+static void *__lock_ctx = NULL;
+
+void fm_set_lock_callbacks(fm_lock_cb_t lock, fm_lock_cb_t unlock,
+                           void *ctx) {
+  __lock = lock;
+  __unlock = unlock;
+  __lock_ctx = ctx;
+}
+
+static void lock() {
+  if (__lock != NULL) {
+    __lock(__lock_ctx);
+  }
+}
+
+static void unlock() {
+  if (__unlock != NULL) {
+    __unlock(__lock_ctx);
+  }
+}
+
+// Mark heap as running a hook or callback, so allocations made from there
+// are rejected instead of corrupting the heap. With lock callbacks
+// installed the lock already serializes calls, other threads entering the
+// heap meanwhile are legitimate, hence nothing is marked. Returns the
+// previous mark, which is put back by callback_end.
+static int callback_begin(fm_heap_t *heap) {
+  int previous = heap->in_callback;
+  if (__lock == NULL) {
+    heap->in_callback = 1;
+  }
+  return previous;
+}
+
+static void callback_end(fm_heap_t *heap, int previous) {
+  heap->in_callback = previous;
+}
+
+// Check for an allocation made from a hook or callback of heap, which fails
+// with FM_ERR_REENTRANT, or aborts with FM_TRAP_REENTRANT.
+static int reentered(const fm_heap_t *heap) {
+  if (heap->in_callback) {
+#ifdef FM_TRAP_REENTRANT
+    FM_ABORT();
+#endif
+    __fm_set_error(FM_ERR_REENTRANT);
+    return 1;
+  }
+  return 0;
+}
+
+static fm_oom_hook_t __oom_hook = NULL;
+static void *__oom_hook_ctx = NULL;
+
+static void set_oom_hook(fm_oom_hook_t hook, void *ctx) {
+  __oom_hook = hook;
+  __oom_hook_ctx = ctx;
+}
+
+void fm_sm_set_oom_hook(fm_oom_hook_t hook, void *ctx) {
+  lock();
+  set_oom_hook(hook, ctx);
+  unlock();
+}
+
+// Called with the lock held, which is released while the hook runs so the
+// hook can still inspect the heap
+static void notify_oom(fm_heap_t *heap, size_t requested) {
+  if (__oom_hook != NULL) {
+    fm_oom_hook_t hook = __oom_hook;
+    void *ctx = __oom_hook_ctx;
+    int previous = callback_begin(heap);
+    unlock();
+    hook(requested, ctx);
+    lock();
+    callback_end(heap, previous);
+  }
+}
+
+static void *sm_malloc(fm_heap_t *heap, size_t size);
+static size_t usable_size(fm_heap_t *heap, const void *ptr);
+
+static void init_slabs(fm_heap_t *heap) {
+  c_list_init(&heap->full_slabs);
+  for (size_t i = 0; i < sizeof(heap->slab_lists) / sizeof(CList); i++) {
+    c_list_init(&heap->slab_lists[i]);
+    heap->class_slabs[i] = 0;
+    heap->class_used_slots[i] = 0;
+  }
+  heap->slab_pages = 0;
+  heap->slab_used_bytes = 0;
+}
+
+static void reset_slabs() {
+#ifdef FM_TEST_SUPPORT
+  // Quarantined pointers belong to the previous buffer
+  __quarantine_start = 0;
+  __quarantine_count = 0;
+  memset(__tags, 0, sizeof(__tags));
+#endif
+  init_slabs(&__default_heap);
+}
+
+static size_t live_allocations() {
+  fm_heap_t *heap = &__default_heap;
+  size_t live = fm_lm_live_blocks() - heap->slab_pages;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    live += heap->class_used_slots[i];
+  }
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks are already freed by the caller
+  live -= __quarantine_count;
+#endif
+  return live;
+}
+
+size_t fm_sm_live_allocations() {
+  lock();
+  size_t result = live_allocations();
+  unlock();
+  return result;
+}
+
+static size_t allocated_bytes() {
+  fm_heap_t *heap = &__default_heap;
+  return (fm_lm_used_pages() - heap->slab_pages) * FM_PAGE_SIZE +
+         heap->slab_used_bytes;
+}
+
+size_t fm_sm_allocated_bytes() {
+  lock();
+  size_t result = allocated_bytes();
+  unlock();
+  return result;
+}
+
+size_t fm_sm_default_memory_size() { return FM_MEMORY_SIZE; }
+
+size_t fm_sm_min_buffer_size() { return FM_MIN_MEMORY_SIZE; }
+
+static int reinit_forced(void *buffer, size_t size, int zero_filled) {
   int ret = fm_lm_reinit(buffer, size, zero_filled);
   if (ret != 0) {
     return ret;
   }
-  for (size_t i = 0; i < sizeof(slab_lists) / sizeof(CList); i++) {
-    c_list_init(&slab_lists[i]);
+  reset_slabs();
+  return 0;
+}
+
+static void deinit() {
+  fm_lm_deinit();
+  reset_slabs();
+}
+
+void fm_sm_deinit() {
+  lock();
+  deinit();
+  unlock();
+}
+
+static int reinit(void *buffer, size_t size, int zero_filled) {
+  if (live_allocations() > 0) {
+    return FM_ERR_LIVE_ALLOCATIONS;
+  }
+  return reinit_forced(buffer, size, zero_filled);
+}
+
+int fm_sm_reinit(void *buffer, size_t size, int zero_filled) {
+  lock();
+  int result = reinit(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+int fm_sm_reinit_forced(void *buffer, size_t size, int zero_filled) {
+  lock();
+  int result = reinit_forced(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+static int reinit_split(void *slab_buffer, size_t slab_size,
+                        void *linear_buffer, size_t linear_size,
+                        int zero_filled) {
+  if (live_allocations() > 0) {
+    return FM_ERR_LIVE_ALLOCATIONS;
+  }
+  int ret = fm_lm_reinit_split(slab_buffer, slab_size, linear_buffer,
+                               linear_size, zero_filled);
+  if (ret != 0) {
+    return ret;
+  }
+  reset_slabs();
+  return 0;
+}
+
+int fm_sm_reinit_split(void *slab_buffer, size_t slab_size,
+                       void *linear_buffer, size_t linear_size,
+                       int zero_filled) {
+  lock();
+  int result = reinit_split(slab_buffer, slab_size, linear_buffer,
+                            linear_size, zero_filled);
+  unlock();
+  return result;
+}
+
+static int add_region(void *buffer, size_t size, int zero_filled) {
+  return fm_lm_add_region(buffer, size, zero_filled);
+}
+
+int fm_sm_add_region(void *buffer, size_t size, int zero_filled) {
+  lock();
+  int result = add_region(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+static int adopt(void *ptr, size_t size) {
+  if ((((size_t)ptr) & 15) != 0) {
+    return FM_ERR_BAD_ALIGNMENT;
+  }
+  if (size > (size_t)-1 - (size_t)ptr) {
+    return FM_ERR_BUFFER_TOO_LARGE;
+  }
+  // Only whole pages within the block can be managed
+  size_t start = __fm_roundup((size_t)ptr, FM_PAGE_SIZE);
+  size_t end = __fm_rounddown((size_t)ptr + size, FM_PAGE_SIZE);
+  if (end < start + FM_MIN_MEMORY_SIZE) {
+    return FM_ERR_BUFFER_TOO_SMALL;
+  }
+  return add_region((void *)start, end - start, 0);
+}
+
+int fm_sm_adopt(void *ptr, size_t size) {
+  lock();
+  int result = adopt(ptr, size);
+  unlock();
+  return result;
+}
+
+static int reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                       void **old_buffer, size_t *old_size) {
+  if (live_allocations() > 0) {
+    return FM_ERR_LIVE_ALLOCATIONS;
+  }
+  int ret = fm_lm_reinit_swap(new_buffer, new_size, zero_filled, old_buffer,
+                              old_size);
+  if (ret != 0) {
+    return ret;
   }
+  reset_slabs();
   return 0;
 }
 
+int fm_sm_reinit_swap(void *new_buffer, size_t new_size, int zero_filled,
+                      void **old_buffer, size_t *old_size) {
+  lock();
+  int result =
+      reinit_swap(new_buffer, new_size, zero_filled, old_buffer, old_size);
+  unlock();
+  return result;
+}
+
 static size_t slab_index(size_t size) {
-  // Right now we only have 5 slabs, a linear search shall be enough,
+  // Right now we have at most 6 slabs, a linear search shall be enough,
   // a binary search might be needed once we have more slabs.
   for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
     if (size <= slab_sizes[i]) {
@@ -37,27 +420,33 @@ static size_t slab_index(size_t size) {
   return FM_SM_INVALID_SLAB;
 }
 
+// Enough bits for all slots of the smallest size class
+#define BITMAP_WORDS ((FM_PAGE_SIZE / FM_SLAB_MIN_SIZE + 63) / 64)
+
 typedef struct page_meta_t {
   CList link;
-  uint64_t bitmap[2];
+  uint64_t bitmap[BITMAP_WORDS];
   size_t size;
   size_t count;
   size_t slab_index;
   size_t _padding;
 } page_meta_t;
 
-#define PAGE_META_RESERVED_SIZE 64
+// Slots start after the page meta, rounded up to 64 bytes. Mirrored by
+// FM_SLAB_RESERVED_SIZE in src/ffi.rs.
+#define PAGE_META_RESERVED_SIZE ((sizeof(page_meta_t) + 63) / 64 * 64)
 
 static size_t ptr_to_index(const page_meta_t *meta, const void *ptr) {
   size_t p = (size_t)ptr;
   size_t base = ((size_t)meta) + PAGE_META_RESERVED_SIZE;
 #ifdef FM_GUARDS
   if ((p - base) % meta->size != 0) {
-    FM_DEBUG("Pointer does not lie on the boundary of slab allocated value!");
+    FM_REPORT(FM_ERR_BAD_POINTER,
+              "Pointer does not lie on the boundary of slab allocated value!");
     FM_ABORT();
   }
   if ((p - base) / meta->size >= meta->count) {
-    FM_DEBUG("Pointer exceeds slab count!");
+    FM_REPORT(FM_ERR_BAD_POINTER, "Pointer exceeds slab count!");
     FM_ABORT();
   }
 #endif
@@ -67,7 +456,7 @@ static size_t ptr_to_index(const page_meta_t *meta, const void *ptr) {
 static void *index_to_ptr(const page_meta_t *meta, size_t index) {
 #ifdef FM_GUARDS
   if (index >= meta->count) {
-    FM_DEBUG("Invalid index in slab!");
+    FM_REPORT(FM_ERR_CORRUPTED, "Invalid index in slab!");
     FM_ABORT();
   }
 #endif
@@ -76,20 +465,33 @@ static void *index_to_ptr(const page_meta_t *meta, size_t index) {
 }
 
 static int bitmap_all_cleared(const page_meta_t *meta) {
-  return (meta->bitmap[0] == 0) && (meta->bitmap[1] == 0);
+  for (size_t i = 0; i < BITMAP_WORDS; i++) {
+    if (meta->bitmap[i] != 0) {
+      return 0;
+    }
+  }
+  return 1;
+}
+
+static size_t used_slots(const page_meta_t *meta) {
+  size_t used = 0;
+  for (size_t i = 0; i < BITMAP_WORDS; i++) {
+    used += __fm_popcount64(meta->bitmap[i]);
+  }
+  return used;
 }
 
 static int bitmap_all_used(const page_meta_t *meta) {
-  return (size_t)(__builtin_popcountl(meta->bitmap[0]) +
-                  __builtin_popcountl(meta->bitmap[1])) == meta->count;
+  return used_slots(meta) == meta->count;
 }
 
 static size_t bitmap_next_free(const page_meta_t *meta) {
   size_t zeros = FM_SM_INVALID_SLAB;
-  if (meta->bitmap[0] != (uint64_t)-1) {
-    zeros = __builtin_ctzl(~(meta->bitmap[0]));
-  } else if (meta->bitmap[1] != (uint64_t)-1) {
-    zeros = 64 + __builtin_ctzl(~(meta->bitmap[1]));
+  for (size_t i = 0; i < BITMAP_WORDS; i++) {
+    if (meta->bitmap[i] != (uint64_t)-1) {
+      zeros = i * 64 + __fm_ctz64(~(meta->bitmap[i]));
+      break;
+    }
   }
   if (zeros >= meta->count) {
     return FM_SM_INVALID_SLAB;
@@ -97,6 +499,52 @@ static size_t bitmap_next_free(const page_meta_t *meta) {
   return zeros;
 }
 
+static int bitmap_is_set(const page_meta_t *meta, size_t index) {
+  return (meta->bitmap[index / 64] >> (index % 64)) & 1;
+}
+
+static int __slab_deterministic = 0;
+
+#ifdef FM_HARDENING
+static int __slab_random_enabled = 0;
+static uint64_t __slab_random_state = 0;
+
+static void set_random_seed(uint64_t seed) {
+  __slab_random_enabled = 1;
+  __slab_random_state = seed;
+  fm_lm_set_random_seed(__fm_random_next(&__slab_random_state));
+}
+
+void fm_sm_set_random_seed(uint64_t seed) {
+  lock();
+  set_random_seed(seed);
+  unlock();
+}
+
+// Start from a random slot, then pick the first free slot, wrapping around
+// at the end of the page.
+static size_t bitmap_random_free(const page_meta_t *meta) {
+  size_t start =
+      (size_t)(__fm_random_next(&__slab_random_state) % meta->count);
+  for (size_t i = 0; i < meta->count; i++) {
+    size_t index = (start + i) % meta->count;
+    if (!bitmap_is_set(meta, index)) {
+      return index;
+    }
+  }
+  return FM_SM_INVALID_SLAB;
+}
+#endif
+
+static size_t pick_free(const page_meta_t *meta) {
+#ifdef FM_HARDENING
+  if (__slab_random_enabled && !__slab_deterministic) {
+    return bitmap_random_free(meta);
+  }
+#endif
+  return bitmap_next_free(meta);
+}
+
 static void bitmap_set(page_meta_t *meta, size_t index) {
   meta->bitmap[index / 64] |= ((uint64_t)1) << (index % 64);
 }
@@ -105,96 +553,1222 @@ static void bitmap_clear(page_meta_t *meta, size_t index) {
   meta->bitmap[index / 64] &= (~(((uint64_t)1) << (index % 64)));
 }
 
-void fm_sm_free(void *ptr) {
+static void release(fm_heap_t *heap, void *ptr) {
   if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
-    fm_lm_free(ptr);
+    fm_lm_state_free(heap->lm, ptr);
     return;
   }
   page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
   size_t element_index = ptr_to_index(meta, ptr);
+  // Freeing a slot twice would break the counts of the slab
+  if (!bitmap_is_set(meta, element_index)) {
+    FM_REPORT(FM_ERR_BAD_POINTER, "Slab object is freed twice!");
+    __fm_set_error(FM_ERR_BAD_POINTER);
+    return;
+  }
   int all_used = bitmap_all_used(meta);
+#ifdef FM_FILL_ON_FREE
+  memset(ptr, FM_FILL_PATTERN, meta->size);
+#ifdef FM_TEST_SUPPORT
+  // Checked while the slot is still allocated, clearing it might release the
+  // whole slab
+  if (__fm_check_fill(ptr, meta->size) != 0) {
+    FM_REPORT(FM_ERR_CORRUPTED, "Memory is not filled after being freed!");
+    FM_ABORT();
+  }
+#endif
+#endif
   bitmap_clear(meta, element_index);
+  heap->slab_used_bytes -= meta->size;
+  heap->class_used_slots[meta->slab_index]--;
   if (all_used) {
-    c_list_link_tail(&slab_lists[meta->slab_index], &meta->link);
-    FM_DEBUG("Retrieving previously fully used slab: %p %ld\n", meta,
+    c_list_unlink(&meta->link);
+    c_list_link_tail(&heap->slab_lists[meta->slab_index], &meta->link);
+    FM_DEBUG("Retrieving previously fully used slab: %p %zu\n", meta,
              meta->size);
   }
+  if (__slab_deterministic && bitmap_all_cleared(meta)) {
+    // Empty slabs are not kept around, which would depend on past frees
+    heap->slab_pages--;
+    heap->class_slabs[meta->slab_index]--;
+    c_list_unlink(&meta->link);
+    fm_lm_state_free(heap->lm, meta);
+  }
 }
 
-void *fm_sm_realloc(void *ptr, size_t size) {
+#ifdef FM_FILL_ON_FREE
+int fm_sm_check_fill(void *ptr) {
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return fm_lm_check_fill(ptr);
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  return __fm_check_fill(ptr, meta->size);
+}
+#endif
+
+#ifdef FM_TEST_SUPPORT
+#ifdef FM_FILL_ON_FREE
+static void fill_block(void *ptr) {
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    fm_lm_fill(ptr);
+    return;
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  memset(ptr, FM_FILL_PATTERN, meta->size);
+}
+#endif
+
+static void evict_quarantine(size_t limit) {
+  while (__quarantine_count > limit) {
+    void *oldest = __quarantine[__quarantine_start];
+    __quarantine_start = (__quarantine_start + 1) % FM_SM_MAX_QUARANTINE;
+    __quarantine_count--;
+#ifdef FM_FILL_ON_FREE
+    if (fm_sm_check_fill(oldest) != 0) {
+      FM_REPORT(FM_ERR_CORRUPTED,
+                "Quarantined memory is modified after being freed!");
+      FM_ABORT();
+    }
+#endif
+    release(&__default_heap, oldest);
+  }
+}
+
+static void set_quarantine(size_t n) {
+  if (n > FM_SM_MAX_QUARANTINE) {
+    n = FM_SM_MAX_QUARANTINE;
+  }
+  __quarantine_limit = n;
+  evict_quarantine(n);
+}
+
+void fm_sm_set_quarantine(size_t n) {
+  lock();
+  set_quarantine(n);
+  unlock();
+}
+
+void fm_sm_set_realloc_fill(int enabled) {
+  lock();
+  __realloc_fill = enabled;
+  unlock();
+}
+
+size_t fm_sm_test_realloc_old_size() {
+  lock();
+  size_t result = __realloc_old_size;
+  unlock();
+  return result;
+}
+#endif
+
+#ifdef FM_HARDENING
+static int valid_pointer(fm_heap_t *heap, void *ptr) {
+  if (!fm_lm_state_contains(heap->lm, ptr)) {
+    return 0;
+  }
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return 1;
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  if ((meta->slab_index >= sizeof(slab_sizes) / sizeof(size_t)) ||
+      (meta->size != slab_sizes[meta->slab_index])) {
+    return 0;
+  }
+  size_t p = (size_t)ptr;
+  size_t base = ((size_t)meta) + PAGE_META_RESERVED_SIZE;
+  if ((p < base) || ((p - base) % meta->size != 0) ||
+      ((p - base) / meta->size >= meta->count)) {
+    return 0;
+  }
+  // Double free is also rejected
+  return bitmap_is_set(meta, (p - base) / meta->size);
+}
+#endif
+
+static void sm_free(fm_heap_t *heap, void *ptr) {
+  if (ptr == NULL) {
+    return;
+  }
+#ifdef FM_HARDENING
+  if (!valid_pointer(heap, ptr)) {
+    FM_REPORT(FM_ERR_BAD_POINTER, "Pointer passed to free is not allocated!");
+    __fm_set_error(FM_ERR_BAD_POINTER);
+    return;
+  }
+#endif
+#ifdef FM_TEST_SUPPORT
+  // Tags and quarantine are only kept for the default instance
+  if (heap == &__default_heap) {
+    tag_remove(ptr);
+  }
+  if (__quarantine_limit > 0 && heap == &__default_heap) {
+#ifdef FM_FILL_ON_FREE
+    // Quarantined memory shall also be poisoned
+    fill_block(ptr);
+#endif
+    // Make room first so the ring buffer never overflows
+    evict_quarantine(__quarantine_limit - 1);
+    size_t end =
+        (__quarantine_start + __quarantine_count) % FM_SM_MAX_QUARANTINE;
+    __quarantine[end] = ptr;
+    __quarantine_count++;
+    return;
+  }
+#endif
+  release(heap, ptr);
+}
+
+void fm_sm_free(void *ptr) { fm_sm_heap_free(&__default_heap, ptr); }
+
+void fm_sm_heap_free(fm_heap_t *heap, void *ptr) {
+  if (reentered(heap)) {
+    return;
+  }
+  lock();
+  sm_free(heap, ptr);
+  unlock();
+}
+
+static void free_many(fm_heap_t *heap, void *const *ptrs, size_t count) {
+  for (size_t i = 0; i < count; i++) {
+    sm_free(heap, ptrs[i]);
+  }
+}
+
+void fm_sm_free_many(void *const *ptrs, size_t count) {
+  if (reentered(&__default_heap)) {
+    return;
+  }
+  lock();
+  free_many(&__default_heap, ptrs, count);
+  unlock();
+}
+
+// Requests larger than any buffer are rejected before size classes or pages
+// are computed, so no rounding can wrap around to a small block.
+static int too_large(size_t size) {
+  if (size > FM_MAX_MEMORY_SIZE) {
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    return 1;
+  }
+  return 0;
+}
+
+static void *sm_realloc(fm_heap_t *heap, void *ptr, size_t size) {
+  if (too_large(size)) {
+    notify_oom(heap, size);
+    return NULL;
+  }
   if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
-    return fm_lm_realloc(ptr, size, FM_LM_T_TRANSIENT);
+    void *p = fm_lm_state_realloc(heap->lm, ptr, size, FM_LM_T_TRANSIENT);
+    if (p == NULL) {
+      notify_oom(heap, size);
+    }
+    return p;
   }
   page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
   if (size <= meta->size) {
     return ptr;
   }
-  void *p = fm_sm_malloc(size);
+  void *p = sm_malloc(heap, size);
   if (p != NULL) {
     memcpy(p, ptr, meta->size);
-    fm_sm_free(ptr);
+    sm_free(heap, ptr);
+  } else {
+    notify_oom(heap, size);
   }
   return p;
 }
 
-static void free_empty_slabs() {
-  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
-    CList *iter = slab_lists[i].next;
-    while (iter != &slab_lists[i]) {
-      page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
-      CList *old = iter;
-      iter = iter->next;
-      if (bitmap_all_cleared(meta)) {
-        c_list_unlink(old);
-        fm_lm_free(meta);
-      }
-    }
+static void *shrink_release(fm_heap_t *heap, void *ptr, size_t size) {
+  if (too_large(size)) {
+    return NULL;
+  }
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return fm_lm_state_shrink_release(heap->lm, ptr, size);
   }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  return (size <= meta->size) ? ptr : NULL;
 }
 
-static void *lm_malloc(size_t size, int t) {
-  void *p = fm_lm_malloc(size, t);
-  if (p == NULL) {
-    // When previous attempt fails, try freeing empty slabs, then retry
-    free_empty_slabs();
-    p = fm_lm_malloc(size, t);
+void *fm_sm_shrink_release(void *ptr, size_t size) {
+  if (reentered(&__default_heap)) {
+    return NULL;
   }
-  return p;
+  lock();
+  void *result = shrink_release(&__default_heap, ptr, size);
+  unlock();
+  return result;
 }
 
-void *fm_sm_malloc(size_t size) {
-  size_t i = slab_index(size);
-  if (i == FM_SM_INVALID_SLAB) {
-    return lm_malloc(size, FM_LM_T_TRANSIENT);
+#ifdef FM_TEST_SUPPORT
+// Record the size ptr had before being reallocated to p, and fill the bytes
+// p gained beyond it when enabled
+static void track_realloc(fm_heap_t *heap, void *p, size_t old_size) {
+  __realloc_old_size = old_size;
+  size_t new_size = usable_size(heap, p);
+  if (__realloc_fill && new_size > old_size) {
+    memset((uint8_t *)p + old_size, FM_REALLOC_FILL_PATTERN,
+           new_size - old_size);
   }
-  for (CList *iter = slab_lists[i].next; iter != &slab_lists[i];
-       iter = iter->next) {
-    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
-    size_t index = bitmap_next_free(meta);
-    if (index != FM_SM_INVALID_SLAB) {
-      bitmap_set(meta, index);
-      if (bitmap_all_used(meta)) {
-        c_list_unlink(iter);
-        FM_DEBUG("Unlinking fully utilized slab: %p %ld\n", meta, meta->size);
-      }
-      return index_to_ptr(meta, index);
-    }
+}
+#endif
+
+static void *heap_realloc(fm_heap_t *heap, void *ptr, size_t size) {
+#ifdef FM_TEST_SUPPORT
+  size_t old_size = (ptr != NULL) ? usable_size(heap, ptr) : 0;
+  void *p = sm_realloc(heap, ptr, size);
+  if (p != NULL) {
+    track_realloc(heap, p, old_size);
+  }
+#else
+  void *p = sm_realloc(heap, ptr, size);
+#endif
+  return p;
+}
+
+void *fm_sm_realloc(void *ptr, size_t size) {
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+#ifdef FM_TEST_SUPPORT
+  tag_entry_t *entry = (ptr != NULL) ? tag_find(ptr) : NULL;
+  uint32_t tag = (entry != NULL) ? entry->tag : 0;
+  void *p = heap_realloc(&__default_heap, ptr, size);
+  if (p != NULL && p != ptr) {
+    // Tags move together with reallocated blocks
+    tag_remove(ptr);
+    tag_set(p, tag);
+  }
+#else
+  void *p = heap_realloc(&__default_heap, ptr, size);
+#endif
+  unlock();
+  return p;
+}
+
+static void *resize_in_place(fm_heap_t *heap, void *ptr, size_t size) {
+  if (too_large(size)) {
+    return NULL;
+  }
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return fm_lm_state_realloc_in_place(heap->lm, ptr, size);
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  return (size <= meta->size) ? ptr : NULL;
+}
+
+void *fm_sm_realloc_flags(void *ptr, size_t size, int flags) {
+  if (ptr == NULL || !(flags & FM_REALLOC_NO_MOVE)) {
+    return fm_sm_realloc(ptr, size);
+  }
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = resize_in_place(&__default_heap, ptr, size);
+  unlock();
+  return result;
+}
+
+void *fm_sm_heap_realloc(fm_heap_t *heap, void *ptr, size_t size) {
+  if (reentered(heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = heap_realloc(heap, ptr, size);
+  unlock();
+  return result;
+}
+
+static size_t free_empty_slabs(fm_heap_t *heap) {
+  size_t freed = 0;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    CList *iter = heap->slab_lists[i].next;
+    while (iter != &heap->slab_lists[i]) {
+      page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+      CList *old = iter;
+      iter = iter->next;
+      if (bitmap_all_cleared(meta)) {
+        c_list_unlink(old);
+        fm_lm_state_free(heap->lm, meta);
+        heap->slab_pages--;
+        heap->class_slabs[i]--;
+        freed++;
+      }
+    }
+  }
+  return freed;
+}
+
+void fm_sm_set_error_sink(fm_error_sink_t sink, void *ctx) {
+  lock();
+  fm_lm_set_error_sink(sink, ctx);
+  unlock();
+}
+
+void fm_sm_set_deterministic(int enabled) {
+  lock();
+  __slab_deterministic = enabled;
+  if (enabled) {
+    free_empty_slabs(&__default_heap);
+  }
+  fm_lm_set_deterministic(enabled);
+  unlock();
+}
+
+size_t fm_sm_trim() {
+  if (reentered(&__default_heap)) {
+    return 0;
+  }
+  lock();
+  size_t result = free_empty_slabs(&__default_heap) * FM_PAGE_SIZE;
+  unlock();
+  return result;
+}
+
+size_t fm_sm_max_alloc_size() {
+  if (reentered(&__default_heap)) {
+    return 0;
+  }
+  lock();
+  fm_heap_t *heap = &__default_heap;
+  free_empty_slabs(heap);
+  size_t result = fm_lm_max_alloc_size();
+  size_t classes = sizeof(slab_sizes) / sizeof(size_t);
+  if (result < slab_sizes[classes - 1] &&
+      fm_lm_max_alloc_size_reserved() >= FM_PAGE_SIZE) {
+    // A new slab can still be created for any class
+    result = slab_sizes[classes - 1];
+  }
+  for (size_t i = classes; i > 0 && result < slab_sizes[i - 1]; i--) {
+    if (!c_list_is_empty(&heap->slab_lists[i - 1])) {
+      result = slab_sizes[i - 1];
+    }
+  }
+  unlock();
+  return result;
+}
+
+static void *lm_malloc(fm_heap_t *heap, size_t size, int t) {
+  void *p = fm_lm_state_malloc(heap->lm, size, t);
+  if (p == NULL) {
+    // When previous attempt fails, try freeing empty slabs, then retry
+    free_empty_slabs(heap);
+    p = fm_lm_state_malloc(heap->lm, size, t);
+  }
+  return p;
+}
+
+static void *sm_malloc(fm_heap_t *heap, size_t size) {
+  if (too_large(size)) {
+    return NULL;
+  }
+  size_t i = slab_index(size);
+  if (i == FM_SM_INVALID_SLAB) {
+    return lm_malloc(heap, size, FM_LM_T_TRANSIENT);
+  }
+  for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
+       iter = iter->next) {
+    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+    size_t index = pick_free(meta);
+    if (index != FM_SM_INVALID_SLAB) {
+      bitmap_set(meta, index);
+      heap->slab_used_bytes += meta->size;
+      heap->class_used_slots[i]++;
+      if (bitmap_all_used(meta)) {
+        c_list_unlink(iter);
+        c_list_link_tail(&heap->full_slabs, &meta->link);
+        FM_DEBUG("Unlinking fully utilized slab: %p %zu\n", meta, meta->size);
+      }
+      return index_to_ptr(meta, index);
+    }
   }
   // Create a new slab here
-  void *slab = fm_lm_malloc(FM_PAGE_SIZE, FM_LM_T_PERSISTENT);
+  void *slab =
+      fm_lm_state_malloc_reserved(heap->lm, FM_PAGE_SIZE, FM_LM_T_PERSISTENT);
   if (slab == NULL) {
     return NULL;
   }
   page_meta_t *meta = (page_meta_t *)slab;
-  meta->bitmap[0] = 0;
-  meta->bitmap[1] = 0;
+#ifdef FM_FILL_ON_FREE
+  // Free slots always hold the fill pattern, so fm_sm_verify can check them
+  memset((uint8_t *)slab + PAGE_META_RESERVED_SIZE, FM_FILL_PATTERN,
+         FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE);
+#endif
+  memset(meta->bitmap, 0, sizeof(meta->bitmap));
   meta->size = slab_sizes[i];
   meta->slab_index = i;
   meta->count = (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / meta->size;
-  c_list_link_front(&slab_lists[i], &meta->link);
-  FM_DEBUG("Creating new slab: %p %ld\n", meta, meta->size);
+  c_list_link_front(&heap->slab_lists[i], &meta->link);
+  FM_DEBUG("Creating new slab: %p %zu\n", meta, meta->size);
+  heap->slab_pages++;
+  heap->slab_used_bytes += meta->size;
+  heap->class_slabs[i]++;
+  heap->class_used_slots[i]++;
 
   size_t element_index = 0;
+#ifdef FM_HARDENING
+  if (__slab_random_enabled && !__slab_deterministic) {
+    // All slots are free in a new slab
+    element_index =
+        (size_t)(__fm_random_next(&__slab_random_state) % meta->count);
+  }
+#endif
   bitmap_set(meta, element_index);
   return index_to_ptr(meta, element_index);
 }
+
+void *fm_sm_malloc(size_t size) {
+  return fm_sm_heap_malloc(&__default_heap, size);
+}
+
+static void *heap_malloc(fm_heap_t *heap, size_t size) {
+  void *p = sm_malloc(heap, size);
+  if (p == NULL) {
+    notify_oom(heap, size);
+  }
+  return p;
+}
+
+void *fm_sm_heap_malloc(fm_heap_t *heap, size_t size) {
+  if (reentered(heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = heap_malloc(heap, size);
+  unlock();
+  return result;
+}
+
+// The control block lives at the end of the bookkeeping page
+static size_t control_size() {
+  return __fm_roundup(sizeof(fm_heap_t) + fm_lm_state_size(), 16);
+}
+
+static fm_heap_t *create(void *buffer, size_t size, int zero_filled) {
+  int ret = fm_lm_check_buffer(buffer, size);
+  // Enough room must be left for page counts in the bookkeeping page
+  if (ret == 0 && size / FM_PAGE_SIZE + 8 > FM_PAGE_SIZE - control_size()) {
+    FM_REPORT(FM_ERR_BUFFER_TOO_LARGE,
+              "Memory size is too large to keep the control block!");
+    ret = FM_ERR_BUFFER_TOO_LARGE;
+  }
+  if (ret != 0) {
+    __fm_set_error(ret);
+    return NULL;
+  }
+  if (!zero_filled) {
+    memset(buffer, 0, FM_PAGE_SIZE);
+  }
+  fm_heap_t *heap =
+      (fm_heap_t *)((uint8_t *)buffer + FM_PAGE_SIZE - control_size());
+  heap->lm = (fm_lm_state_t *)(heap + 1);
+  fm_lm_state_reinit(heap->lm, buffer, size, 1);
+  init_slabs(heap);
+  return heap;
+}
+
+fm_heap_t *fm_sm_create(void *buffer, size_t size, int zero_filled) {
+  lock();
+  fm_heap_t *result = create(buffer, size, zero_filled);
+  unlock();
+  return result;
+}
+
+void fm_sm_destroy(fm_heap_t *heap) { memset(heap, 0, control_size()); }
+
+void *fm_sm_calloc(size_t n, size_t size) {
+  size_t total;
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  if (__fm_mul_overflow(n, size, &total)) {
+    lock();
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    // The actual size cannot be represented, use the largest value instead
+    notify_oom(&__default_heap, (size_t)-1);
+    unlock();
+    return NULL;
+  }
+  void *p = fm_sm_malloc(total);
+  if (p != NULL) {
+    memset(p, 0, total);
+  }
+  return p;
+}
+
+static void *page_alloc(size_t pages) {
+  size_t size;
+  if (pages == 0) {
+    return NULL;
+  }
+  if (__fm_mul_overflow(pages, FM_PAGE_SIZE, &size)) {
+    __fm_set_error(FM_ERR_TOO_LARGE);
+    notify_oom(&__default_heap, (size_t)-1);
+    return NULL;
+  }
+  // Blocks served by linear malloc always start on a page boundary
+  void *p = lm_malloc(&__default_heap, size, FM_LM_T_TRANSIENT);
+  if (p == NULL) {
+    notify_oom(&__default_heap, size);
+  }
+  return p;
+}
+
+void *fm_sm_page_alloc(size_t pages) {
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+  void *p = page_alloc(pages);
+  unlock();
+  return p;
+}
+
+void fm_sm_page_free(void *ptr) {
+  if (ptr == NULL || reentered(&__default_heap)) {
+    return;
+  }
+  lock();
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) != 0) {
+    __fm_set_error(FM_ERR_BAD_POINTER);
+  } else {
+    sm_free(&__default_heap, ptr);
+  }
+  unlock();
+}
+
+static void collect_stats(fm_stats_t *stats) {
+  fm_heap_t *heap = &__default_heap;
+  fm_lm_stats(&stats->total_pages, &stats->free_pages);
+  stats->used_pages = stats->total_pages - stats->free_pages;
+  // Each region has its own bookkeeping page
+  size_t pages = stats->total_pages + fm_lm_regions();
+  stats->total_bytes = (stats->total_pages > 0) ? pages * FM_PAGE_SIZE : 0;
+  stats->used_bytes = (stats->used_pages - heap->slab_pages) * FM_PAGE_SIZE +
+                      heap->slab_used_bytes;
+  stats->free_bytes = stats->free_pages * FM_PAGE_SIZE;
+}
+
+void fm_sm_stats(fm_stats_t *stats) {
+  lock();
+  collect_stats(stats);
+  unlock();
+}
+
+static int is_slab(const fm_heap_t *heap, const void *page) {
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
+         iter = iter->next) {
+      if ((void *)c_list_entry(iter, page_meta_t, link) == page) {
+        return 1;
+      }
+    }
+  }
+  for (CList *iter = heap->full_slabs.next; iter != &heap->full_slabs;
+       iter = iter->next) {
+    if ((void *)c_list_entry(iter, page_meta_t, link) == page) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+static size_t usable_size(fm_heap_t *heap, const void *ptr) {
+  if (!fm_lm_state_contains(heap->lm, ptr)) {
+    return 0;
+  }
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks are already freed by the caller
+  for (size_t i = 0; i < __quarantine_count; i++) {
+    if (__quarantine[(__quarantine_start + i) % FM_SM_MAX_QUARANTINE] == ptr) {
+      return 0;
+    }
+  }
+#endif
+  if ((((size_t)ptr) & (FM_PAGE_SIZE - 1)) == 0) {
+    return is_slab(heap, ptr) ? 0 : fm_lm_state_block_size(heap->lm, ptr);
+  }
+  page_meta_t *meta = (page_meta_t *)__fm_rounddown((size_t)ptr, FM_PAGE_SIZE);
+  if (!is_slab(heap, meta)) {
+    return 0;
+  }
+  size_t p = (size_t)ptr;
+  size_t base = ((size_t)meta) + PAGE_META_RESERVED_SIZE;
+  if ((p < base) || ((p - base) % meta->size != 0) ||
+      ((p - base) / meta->size >= meta->count)) {
+    return 0;
+  }
+  return bitmap_is_set(meta, (p - base) / meta->size) ? meta->size : 0;
+}
+
+size_t fm_sm_usable_size(const void *ptr) {
+  lock();
+  size_t result = usable_size(&__default_heap, ptr);
+  unlock();
+  return result;
+}
+
+typedef struct walk_ctx_t {
+  fm_walk_cb_t callback;
+  void *user;
+} walk_ctx_t;
+
+static void walk_block(void *ptr, size_t size, void *user) {
+  walk_ctx_t *ctx = (walk_ctx_t *)user;
+  if (!is_slab(&__default_heap, ptr)) {
+    ctx->callback(ptr, size, ctx->user);
+    return;
+  }
+  page_meta_t *meta = (page_meta_t *)ptr;
+  for (size_t i = 0; i < meta->count; i++) {
+    if (meta->bitmap[i / 64] & (((uint64_t)1) << (i % 64))) {
+      ctx->callback(index_to_ptr(meta, i), meta->size, ctx->user);
+    }
+  }
+}
+
+// Invoke callback for each live allocation in address order, slab pages
+// themselves are not reported but the slab objects in them are.
+static void walk_allocations(fm_walk_cb_t callback, void *user) {
+  walk_ctx_t ctx = {callback, user};
+  fm_lm_walk(walk_block, &ctx);
+}
+
+#ifdef FM_TEST_SUPPORT
+static void class_stats(fm_class_stats_cb_t callback, void *user) {
+  fm_heap_t *heap = &__default_heap;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    size_t count = (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / slab_sizes[i];
+    size_t used = heap->class_used_slots[i];
+    callback(slab_sizes[i], heap->class_slabs[i], used,
+             heap->class_slabs[i] * count - used, user);
+  }
+}
+
+void fm_sm_class_stats(fm_class_stats_cb_t callback, void *user) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  class_stats(callback, user);
+  callback_end(&__default_heap, previous);
+  unlock();
+}
+
+void fm_sm_test_report_oom(size_t requested) {
+  (void)requested;
+  fm_stats_t stats;
+  fm_sm_stats(&stats);
+  FM_PRINT("Failed to allocate %zu bytes, used: %zu bytes, free: %zu bytes\n",
+           requested, stats.used_bytes, stats.free_bytes);
+}
+
+typedef struct test_walk_ctx_t {
+  fm_sm_walk_cb_t callback;
+  void *user;
+} test_walk_ctx_t;
+
+static uint32_t tag_of(const void *ptr) {
+  tag_entry_t *entry = tag_find(ptr);
+  return (entry != NULL) ? entry->tag : 0;
+}
+
+static void test_walk_block(void *ptr, size_t size, void *user) {
+  test_walk_ctx_t *ctx = (test_walk_ctx_t *)user;
+  ctx->callback(ptr, size, tag_of(ptr), ctx->user);
+}
+
+static void *malloc_tagged(size_t size, uint32_t tag) {
+  void *p = heap_malloc(&__default_heap, size);
+  if (p != NULL) {
+    tag_set(p, tag);
+  }
+  return p;
+}
+
+void *fm_sm_malloc_tagged(size_t size, uint32_t tag) {
+  if (reentered(&__default_heap)) {
+    return NULL;
+  }
+  lock();
+  void *result = malloc_tagged(size, tag);
+  unlock();
+  return result;
+}
+
+static int free_sized_checked(void *ptr, size_t size) {
+  if (ptr == NULL) {
+    return 0;
+  }
+  size_t usable = usable_size(&__default_heap, ptr);
+  int mismatch = (usable == 0) || (size > usable);
+  if (!mismatch) {
+    sm_free(&__default_heap, ptr);
+  }
+  return mismatch;
+}
+
+int fm_sm_free_sized_checked(void *ptr, size_t size) {
+  if (reentered(&__default_heap)) {
+    return 0;
+  }
+  lock();
+  int result = free_sized_checked(ptr, size);
+  unlock();
+  return result;
+}
+
+static void test_walk(fm_sm_walk_cb_t callback, void *user) {
+  test_walk_ctx_t ctx = {callback, user};
+  walk_allocations(test_walk_block, &ctx);
+}
+
+void fm_sm_test_walk(fm_sm_walk_cb_t callback, void *user) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  test_walk(callback, user);
+  callback_end(&__default_heap, previous);
+  unlock();
+}
+
+static void histogram_block(void *ptr, size_t size, void *user) {
+  (void)ptr;
+  uint64_t *buckets = (uint64_t *)user;
+  size_t i = 0;
+  while (i + 1 < FM_SM_HISTOGRAM_BUCKETS && (size >> (i + 1)) != 0) {
+    i++;
+  }
+  buckets[i]++;
+}
+
+void fm_sm_test_size_histogram(uint64_t *buckets) {
+  for (size_t i = 0; i < FM_SM_HISTOGRAM_BUCKETS; i++) {
+    buckets[i] = 0;
+  }
+  lock();
+  walk_allocations(histogram_block, buckets);
+  unlock();
+}
+#endif
+
+typedef struct migrate_ctx_t {
+  fm_relocate_cb_t callback;
+  void *ctx;
+  size_t delta;
+  // Range of the new buffer
+  size_t start;
+  size_t end;
+} migrate_ctx_t;
+
+static void migrate_block(void *ptr, size_t size, void *user) {
+  migrate_ctx_t *m = (migrate_ctx_t *)user;
+  if ((size_t)ptr >= m->start && (size_t)ptr < m->end) {
+    m->callback((void *)((size_t)ptr - m->delta), ptr, size, m->ctx);
+  }
+}
+
+static int migrate(void *new_buffer, size_t new_size,
+                   fm_relocate_cb_t callback, void *ctx) {
+  fm_heap_t *heap = &__default_heap;
+  void *old_buffer;
+  size_t old_size;
+  int ret = fm_lm_migrate(new_buffer, new_size, &old_buffer, &old_size);
+  if (ret != 0) {
+    return ret;
+  }
+  size_t start = (size_t)old_buffer;
+  size_t end = start + old_size;
+  size_t delta = (size_t)new_buffer - start;
+  for (size_t i = 0; i < sizeof(heap->slab_lists) / sizeof(CList); i++) {
+    __fm_relocate_list(&heap->slab_lists[i], start, end, delta);
+  }
+  __fm_relocate_list(&heap->full_slabs, start, end, delta);
+#ifdef FM_TEST_SUPPORT
+  for (size_t i = 0; i < __quarantine_count; i++) {
+    size_t index = (__quarantine_start + i) % FM_SM_MAX_QUARANTINE;
+    size_t p = (size_t)__quarantine[index];
+    if (p >= start && p < end) {
+      __quarantine[index] = (void *)(p + delta);
+    }
+  }
+#endif
+  if (callback != NULL) {
+    migrate_ctx_t m = {callback, ctx, delta, (size_t)new_buffer,
+                       (size_t)new_buffer + new_size};
+    walk_allocations(migrate_block, &m);
+  }
+  return 0;
+}
+
+int fm_sm_migrate(void *new_buffer, size_t new_size, fm_relocate_cb_t callback,
+                  void *ctx) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  int result = migrate(new_buffer, new_size, callback, ctx);
+  callback_end(&__default_heap, previous);
+  unlock();
+  return result;
+}
+
+static int extend(size_t additional_bytes) {
+  return fm_lm_extend(additional_bytes);
+}
+
+int fm_sm_extend(size_t additional_bytes) {
+  lock();
+  int result = extend(additional_bytes);
+  unlock();
+  return result;
+}
+
+// Partially used slab in class i with the most used slots, except skipped
+static page_meta_t *densest_slab(const fm_heap_t *heap, size_t i,
+                                 const page_meta_t *skipped) {
+  page_meta_t *densest = NULL;
+  for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
+       iter = iter->next) {
+    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+    if (meta != skipped &&
+        (densest == NULL || used_slots(meta) > used_slots(densest))) {
+      densest = meta;
+    }
+  }
+  return densest;
+}
+
+static void move_object(fm_heap_t *heap, page_meta_t *source, size_t index,
+                        page_meta_t *target, fm_relocate_cb_t callback,
+                        void *ctx) {
+  void *old_ptr = index_to_ptr(source, index);
+  size_t slot = bitmap_next_free(target);
+  void *new_ptr = index_to_ptr(target, slot);
+  memcpy(new_ptr, old_ptr, source->size);
+  bitmap_set(target, slot);
+  bitmap_clear(source, index);
+  if (bitmap_all_used(target)) {
+    c_list_unlink(&target->link);
+    c_list_link_tail(&heap->full_slabs, &target->link);
+  }
+#ifdef FM_TEST_SUPPORT
+  uint32_t tag = tag_remove(old_ptr);
+  tag_set(new_ptr, tag);
+#endif
+  if (callback != NULL) {
+    callback(old_ptr, new_ptr, source->size, ctx);
+  }
+}
+
+static size_t compact(fm_relocate_cb_t callback, void *ctx) {
+  fm_heap_t *heap = &__default_heap;
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks are already freed, they shall never be moved
+  evict_quarantine(0);
+#endif
+  size_t reclaimed = 0;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    while (1) {
+      // Empty the sparsest slab, as long as its objects fit in other slabs
+      page_meta_t *source = NULL;
+      size_t free_slots = 0;
+      for (CList *iter = heap->slab_lists[i].next; iter != &heap->slab_lists[i];
+           iter = iter->next) {
+        page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+        free_slots += meta->count - used_slots(meta);
+        if (source == NULL || used_slots(meta) < used_slots(source)) {
+          source = meta;
+        }
+      }
+      if (source == NULL) {
+        break;
+      }
+      size_t used = used_slots(source);
+      if (free_slots - (source->count - used) < used) {
+        break;
+      }
+      for (size_t index = 0; index < source->count; index++) {
+        if (bitmap_is_set(source, index)) {
+          move_object(heap, source, index, densest_slab(heap, i, source),
+                      callback, ctx);
+        }
+      }
+      c_list_unlink(&source->link);
+      fm_lm_free(source);
+      heap->slab_pages--;
+      heap->class_slabs[i]--;
+      reclaimed++;
+    }
+  }
+  return reclaimed;
+}
+
+size_t fm_sm_compact(fm_relocate_cb_t callback, void *ctx) {
+  lock();
+  int previous = callback_begin(&__default_heap);
+  size_t result = compact(callback, ctx);
+  callback_end(&__default_heap, previous);
+  unlock();
+  return result;
+}
+
+static int slab_report(fm_heap_error_t *error, int kind, void *address) {
+  if (error != NULL) {
+    error->kind = kind;
+    error->address = address;
+  }
+  return kind;
+}
+
+static int verify_slab(const page_meta_t *meta, fm_heap_error_t *error) {
+  size_t i = meta->slab_index;
+  if (i >= sizeof(slab_sizes) / sizeof(size_t) ||
+      meta->size != slab_sizes[i] ||
+      meta->count != (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / meta->size) {
+    return slab_report(error, FM_HEAP_CORRUPTED_HEADER, (void *)meta);
+  }
+  // Bits beyond the slot count are never set
+  for (size_t index = meta->count; index < BITMAP_WORDS * 64; index++) {
+    if (bitmap_is_set(meta, index)) {
+      return slab_report(error, FM_HEAP_CORRUPTED_HEADER, (void *)meta);
+    }
+  }
+#ifdef FM_FILL_ON_FREE
+  for (size_t index = 0; index < meta->count; index++) {
+    if (bitmap_is_set(meta, index)) {
+      continue;
+    }
+    const uint8_t *p = (const uint8_t *)index_to_ptr(meta, index);
+    for (size_t offset = 0; offset < meta->size; offset++) {
+      if (p[offset] != FM_FILL_PATTERN) {
+        return slab_report(error,
+                           (offset < meta->size / 2) ? FM_HEAP_OVERFLOW_GUARD
+                                                     : FM_HEAP_UNDERFLOW_GUARD,
+                           (void *)p);
+      }
+    }
+  }
+#endif
+  return FM_HEAP_OK;
+}
+
+// Walk a slab list, full is 1 for the list of fully used slabs, slabs count
+// the number of slabs seen so far.
+static int verify_slab_list(const fm_heap_t *heap, const CList *list,
+                            size_t class_index, int full, size_t *slabs,
+                            fm_heap_error_t *error) {
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    if (iter->next->prev != iter || ++(*slabs) > heap->slab_pages) {
+      return slab_report(error, FM_HEAP_FREE_LIST_CYCLE, iter);
+    }
+    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+    if ((((size_t)meta) & (FM_PAGE_SIZE - 1)) != 0 || !fm_lm_contains(meta)) {
+      return slab_report(error, FM_HEAP_CORRUPTED_HEADER, iter);
+    }
+    int ret = verify_slab(meta, error);
+    if (ret != FM_HEAP_OK) {
+      return ret;
+    }
+    if (bitmap_all_used(meta) != full ||
+        (!full && meta->slab_index != class_index)) {
+      return slab_report(error, FM_HEAP_CORRUPTED_HEADER, meta);
+    }
+  }
+  return FM_HEAP_OK;
+}
+
+static int verify(fm_heap_error_t *error) {
+  int ret = fm_lm_verify(error);
+  if (ret != FM_HEAP_OK) {
+    return ret;
+  }
+  const fm_heap_t *heap = &__default_heap;
+  size_t slabs = 0;
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    ret = verify_slab_list(heap, &heap->slab_lists[i], i, 0, &slabs, error);
+    if (ret != FM_HEAP_OK) {
+      return ret;
+    }
+  }
+  ret = verify_slab_list(heap, &heap->full_slabs, 0, 1, &slabs, error);
+  if (ret != FM_HEAP_OK) {
+    return ret;
+  }
+  if (slabs != heap->slab_pages) {
+    // Some slab is no longer linked in any list
+    return slab_report(error, FM_HEAP_CORRUPTED_HEADER, NULL);
+  }
+  return FM_HEAP_OK;
+}
+
+int fm_sm_verify(fm_heap_error_t *error) {
+  lock();
+  int result = verify(error);
+  unlock();
+  return result;
+}
+
+// Pointers collected per heap walk, since blocks cannot be freed while the
+// heap is being walked
+#define FM_SM_FREE_BATCH 64
+
+typedef struct free_all_ctx_t {
+  void *ptrs[FM_SM_FREE_BATCH];
+  size_t count;
+} free_all_ctx_t;
+
+static void collect_block(void *ptr, size_t size, void *user) {
+  (void)size;
+  free_all_ctx_t *ctx = (free_all_ctx_t *)user;
+  if (ctx->count < FM_SM_FREE_BATCH) {
+    ctx->ptrs[ctx->count++] = ptr;
+  }
+}
+
+static size_t free_all() {
+#ifdef FM_TEST_SUPPORT
+  // Quarantined blocks would otherwise stay live forever
+  size_t quarantine_limit = __quarantine_limit;
+  evict_quarantine(0);
+  __quarantine_limit = 0;
+#endif
+  size_t freed = 0;
+  free_all_ctx_t ctx;
+  do {
+    ctx.count = 0;
+    walk_allocations(collect_block, &ctx);
+    for (size_t i = 0; i < ctx.count; i++) {
+      sm_free(&__default_heap, ctx.ptrs[i]);
+    }
+    freed += ctx.count;
+  } while (ctx.count > 0);
+#ifdef FM_TEST_SUPPORT
+  __quarantine_limit = quarantine_limit;
+#endif
+  return freed;
+}
+
+size_t fm_sm_free_all() {
+  lock();
+  size_t result = free_all();
+  unlock();
+  return result;
+}
+
+static size_t count_slabs(const CList *list) {
+  size_t count = 0;
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    count++;
+  }
+  return count;
+}
+
+static void put_slabs(void *out, size_t len, size_t *pos, const CList *list) {
+  for (CList *iter = list->next; iter != list; iter = iter->next) {
+    page_meta_t *meta = c_list_entry(iter, page_meta_t, link);
+    __fm_snapshot_put(out, len, pos, &meta, sizeof(page_meta_t *));
+    __fm_snapshot_put(out, len, pos, meta, sizeof(page_meta_t));
+  }
+}
+
+// Snapshot layout: size of the linear malloc snapshot followed by itself,
+// a copy of the heap, the number of slabs followed by their addresses and
+// headers, then the quarantine with FM_TEST_SUPPORT.
+static ptrdiff_t snapshot(void *out, size_t out_len) {
+  fm_heap_t *heap = &__default_heap;
+  size_t pos = 0;
+  int fits = out_len >= sizeof(size_t);
+  ptrdiff_t lm = fm_lm_snapshot(fits ? (uint8_t *)out + sizeof(size_t) : NULL,
+                                fits ? out_len - sizeof(size_t) : 0);
+  size_t lm_size = (lm < 0) ? (size_t)(-lm) : (size_t)lm;
+  __fm_snapshot_put(out, out_len, &pos, &lm_size, sizeof(size_t));
+  pos += lm_size;
+  __fm_snapshot_put(out, out_len, &pos, heap, sizeof(fm_heap_t));
+  size_t count = count_slabs(&heap->full_slabs);
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    count += count_slabs(&heap->slab_lists[i]);
+  }
+  __fm_snapshot_put(out, out_len, &pos, &count, sizeof(size_t));
+  put_slabs(out, out_len, &pos, &heap->full_slabs);
+  for (size_t i = 0; i < sizeof(slab_sizes) / sizeof(size_t); i++) {
+    put_slabs(out, out_len, &pos, &heap->slab_lists[i]);
+  }
+#ifdef FM_TEST_SUPPORT
+  __fm_snapshot_put(out, out_len, &pos, __quarantine, sizeof(__quarantine));
+  __fm_snapshot_put(out, out_len, &pos, &__quarantine_limit, sizeof(size_t));
+  __fm_snapshot_put(out, out_len, &pos, &__quarantine_start, sizeof(size_t));
+  __fm_snapshot_put(out, out_len, &pos, &__quarantine_count, sizeof(size_t));
+#endif
+  return (pos <= out_len) ? (ptrdiff_t)pos : -(ptrdiff_t)pos;
+}
+
+ptrdiff_t fm_sm_snapshot(void *out, size_t out_len) {
+  lock();
+  ptrdiff_t result = snapshot(out, out_len);
+  unlock();
+  return result;
+}
+
+static int restore(const void *in, size_t len) {
+  fm_heap_t *heap = &__default_heap;
+  // Validate the slab part first, linear malloc validates its own part
+  size_t pos = 0;
+  size_t lm_size;
+  if (__fm_snapshot_get(in, len, &pos, &lm_size, sizeof(size_t)) ||
+      __fm_snapshot_get(in, len, &pos, NULL, lm_size)) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  fm_heap_t copy;
+  size_t count;
+  if (__fm_snapshot_get(in, len, &pos, &copy, sizeof(fm_heap_t)) ||
+      copy.lm != heap->lm ||
+      __fm_snapshot_get(in, len, &pos, &count, sizeof(size_t))) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  size_t slabs_start = pos;
+  for (size_t i = 0; i < count; i++) {
+    page_meta_t *meta = NULL;
+    if (__fm_snapshot_get(in, len, &pos, &meta, sizeof(page_meta_t *)) ||
+        (((size_t)meta) & (FM_PAGE_SIZE - 1)) != 0 || !fm_lm_contains(meta) ||
+        __fm_snapshot_get(in, len, &pos, NULL, sizeof(page_meta_t))) {
+      return FM_ERR_BAD_SNAPSHOT;
+    }
+  }
+#ifdef FM_TEST_SUPPORT
+  size_t quarantine_start = pos;
+  if (__fm_snapshot_get(in, len, &pos, NULL,
+                        sizeof(__quarantine) + 3 * sizeof(size_t))) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+#endif
+  if (pos != len) {
+    return FM_ERR_BAD_SNAPSHOT;
+  }
+  int ret = fm_lm_restore((const uint8_t *)in + sizeof(size_t), lm_size);
+  if (ret != 0) {
+    return ret;
+  }
+
+  pos = slabs_start;
+  for (size_t i = 0; i < count; i++) {
+    page_meta_t *meta = NULL;
+    __fm_snapshot_get(in, len, &pos, &meta, sizeof(page_meta_t *));
+    __fm_snapshot_get(in, len, &pos, meta, sizeof(page_meta_t));
+  }
+  memcpy(heap, &copy, sizeof(fm_heap_t));
+#ifdef FM_TEST_SUPPORT
+  pos = quarantine_start;
+  __fm_snapshot_get(in, len, &pos, __quarantine, sizeof(__quarantine));
+  __fm_snapshot_get(in, len, &pos, &__quarantine_limit, sizeof(size_t));
+  __fm_snapshot_get(in, len, &pos, &__quarantine_start, sizeof(size_t));
+  __fm_snapshot_get(in, len, &pos, &__quarantine_count, sizeof(size_t));
+#endif
+  return 0;
+}
+
+int fm_sm_restore(const void *in, size_t len) {
+  lock();
+  int result = restore(in, len);
+  unlock();
+  return result;
+}
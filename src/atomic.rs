@@ -0,0 +1,9 @@
+// Atomics used by locks and init-once guards, which are taken from
+// `portable-atomic` instead of `core` with the `portable-atomic` feature, so
+// targets without atomic CAS can be supported via its fallbacks.
+#![allow(unused_imports)]
+
+#[cfg(not(feature = "portable-atomic"))]
+pub(crate) use core::sync::atomic::{AtomicBool, AtomicI8, AtomicUsize, Ordering};
+#[cfg(feature = "portable-atomic")]
+pub(crate) use portable_atomic::{AtomicBool, AtomicI8, AtomicUsize, Ordering};
@@ -0,0 +1,97 @@
+// Glue for CKB scripts running on CKB-VM. `init` routes the debug messages of
+// the allocator to the ckb_debug syscall, and makes failed allocations exit
+// the script with the code set via `set_oom_exit_code` instead of trapping.
+// `ckb_global_allocator!` serves the heap of the script from the static
+// buffer.
+
+use crate::atomic::{AtomicI8, Ordering};
+use crate::ffi;
+use core::ffi::{c_char, c_void, CStr};
+
+// Exit code of scripts running out of memory unless changed, which is far
+// from the small codes scripts usually fail with
+pub const DEFAULT_OOM_EXIT_CODE: i8 = i8::MIN;
+
+static OOM_EXIT_CODE: AtomicI8 = AtomicI8::new(DEFAULT_OOM_EXIT_CODE);
+
+pub fn set_oom_exit_code(code: i8) {
+    OOM_EXIT_CODE.store(code, Ordering::Relaxed);
+}
+
+pub fn oom_exit_code() -> i8 {
+    OOM_EXIT_CODE.load(Ordering::Relaxed)
+}
+
+// Define the `#[global_allocator]` of a script, which uses the static buffer.
+// It is only `Sync` since CKB-VM runs a single thread, as acknowledged by the
+// `single-threaded` feature `ckb` enables. For the same reason the handle is
+// never used by two threads at once.
+#[cfg(not(feature = "manual-init"))]
+#[macro_export]
+macro_rules! ckb_global_allocator {
+    () => {
+        #[global_allocator]
+        static FIXED_MALLOC_ALLOC: $crate::FixedAlloc = unsafe { $crate::FixedAlloc::new_static() };
+    };
+}
+
+// The hooks are passed to C as the context pointers
+unsafe extern "C" fn debug_trampoline(message: *const c_char, ctx: *mut c_void) {
+    let debug: fn(&CStr) = core::mem::transmute(ctx);
+    debug(CStr::from_ptr(message))
+}
+
+unsafe extern "C" fn oom_trampoline(_requested: usize, ctx: *mut c_void) {
+    let exit: fn(i8) -> ! = core::mem::transmute(ctx);
+    exit(oom_exit_code())
+}
+
+// Same as `init`, but with the syscalls replaced by `debug` and `exit`.
+// `debug` runs in the middle of an allocator call, under the lock callbacks
+// if any are set, and must not allocate. `exit` runs from the OOM hook, which
+// the allocator calls after releasing its lock. It is therefore not
+// serialized with other allocator calls. Without lock callbacks, allocating
+// from it fails with `FmError::Reentrant`.
+pub fn init_with(debug: fn(&CStr), exit: fn(i8) -> !) {
+    unsafe {
+        ffi::fm_set_debug_callback(Some(debug_trampoline), debug as *mut c_void);
+        ffi::fm_sm_set_oom_hook(Some(oom_trampoline), exit as *mut c_void);
+    }
+}
+
+// Install the debug and OOM hooks, which is best done first thing in the
+// entry of the script
+#[cfg(target_arch = "riscv64")]
+pub fn init() {
+    init_with(syscalls::debug, syscalls::exit)
+}
+
+// The syscalls are also handy for scripts not using other CKB libraries
+#[cfg(target_arch = "riscv64")]
+pub use syscalls::{debug, exit};
+
+#[cfg(target_arch = "riscv64")]
+mod syscalls {
+    use core::arch::asm;
+    use core::ffi::CStr;
+
+    const SYS_EXIT: u64 = 93;
+    const SYS_DEBUG: u64 = 2177;
+
+    // Terminate the script with `code`, 0 means success
+    pub fn exit(code: i8) -> ! {
+        unsafe { asm!("ecall", in("a0") code as i64, in("a7") SYS_EXIT, options(noreturn)) }
+    }
+
+    // Print `message` to the debug output of the node running the script
+    pub fn debug(message: &CStr) {
+        unsafe {
+            asm!(
+                "ecall",
+                inlateout("a0") message.as_ptr() => _,
+                in("a7") SYS_DEBUG,
+                options(nostack)
+            )
+        }
+    }
+}
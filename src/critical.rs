@@ -0,0 +1,36 @@
+use crate::lock::{forward_locked, with_heap_lock, Locked, Section};
+use crate::FixedAlloc;
+
+pub(crate) struct Critical;
+
+// The critical section keeps interrupt handlers on this core out, the
+// spinlock shared with the other wrappers keeps out the other cores.
+impl Section for Critical {
+    fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        critical_section::with(|_| with_heap_lock(f))
+    }
+}
+
+// Wraps `FixedAlloc` in a critical section provided by the `critical-section`
+// crate, so allocations from thread mode and interrupt handlers cannot race.
+// On single core targets this usually masks interrupts for the duration of
+// each call. Slab allocations are short, but large allocations search free
+// regions and zeroing or moving reallocs touch every byte of the block, which
+// all adds to interrupt latency. The OOM hook also runs inside the critical
+// section. An interrupt handler allocating through this wrapper deadlocks if
+// it preempts thread mode code holding the lock via `SyncAlloc` or
+// `LockedFixedAlloc`, so code sharing the heap with interrupt handlers must
+// only use this one.
+pub struct CriticalSectionAlloc {
+    inner: Locked<Critical>,
+}
+
+impl CriticalSectionAlloc {
+    pub const fn new(alloc: FixedAlloc) -> Self {
+        Self {
+            inner: Locked::new(alloc, Critical),
+        }
+    }
+}
+
+forward_locked!(CriticalSectionAlloc);
@@ -0,0 +1,221 @@
+use crate::ffi;
+use core::ffi::c_int;
+#[cfg(feature = "fmt")]
+use core::fmt;
+
+// Error codes parsed from linear-malloc.h by build.rs
+mod c_header {
+    include!(concat!(env!("OUT_DIR"), "/error_codes.rs"));
+}
+
+const _: () = {
+    assert!(ffi::FM_ABI_VERSION == c_header::FM_ABI_VERSION);
+    assert!(ffi::FM_OK == c_header::FM_OK);
+    assert!(ffi::FM_ERR_TOO_LARGE == c_header::FM_ERR_TOO_LARGE);
+    assert!(ffi::FM_ERR_NO_MEMORY == c_header::FM_ERR_NO_MEMORY);
+    assert!(ffi::FM_ERR_NOT_INITIALIZED == c_header::FM_ERR_NOT_INITIALIZED);
+    assert!(ffi::FM_ERR_BAD_POINTER == c_header::FM_ERR_BAD_POINTER);
+    assert!(ffi::FM_ERR_BAD_ALIGNMENT == c_header::FM_ERR_BAD_ALIGNMENT);
+    assert!(ffi::FM_ERR_NULL_BUFFER == c_header::FM_ERR_NULL_BUFFER);
+    assert!(ffi::FM_ERR_UNALIGNED_BUFFER == c_header::FM_ERR_UNALIGNED_BUFFER);
+    assert!(ffi::FM_ERR_UNALIGNED_SIZE == c_header::FM_ERR_UNALIGNED_SIZE);
+    assert!(ffi::FM_ERR_BUFFER_TOO_SMALL == c_header::FM_ERR_BUFFER_TOO_SMALL);
+    assert!(ffi::FM_ERR_BUFFER_TOO_LARGE == c_header::FM_ERR_BUFFER_TOO_LARGE);
+    assert!(ffi::FM_ERR_LIVE_ALLOCATIONS == c_header::FM_ERR_LIVE_ALLOCATIONS);
+    assert!(ffi::FM_ERR_BUFFER_OVERLAP == c_header::FM_ERR_BUFFER_OVERLAP);
+    assert!(ffi::FM_ERR_TOO_MANY_REGIONS == c_header::FM_ERR_TOO_MANY_REGIONS);
+    assert!(ffi::FM_ERR_BAD_SNAPSHOT == c_header::FM_ERR_BAD_SNAPSHOT);
+    assert!(ffi::FM_ERR_REENTRANT == c_header::FM_ERR_REENTRANT);
+    assert!(ffi::FM_ERR_CORRUPTED == c_header::FM_ERR_CORRUPTED);
+    assert!(ffi::FM_HEAP_OK == c_header::FM_HEAP_OK);
+    assert!(ffi::FM_HEAP_CORRUPTED_HEADER == c_header::FM_HEAP_CORRUPTED_HEADER);
+    assert!(ffi::FM_HEAP_FREE_LIST_CYCLE == c_header::FM_HEAP_FREE_LIST_CYCLE);
+    assert!(ffi::FM_HEAP_UNDERFLOW_GUARD == c_header::FM_HEAP_UNDERFLOW_GUARD);
+    assert!(ffi::FM_HEAP_OVERFLOW_GUARD == c_header::FM_HEAP_OVERFLOW_GUARD);
+    assert!(ffi::FM_FEATURE_GUARDS as c_int == c_header::FM_FEATURE_GUARDS);
+    assert!(ffi::FM_FEATURE_TEST_SUPPORT as c_int == c_header::FM_FEATURE_TEST_SUPPORT);
+    assert!(ffi::FM_FEATURE_MANUAL_INIT as c_int == c_header::FM_FEATURE_MANUAL_INIT);
+    assert!(ffi::FM_FEATURE_HARDENING as c_int == c_header::FM_FEATURE_HARDENING);
+    assert!(ffi::FM_FEATURE_FILL_ON_FREE as c_int == c_header::FM_FEATURE_FILL_ON_FREE);
+    assert!(ffi::FM_FEATURE_DEBUG_CALLBACK as c_int == c_header::FM_FEATURE_DEBUG_CALLBACK);
+    assert!(ffi::FM_FEATURE_TRAP_REENTRANT as c_int == c_header::FM_FEATURE_TRAP_REENTRANT);
+};
+
+// Errors reported by the C allocator
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FmError {
+    // Requested size is larger than the whole heap
+    TooLarge,
+    // Heap is exhausted or too fragmented to satisfy the request
+    NoMemory,
+    NotInitialized,
+    // Pointer is not allocated by fixed-malloc
+    BadPointer,
+    // Alignment is not a power of two
+    BadAlignment,
+    // Memory buffer passed to reinit is NULL
+    NullBuffer,
+    // Memory buffer passed to reinit is not aligned on page boundary
+    UnalignedBuffer,
+    // Memory size passed to reinit is not a multiple of page size
+    UnalignedSize,
+    // Memory size is smaller than `FM_MIN_MEMORY_SIZE`
+    BufferTooSmall,
+    // Memory size is larger than `FM_MAX_MEMORY_SIZE`
+    BufferTooLarge,
+    // Reinit is refused since there are still live allocations
+    LiveAllocations,
+    // New memory buffer overlaps memory regions already in use
+    BufferOverlap,
+    // All slots for extra memory regions are taken
+    TooManyRegions,
+    // Snapshot is truncated or taken from other memory regions
+    BadSnapshot,
+    // Allocator called from a hook or callback it is running
+    Reentrant,
+    // Internal check found the bookkeeping data corrupted
+    Corrupted,
+    Unknown(c_int),
+}
+
+// Mapping between error variants and C error codes
+const CODES: [(FmError, c_int); 16] = [
+    (FmError::TooLarge, ffi::FM_ERR_TOO_LARGE),
+    (FmError::NoMemory, ffi::FM_ERR_NO_MEMORY),
+    (FmError::NotInitialized, ffi::FM_ERR_NOT_INITIALIZED),
+    (FmError::BadPointer, ffi::FM_ERR_BAD_POINTER),
+    (FmError::BadAlignment, ffi::FM_ERR_BAD_ALIGNMENT),
+    (FmError::NullBuffer, ffi::FM_ERR_NULL_BUFFER),
+    (FmError::UnalignedBuffer, ffi::FM_ERR_UNALIGNED_BUFFER),
+    (FmError::UnalignedSize, ffi::FM_ERR_UNALIGNED_SIZE),
+    (FmError::BufferTooSmall, ffi::FM_ERR_BUFFER_TOO_SMALL),
+    (FmError::BufferTooLarge, ffi::FM_ERR_BUFFER_TOO_LARGE),
+    (FmError::LiveAllocations, ffi::FM_ERR_LIVE_ALLOCATIONS),
+    (FmError::BufferOverlap, ffi::FM_ERR_BUFFER_OVERLAP),
+    (FmError::TooManyRegions, ffi::FM_ERR_TOO_MANY_REGIONS),
+    (FmError::BadSnapshot, ffi::FM_ERR_BAD_SNAPSHOT),
+    (FmError::Reentrant, ffi::FM_ERR_REENTRANT),
+    (FmError::Corrupted, ffi::FM_ERR_CORRUPTED),
+];
+
+impl FmError {
+    // `None` is returned for `FM_OK`
+    pub fn from_code(code: c_int) -> Option<Self> {
+        if code == ffi::FM_OK {
+            return None;
+        }
+        Some(
+            CODES
+                .iter()
+                .find(|(_, c)| *c == code)
+                .map(|(e, _)| *e)
+                .unwrap_or(FmError::Unknown(code)),
+        )
+    }
+
+    // Convert a C status code into a `Result`
+    pub fn check(code: c_int) -> Result<(), Self> {
+        match Self::from_code(code) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub fn code(&self) -> c_int {
+        match self {
+            FmError::Unknown(code) => *code,
+            e => CODES.iter().find(|(v, _)| v == e).map(|(_, c)| *c).unwrap(),
+        }
+    }
+}
+
+// Fails only for `FM_OK`, which is not an error
+impl TryFrom<c_int> for FmError {
+    type Error = ();
+
+    fn try_from(code: c_int) -> Result<Self, ()> {
+        Self::from_code(code).ok_or(())
+    }
+}
+
+impl From<FmError> for c_int {
+    fn from(e: FmError) -> c_int {
+        e.code()
+    }
+}
+
+#[cfg(feature = "fmt")]
+impl fmt::Display for FmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FmError::TooLarge => write!(f, "requested size is too large"),
+            FmError::NoMemory => write!(f, "out of memory"),
+            FmError::NotInitialized => write!(f, "allocator is not initialized"),
+            FmError::BadPointer => write!(f, "pointer is not allocated by fixed-malloc"),
+            FmError::BadAlignment => write!(f, "alignment is not a power of two"),
+            FmError::NullBuffer => write!(f, "memory buffer is null"),
+            FmError::UnalignedBuffer => write!(f, "memory buffer is not page aligned"),
+            FmError::UnalignedSize => write!(f, "memory size is not a multiple of page size"),
+            FmError::BufferTooSmall => write!(f, "memory size is too small"),
+            FmError::BufferTooLarge => write!(f, "memory size is too large"),
+            FmError::LiveAllocations => write!(f, "there are still live allocations"),
+            FmError::BufferOverlap => write!(f, "memory buffers overlap"),
+            FmError::TooManyRegions => write!(f, "too many memory regions"),
+            FmError::BadSnapshot => write!(f, "snapshot does not match the heap"),
+            FmError::Reentrant => write!(f, "allocator called from its own hook"),
+            FmError::Corrupted => write!(f, "heap bookkeeping is corrupted"),
+            FmError::Unknown(code) => write!(f, "unknown error code {}", code),
+        }
+    }
+}
+
+// Kinds of heap corruption detected by `FixedAlloc::verify_heap_integrity`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapErrorKind {
+    // Bookkeeping data of a page block, free region or slab is inconsistent
+    CorruptedHeader,
+    // A free list or slab list is broken or loops forever
+    FreeListCycle,
+    // The end of a freed slab slot is modified, which requires `fill-on-free`
+    UnderflowGuard,
+    // The start of a freed slab slot is modified, which requires
+    // `fill-on-free`
+    OverflowGuard,
+    Unknown(c_int),
+}
+
+impl HeapErrorKind {
+    pub fn from_code(code: c_int) -> Self {
+        match code {
+            ffi::FM_HEAP_CORRUPTED_HEADER => HeapErrorKind::CorruptedHeader,
+            ffi::FM_HEAP_FREE_LIST_CYCLE => HeapErrorKind::FreeListCycle,
+            ffi::FM_HEAP_UNDERFLOW_GUARD => HeapErrorKind::UnderflowGuard,
+            ffi::FM_HEAP_OVERFLOW_GUARD => HeapErrorKind::OverflowGuard,
+            code => HeapErrorKind::Unknown(code),
+        }
+    }
+}
+
+// First corruption found in the heap, `address` is 0 when the corrupted
+// structure cannot be located
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapError {
+    pub kind: HeapErrorKind,
+    pub address: usize,
+}
+
+#[cfg(feature = "fmt")]
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            HeapErrorKind::CorruptedHeader => "corrupted header",
+            HeapErrorKind::FreeListCycle => "broken free list",
+            HeapErrorKind::UnderflowGuard => "underflow into freed memory",
+            HeapErrorKind::OverflowGuard => "overflow into freed memory",
+            HeapErrorKind::Unknown(_) => "unknown corruption",
+        };
+        write!(f, "{} at {:#x}", kind, self.address)
+    }
+}
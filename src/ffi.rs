@@ -1,27 +1,325 @@
 use core::ffi::{c_int, c_void};
 
-pub const FM_PAGE_SHIFT: usize = 12;
-pub const FM_PAGE_SIZE: usize = 1 << FM_PAGE_SHIFT;
+// FM_PAGE_SHIFT and FM_PAGE_SIZE, generated by build.rs from the same
+// FIXED_MALLOC_PAGE_SHIFT value passed to the C sources
+include!(concat!(env!("OUT_DIR"), "/page_size.rs"));
+
+pub const FM_MIN_MEMORY_SIZE: usize = 2 * FM_PAGE_SIZE;
+pub const FM_MAX_MEMORY_SIZE: usize = FM_PAGE_SIZE * FM_PAGE_SIZE - FM_PAGE_SIZE;
+
+// Every block is aligned to at least FM_MIN_ALIGN. Larger alignments up to
+// FM_MAX_ALIGN are served by rounding the block up to whole pages, anything
+// beyond that fails.
+pub const FM_MIN_ALIGN: usize = 16;
+pub const FM_MAX_ALIGN: usize = FM_PAGE_SIZE;
+
+// Size of the static memory buffer, see `STATIC_MEMORY_SIZE`
+pub const FM_MEMORY_SIZE: usize = crate::STATIC_MEMORY_SIZE;
+
+// Size of the smallest slab size class, selected by the slab-size-*
+// features. Smaller allocations are rounded up to it.
+pub const FM_SLAB_MIN_SIZE: usize = parse_size(env!("FIXED_MALLOC_SLAB_MIN_SIZE"));
+
+// Bytes reserved for the header of each slab page, slots start right after
+// it. Same as PAGE_META_RESERVED_SIZE in slab-malloc.c: a list link, a bitmap
+// with one bit per slot of the smallest class and 4 counters, rounded up to
+// 64 bytes.
+pub const FM_SLAB_RESERVED_SIZE: usize =
+    (6 * core::mem::size_of::<usize>() + 8 * SLAB_BITMAP_WORDS).next_multiple_of(64);
+const SLAB_BITMAP_WORDS: usize = (FM_PAGE_SIZE / FM_SLAB_MIN_SIZE).div_ceil(64);
+
+const fn parse_size(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    value
+}
 
 pub const FM_LM_T_TRANSIENT: c_int = 0x1;
 pub const FM_LM_T_PERSISTENT: c_int = 0x2;
 
+pub const FM_REALLOC_NO_MOVE: c_int = 0x1;
+
+// Allocation types accepted by linear malloc. Transient allocations are taken
+// from the start of the heap, persistent ones from the end.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocType {
+    Transient = FM_LM_T_TRANSIENT,
+    Persistent = FM_LM_T_PERSISTENT,
+}
+
+impl From<AllocType> for c_int {
+    fn from(t: AllocType) -> c_int {
+        t as c_int
+    }
+}
+
+// See fm_abi_version in linear-malloc.h
+pub const FM_ABI_VERSION: c_int = 1;
+
+// Version of this crate packed as major << 16 | minor << 8 | patch, the
+// same as the FM_VERSION build.rs passes to the C sources
+pub const FM_VERSION: u32 = (parse_size(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+    | parse_size(env!("CARGO_PKG_VERSION_MINOR")) << 8
+    | parse_size(env!("CARGO_PKG_VERSION_PATCH"))) as u32;
+
+// Bits of fm_features
+pub const FM_FEATURE_GUARDS: u32 = 0x1;
+pub const FM_FEATURE_TEST_SUPPORT: u32 = 0x2;
+pub const FM_FEATURE_MANUAL_INIT: u32 = 0x4;
+pub const FM_FEATURE_HARDENING: u32 = 0x8;
+pub const FM_FEATURE_FILL_ON_FREE: u32 = 0x10;
+pub const FM_FEATURE_DEBUG_CALLBACK: u32 = 0x20;
+pub const FM_FEATURE_TRAP_REENTRANT: u32 = 0x40;
+
+pub const FM_OK: c_int = 0;
+pub const FM_ERR_TOO_LARGE: c_int = 1;
+pub const FM_ERR_NO_MEMORY: c_int = 2;
+pub const FM_ERR_NOT_INITIALIZED: c_int = 3;
+pub const FM_ERR_BAD_POINTER: c_int = 4;
+pub const FM_ERR_BAD_ALIGNMENT: c_int = 5;
+pub const FM_ERR_NULL_BUFFER: c_int = 6;
+pub const FM_ERR_UNALIGNED_BUFFER: c_int = 7;
+pub const FM_ERR_UNALIGNED_SIZE: c_int = 8;
+pub const FM_ERR_BUFFER_TOO_SMALL: c_int = 9;
+pub const FM_ERR_BUFFER_TOO_LARGE: c_int = 10;
+pub const FM_ERR_LIVE_ALLOCATIONS: c_int = 11;
+pub const FM_ERR_BUFFER_OVERLAP: c_int = 12;
+pub const FM_ERR_TOO_MANY_REGIONS: c_int = 13;
+pub const FM_ERR_BAD_SNAPSHOT: c_int = 14;
+pub const FM_ERR_REENTRANT: c_int = 15;
+pub const FM_ERR_CORRUPTED: c_int = 16;
+
+pub const FM_HEAP_OK: c_int = 0;
+pub const FM_HEAP_CORRUPTED_HEADER: c_int = 1;
+pub const FM_HEAP_FREE_LIST_CYCLE: c_int = 2;
+pub const FM_HEAP_UNDERFLOW_GUARD: c_int = 3;
+pub const FM_HEAP_OVERFLOW_GUARD: c_int = 4;
+
+// Heap corruption found by `fm_sm_verify`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FmHeapError {
+    pub kind: c_int,
+    // Start of the corrupted structure, NULL when it cannot be located
+    pub address: *mut c_void,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FmStats {
+    // Size of all memory regions, including their bookkeeping pages
+    pub total_bytes: usize,
+    // Bytes held by live allocations, rounded up to size classes or pages
+    pub used_bytes: usize,
+    // Bytes in free pages, which are available for new allocations
+    pub free_bytes: usize,
+    // Pages available for allocations, excluding bookkeeping pages
+    pub total_pages: usize,
+    // Allocated pages, including pages used by slabs
+    pub used_pages: usize,
+    pub free_pages: usize,
+}
+
+// Opaque control block of a heap instance
+#[repr(C)]
+pub struct FmHeap {
+    _private: [u8; 0],
+}
+
+pub type FmErrorSink =
+    Option<unsafe extern "C" fn(code: c_int, message: *const core::ffi::c_char, ctx: *mut c_void)>;
+
+pub type FmOomHook = Option<unsafe extern "C" fn(requested: usize, ctx: *mut c_void)>;
+
+// Lock callbacks must not allocate, see fm_set_lock_callbacks in
+// slab-malloc.h for the reentrancy rules.
+pub type FmLockCallback = Option<unsafe extern "C" fn(ctx: *mut c_void)>;
+
+pub type FmWalkCallback = unsafe extern "C" fn(ptr: *mut c_void, size: usize, user: *mut c_void);
+pub type FmSmWalkCallback =
+    unsafe extern "C" fn(ptr: *mut c_void, size: usize, tag: u32, user: *mut c_void);
+
+pub type FmRelocateCallback =
+    unsafe extern "C" fn(old_ptr: *mut c_void, new_ptr: *mut c_void, size: usize, ctx: *mut c_void);
+
+pub type FmClassStatsCallback = unsafe extern "C" fn(
+    class_size: usize,
+    slabs: usize,
+    used_slots: usize,
+    free_slots: usize,
+    user: *mut c_void,
+);
+
+#[cfg(not(feature = "pure-rust"))]
 #[link(name = "fixed-malloc", kind = "static")]
 extern "C" {
     pub fn fm_sm_reinit(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int;
+    pub fn fm_sm_reinit_forced(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int;
+    pub fn fm_sm_deinit();
+    pub fn fm_sm_reinit_swap(
+        new_buffer: *mut c_void,
+        new_size: usize,
+        zero_filled: c_int,
+        old_buffer: *mut *mut c_void,
+        old_size: *mut usize,
+    ) -> c_int;
     pub fn fm_sm_malloc(size: usize) -> *mut c_void;
     pub fn fm_sm_free(ptr: *mut c_void);
+    pub fn fm_sm_free_many(ptrs: *const *mut c_void, count: usize);
     pub fn fm_sm_realloc(ptr: *mut c_void, size: usize) -> *mut c_void;
+    pub fn fm_sm_realloc_flags(ptr: *mut c_void, size: usize, flags: c_int) -> *mut c_void;
+    pub fn fm_sm_shrink_release(ptr: *mut c_void, size: usize) -> *mut c_void;
+    pub fn fm_sm_calloc(n: usize, size: usize) -> *mut c_void;
+    pub fn fm_sm_set_oom_hook(hook: FmOomHook, ctx: *mut c_void);
+    pub fn fm_sm_set_error_sink(sink: FmErrorSink, ctx: *mut c_void);
+    pub fn fm_set_lock_callbacks(lock: FmLockCallback, unlock: FmLockCallback, ctx: *mut c_void);
+    pub fn fm_sm_stats(stats: *mut FmStats);
+    pub fn fm_sm_live_allocations() -> usize;
+    pub fn fm_sm_allocated_bytes() -> usize;
+    pub fn fm_sm_free_all() -> usize;
+    pub fn fm_sm_page_alloc(pages: usize) -> *mut c_void;
+    pub fn fm_sm_page_free(ptr: *mut c_void);
+    pub fn fm_sm_snapshot(out: *mut c_void, out_len: usize) -> isize;
+    pub fn fm_sm_restore(input: *const c_void, len: usize) -> c_int;
+    pub fn fm_sm_verify(error: *mut FmHeapError) -> c_int;
+    pub fn fm_sm_default_memory_size() -> usize;
+    pub fn fm_sm_min_buffer_size() -> usize;
+    pub fn fm_sm_create(buffer: *mut c_void, size: usize, zero_filled: c_int) -> *mut FmHeap;
+    pub fn fm_sm_heap_malloc(heap: *mut FmHeap, size: usize) -> *mut c_void;
+    pub fn fm_sm_heap_free(heap: *mut FmHeap, ptr: *mut c_void);
+    pub fn fm_sm_heap_realloc(heap: *mut FmHeap, ptr: *mut c_void, size: usize) -> *mut c_void;
+    pub fn fm_sm_destroy(heap: *mut FmHeap);
+    pub fn fm_sm_usable_size(ptr: *const c_void) -> usize;
+    pub fn fm_sm_migrate(
+        new_buffer: *mut c_void,
+        new_size: usize,
+        callback: Option<FmRelocateCallback>,
+        ctx: *mut c_void,
+    ) -> c_int;
+
+    pub fn fm_sm_extend(additional_bytes: usize) -> c_int;
+    pub fn fm_sm_reinit_split(
+        slab_buffer: *mut c_void,
+        slab_size: usize,
+        linear_buffer: *mut c_void,
+        linear_size: usize,
+        zero_filled: c_int,
+    ) -> c_int;
+    pub fn fm_sm_add_region(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int;
+    pub fn fm_sm_adopt(ptr: *mut c_void, size: usize) -> c_int;
+    pub fn fm_sm_compact(callback: Option<FmRelocateCallback>, ctx: *mut c_void) -> usize;
+    pub fn fm_sm_trim() -> usize;
+    pub fn fm_sm_max_alloc_size() -> usize;
+    pub fn fm_sm_set_deterministic(enabled: c_int);
 
     pub fn fm_lm_reinit(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int;
+    pub fn fm_lm_deinit();
+    pub fn fm_lm_add_region(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int;
+    pub fn fm_lm_reinit_swap(
+        new_buffer: *mut c_void,
+        new_size: usize,
+        zero_filled: c_int,
+        old_buffer: *mut *mut c_void,
+        old_size: *mut usize,
+    ) -> c_int;
+    pub fn fm_lm_reinit_split(
+        reserved_buffer: *mut c_void,
+        reserved_size: usize,
+        buffer: *mut c_void,
+        size: usize,
+        zero_filled: c_int,
+    ) -> c_int;
     pub fn fm_lm_malloc(size: usize, t: c_int) -> *mut c_void;
+    pub fn fm_lm_malloc_reserved(size: usize, t: c_int) -> *mut c_void;
+    pub fn fm_lm_max_alloc_size() -> usize;
+    pub fn fm_lm_max_alloc_size_reserved() -> usize;
+    pub fn fm_lm_aligned_malloc(size: usize, align: usize, t: c_int) -> *mut c_void;
+    pub fn fm_lm_calloc(n: usize, size: usize, t: c_int) -> *mut c_void;
     pub fn fm_lm_free(ptr: *mut c_void);
     pub fn fm_lm_realloc(ptr: *mut c_void, size: usize, t: c_int) -> *mut c_void;
+    pub fn fm_lm_realloc_in_place(ptr: *mut c_void, size: usize) -> *mut c_void;
+    pub fn fm_lm_stats(total_pages: *mut usize, free_pages: *mut usize);
+    pub fn fm_lm_live_blocks() -> usize;
+    pub fn fm_lm_used_pages() -> usize;
+    pub fn fm_lm_regions() -> usize;
+    pub fn fm_lm_walk(callback: FmWalkCallback, user: *mut c_void);
+    pub fn fm_lm_migrate(
+        new_buffer: *mut c_void,
+        new_size: usize,
+        old_buffer: *mut *mut c_void,
+        old_size: *mut usize,
+    ) -> c_int;
+    pub fn fm_lm_extend(additional_bytes: usize) -> c_int;
+    pub fn fm_lm_buffer_range(start: *mut *mut c_void, size: *mut usize);
+    pub fn fm_lm_contains(ptr: *const c_void) -> c_int;
+    pub fn fm_lm_block_size(ptr: *const c_void) -> usize;
+    pub fn fm_lm_verify(error: *mut FmHeapError) -> c_int;
+
+    pub fn fm_abi_version() -> c_int;
+    pub fn fm_version() -> u32;
+    pub fn fm_features() -> u32;
+    pub fn fm_last_error() -> c_int;
+    pub fn fm_clear_error();
+    pub fn fm_lm_set_error_sink(sink: FmErrorSink, ctx: *mut c_void);
+}
+
+#[cfg(feature = "debug-hook")]
+pub type FmDebugCallback =
+    Option<unsafe extern "C" fn(message: *const core::ffi::c_char, ctx: *mut c_void)>;
+
+#[cfg(all(feature = "debug-hook", not(feature = "pure-rust")))]
+#[link(name = "fixed-malloc", kind = "static")]
+extern "C" {
+    pub fn fm_set_debug_callback(callback: FmDebugCallback, ctx: *mut c_void);
+}
+
+#[cfg(feature = "fill-on-free")]
+pub const FM_FILL_PATTERN: u8 = 0xAB;
+
+#[cfg(all(feature = "fill-on-free", not(feature = "pure-rust")))]
+#[link(name = "fixed-malloc", kind = "static")]
+extern "C" {
+    pub fn fm_sm_check_fill(ptr: *mut c_void) -> c_int;
+    pub fn fm_lm_fill(ptr: *mut c_void);
+    pub fn fm_lm_check_fill(ptr: *mut c_void) -> c_int;
+}
+
+#[cfg(all(feature = "hardening", not(feature = "pure-rust")))]
+#[link(name = "fixed-malloc", kind = "static")]
+extern "C" {
+    pub fn fm_sm_set_random_seed(seed: u64);
+    pub fn fm_lm_set_random_seed(seed: u64);
 }
 
 #[cfg(feature = "test-support")]
+pub const FM_REALLOC_FILL_PATTERN: u8 = 0xCD;
+#[cfg(feature = "test-support")]
+pub const FM_SM_HISTOGRAM_BUCKETS: usize = 16;
+
+#[cfg(all(feature = "test-support", not(feature = "pure-rust")))]
 #[link(name = "fixed-malloc", kind = "static")]
 extern "C" {
     pub fn fm_lm_test_buffer_pointer() -> *mut c_void;
+    pub fn fm_lm_test_static_buffer() -> *mut c_void;
     pub fn fm_lm_test_total_buffer_size() -> usize;
+    pub fn fm_lm_test_region(index: usize, buffer: *mut *mut c_void, size: *mut usize);
+    pub fn fm_sm_set_quarantine(n: usize);
+    pub fn fm_sm_set_realloc_fill(enabled: c_int);
+    pub fn fm_sm_test_realloc_old_size() -> usize;
+    pub fn fm_sm_test_report_oom(requested: usize);
+    pub fn fm_sm_class_stats(callback: FmClassStatsCallback, user: *mut c_void);
+    pub fn fm_sm_test_walk(callback: FmSmWalkCallback, user: *mut c_void);
+    pub fn fm_sm_test_size_histogram(buckets: *mut u64);
+    pub fn fm_sm_malloc_tagged(size: usize, tag: u32) -> *mut c_void;
+    pub fn fm_sm_free_sized_checked(ptr: *mut c_void, size: usize) -> c_int;
 }
+
+// Same functions, implemented in Rust
+#[cfg(feature = "pure-rust")]
+pub use crate::rust_impl::*;
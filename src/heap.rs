@@ -0,0 +1,88 @@
+use crate::error::FmError;
+use crate::ffi;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ffi::c_void;
+use core::ptr::NonNull;
+
+// Heap instance independent of the global allocator, all its state is kept in
+// the buffer it is created on. The memory can be reused once the heap is
+// dropped, all allocations made from it become invalid at that point. Like the
+// C allocator itself, heap instances are not thread safe.
+pub struct Heap {
+    handle: NonNull<ffi::FmHeap>,
+}
+
+// Same as for `FixedAlloc`
+#[cfg(feature = "single-threaded")]
+unsafe impl Send for Heap {}
+#[cfg(feature = "single-threaded")]
+unsafe impl Sync for Heap {}
+
+impl Heap {
+    /// The same buffer requirements as `try_reinitialize` apply, except that
+    /// the maximum size is slightly lower since the control block also lives
+    /// in the bookkeeping page.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must meet these requirements. Hooks and the last error are
+    /// still shared with the global heap, hence the same rules as for
+    /// `FixedAlloc::new_static` apply.
+    pub unsafe fn new(buffer: *mut u8, len: usize, zero_filled: bool) -> Result<Self, FmError> {
+        let handle = ffi::fm_sm_create(buffer as *mut c_void, len, if zero_filled { 1 } else { 0 });
+        match NonNull::new(handle) {
+            Some(handle) => Ok(Self { handle }),
+            None => {
+                Err(FmError::from_code(ffi::fm_last_error())
+                    .unwrap_or(FmError::Unknown(ffi::FM_OK)))
+            }
+        }
+    }
+
+    pub fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let size = crate::malloc_size(layout)?;
+        NonNull::new(unsafe { ffi::fm_sm_heap_malloc(self.handle.as_ptr(), size) } as *mut u8)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be allocated from this heap and not yet freed.
+    pub unsafe fn dealloc(&self, ptr: NonNull<u8>) {
+        ffi::fm_sm_heap_free(self.handle.as_ptr(), ptr.as_ptr() as *mut c_void)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be allocated from this heap and not yet freed. It stays
+    /// valid when `None` is returned.
+    pub unsafe fn realloc(&self, ptr: NonNull<u8>, new_size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(ffi::fm_sm_heap_realloc(
+            self.handle.as_ptr(),
+            ptr.as_ptr() as *mut c_void,
+            new_size,
+        ) as *mut u8)
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        unsafe { ffi::fm_sm_destroy(self.handle.as_ptr()) }
+    }
+}
+
+unsafe impl GlobalAlloc for &'static Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Heap::alloc(self, layout).map_or(core::ptr::null_mut(), |p| p.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            Heap::dealloc(self, ptr)
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, _layout: Layout, new_size: usize) -> *mut u8 {
+        // Page aligned blocks are kept page aligned when realloced
+        ffi::fm_sm_heap_realloc(self.handle.as_ptr(), ptr as *mut c_void, new_size) as *mut u8
+    }
+}
@@ -0,0 +1,82 @@
+// Records the size of each live allocation made via `GlobalAlloc`, so
+// `dealloc` can validate the layout passed in. No extra heap allocation can
+// be used here, hence a fixed size open addressing hash table. When the table
+// is full, new allocations are simply not tracked.
+
+use core::cell::UnsafeCell;
+
+const CAPACITY: usize = 4096;
+const EMPTY: usize = 0;
+const TOMBSTONE: usize = usize::MAX;
+
+struct Table {
+    entries: UnsafeCell<[(usize, usize); CAPACITY]>,
+}
+
+// Like the C allocator itself, the table is not thread safe.
+unsafe impl Sync for Table {}
+
+static TABLE: Table = Table {
+    entries: UnsafeCell::new([(EMPTY, 0); CAPACITY]),
+};
+
+fn slot(ptr: usize) -> usize {
+    // All pointers are aligned on 16-byte boundary
+    (ptr >> 4).wrapping_mul(0x9E3779B97F4A7C15u64 as usize) % CAPACITY
+}
+
+fn entries() -> &'static mut [(usize, usize); CAPACITY] {
+    unsafe { &mut *TABLE.entries.get() }
+}
+
+pub fn record(ptr: *mut u8, size: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    let ptr = ptr as usize;
+    let entries = entries();
+    let start = slot(ptr);
+    let mut free = None;
+    for i in 0..CAPACITY {
+        let index = (start + i) % CAPACITY;
+        match entries[index].0 {
+            p if p == ptr => {
+                entries[index].1 = size;
+                return;
+            }
+            TOMBSTONE => {
+                free.get_or_insert(index);
+            }
+            EMPTY => {
+                free.get_or_insert(index);
+                break;
+            }
+            _ => (),
+        }
+    }
+    if let Some(index) = free {
+        entries[index] = (ptr, size);
+    }
+}
+
+pub fn remove(ptr: *mut u8) -> Option<usize> {
+    let ptr = ptr as usize;
+    let entries = entries();
+    let start = slot(ptr);
+    for i in 0..CAPACITY {
+        let index = (start + i) % CAPACITY;
+        match entries[index].0 {
+            p if p == ptr => {
+                entries[index].0 = TOMBSTONE;
+                return Some(entries[index].1);
+            }
+            EMPTY => return None,
+            _ => (),
+        }
+    }
+    None
+}
+
+pub fn clear() {
+    entries().fill((EMPTY, 0));
+}
@@ -1,42 +1,1405 @@
 #![no_std]
+#![cfg_attr(feature = "alloc-error-handler", feature(alloc_error_handler))]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "tls-cache")]
+extern crate std;
+
+mod atomic;
+#[cfg(feature = "ckb")]
+pub mod ckb;
+#[cfg(feature = "critical-section")]
+mod critical;
+mod error;
 pub mod ffi;
+mod heap;
+#[cfg(feature = "test-support")]
+mod layout_check;
+#[cfg(any(feature = "sync", feature = "spin", feature = "critical-section"))]
+mod lock;
+#[cfg(feature = "spin")]
+mod locked;
+#[cfg(all(feature = "pure-rust", not(feature = "differential")))]
+mod rust_impl;
+#[cfg(feature = "differential")]
+#[doc(hidden)]
+pub mod rust_impl;
+mod slice;
+mod string;
+#[cfg(feature = "sync")]
+mod sync;
+mod tiered;
+#[cfg(feature = "tls-cache")]
+mod tls_cache;
+mod tracked;
+mod version;
 
+#[cfg(feature = "manual-init")]
+use atomic::{AtomicBool, Ordering};
 use core::alloc::{GlobalAlloc, Layout};
 use core::ffi::c_void;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+#[cfg(feature = "critical-section")]
+pub use critical::CriticalSectionAlloc;
+pub use error::{FmError, HeapError, HeapErrorKind};
+pub use ffi::AllocType;
+pub use heap::Heap;
+#[cfg(feature = "spin")]
+pub use locked::{LockedFixedAlloc, TicketLock};
+pub use slice::{FixedAllocSlice, Zeroable};
+pub use string::BumpString;
+#[cfg(feature = "sync")]
+pub use sync::SyncAlloc;
+pub use tiered::{TieredAlloc, TIERED_SMALL_MAX};
+#[cfg(feature = "tls-cache")]
+pub use tls_cache::TlsCacheAlloc;
+pub use tracked::{FixedAllocRef, Tracked};
+pub use version::{features, version, Features, Version};
+
+#[cfg(all(feature = "guard-pages", unix))]
+fn protect_page(page: *mut u8, prot: libc::c_int) {
+    let ret = unsafe {
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        libc::mprotect(page as *mut libc::c_void, page_size, prot)
+    };
+    if ret != 0 {
+        panic!("Failed to change protection of page {:p}", page);
+    }
+}
+
+// Size of the static memory buffer used by `new_static` and `init_static`,
+// which can be set at build time via the FIXED_MALLOC_MEMORY_SIZE
+// environment variable
+include!(concat!(env!("OUT_DIR"), "/static_memory_size.rs"));
+
+// Same as `STATIC_MEMORY_SIZE`, but read from the C side
+pub fn default_static_size() -> usize {
+    unsafe { ffi::fm_sm_default_memory_size() }
+}
+
+// Smallest `len` accepted by `try_reinitialize`, the buffer must still be page
+// aligned
+pub fn min_buffer_size() -> usize {
+    unsafe { ffi::fm_sm_min_buffer_size() }
+}
+
+// Drop whatever is kept about live allocations once the heap no longer has
+// them
+fn forget_allocations() {
+    #[cfg(feature = "test-support")]
+    layout_check::clear();
+    #[cfg(feature = "tls-cache")]
+    tls_cache::invalidate();
+}
 
+// All live allocations are discarded
 pub fn reinitialize(buffer: *mut u8, len: usize, zero_filled: bool) {
     let ret = unsafe {
+        crate::ffi::fm_sm_reinit_forced(buffer as *mut c_void, len, if zero_filled { 1 } else { 0 })
+    };
+    if let Err(e) = FmError::check(ret) {
+        panic!("Initialization failure: {:?}", e);
+    }
+    forget_allocations();
+}
+
+// The buffer must be page aligned, and its size must be a multiple of page
+// size between `FM_MIN_MEMORY_SIZE` and `FM_MAX_MEMORY_SIZE`. Reinit is
+// refused when there are still live allocations.
+pub fn try_reinitialize(buffer: *mut u8, len: usize, zero_filled: bool) -> Result<(), ReinitError> {
+    FmError::check(unsafe {
         crate::ffi::fm_sm_reinit(buffer as *mut c_void, len, if zero_filled { 1 } else { 0 })
+    })
+    .map_err(ReinitError::from)?;
+    forget_allocations();
+    Ok(())
+}
+
+// Switch to a new buffer, the previously installed buffer and its size are
+// returned so they can be released by the caller. Like `try_reinitialize`,
+// this is refused when there are still live allocations.
+pub fn reinitialize_swap(
+    buffer: *mut u8,
+    len: usize,
+    zero_filled: bool,
+) -> Result<(*mut u8, usize), ReinitError> {
+    let mut old_buffer = core::ptr::null_mut();
+    let mut old_size = 0;
+    FmError::check(unsafe {
+        crate::ffi::fm_sm_reinit_swap(
+            buffer as *mut c_void,
+            len,
+            if zero_filled { 1 } else { 0 },
+            &mut old_buffer,
+            &mut old_size,
+        )
+    })
+    .map_err(ReinitError::from)?;
+    forget_allocations();
+    Ok((old_buffer as *mut u8, old_size))
+}
+
+unsafe extern "C" fn relocate_trampoline(
+    old_ptr: *mut c_void,
+    new_ptr: *mut c_void,
+    size: usize,
+    ctx: *mut c_void,
+) {
+    #[cfg(feature = "test-support")]
+    if let Some(recorded) = layout_check::remove(old_ptr as *mut u8) {
+        layout_check::record(new_ptr as *mut u8, recorded);
+    }
+    let f = &mut *(ctx as *mut &mut dyn FnMut(*mut u8, *mut u8, usize));
+    f(old_ptr as *mut u8, new_ptr as *mut u8, size)
+}
+
+// Move the heap into a larger buffer preserving all live allocations. `f` is
+// called with the old pointer, new pointer and size of each live allocation,
+// so pointers held elsewhere can be fixed. The current buffer is untouched
+// when an error is returned, otherwise it can be released afterwards.
+pub fn migrate<F: FnMut(*mut u8, *mut u8, usize)>(
+    buffer: *mut u8,
+    len: usize,
+    mut f: F,
+) -> Result<(), ReinitError> {
+    let mut f: &mut dyn FnMut(*mut u8, *mut u8, usize) = &mut f;
+    FmError::check(unsafe {
+        crate::ffi::fm_sm_migrate(
+            buffer as *mut c_void,
+            len,
+            Some(relocate_trampoline),
+            &mut f as *mut &mut dyn FnMut(*mut u8, *mut u8, usize) as *mut c_void,
+        )
+    })
+    .map_err(ReinitError::from)?;
+    #[cfg(feature = "tls-cache")]
+    tls_cache::invalidate();
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReinitError {
+    // The static memory has already been initialized
+    AlreadyInitialized,
+    // The buffer does not start on a `FM_PAGE_SIZE` boundary
+    UnalignedBuffer,
+    // The buffer size is not a multiple of `FM_PAGE_SIZE`
+    UnalignedSize,
+    // Other errors returned by the C initialization function
+    Failed(FmError),
+}
+
+impl From<FmError> for ReinitError {
+    fn from(e: FmError) -> Self {
+        match e {
+            FmError::UnalignedBuffer => ReinitError::UnalignedBuffer,
+            FmError::UnalignedSize => ReinitError::UnalignedSize,
+            e => ReinitError::Failed(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdoptError {
+    // The block is not aligned on 16 bytes
+    Unaligned,
+    // The block holds less than `FM_MIN_MEMORY_SIZE` bytes of whole pages
+    TooSmall,
+    // Error returned when adding the pages as a memory region
+    Failed(FmError),
+}
+
+#[cfg(feature = "manual-init")]
+static STATIC_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "manual-init")]
+include!(concat!(env!("OUT_DIR"), "/static_buffer.rs"));
+
+// Only accessed by `init_static`, which hands it over to the C side once
+#[cfg(feature = "manual-init")]
+unsafe impl Sync for StaticBuffer {}
+
+// Initialize the allocator using static memory, this can only be done once.
+// The buffer is not part of the C library, programs which never call this
+// don't reserve it.
+#[cfg(feature = "manual-init")]
+pub fn init_static() -> Result<(), ReinitError> {
+    if STATIC_INITIALIZED.swap(true, Ordering::SeqCst) {
+        return Err(ReinitError::AlreadyInitialized);
+    }
+    let buffer = BUFFER.0.get() as *mut c_void;
+    FmError::check(unsafe { ffi::fm_sm_reinit(buffer, STATIC_MEMORY_SIZE, 1) })
+        .map_err(ReinitError::from)
+}
+
+// Bounds of the heap defined by the linker script, see
+// `FixedAlloc::from_linker_symbols`
+#[cfg(feature = "linker-heap")]
+include!(concat!(env!("OUT_DIR"), "/linker_heap.rs"));
+
+// Report the failed allocation when test support is enabled, then trap.
+// Downstream crates supplying their own `alloc_error_handler` can call this
+// as well.
+pub fn handle_alloc_error(layout: Layout) -> ! {
+    #[cfg(feature = "test-support")]
+    unsafe {
+        ffi::fm_sm_test_report_oom(layout.size())
     };
-    assert_eq!(ret, 0, "Initialization failure: {}", ret);
+    #[cfg(not(feature = "test-support"))]
+    let _ = layout;
+    trap()
 }
 
-pub struct FixedAlloc {}
+#[cfg(feature = "alloc-error-handler")]
+#[alloc_error_handler]
+fn default_alloc_error_handler(layout: Layout) -> ! {
+    handle_alloc_error(layout)
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+fn trap() -> ! {
+    unsafe { core::arch::asm!("unimp", options(noreturn)) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn trap() -> ! {
+    unsafe { core::arch::asm!("ud2", options(noreturn)) }
+}
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+fn trap() -> ! {
+    unsafe { core::arch::asm!("udf #0", options(noreturn)) }
+}
+
+#[cfg(not(any(
+    target_arch = "riscv32",
+    target_arch = "riscv64",
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+)))]
+fn trap() -> ! {
+    loop {
+        core::hint::spin_loop()
+    }
+}
+
+// The Rust hook is passed to C as the context pointer
+unsafe extern "C" fn oom_hook_trampoline(requested: usize, ctx: *mut c_void) {
+    let hook: fn(usize) = core::mem::transmute(ctx);
+    hook(requested)
+}
+
+// Same as `oom_hook_trampoline`, for the debug message hook
+#[cfg(feature = "debug-hook")]
+unsafe extern "C" fn debug_hook_trampoline(message: *const core::ffi::c_char, ctx: *mut c_void) {
+    let hook: fn(&core::ffi::CStr) = core::mem::transmute(ctx);
+    hook(core::ffi::CStr::from_ptr(message))
+}
+
+fn page_aligned_layout(n_pages: usize) -> Option<Layout> {
+    if n_pages == 0 {
+        return None;
+    }
+    let size = n_pages.checked_mul(ffi::FM_PAGE_SIZE)?;
+    Layout::from_size_align(size, ffi::FM_PAGE_SIZE).ok()
+}
+
+// Size to request from slab malloc for `layout`, `None` when its alignment
+// exceeds `FM_MAX_ALIGN`. Blocks of at least one page are served by linear
+// malloc, which always returns page aligned memory. The pointer is then the
+// start of the block itself, so `dealloc` and `realloc` need no header to
+// find it, and realloc keeps such blocks page aligned.
+pub(crate) fn malloc_size(layout: Layout) -> Option<usize> {
+    if layout.align() <= ffi::FM_MIN_ALIGN {
+        Some(layout.size())
+    } else if layout.align() <= ffi::FM_MAX_ALIGN {
+        Some(layout.size().max(ffi::FM_PAGE_SIZE))
+    } else {
+        None
+    }
+}
+
+// Same as `oom_hook_trampoline`, for the error sink
+unsafe extern "C" fn error_sink_trampoline(
+    code: core::ffi::c_int,
+    message: *const core::ffi::c_char,
+    ctx: *mut c_void,
+) {
+    let sink: fn(FmError, &core::ffi::CStr) = core::mem::transmute(ctx);
+    sink(
+        FmError::from_code(code).unwrap_or(FmError::Unknown(code)),
+        core::ffi::CStr::from_ptr(message),
+    )
+}
+
+// Usage of slabs in one size class
+#[cfg(feature = "test-support")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassStat {
+    pub class_size: usize,
+    pub slabs: usize,
+    pub used_slots: usize,
+    pub free_slots: usize,
+}
+
+#[cfg(feature = "test-support")]
+unsafe extern "C" fn class_stats_trampoline(
+    class_size: usize,
+    slabs: usize,
+    used_slots: usize,
+    free_slots: usize,
+    user: *mut c_void,
+) {
+    let f = &mut *(user as *mut &mut dyn FnMut(ClassStat));
+    f(ClassStat {
+        class_size,
+        slabs,
+        used_slots,
+        free_slots,
+    })
+}
+
+// Live allocation reported by `FixedAlloc::walk`
+#[cfg(feature = "test-support")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub ptr: *mut u8,
+    // Slab objects are reported with the size of their size classes
+    pub size: usize,
+    // 0 for untagged allocations
+    pub tag: u32,
+}
+
+#[cfg(feature = "test-support")]
+unsafe extern "C" fn walk_trampoline(ptr: *mut c_void, size: usize, tag: u32, user: *mut c_void) {
+    let f = &mut *(user as *mut &mut dyn FnMut(BlockInfo));
+    f(BlockInfo {
+        ptr: ptr as *mut u8,
+        size,
+        tag,
+    })
+}
+
+// Bookkeeping data of the heap taken by `FixedAlloc::snapshot`, which is
+// only the headers of blocks, slabs and free regions rather than the whole
+// buffer.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocSnapshot {
+    data: alloc::vec::Vec<u8>,
+}
+
+#[cfg(feature = "alloc")]
+impl AllocSnapshot {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+// The C allocator keeps its state in globals without any locking, and every
+// `FixedAlloc` is a handle of that one global heap. Handles are hence neither
+// `Send` nor `Sync`, so they cannot be used as a `#[global_allocator]`, unless
+// the `single-threaded` feature is enabled on targets that never run more than
+// one thread. A thread can still create a handle of its own, which is why the
+// constructors are unsafe: no two threads may be inside the allocator at the
+// same time. All lock wrappers share one lock, so handles used only through
+// them always satisfy this.
+#[cfg(not(feature = "single-threaded"))]
+type ThreadMarker = PhantomData<*mut ()>;
+#[cfg(feature = "single-threaded")]
+type ThreadMarker = PhantomData<()>;
+
+pub struct FixedAlloc {
+    _marker: ThreadMarker,
+    #[cfg(feature = "owned-buffer")]
+    owned: Option<OwnedBuffer>,
+}
+
+// Buffer allocated by `new_owned`, released when the handle is dropped
+#[cfg(feature = "owned-buffer")]
+struct OwnedBuffer {
+    buffer: NonNull<u8>,
+    layout: Layout,
+}
+
+// Only touched when the handle is dropped, whether the handle can be moved
+// or shared is still decided by `ThreadMarker`
+#[cfg(feature = "owned-buffer")]
+unsafe impl Send for OwnedBuffer {}
+#[cfg(feature = "owned-buffer")]
+unsafe impl Sync for OwnedBuffer {}
 
 impl FixedAlloc {
-    // Initialize using static memory
+    const fn handle() -> Self {
+        Self {
+            _marker: PhantomData,
+            #[cfg(feature = "owned-buffer")]
+            owned: None,
+        }
+    }
+
+    /// Initialize using static memory
+    ///
+    /// # Safety
+    ///
+    /// The heap must not be used by another thread at the same time, unless
+    /// every use goes through the lock wrappers.
     #[cfg(not(feature = "manual-init"))]
-    pub fn new_static() -> Self {
-        Self {}
+    pub const unsafe fn new_static() -> Self {
+        Self::handle()
     }
 
-    pub fn new(buffer: *mut u8, len: usize, zero_filled: bool) -> Self {
+    /// Refer to static memory without initializing it, `init_static` must be
+    /// called before any allocation is made.
+    ///
+    /// # Safety
+    ///
+    /// See `new_static`.
+    #[cfg(feature = "manual-init")]
+    pub const unsafe fn new_static_uninit() -> Self {
+        Self::handle()
+    }
+
+    /// Initialize using `buffer`
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must meet the requirements of `try_reinitialize`, and the
+    /// heap is shared as in `new_static`.
+    pub unsafe fn new(buffer: *mut u8, len: usize, zero_filled: bool) -> Self {
         reinitialize(buffer, len, zero_filled);
-        Self {}
+        Self::handle()
+    }
+
+    /// Initialize using a zero-filled buffer of `len` bytes taken from the
+    /// global allocator, which is released again when the result is dropped.
+    /// `len` has the same requirements as in `try_reinitialize`. Dropping the
+    /// result detaches the heap from the buffer first, so all allocations
+    /// must be freed by then, later ones fail until the next reinit.
+    ///
+    /// # Safety
+    ///
+    /// See `new_static`.
+    #[cfg(feature = "owned-buffer")]
+    pub unsafe fn new_owned(len: usize) -> Result<Self, ReinitError> {
+        if len < ffi::FM_MIN_MEMORY_SIZE {
+            return Err(ReinitError::Failed(FmError::BufferTooSmall));
+        }
+        let layout = Layout::from_size_align(len, ffi::FM_PAGE_SIZE)
+            .map_err(|_| ReinitError::Failed(FmError::BufferTooLarge))?;
+        let buffer = NonNull::new(alloc::alloc::alloc_zeroed(layout))
+            .ok_or(ReinitError::Failed(FmError::NoMemory))?;
+        if let Err(e) = try_reinitialize(buffer.as_ptr(), len, true) {
+            alloc::alloc::dealloc(buffer.as_ptr(), layout);
+            return Err(e);
+        }
+        let mut alloc = Self::handle();
+        alloc.owned = Some(OwnedBuffer { buffer, layout });
+        Ok(alloc)
+    }
+
+    /// Initialize using the memory between the `_heap_start` and `_heap_end`
+    /// symbols of the linker script, which can be renamed at build time via
+    /// `FIXED_MALLOC_HEAP_START_SYMBOL` and `FIXED_MALLOC_HEAP_END_SYMBOL`.
+    /// The region is shrunk to whole pages and need not be zero-filled, less
+    /// than `FM_MIN_MEMORY_SIZE` bytes of them fail with
+    /// `ReinitError::Failed(FmError::BufferTooSmall)`. Combine it with
+    /// `manual-init` so no static buffer is reserved besides the region.
+    ///
+    /// # Safety
+    ///
+    /// The region between the symbols must be valid memory used by nothing
+    /// else, and the heap is shared as in `new_static`.
+    #[cfg(feature = "linker-heap")]
+    pub unsafe fn from_linker_symbols() -> Result<Self, ReinitError> {
+        let start = core::ptr::addr_of_mut!(HEAP_START);
+        let end = core::ptr::addr_of!(HEAP_END) as usize;
+        Self::from_pages_within(start, end)
+    }
+
+    /// Initialize using a static buffer, e.g. one exported by a WebAssembly
+    /// module so the host can place it at instantiation time. Like
+    /// `from_linker_symbols`, the buffer is shrunk to whole pages and need not
+    /// be zero-filled, so it has no alignment requirements. The buffer is
+    /// handed over to the heap for good.
+    ///
+    /// # Safety
+    ///
+    /// See `new_static`.
+    #[cfg(feature = "wasm")]
+    pub unsafe fn from_static_buffer(buffer: &'static mut [u8]) -> Result<Self, ReinitError> {
+        let range = buffer.as_mut_ptr_range();
+        Self::from_pages_within(range.start, range.end as usize)
+    }
+
+    // Initialize using the whole pages between `start` and `end`, which must
+    // be valid memory used by nothing else
+    #[cfg(any(feature = "linker-heap", feature = "wasm"))]
+    unsafe fn from_pages_within(start: *mut u8, end: usize) -> Result<Self, ReinitError> {
+        // Round the start up and the end down to page boundaries
+        let offset = (start as usize).wrapping_neg() % ffi::FM_PAGE_SIZE;
+        let len =
+            end.saturating_sub((start as usize).saturating_add(offset)) & !(ffi::FM_PAGE_SIZE - 1);
+        if len < ffi::FM_MIN_MEMORY_SIZE {
+            return Err(ReinitError::Failed(FmError::BufferTooSmall));
+        }
+        try_reinitialize(start.add(offset), len, false)?;
+        Ok(Self::handle())
+    }
+
+    /// Initialize using `len` bytes of fresh WebAssembly memory, which is
+    /// grown by as many 64 KiB wasm pages as needed. Grown memory is zero
+    /// filled and page aligned, `len` must still be a multiple of
+    /// `FM_PAGE_SIZE`. Combine it with `manual-init` so no static buffer is
+    /// reserved in the data section. The memory cannot be returned to the
+    /// host, later calls grow it further.
+    ///
+    /// # Safety
+    ///
+    /// See `new_static`.
+    #[cfg(target_arch = "wasm32")]
+    pub unsafe fn new_wasm_memory(len: usize) -> Result<Self, ReinitError> {
+        const WASM_PAGE_SIZE: usize = 65536;
+        // Checked first so no memory is grown in vain
+        if !len.is_multiple_of(ffi::FM_PAGE_SIZE) {
+            return Err(ReinitError::UnalignedSize);
+        }
+        let pages = len.div_ceil(WASM_PAGE_SIZE);
+        let previous = core::arch::wasm32::memory_grow(0, pages);
+        if previous == usize::MAX {
+            return Err(ReinitError::Failed(FmError::NoMemory));
+        }
+        try_reinitialize((previous * WASM_PAGE_SIZE) as *mut u8, len, true)?;
+        Ok(Self::handle())
+    }
+
+    /// Keep slabs in `slab_buffer` and serve larger allocations from
+    /// `linear_buffer`, so each tier can live in a different kind of memory.
+    ///
+    /// # Safety
+    ///
+    /// Both buffers must meet the requirements of `try_reinitialize`, and the
+    /// heap is shared as in `new_static`.
+    pub unsafe fn new_split(
+        slab_buffer: *mut u8,
+        slab_len: usize,
+        linear_buffer: *mut u8,
+        linear_len: usize,
+        zero_filled: bool,
+    ) -> Result<Self, FmError> {
+        FmError::check(ffi::fm_sm_reinit_split(
+            slab_buffer as *mut c_void,
+            slab_len,
+            linear_buffer as *mut c_void,
+            linear_len,
+            if zero_filled { 1 } else { 0 },
+        ))?;
+        forget_allocations();
+        Ok(Self::handle())
+    }
+
+    /// Initialize, then allocate and free `warmup_bytes` to touch the memory
+    /// and internal data structures ahead of time. `warmup_bytes` is rounded
+    /// up to whole pages, and clamped to the allocatable part of `len`.
+    ///
+    /// # Safety
+    ///
+    /// See `new`.
+    pub unsafe fn with_capacity_hint(
+        buffer: *mut u8,
+        len: usize,
+        zero_filled: bool,
+        warmup_bytes: usize,
+    ) -> Self {
+        let alloc = Self::new(buffer, len, zero_filled);
+        let size = warmup_bytes
+            .min(len - ffi::FM_PAGE_SIZE)
+            .next_multiple_of(ffi::FM_PAGE_SIZE);
+        if size > 0 {
+            let p = ffi::fm_lm_malloc(size, AllocType::Transient.into());
+            if !p.is_null() {
+                core::ptr::write_bytes(p as *mut u8, 0, size);
+                ffi::fm_lm_free(p);
+            }
+        }
+        alloc
+    }
+
+    /// Same as `new`, but the page right after the buffer is made inaccessible
+    /// via `mprotect`, so writes past the end of the buffer fault right away.
+    /// Call `remove_guard` before the memory is released.
+    ///
+    /// # Safety
+    ///
+    /// Same as `new`, the caller must also own the page after the buffer, and
+    /// `buffer + len` must be aligned on the system page size.
+    #[cfg(all(feature = "guard-pages", unix))]
+    pub unsafe fn new_with_guard(buffer: *mut u8, len: usize, zero_filled: bool) -> Self {
+        let alloc = Self::new(buffer, len, zero_filled);
+        protect_page(buffer.wrapping_add(len), libc::PROT_NONE);
+        alloc
+    }
+
+    // Make the guard page installed by `new_with_guard` accessible again
+    #[cfg(all(feature = "guard-pages", unix))]
+    pub fn remove_guard(buffer: *mut u8, len: usize) {
+        protect_page(buffer.wrapping_add(len), libc::PROT_READ | libc::PROT_WRITE);
+    }
+
+    // Allocate zeroed memory for `n` elements of `size` bytes each, `None` is
+    // returned when the size overflows or the heap is exhausted.
+    pub fn calloc(&self, n: usize, size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { ffi::fm_sm_calloc(n, size) } as *mut u8)
+    }
+
+    pub fn stats(&self) -> ffi::FmStats {
+        let mut stats = ffi::FmStats::default();
+        unsafe { ffi::fm_sm_stats(&mut stats) };
+        stats
+    }
+
+    // Number of allocations that are not yet freed
+    pub fn live_allocations(&self) -> usize {
+        unsafe { ffi::fm_sm_live_allocations() }
+    }
+
+    // Bytes held by live allocations, the same as `used_bytes` of `stats`
+    // but without walking the free lists. Slab objects count with the size
+    // of their size classes, larger blocks with whole pages.
+    pub fn allocated_bytes(&self) -> usize {
+        unsafe { ffi::fm_sm_allocated_bytes() }
+    }
+
+    // Check the bookkeeping data of the whole heap, freed slab slots are also
+    // checked for modifications with `fill-on-free`.
+    pub fn verify_heap_integrity(&self) -> Result<(), HeapError> {
+        let mut error = ffi::FmHeapError {
+            kind: ffi::FM_HEAP_OK,
+            address: core::ptr::null_mut(),
+        };
+        match unsafe { ffi::fm_sm_verify(&mut error) } {
+            ffi::FM_HEAP_OK => Ok(()),
+            kind => Err(HeapError {
+                kind: HeapErrorKind::from_code(kind),
+                address: error.address as usize,
+            }),
+        }
+    }
+
+    // Free every live allocation, unlike `reinitialize` this goes through the
+    // regular free path so all checks done on free still apply. Returns the
+    // number of freed allocations.
+    pub fn free_all(&self) -> usize {
+        let freed = unsafe { ffi::fm_sm_free_all() };
+        forget_allocations();
+        freed
+    }
+
+    /// Free all blocks in `ptrs` with a single call into the allocator, which
+    /// is much cheaper than freeing them one by one. Null pointers are
+    /// skipped.
+    ///
+    /// # Safety
+    ///
+    /// Each pointer must be null or allocated by this allocator and not yet
+    /// freed, and must appear only once.
+    pub unsafe fn free_many(&self, ptrs: &[*mut u8]) {
+        #[cfg(feature = "test-support")]
+        for ptr in ptrs {
+            layout_check::remove(*ptr);
+        }
+        ffi::fm_sm_free_many(ptrs.as_ptr() as *const *mut c_void, ptrs.len())
+    }
+
+    // Feed the heap with another memory region, which does not need to be
+    // contiguous with existing ones. A single allocation never spans regions,
+    // and all extra regions are dropped on reinitialization.
+    pub fn add_region(
+        &self,
+        buffer: *mut u8,
+        len: usize,
+        zero_filled: bool,
+    ) -> Result<(), FmError> {
+        FmError::check(unsafe {
+            ffi::fm_sm_add_region(buffer as *mut c_void, len, if zero_filled { 1 } else { 0 })
+        })
+    }
+
+    /// Hand a block allocated elsewhere, such as a DMA buffer, over to the heap
+    /// as free memory. Only the whole pages within the block are used, they
+    /// are added as another memory region like in `add_region`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be 16-byte aligned and valid for `size` bytes, which are
+    /// owned by the heap from now on. Allocations made from the block must
+    /// all be freed via `dealloc` before the block is released to its
+    /// original allocator, and the heap must be reinitialized in between so
+    /// it no longer refers to the block.
+    pub unsafe fn adopt(&self, ptr: NonNull<u8>, size: usize) -> Result<(), AdoptError> {
+        FmError::check(ffi::fm_sm_adopt(ptr.as_ptr() as *mut c_void, size)).map_err(|e| match e {
+            FmError::BadAlignment => AdoptError::Unaligned,
+            FmError::BufferTooSmall => AdoptError::TooSmall,
+            e => AdoptError::Failed(e),
+        })
+    }
+
+    /// Grow the heap with `additional` bytes right after the end of the current
+    /// buffer, which must be a multiple of `FM_PAGE_SIZE`. Live allocations stay
+    /// valid.
+    ///
+    /// # Safety
+    ///
+    /// The memory directly following the current buffer must be valid, unused,
+    /// and handed over to the allocator for the rest of its lifetime.
+    pub unsafe fn extend(&self, additional: usize) -> Result<(), FmError> {
+        FmError::check(ffi::fm_sm_extend(additional))
+    }
+
+    /// Move slab objects out of sparsely used pages into denser ones, then free
+    /// the emptied pages. `f` is called with the old pointer, new pointer and
+    /// size of each moved object. Returns the number of pages reclaimed.
+    ///
+    /// # Safety
+    ///
+    /// This invalidates pointers! Any moved object must only be accessed via
+    /// the new pointer passed to `f` afterwards, all other pointers to it are
+    /// left dangling.
+    pub unsafe fn compact<F: FnMut(*mut u8, *mut u8, usize)>(&self, mut f: F) -> usize {
+        let mut f: &mut dyn FnMut(*mut u8, *mut u8, usize) = &mut f;
+        let reclaimed = ffi::fm_sm_compact(
+            Some(relocate_trampoline),
+            &mut f as *mut &mut dyn FnMut(*mut u8, *mut u8, usize) as *mut c_void,
+        );
+        #[cfg(feature = "tls-cache")]
+        tls_cache::invalidate();
+        reclaimed
+    }
+
+    // Return empty slabs to the free pages so larger allocations can use
+    // them, objects stay where they are. Returns the number of bytes recovered.
+    pub fn trim(&self) -> usize {
+        unsafe { ffi::fm_sm_trim() }
+    }
+
+    // Largest size alloc can satisfy right now, which depends on the buffers
+    // and on what is allocated from them. Empty slabs are trimmed on the way.
+    pub fn max_alloc_size(&self) -> usize {
+        unsafe { ffi::fm_sm_max_alloc_size() }
+    }
+
+    // Usable size of ptr if it is a live allocation from this heap, interior
+    // pointers and pointers from other allocators give None
+    pub fn owns_and_size(&self, ptr: *const u8) -> Option<usize> {
+        match unsafe { ffi::fm_sm_usable_size(ptr as *const c_void) } {
+            0 => None,
+            size => Some(size),
+        }
+    }
+
+    /// Resize ptr to `new_size` bytes without moving it, which only succeeds
+    /// within the size class of a slab object, or when the pages following a
+    /// larger block are free. `None` is returned otherwise, ptr is then still
+    /// valid with its old size.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation of this heap. When allocated through
+    /// `GlobalAlloc`, the layout passed to `dealloc` must use `new_size`
+    /// after a successful call.
+    pub unsafe fn realloc_no_move(&self, ptr: *mut u8, new_size: usize) -> Option<NonNull<u8>> {
+        let p = NonNull::new(ffi::fm_sm_realloc_flags(
+            ptr as *mut c_void,
+            new_size,
+            ffi::FM_REALLOC_NO_MOVE,
+        ) as *mut u8)?;
+        #[cfg(feature = "test-support")]
+        layout_check::record(p.as_ptr(), new_size);
+        Some(p)
+    }
+
+    /// Shrink ptr to `new_size` bytes in place, a block of whole pages returns
+    /// the pages beyond `new_size` to the heap right away but keeps at least
+    /// one. Slab objects keep their size classes. `None` is returned when
+    /// `new_size` does not fit in the block, ptr is then left untouched.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation of this heap. When allocated through
+    /// `GlobalAlloc`, the layout passed to `dealloc` must use `new_size`
+    /// after a successful call.
+    pub unsafe fn shrink_release(&self, ptr: *mut u8, new_size: usize) -> Option<NonNull<u8>> {
+        let p = NonNull::new(ffi::fm_sm_shrink_release(ptr as *mut c_void, new_size) as *mut u8)?;
+        #[cfg(feature = "test-support")]
+        layout_check::record(p.as_ptr(), new_size);
+        Some(p)
+    }
+
+    // Like `malloc_usable_size`, the number of bytes usable at ptr, which is
+    // the size of its size class for slab objects and whole pages otherwise.
+    // 0 is returned if ptr is not a live allocation from this heap.
+    pub fn usable_size(&self, ptr: *const u8) -> usize {
+        unsafe { ffi::fm_sm_usable_size(ptr as *const c_void) }
+    }
+
+    // Layout covering the full size class of ptr allocated with `layout`, so
+    // callers such as growable buffers can use the extra capacity without a
+    // realloc. The returned layout can be passed to `dealloc` and `realloc`
+    // afterwards. `layout` is returned as is if ptr is not allocated here.
+    pub fn shrink_to_fit_class(&self, ptr: *mut u8, layout: Layout) -> Layout {
+        let size = self.usable_size(ptr);
+        if size <= layout.size() {
+            return layout;
+        }
+        #[cfg(feature = "test-support")]
+        layout_check::record(ptr, size);
+        Layout::from_size_align(size, layout.align()).unwrap_or(layout)
+    }
+
+    // Allocate `n` contiguous pages aligned to `FM_PAGE_SIZE`, or null when
+    // `n` is 0 or the heap runs out of memory.
+    pub fn alloc_pages(&self, n: usize) -> *mut u8 {
+        unsafe { ffi::fm_sm_page_alloc(n) as *mut u8 }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be returned by `alloc_pages` and not yet freed.
+    pub unsafe fn free_pages(&self, ptr: *mut u8) {
+        ffi::fm_sm_page_free(ptr as *mut c_void)
+    }
+
+    // Allocate `n_pages` pages aligned to `FM_PAGE_SIZE`, such as buffers
+    // for DMA. `None` is returned when `n_pages` is 0, the size overflows or
+    // the heap is exhausted.
+    pub fn alloc_page_aligned(&self, n_pages: usize) -> Option<NonNull<u8>> {
+        let layout = page_aligned_layout(n_pages)?;
+        NonNull::new(unsafe { self.alloc(layout) })
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be returned by `alloc_page_aligned` on this allocator using
+    /// the same `n_pages`.
+    pub unsafe fn dealloc_page_aligned(&self, ptr: NonNull<u8>, n_pages: usize) {
+        let layout = page_aligned_layout(n_pages).expect("layout");
+        self.dealloc(ptr.as_ptr(), layout)
+    }
+
+    // Copy the bookkeeping data of the heap, which can be written back via
+    // `restore`. Allocated memory itself is not part of the snapshot.
+    #[cfg(feature = "alloc")]
+    pub fn snapshot(&self) -> AllocSnapshot {
+        let needed = unsafe { ffi::fm_sm_snapshot(core::ptr::null_mut(), 0) };
+        let mut data = alloc::vec![0u8; needed.unsigned_abs()];
+        let written = unsafe { ffi::fm_sm_snapshot(data.as_mut_ptr() as *mut c_void, data.len()) };
+        debug_assert_eq!(written.unsigned_abs(), data.len());
+        AllocSnapshot { data }
+    }
+
+    // Same as `snapshot`, but written into `out`. Returns the number of bytes
+    // written, or the size needed when `out` is too small.
+    pub fn snapshot_into(&self, out: &mut [u8]) -> Result<usize, usize> {
+        match unsafe { ffi::fm_sm_snapshot(out.as_mut_ptr() as *mut c_void, out.len()) } {
+            n if n < 0 => Err(n.unsigned_abs()),
+            n => Ok(n as usize),
+        }
+    }
+
+    /// Bring the heap back to the state of `snapshot`, which must be taken from
+    /// the same memory regions. Nothing is changed when an error is returned.
+    ///
+    /// # Safety
+    ///
+    /// Allocations made after the snapshot are dropped and must no longer be
+    /// used, while allocations freed after the snapshot become live again
+    /// with whatever they hold now.
+    #[cfg(feature = "alloc")]
+    pub unsafe fn restore(&self, snapshot: AllocSnapshot) -> Result<(), FmError> {
+        self.restore_from(snapshot.as_bytes())
+    }
+
+    /// Same as `restore`, but with a snapshot written by `snapshot_into`
+    ///
+    /// # Safety
+    ///
+    /// See `restore`.
+    pub unsafe fn restore_from(&self, snapshot: &[u8]) -> Result<(), FmError> {
+        FmError::check(ffi::fm_sm_restore(
+            snapshot.as_ptr() as *const c_void,
+            snapshot.len(),
+        ))?;
+        forget_allocations();
+        Ok(())
+    }
+
+    // Error of the last failing operation, which is kept until cleared
+    pub fn last_error(&self) -> Option<FmError> {
+        FmError::from_code(unsafe { ffi::fm_last_error() })
+    }
+
+    pub fn clear_error(&self) {
+        unsafe { ffi::fm_clear_error() }
+    }
+
+    // Install a hook called with the requested size whenever an allocation
+    // fails. The hook can inspect the heap but must not allocate, without
+    // lock callbacks such allocations fail with `FmError::Reentrant`.
+    pub fn set_oom_hook(&self, hook: fn(usize)) {
+        unsafe { ffi::fm_sm_set_oom_hook(Some(oom_hook_trampoline), hook as *mut c_void) }
+    }
+
+    pub fn clear_oom_hook(&self) {
+        unsafe { ffi::fm_sm_set_oom_hook(None, core::ptr::null_mut()) }
+    }
+
+    // Install a sink receiving internal errors along with their messages,
+    // such as invalid buffers or double frees. Unlike debug messages they are
+    // always reported, including right before aborting on a failed guard
+    // check. The sink must not call into the allocator.
+    pub fn set_error_sink(&self, sink: fn(FmError, &core::ffi::CStr)) {
+        unsafe { ffi::fm_sm_set_error_sink(Some(error_sink_trampoline), sink as *mut c_void) }
+    }
+
+    pub fn clear_error_sink(&self) {
+        unsafe { ffi::fm_sm_set_error_sink(None, core::ptr::null_mut()) }
+    }
+
+    // Install a hook receiving the debug messages of the C allocator, which
+    // are only the format strings without values filled in. The hook is
+    // called with the heap locked, so it must not allocate either.
+    #[cfg(feature = "debug-hook")]
+    pub fn set_debug_hook(&self, hook: fn(&core::ffi::CStr)) {
+        unsafe { ffi::fm_set_debug_callback(Some(debug_hook_trampoline), hook as *mut c_void) }
+    }
+
+    #[cfg(feature = "debug-hook")]
+    pub fn clear_debug_hook(&self) {
+        unsafe { ffi::fm_set_debug_callback(None, core::ptr::null_mut()) }
+    }
+
+    // Place allocations only based on the calls made since the heap was last
+    // empty, so an alloc/free sequence replayed on an empty heap returns the
+    // same addresses. Freed memory is merged right away, and the random seed
+    // is ignored while enabled.
+    pub fn set_deterministic(&self, enabled: bool) {
+        unsafe { ffi::fm_sm_set_deterministic(if enabled { 1 } else { 0 }) }
+    }
+
+    // Randomize the placement of allocations, the same seed always leads to
+    // the same sequence of allocated addresses.
+    #[cfg(feature = "hardening")]
+    pub fn set_random_seed(&self, seed: u64) {
+        unsafe { ffi::fm_sm_set_random_seed(seed) }
+    }
+
+    // Delay reuse of the last `n` freed blocks
+    #[cfg(feature = "test-support")]
+    pub fn set_quarantine(&self, n: usize) {
+        unsafe { ffi::fm_sm_set_quarantine(n) }
+    }
+
+    // Fill the bytes gained by reallocated blocks with
+    // `FM_REALLOC_FILL_PATTERN`, so they can be told apart from preserved ones
+    #[cfg(feature = "test-support")]
+    pub fn set_realloc_fill(&self, enabled: bool) {
+        unsafe { ffi::fm_sm_set_realloc_fill(if enabled { 1 } else { 0 }) }
+    }
+
+    // Call `f` once for each size class, from the smallest to the largest
+    #[cfg(feature = "test-support")]
+    pub fn class_stats<F: FnMut(ClassStat)>(&self, mut f: F) {
+        let mut f: &mut dyn FnMut(ClassStat) = &mut f;
+        unsafe {
+            ffi::fm_sm_class_stats(
+                class_stats_trampoline,
+                &mut f as *mut &mut dyn FnMut(ClassStat) as *mut c_void,
+            )
+        }
+    }
+
+    // Direct access to linear malloc of the same heap
+    pub fn linear(&self) -> LinearAlloc {
+        LinearAlloc {
+            _marker: PhantomData,
+        }
+    }
+
+    // Call `f` for each live allocation in address order
+    #[cfg(feature = "test-support")]
+    pub fn walk<F: FnMut(BlockInfo)>(&self, mut f: F) {
+        let mut f: &mut dyn FnMut(BlockInfo) = &mut f;
+        unsafe {
+            ffi::fm_sm_test_walk(
+                walk_trampoline,
+                &mut f as *mut &mut dyn FnMut(BlockInfo) as *mut c_void,
+            )
+        }
+    }
+
+    // Number of live allocations by size, bucket `i` counts blocks of
+    // `[2^i, 2^(i+1))` bytes and the last one also counts larger blocks.
+    // Sizes are the ones reported by `walk`.
+    #[cfg(feature = "test-support")]
+    pub fn size_histogram(&self) -> [u64; ffi::FM_SM_HISTOGRAM_BUCKETS] {
+        let mut buckets = [0; ffi::FM_SM_HISTOGRAM_BUCKETS];
+        unsafe { ffi::fm_sm_test_size_histogram(buckets.as_mut_ptr()) };
+        buckets
+    }
+
+    // Allocate a block tagged with a caller supplied id, which is reported
+    // by `walk` to attribute leaks.
+    #[cfg(feature = "test-support")]
+    pub fn malloc_tagged(&self, size: usize, tag: u32) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { ffi::fm_sm_malloc_tagged(size, tag) } as *mut u8)
+    }
+
+    // Move `val` into this heap, the memory is freed here when the returned
+    // pointer is dropped
+    pub fn alloc_tracked<T>(&self, val: T) -> Option<Tracked<T, &FixedAlloc>> {
+        Tracked::new_in(val, self)
+    }
+
+    // Same as `GlobalAlloc::alloc`, but `None` is returned instead of null
+    // when the heap is exhausted
+    pub fn alloc_nonnull(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { self.alloc(layout) })
+    }
+
+    /// Same as `GlobalAlloc::realloc`, but `None` is returned instead of null
+    /// when the block cannot be resized, `ptr` then stays valid.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be allocated by this allocator with `layout` and not yet
+    /// freed.
+    pub unsafe fn realloc_nonnull(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Option<NonNull<u8>> {
+        NonNull::new(self.realloc(ptr.as_ptr(), layout, new_size))
+    }
+
+    // Allocate memory for `n` consecutive values of `T`, `None` is returned
+    // when the size overflows or the heap is exhausted.
+    pub fn alloc_array<T>(&self, n: usize) -> Option<NonNull<T>> {
+        let layout = Layout::array::<T>(n).ok()?;
+        NonNull::new(unsafe { self.alloc(layout) } as *mut T)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be returned by `alloc_array` on this allocator using the
+    /// same `n`.
+    pub unsafe fn dealloc_array<T>(&self, ptr: NonNull<T>, n: usize) {
+        let layout = Layout::array::<T>(n).expect("layout");
+        self.dealloc(ptr.as_ptr() as *mut u8, layout)
+    }
+
+    // Allocate `len` uninitialized values of `T` as a slice which frees
+    // itself, `None` is returned when the size overflows, the alignment
+    // exceeds `FM_MAX_ALIGN` or the heap is exhausted.
+    pub fn alloc_slice<T>(&self, len: usize) -> Option<FixedAllocSlice<'_, MaybeUninit<T>>> {
+        FixedAllocSlice::allocate(self, len, false)
+    }
+
+    // Same as `alloc_slice`, but the values are zero-filled and ready to use
+    pub fn alloc_slice_zeroed<T: Zeroable>(&self, len: usize) -> Option<FixedAllocSlice<'_, T>> {
+        FixedAllocSlice::allocate(self, len, true)
+    }
+}
+
+// Page granularity allocations, memory allocated here can also be freed or
+// realloced via `FixedAlloc`. Like `FixedAlloc`, it is a handle of the global
+// heap and bound to the thread it is used on.
+pub struct LinearAlloc {
+    _marker: ThreadMarker,
+}
+
+impl LinearAlloc {
+    // Whether `ptr` lies within the pages of any memory region available for
+    // allocations, the bookkeeping pages are excluded
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        unsafe { ffi::fm_lm_contains(ptr as *const c_void) != 0 }
+    }
+
+    // Allocate whole pages, alignment larger than a page is also supported.
+    pub fn alloc_aligned(&self, layout: Layout, kind: AllocType) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe {
+            ffi::fm_lm_aligned_malloc(layout.size(), layout.align(), kind.into())
+        } as *mut u8)
+    }
+
+    // Same as `alloc_aligned`, but the memory is always zero-filled
+    pub fn alloc_zeroed(&self, layout: Layout, kind: AllocType) -> Option<NonNull<u8>> {
+        if layout.align() <= ffi::FM_PAGE_SIZE {
+            return NonNull::new(
+                unsafe { ffi::fm_lm_calloc(1, layout.size(), kind.into()) } as *mut u8
+            );
+        }
+        let ptr = self.alloc_aligned(layout, kind)?;
+        unsafe { core::ptr::write_bytes(ptr.as_ptr(), 0, layout.size()) };
+        Some(ptr)
+    }
+
+    // Allocate exactly `n` contiguous pages, returned as a pointer to the
+    // first one.
+    pub fn alloc_pages(
+        &self,
+        n: usize,
+        kind: AllocType,
+    ) -> Option<NonNull<[u8; ffi::FM_PAGE_SIZE]>> {
+        if n == 0 {
+            return None;
+        }
+        let size = n.checked_mul(ffi::FM_PAGE_SIZE)?;
+        NonNull::new(unsafe { ffi::fm_lm_malloc(size, kind.into()) } as *mut [u8; ffi::FM_PAGE_SIZE])
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be returned by `alloc_pages` with the same `n`, and not yet
+    /// freed.
+    pub unsafe fn free_pages(&self, ptr: NonNull<[u8; ffi::FM_PAGE_SIZE]>, n: usize) {
+        debug_assert_eq!(
+            ffi::fm_sm_usable_size(ptr.as_ptr() as *const c_void),
+            n * ffi::FM_PAGE_SIZE
+        );
+        ffi::fm_lm_free(ptr.as_ptr() as *mut c_void)
+    }
+
+    /// Resize an allocation to `new_size` bytes. Pages directly following the
+    /// block are taken when free, otherwise the contents are copied to a new
+    /// block, so `ptr` must not be used afterwards. Alignment larger than a
+    /// page, as given by `old_layout`, is kept. On failure, `ptr` stays valid.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be allocated by linear malloc with `old_layout` and not yet
+    /// freed. `kind` need not match the type it was allocated with, see
+    /// `realloc_with`.
+    pub unsafe fn realloc(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_size: usize,
+        kind: AllocType,
+    ) -> Option<NonNull<u8>> {
+        if old_layout.align() <= ffi::FM_PAGE_SIZE {
+            return self.realloc_with(ptr, new_size, kind);
+        }
+        // Linear malloc only keeps page alignment when moving blocks
+        if new_size <= old_layout.size() {
+            return Some(ptr);
+        }
+        let layout = Layout::from_size_align(new_size, old_layout.align()).ok()?;
+        let new_ptr = self.alloc_aligned(layout, kind)?;
+        core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr(), old_layout.size());
+        self.free(ptr);
+        Some(new_ptr)
+    }
+
+    /// Resize a page aligned allocation to `new_size` bytes, returning a block
+    /// of type `kind`. Types may differ: the block stays in place whenever it
+    /// can be resized there, whatever type it has. Only a block that has to
+    /// move is migrated, to pages taken like `alloc_pages` does for `kind`,
+    /// e.g. from the start of the heap when a persistent block is reallocated
+    /// as transient. On failure, `ptr` stays valid.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be allocated by linear malloc with alignment of at most a
+    /// page, and not yet freed.
+    pub unsafe fn realloc_with(
+        &self,
+        ptr: NonNull<u8>,
+        new_size: usize,
+        kind: AllocType,
+    ) -> Option<NonNull<u8>> {
+        NonNull::new(
+            ffi::fm_lm_realloc(ptr.as_ptr() as *mut c_void, new_size, kind.into()) as *mut u8,
+        )
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be allocated by linear malloc and not yet freed.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        ffi::fm_lm_free(ptr.as_ptr() as *mut c_void)
+    }
+}
+
+#[cfg(feature = "owned-buffer")]
+impl Drop for FixedAlloc {
+    fn drop(&mut self) {
+        if let Some(OwnedBuffer { buffer, layout }) = self.owned.take() {
+            // Unless the heap has been moved to other memory meanwhile, it
+            // must not keep referring to the released buffer
+            let first_page = buffer.as_ptr().wrapping_add(ffi::FM_PAGE_SIZE);
+            if unsafe { ffi::fm_lm_contains(first_page as *const c_void) } != 0 {
+                unsafe { ffi::fm_sm_deinit() };
+                forget_allocations();
+            }
+            unsafe { alloc::alloc::dealloc(buffer.as_ptr(), layout) };
+        }
+    }
+}
+
+impl fmt::Debug for FixedAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats();
+        f.debug_struct("FixedAlloc")
+            .field("total_bytes", &stats.total_bytes)
+            .field("used_bytes", &stats.used_bytes)
+            .field("free_bytes", &stats.free_bytes)
+            .field("total_pages", &stats.total_pages)
+            .field("used_pages", &stats.used_pages)
+            .field("free_pages", &stats.free_pages)
+            .finish()
+    }
+}
+
+// Writes into a byte slice, output beyond its end is dropped
+struct SliceWriter<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.out.len() - self.len);
+        self.out[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
     }
 }
 
+// Tenths of a percent of `part` in `whole`
+fn permille(part: usize, whole: usize) -> usize {
+    if whole == 0 {
+        0
+    } else {
+        (part as u128 * 1000 / whole as u128) as usize
+    }
+}
+
+// Write a one line summary of the heap into `out` without allocating, e.g.
+// for debug output over UART. The summary is cut off when `out` is too
+// short. frag is the share of allocated pages not held by allocations, such
+// as free slots of slabs. Returns the number of bytes written.
+pub fn format_alloc_stats(alloc: &FixedAlloc, out: &mut [u8]) -> usize {
+    let stats = alloc.stats();
+    let page_bytes = stats.used_pages * ffi::FM_PAGE_SIZE;
+    let used = permille(stats.used_bytes, stats.total_bytes);
+    let frag = permille(page_bytes.saturating_sub(stats.used_bytes), page_bytes);
+    let mut writer = SliceWriter { out, len: 0 };
+    let _ = fmt::write(
+        &mut writer,
+        format_args!(
+            "FixedAlloc: {}/{} bytes used ({}.{}%), {} live allocs, frag={}.{}%",
+            stats.used_bytes,
+            stats.total_bytes,
+            used / 10,
+            used % 10,
+            alloc.live_allocations(),
+            frag / 10,
+            frag % 10
+        ),
+    );
+    writer.len
+}
+
 unsafe impl GlobalAlloc for FixedAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        ffi::fm_sm_malloc(layout.size()) as *mut u8
+        debug_assert!(
+            layout.align() <= ffi::FM_MAX_ALIGN,
+            "Requested alignment of {} bytes exceeds FM_MAX_ALIGN of {} bytes!",
+            layout.align(),
+            ffi::FM_MAX_ALIGN
+        );
+        let ptr = match malloc_size(layout) {
+            Some(size) => ffi::fm_sm_malloc(size) as *mut u8,
+            None => core::ptr::null_mut(),
+        };
+        #[cfg(feature = "test-support")]
+        layout_check::record(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if layout.align() <= ffi::FM_MIN_ALIGN {
+            let ptr = ffi::fm_sm_calloc(1, layout.size()) as *mut u8;
+            #[cfg(feature = "test-support")]
+            layout_check::record(ptr, layout.size());
+            return ptr;
+        }
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            core::ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        ffi::fm_sm_free(ptr as *mut c_void)
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "test-support")]
+        if let Some(size) = layout_check::remove(ptr) {
+            assert!(
+                layout.size() <= size,
+                "Deallocating {:p} using layout of {} bytes, but only {} bytes are allocated!",
+                ptr,
+                layout.size(),
+                size
+            );
+        }
+        // Also covers allocations the table has no room for
+        #[cfg(feature = "test-support")]
+        assert!(
+            ffi::fm_sm_free_sized_checked(ptr as *mut c_void, layout.size()) == 0,
+            "Deallocating {:p} using layout of {} bytes, which does not fit the block there!",
+            ptr,
+            layout.size()
+        );
+        #[cfg(not(feature = "test-support"))]
+        {
+            let _ = layout;
+            ffi::fm_sm_free(ptr as *mut c_void);
+        }
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, _layout: Layout, new_size: usize) -> *mut u8 {
-        ffi::fm_sm_realloc(ptr as *mut c_void, new_size) as *mut u8
+        let new_ptr = ffi::fm_sm_realloc(ptr as *mut c_void, new_size) as *mut u8;
+        #[cfg(feature = "test-support")]
+        if !new_ptr.is_null() {
+            layout_check::remove(ptr);
+            layout_check::record(new_ptr, new_size);
+        }
+        new_ptr
+    }
+}
+
+// Shared handles simply delegate to the allocator they refer to
+unsafe impl GlobalAlloc for &FixedAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        (**self).alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        (**self).alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        (**self).dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        (**self).realloc(ptr, layout, new_size)
     }
 }
@@ -0,0 +1,115 @@
+use crate::atomic::{AtomicBool, Ordering};
+use crate::FixedAlloc;
+use core::alloc::{GlobalAlloc, Layout};
+
+// Spinlock taken by every lock wrapper. All `FixedAlloc` handles refer to
+// the same global heap, so one lock per wrapper would still let two wrapper
+// statics enter the C allocator at the same time. The lock is not reentrant.
+static HEAP_LOCK: AtomicBool = AtomicBool::new(false);
+
+struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        HEAP_LOCK.store(false, Ordering::Release);
+    }
+}
+
+// Run `f` with `HEAP_LOCK` held
+pub(crate) fn with_heap_lock<R, F: FnOnce() -> R>(f: F) -> R {
+    while HEAP_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        while HEAP_LOCK.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    }
+    let _guard = Guard;
+    f()
+}
+
+// How a wrapper runs its calls into the C allocator, which must take
+// `HEAP_LOCK` in the end
+pub(crate) trait Section {
+    fn with<R, F: FnOnce() -> R>(&self, f: F) -> R;
+}
+
+// `FixedAlloc` only reached inside section `S`. The public lock wrappers are
+// built on it, so they only differ in how they get to `HEAP_LOCK`.
+pub(crate) struct Locked<S> {
+    alloc: FixedAlloc,
+    section: S,
+}
+
+impl<S: Section> Locked<S> {
+    pub(crate) const fn new(alloc: FixedAlloc, section: S) -> Self {
+        Self { alloc, section }
+    }
+
+    pub(crate) fn with<R, F: FnOnce(&FixedAlloc) -> R>(&self, f: F) -> R {
+        self.section.with(|| f(&self.alloc))
+    }
+}
+
+// The inner allocator is only reached with `HEAP_LOCK` held, no matter from
+// which thread
+unsafe impl<S: Send> Send for Locked<S> {}
+unsafe impl<S: Sync> Sync for Locked<S> {}
+
+unsafe impl<S: Section> GlobalAlloc for Locked<S> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.section.with(|| self.alloc.alloc(layout))
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.section.with(|| self.alloc.alloc_zeroed(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.section.with(|| self.alloc.dealloc(ptr, layout))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.section
+            .with(|| self.alloc.realloc(ptr, layout, new_size))
+    }
+}
+
+// Forward `with` and `GlobalAlloc` of a lock wrapper to its `Locked` field
+// `inner`
+macro_rules! forward_locked {
+    ($wrapper:ty) => {
+        impl $wrapper {
+            // Run `f` while holding the lock, so other `FixedAlloc` APIs can
+            // be used safely as well.
+            pub fn with<R, F: FnOnce(&$crate::FixedAlloc) -> R>(&self, f: F) -> R {
+                self.inner.with(f)
+            }
+        }
+
+        unsafe impl core::alloc::GlobalAlloc for $wrapper {
+            unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+                self.inner.alloc(layout)
+            }
+
+            unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
+                self.inner.alloc_zeroed(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+                self.inner.dealloc(ptr, layout)
+            }
+
+            unsafe fn realloc(
+                &self,
+                ptr: *mut u8,
+                layout: core::alloc::Layout,
+                new_size: usize,
+            ) -> *mut u8 {
+                self.inner.realloc(ptr, layout, new_size)
+            }
+        }
+    };
+}
+pub(crate) use forward_locked;
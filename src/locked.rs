@@ -0,0 +1,86 @@
+use crate::atomic::{AtomicUsize, Ordering};
+use crate::lock::{forward_locked, with_heap_lock, Locked, Section};
+use crate::FixedAlloc;
+
+// Ticket lock, which unlike a plain spinlock grants the lock in the order it
+// is requested, so no core can be starved by the others.
+pub struct TicketLock {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+}
+
+impl TicketLock {
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+        }
+    }
+
+    // Queue up for the lock, which is held once `is_serving` returns true
+    // for the returned ticket.
+    pub fn take_ticket(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn is_serving(&self, ticket: usize) -> bool {
+        self.serving.load(Ordering::Acquire) == ticket
+    }
+
+    pub fn lock(&self) {
+        let ticket = self.take_ticket();
+        while !self.is_serving(ticket) {
+            core::hint::spin_loop();
+        }
+    }
+
+    // Pass the lock to the next ticket, must only be called by the holder
+    pub fn unlock(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl Default for TicketLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Guard<'a> {
+    lock: &'a TicketLock,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+// Cores queue up on the ticket lock before taking the spinlock shared by all
+// wrappers, so among the users of one `LockedFixedAlloc` the heap is still
+// handed out in order.
+impl Section for TicketLock {
+    fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        self.lock();
+        let _guard = Guard { lock: self };
+        with_heap_lock(f)
+    }
+}
+
+// Wraps `FixedAlloc` with a ticket lock for multi-core targets without an
+// OS. The locks are not reentrant, an OOM hook installed via
+// `fm_sm_set_oom_hook` runs with them held, hence it must not allocate
+// through this or any other lock wrapper.
+pub struct LockedFixedAlloc {
+    inner: Locked<TicketLock>,
+}
+
+impl LockedFixedAlloc {
+    pub const fn new(alloc: FixedAlloc) -> Self {
+        Self {
+            inner: Locked::new(alloc, TicketLock::new()),
+        }
+    }
+}
+
+forward_locked!(LockedFixedAlloc);
@@ -0,0 +1,297 @@
+// Rust implementation of the C allocator selected by the pure-rust feature,
+// so programs using fixed-malloc can be checked with Miri. It mirrors
+// linear-malloc.c and slab-malloc.c function by function, keeping the same
+// bookkeeping structures within the managed buffers, and exports the same
+// functions ffi declares for the C library. The contracts of those functions
+// are documented in linear-malloc.h and slab-malloc.h. With the differential
+// feature it is built next to the C allocator instead, keeping its own state,
+// so tests can check that both behave the same.
+#![allow(clippy::missing_safety_doc)]
+
+use core::cell::UnsafeCell;
+use core::ffi::c_int;
+use core::ptr;
+
+mod linear;
+mod slab;
+
+pub use linear::*;
+pub use slab::*;
+
+// Mutable global state of the allocator, the same as static variables in C.
+// Callers serialize all calls, either by running a single thread or via the
+// lock callbacks.
+struct Global<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for Global<T> {}
+
+impl<T> Global<T> {
+    const fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    fn ptr(&self) -> *mut T {
+        self.0.get()
+    }
+}
+
+impl<T: Copy> Global<T> {
+    unsafe fn get(&self) -> T {
+        *self.0.get()
+    }
+
+    unsafe fn set(&self, value: T) {
+        *self.0.get() = value;
+    }
+}
+
+// Intrusive doubly linked list as in c-list.h, a list head links to itself
+// when empty
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CList {
+    next: *mut CList,
+    prev: *mut CList,
+}
+
+impl CList {
+    // Heads in static state are initialized before first use, since they
+    // have to point to themselves
+    const UNLINKED: CList = CList {
+        next: ptr::null_mut(),
+        prev: ptr::null_mut(),
+    };
+}
+
+unsafe fn list_init(what: *mut CList) {
+    (*what).next = what;
+    (*what).prev = what;
+}
+
+unsafe fn list_is_linked(what: *const CList) -> bool {
+    !ptr::eq((*what).next, what)
+}
+
+// Link what in front of at, which links what as the tail when at is the head
+unsafe fn list_link_before(at: *mut CList, what: *mut CList) {
+    let prev = (*at).prev;
+    (*at).prev = what;
+    (*what).next = at;
+    (*what).prev = prev;
+    (*prev).next = what;
+}
+
+// Link what after at, which links what at the front when at is the head
+unsafe fn list_link_after(at: *mut CList, what: *mut CList) {
+    let next = (*at).next;
+    (*next).prev = what;
+    (*what).next = next;
+    (*what).prev = at;
+    (*at).next = what;
+}
+
+unsafe fn list_unlink(what: *mut CList) {
+    if list_is_linked(what) {
+        let prev = (*what).prev;
+        let next = (*what).next;
+        (*next).prev = prev;
+        (*prev).next = next;
+        list_init(what);
+    }
+}
+
+// Shift pointers of list nodes lying within [start, end) by delta, used when
+// the memory holding the nodes is copied elsewhere. head itself must not be
+// moved.
+unsafe fn relocate_list(head: *mut CList, start: usize, end: usize, new_start: *mut u8) {
+    let relocate = |node: *mut CList| -> *mut CList {
+        let p = node as usize;
+        if p >= start && p < end {
+            new_start.wrapping_add(p - start) as *mut CList
+        } else {
+            node
+        }
+    };
+    let mut node = head;
+    loop {
+        (*node).next = relocate((*node).next);
+        (*node).prev = relocate((*node).prev);
+        node = (*node).next;
+        if node == head {
+            break;
+        }
+    }
+}
+
+fn roundup(x: usize, round: usize) -> usize {
+    x.wrapping_add(round - 1) / round * round
+}
+
+fn rounddown(x: usize, round: usize) -> usize {
+    x / round * round
+}
+
+// Move ptr down to a multiple of round, keeping its provenance
+fn align_down<T>(ptr: *mut T, round: usize) -> *mut u8 {
+    (ptr as *mut u8).wrapping_sub(ptr as usize % round)
+}
+
+fn is_page_aligned<T>(ptr: *const T) -> bool {
+    (ptr as usize).is_multiple_of(crate::ffi::FM_PAGE_SIZE)
+}
+
+// Append n bytes to a snapshot, bytes are only copied while they fit, but pos
+// always advances so the needed size is known in the end.
+unsafe fn snapshot_put(out: *mut u8, len: usize, pos: &mut usize, src: *const u8, n: usize) {
+    if *pos <= len && n <= len - *pos {
+        ptr::copy_nonoverlapping(src, out.add(*pos), n);
+    }
+    *pos += n;
+}
+
+// Read n bytes from a snapshot, returns true when the snapshot is too short
+unsafe fn snapshot_get(
+    input: *const u8,
+    len: usize,
+    pos: &mut usize,
+    dst: *mut u8,
+    n: usize,
+) -> bool {
+    if *pos > len || n > len - *pos {
+        return true;
+    }
+    if !dst.is_null() {
+        ptr::copy_nonoverlapping(input.add(*pos), dst, n);
+    }
+    *pos += n;
+    false
+}
+
+// Bytes of value, for copying structures into snapshots
+fn bytes_of<T>(value: *const T) -> *const u8 {
+    value as *const u8
+}
+
+// splitmix64, used by hardening mode to pick pseudo random locations
+#[cfg(feature = "hardening")]
+fn random_next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+// Returns true unless all bytes still hold the fill pattern
+#[cfg(feature = "fill-on-free")]
+unsafe fn check_fill(ptr: *const u8, size: usize) -> bool {
+    core::slice::from_raw_parts(ptr, size)
+        .iter()
+        .any(|b| *b != crate::ffi::FM_FILL_PATTERN)
+}
+
+// Unwinding out of an extern "C" function aborts the process, the same as
+// abort in C
+extern "C" fn abort() -> ! {
+    panic!("fixed-malloc aborted on a corrupted heap");
+}
+
+static LAST_ERROR: Global<c_int> = Global::new(crate::ffi::FM_OK);
+
+unsafe fn set_error(code: c_int) {
+    LAST_ERROR.set(code);
+}
+
+pub unsafe fn fm_abi_version() -> c_int {
+    crate::ffi::FM_ABI_VERSION
+}
+
+pub unsafe fn fm_version() -> u32 {
+    crate::ffi::FM_VERSION
+}
+
+pub unsafe fn fm_features() -> u32 {
+    use crate::ffi::*;
+    [
+        (cfg!(feature = "test-support"), FM_FEATURE_GUARDS),
+        (cfg!(feature = "test-support"), FM_FEATURE_TEST_SUPPORT),
+        (cfg!(feature = "manual-init"), FM_FEATURE_MANUAL_INIT),
+        (cfg!(feature = "hardening"), FM_FEATURE_HARDENING),
+        (cfg!(feature = "fill-on-free"), FM_FEATURE_FILL_ON_FREE),
+        (cfg!(feature = "debug-hook"), FM_FEATURE_DEBUG_CALLBACK),
+        (cfg!(feature = "trap-reentrant"), FM_FEATURE_TRAP_REENTRANT),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .fold(0, |features, (_, bit)| features | bit)
+}
+
+pub unsafe fn fm_last_error() -> c_int {
+    LAST_ERROR.get()
+}
+
+pub unsafe fn fm_clear_error() {
+    LAST_ERROR.set(crate::ffi::FM_OK);
+}
+
+static ERROR_SINK: Global<crate::ffi::FmErrorSink> = Global::new(None);
+static ERROR_SINK_CTX: Global<*mut core::ffi::c_void> = Global::new(ptr::null_mut());
+
+pub unsafe fn fm_lm_set_error_sink(sink: crate::ffi::FmErrorSink, ctx: *mut core::ffi::c_void) {
+    ERROR_SINK.set(sink);
+    ERROR_SINK_CTX.set(ctx);
+}
+
+unsafe fn report_error(code: c_int, message: &'static [u8]) {
+    if let Some(sink) = ERROR_SINK.get() {
+        sink(
+            code,
+            message.as_ptr() as *const core::ffi::c_char,
+            ERROR_SINK_CTX.get(),
+        );
+    }
+}
+
+#[cfg(feature = "debug-hook")]
+static DEBUG_CALLBACK: Global<crate::ffi::FmDebugCallback> = Global::new(None);
+#[cfg(feature = "debug-hook")]
+static DEBUG_CTX: Global<*mut core::ffi::c_void> = Global::new(ptr::null_mut());
+
+#[cfg(feature = "debug-hook")]
+pub unsafe fn fm_set_debug_callback(
+    callback: crate::ffi::FmDebugCallback,
+    ctx: *mut core::ffi::c_void,
+) {
+    DEBUG_CALLBACK.set(callback);
+    DEBUG_CTX.set(ctx);
+}
+
+// Like the C side, the format string is passed to the debug callback as is
+#[cfg(feature = "debug-hook")]
+unsafe fn debug(message: &'static [u8]) {
+    if let Some(callback) = DEBUG_CALLBACK.get() {
+        callback(
+            message.as_ptr() as *const core::ffi::c_char,
+            DEBUG_CTX.get(),
+        );
+    }
+}
+
+// Debug messages use the same format strings as in C, arguments are dropped
+macro_rules! fm_debug {
+    ($message:literal $(, $arg:expr)* $(,)?) => {{
+        #[cfg(feature = "debug-hook")]
+        $crate::rust_impl::debug(concat!($message, "\0").as_bytes());
+    }};
+}
+use fm_debug;
+
+// Internal errors are printed as debug messages, and always passed to the
+// error sink
+macro_rules! fm_report {
+    ($code:expr, $message:literal) => {{
+        fm_debug!($message);
+        $crate::rust_impl::report_error($code, concat!($message, "\0").as_bytes());
+    }};
+}
+use fm_report;
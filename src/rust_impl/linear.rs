@@ -0,0 +1,1429 @@
+// Port of linear-malloc.c
+use super::*;
+use crate::ffi::*;
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr::addr_of_mut;
+
+#[cfg(not(feature = "manual-init"))]
+use crate::STATIC_MEMORY_SIZE;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Region {
+    link: CList,
+    start_page: usize,
+    pages: usize,
+}
+
+// State of a memory region, each page is tracked by one byte of its
+// bookkeeping page starting at meta
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Heap {
+    buffer_start: *mut u8,
+    buffer_size: usize,
+    meta: *mut u8,
+    free_regions: CList,
+    freed_memories: CList,
+}
+
+impl Heap {
+    const EMPTY: Heap = Heap {
+        buffer_start: ptr::null_mut(),
+        buffer_size: 0,
+        meta: ptr::null_mut(),
+        free_regions: CList::UNLINKED,
+        freed_memories: CList::UNLINKED,
+    };
+}
+
+const MAX_EXTRA_REGIONS: usize = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(super) struct LmState {
+    heaps: [Heap; 1 + MAX_EXTRA_REGIONS],
+    heap_count: usize,
+    // 1 when the first region is reserved for fm_lm_malloc_reserved
+    first_region: usize,
+    // Number of allocated blocks, including pages used by slabs
+    live_blocks: usize,
+    // Number of pages held by those blocks
+    used_pages: usize,
+}
+
+impl LmState {
+    const EMPTY: LmState = LmState {
+        heaps: [Heap::EMPTY; 1 + MAX_EXTRA_REGIONS],
+        heap_count: 1,
+        first_region: 0,
+        live_blocks: 0,
+        used_pages: 0,
+    };
+}
+
+#[cfg(not(feature = "manual-init"))]
+include!(concat!(env!("OUT_DIR"), "/static_buffer.rs"));
+
+// Only handed out to the default state, which owns the buffer
+#[cfg(not(feature = "manual-init"))]
+unsafe impl Sync for StaticBuffer {}
+
+static DEFAULT_STATE: Global<LmState> = Global::new(LmState::EMPTY);
+// C initializes the default state at compile time, which takes pointers to
+// itself, so it is set up on first use here instead
+static DEFAULT_READY: Global<bool> = Global::new(false);
+
+unsafe fn default_state() -> *mut LmState {
+    let state = DEFAULT_STATE.ptr();
+    if !DEFAULT_READY.get() {
+        DEFAULT_READY.set(true);
+        let heap = addr_of_mut!((*state).heaps[0]);
+        list_init(addr_of_mut!((*heap).free_regions));
+        list_init(addr_of_mut!((*heap).freed_memories));
+        #[cfg(not(feature = "manual-init"))]
+        init_heap(heap, BUFFER.0.get() as *mut u8, STATIC_MEMORY_SIZE, true);
+    }
+    state
+}
+
+pub(super) fn fm_lm_state_size() -> usize {
+    size_of::<LmState>()
+}
+
+// NULL stands for the default state
+unsafe fn state_of(lm: *mut LmState) -> *mut LmState {
+    if lm.is_null() {
+        default_state()
+    } else {
+        lm
+    }
+}
+
+unsafe fn heap_at(lm: *mut LmState, index: usize) -> *mut Heap {
+    addr_of_mut!((*lm).heaps[index])
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_lm_test_buffer_pointer() -> *mut c_void {
+    let mut start = ptr::null_mut();
+    let mut size = 0;
+    fm_lm_buffer_range(&mut start, &mut size);
+    start
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_lm_test_static_buffer() -> *mut c_void {
+    #[cfg(feature = "manual-init")]
+    return ptr::null_mut();
+    #[cfg(not(feature = "manual-init"))]
+    return BUFFER.0.get() as *mut c_void;
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_lm_test_total_buffer_size() -> usize {
+    let mut start = ptr::null_mut();
+    let mut size = 0;
+    fm_lm_buffer_range(&mut start, &mut size);
+    size
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_lm_test_region(index: usize, buffer: *mut *mut c_void, size: *mut usize) {
+    let heap = heap_at(default_state(), index);
+    *buffer = (*heap).buffer_start as *mut c_void;
+    *size = (*heap).buffer_size;
+}
+
+pub(super) unsafe fn fm_lm_check_buffer(buffer: *mut c_void, size: usize) -> c_int {
+    if buffer.is_null() {
+        fm_report!(FM_ERR_NULL_BUFFER, "Memory buffer must not be NULL!");
+        return FM_ERR_NULL_BUFFER;
+    }
+    if !is_page_aligned(buffer) {
+        fm_report!(
+            FM_ERR_UNALIGNED_BUFFER,
+            "Memory buffer must be aligned at page boundary!"
+        );
+        return FM_ERR_UNALIGNED_BUFFER;
+    }
+    if !size.is_multiple_of(FM_PAGE_SIZE) {
+        fm_report!(
+            FM_ERR_UNALIGNED_SIZE,
+            "Memory size must be aligned to pages!"
+        );
+        return FM_ERR_UNALIGNED_SIZE;
+    }
+    if size < FM_MIN_MEMORY_SIZE {
+        fm_report!(
+            FM_ERR_BUFFER_TOO_SMALL,
+            "Memory size must be at least 2 pages!"
+        );
+        return FM_ERR_BUFFER_TOO_SMALL;
+    }
+    if size > FM_MAX_MEMORY_SIZE {
+        fm_report!(
+            FM_ERR_BUFFER_TOO_LARGE,
+            "Memory size must be less than FM_MAX_MEMORY_SIZE!"
+        );
+        return FM_ERR_BUFFER_TOO_LARGE;
+    }
+    0
+}
+
+unsafe fn init_heap(heap: *mut Heap, buffer: *mut u8, size: usize, zero_filled: bool) {
+    (*heap).buffer_start = buffer;
+    (*heap).buffer_size = size;
+    (*heap).meta = buffer;
+    if !zero_filled {
+        ptr::write_bytes(buffer, 0, FM_PAGE_SIZE);
+    }
+    let region = buffer.wrapping_add(FM_PAGE_SIZE) as *mut Region;
+    (*region).start_page = 1;
+    (*region).pages = size / FM_PAGE_SIZE - 1;
+    list_init(addr_of_mut!((*heap).free_regions));
+    list_link_after(
+        addr_of_mut!((*heap).free_regions),
+        addr_of_mut!((*region).link),
+    );
+    list_init(addr_of_mut!((*heap).freed_memories));
+}
+
+pub(super) unsafe fn fm_lm_state_reinit(
+    lm: *mut LmState,
+    buffer: *mut c_void,
+    size: usize,
+    zero_filled: c_int,
+) -> c_int {
+    let ret = fm_lm_check_buffer(buffer, size);
+    if ret != 0 {
+        return ret;
+    }
+    let lm = state_of(lm);
+    (*lm).live_blocks = 0;
+    (*lm).used_pages = 0;
+    // Extra regions are dropped as well
+    (*lm).heap_count = 1;
+    (*lm).first_region = 0;
+    init_heap(heap_at(lm, 0), buffer as *mut u8, size, zero_filled != 0);
+    0
+}
+
+pub unsafe fn fm_lm_reinit(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    fm_lm_state_reinit(ptr::null_mut(), buffer, size, zero_filled)
+}
+
+pub unsafe fn fm_lm_deinit() {
+    let lm = default_state();
+    (*lm).live_blocks = 0;
+    (*lm).used_pages = 0;
+    (*lm).heap_count = 1;
+    (*lm).first_region = 0;
+    let heap = heap_at(lm, 0);
+    (*heap).buffer_start = ptr::null_mut();
+    (*heap).buffer_size = 0;
+    (*heap).meta = ptr::null_mut();
+    list_init(addr_of_mut!((*heap).free_regions));
+    list_init(addr_of_mut!((*heap).freed_memories));
+}
+
+// Returns true if [start, start + size) overlaps any region other than skipped
+unsafe fn overlaps_heaps(
+    lm: *mut LmState,
+    start: usize,
+    size: usize,
+    skipped: *const Heap,
+) -> bool {
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        let heap_start = (*heap).buffer_start as usize;
+        if !ptr::eq(heap, skipped)
+            && start < heap_start.wrapping_add((*heap).buffer_size)
+            && heap_start < start.wrapping_add(size)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+pub unsafe fn fm_lm_add_region(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    let lm = default_state();
+    if (*heap_at(lm, 0)).buffer_start.is_null() {
+        return FM_ERR_NOT_INITIALIZED;
+    }
+    let ret = fm_lm_check_buffer(buffer, size);
+    if ret != 0 {
+        return ret;
+    }
+    if (*lm).heap_count > MAX_EXTRA_REGIONS {
+        fm_report!(FM_ERR_TOO_MANY_REGIONS, "Too many memory regions!");
+        return FM_ERR_TOO_MANY_REGIONS;
+    }
+    if overlaps_heaps(lm, buffer as usize, size, ptr::null()) {
+        fm_report!(
+            FM_ERR_BUFFER_OVERLAP,
+            "Memory region must not overlap existing ones!"
+        );
+        return FM_ERR_BUFFER_OVERLAP;
+    }
+    init_heap(
+        heap_at(lm, (*lm).heap_count),
+        buffer as *mut u8,
+        size,
+        zero_filled != 0,
+    );
+    (*lm).heap_count += 1;
+    0
+}
+
+pub unsafe fn fm_lm_reinit_split(
+    reserved_buffer: *mut c_void,
+    reserved_size: usize,
+    buffer: *mut c_void,
+    size: usize,
+    zero_filled: c_int,
+) -> c_int {
+    // Validate the second buffer first so reinit is never half done
+    let ret = fm_lm_check_buffer(buffer, size);
+    if ret != 0 {
+        return ret;
+    }
+    let (reserved_start, start) = (reserved_buffer as usize, buffer as usize);
+    if reserved_start < start.wrapping_add(size)
+        && start < reserved_start.wrapping_add(reserved_size)
+    {
+        fm_report!(
+            FM_ERR_BUFFER_OVERLAP,
+            "Memory region must not overlap existing ones!"
+        );
+        return FM_ERR_BUFFER_OVERLAP;
+    }
+    let ret = fm_lm_reinit(reserved_buffer, reserved_size, zero_filled);
+    if ret != 0 {
+        return ret;
+    }
+    fm_lm_add_region(buffer, size, zero_filled);
+    (*default_state()).first_region = 1;
+    0
+}
+
+// Find the region containing ptr, the first region is picked when none does
+unsafe fn heap_of<T>(lm: *mut LmState, ptr: *const T) -> *mut Heap {
+    let p = ptr as usize;
+    for i in 1..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        let start = (*heap).buffer_start as usize;
+        if p >= start && p < start + (*heap).buffer_size {
+            return heap;
+        }
+    }
+    heap_at(lm, 0)
+}
+
+pub unsafe fn fm_lm_reinit_swap(
+    new_buffer: *mut c_void,
+    new_size: usize,
+    zero_filled: c_int,
+    old_buffer: *mut *mut c_void,
+    old_size: *mut usize,
+) -> c_int {
+    let heap = heap_at(default_state(), 0);
+    *old_buffer = (*heap).buffer_start as *mut c_void;
+    *old_size = (*heap).buffer_size;
+    fm_lm_reinit(new_buffer, new_size, zero_filled)
+}
+
+unsafe fn mark_alloced_pages(heap: *mut Heap, first_page: usize, pages: usize) {
+    let meta = (*heap).meta;
+    if pages < 0xFF {
+        *meta.add(first_page) = pages as u8;
+    } else {
+        *meta.add(first_page) = 0xFF;
+        let aligned_page = roundup(first_page + 1, 4);
+        *(meta.add(aligned_page) as *mut u32) = pages as u32;
+    }
+}
+
+unsafe fn fetch_alloced_pages(heap: *const Heap, first_page: usize) -> usize {
+    let meta = (*heap).meta;
+    let pages = *meta.add(first_page);
+    if pages < 0xFF {
+        return pages as usize;
+    }
+    let aligned_page = roundup(first_page + 1, 4);
+    *(meta.add(aligned_page) as *const u32) as usize
+}
+
+unsafe fn ptr_to_page<T>(heap: *const Heap, ptr: *const T) -> usize {
+    (ptr as usize).wrapping_sub((*heap).buffer_start as usize) / FM_PAGE_SIZE
+}
+
+unsafe fn page_to_ptr(heap: *const Heap, page: usize) -> *mut u8 {
+    (*heap).buffer_start.wrapping_add(page * FM_PAGE_SIZE)
+}
+
+unsafe fn move_region(heap: *const Heap, src: *mut Region) -> *mut Region {
+    let dst = page_to_ptr(heap, (*src).start_page) as *mut Region;
+    if dst == src {
+        return dst;
+    }
+
+    ptr::copy(src, dst, 1);
+    (*(*dst).link.next).prev = addr_of_mut!((*dst).link);
+    (*(*dst).link.prev).next = addr_of_mut!((*dst).link);
+
+    dst
+}
+
+unsafe fn count_pages(list: *mut CList) -> usize {
+    let mut pages = 0;
+    let mut iter = (*list).next;
+    while iter != list {
+        pages += (*(iter as *mut Region)).pages;
+        iter = (*iter).next;
+    }
+    pages
+}
+
+pub unsafe fn fm_lm_stats(total_pages: *mut usize, free_pages: *mut usize) {
+    *total_pages = 0;
+    *free_pages = 0;
+    let lm = default_state();
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        if (*heap).buffer_size > 0 {
+            // The first page is set aside for accounting purposes
+            *total_pages += (*heap).buffer_size / FM_PAGE_SIZE - 1;
+        }
+        *free_pages += count_pages(addr_of_mut!((*heap).free_regions))
+            + count_pages(addr_of_mut!((*heap).freed_memories));
+    }
+}
+
+// The region header used by freed memory is kept intact
+#[cfg(feature = "fill-on-free")]
+unsafe fn fill(heap: *const Heap, ptr: *mut c_void) {
+    let pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+    ptr::write_bytes(
+        (ptr as *mut u8).add(size_of::<Region>()),
+        FM_FILL_PATTERN,
+        pages * FM_PAGE_SIZE - size_of::<Region>(),
+    );
+}
+
+#[cfg(feature = "fill-on-free")]
+unsafe fn heap_check_fill(heap: *const Heap, ptr: *mut c_void) -> bool {
+    let pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+    check_fill(
+        (ptr as *const u8).add(size_of::<Region>()),
+        pages * FM_PAGE_SIZE - size_of::<Region>(),
+    )
+}
+
+#[cfg(feature = "fill-on-free")]
+pub unsafe fn fm_lm_fill(ptr: *mut c_void) {
+    fill(heap_of(default_state(), ptr), ptr);
+}
+
+#[cfg(feature = "fill-on-free")]
+pub unsafe fn fm_lm_check_fill(ptr: *mut c_void) -> c_int {
+    heap_check_fill(heap_of(default_state(), ptr), ptr) as c_int
+}
+
+// Number of pages in the free region starting at page, or 0 if there is none
+unsafe fn free_pages_at(heap: *mut Heap, page: usize) -> usize {
+    let lists = [
+        addr_of_mut!((*heap).free_regions),
+        addr_of_mut!((*heap).freed_memories),
+    ];
+    for list in lists {
+        let mut iter = (*list).next;
+        while iter != list {
+            let region = iter as *mut Region;
+            if (*region).start_page == page {
+                return (*region).pages;
+            }
+            iter = (*iter).next;
+        }
+    }
+    0
+}
+
+pub unsafe fn fm_lm_walk(callback: FmWalkCallback, user: *mut c_void) {
+    let lm = default_state();
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        let total_pages = (*heap).buffer_size / FM_PAGE_SIZE;
+        let mut page = 1;
+        while page < total_pages {
+            let mut pages = free_pages_at(heap, page);
+            if pages == 0 {
+                pages = fetch_alloced_pages(heap, page);
+                if pages == 0 {
+                    fm_report!(FM_ERR_CORRUPTED, "Page is neither free nor allocated!");
+                    abort();
+                }
+                callback(
+                    page_to_ptr(heap, page) as *mut c_void,
+                    pages * FM_PAGE_SIZE,
+                    user,
+                );
+            }
+            page += pages;
+        }
+    }
+}
+
+pub(super) unsafe fn report<T>(error: *mut FmHeapError, kind: c_int, address: *const T) -> c_int {
+    if !error.is_null() {
+        (*error).kind = kind;
+        (*error).address = address as *mut c_void;
+    }
+    kind
+}
+
+// Free lists are bounded by the number of pages in the region
+unsafe fn verify_list(
+    heap: *const Heap,
+    list: *mut CList,
+    total_pages: usize,
+    error: *mut FmHeapError,
+) -> c_int {
+    let mut steps = 0;
+    let mut iter = (*list).next;
+    while iter != list {
+        steps += 1;
+        if (*(*iter).next).prev != iter || steps >= total_pages {
+            return report(error, FM_HEAP_FREE_LIST_CYCLE, iter);
+        }
+        let region = iter as *mut Region;
+        let misplaced = region as *mut u8 != page_to_ptr(heap, (*region).start_page);
+        if (*region).start_page == 0
+            || (*region).pages == 0
+            || (*region).start_page + (*region).pages > total_pages
+            || misplaced
+        {
+            return report(error, FM_HEAP_CORRUPTED_HEADER, region);
+        }
+        iter = (*iter).next;
+    }
+    FM_HEAP_OK
+}
+
+pub unsafe fn fm_lm_verify(error: *mut FmHeapError) -> c_int {
+    let lm = default_state();
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        let total_pages = (*heap).buffer_size / FM_PAGE_SIZE;
+        // Lists are walked below, so they must be checked first
+        let mut ret = verify_list(heap, addr_of_mut!((*heap).free_regions), total_pages, error);
+        if ret == FM_HEAP_OK {
+            ret = verify_list(
+                heap,
+                addr_of_mut!((*heap).freed_memories),
+                total_pages,
+                error,
+            );
+        }
+        if ret != FM_HEAP_OK {
+            return ret;
+        }
+        let mut page = 1;
+        while page < total_pages {
+            let mut pages = free_pages_at(heap, page);
+            if pages == 0 {
+                pages = fetch_alloced_pages(heap, page);
+            }
+            if pages == 0 || pages > total_pages - page {
+                return report(error, FM_HEAP_CORRUPTED_HEADER, page_to_ptr(heap, page));
+            }
+            page += pages;
+        }
+    }
+    FM_HEAP_OK
+}
+
+pub(super) unsafe fn fm_lm_state_block_size(lm: *mut LmState, ptr: *const c_void) -> usize {
+    let lm = state_of(lm);
+    if fm_lm_state_contains(lm, ptr) == 0 || !is_page_aligned(ptr) {
+        return 0;
+    }
+    // Page counts are left behind when blocks are freed, so the pages have to
+    // be walked from the start to tell whether ptr is a live block.
+    let heap = heap_of(lm, ptr);
+    let target = ptr_to_page(heap, ptr);
+    let mut page = 1;
+    while page <= target {
+        let mut pages = free_pages_at(heap, page);
+        if pages > 0 {
+            page += pages;
+            continue;
+        }
+        pages = fetch_alloced_pages(heap, page);
+        if pages == 0 {
+            return 0;
+        }
+        if page == target {
+            return pages * FM_PAGE_SIZE;
+        }
+        page += pages;
+    }
+    0
+}
+
+pub unsafe fn fm_lm_block_size(ptr: *const c_void) -> usize {
+    fm_lm_state_block_size(ptr::null_mut(), ptr)
+}
+
+pub unsafe fn fm_lm_live_blocks() -> usize {
+    (*default_state()).live_blocks
+}
+
+pub unsafe fn fm_lm_used_pages() -> usize {
+    (*default_state()).used_pages
+}
+
+pub unsafe fn fm_lm_regions() -> usize {
+    (*default_state()).heap_count
+}
+
+pub unsafe fn fm_lm_buffer_range(start: *mut *mut c_void, size: *mut usize) {
+    let heap = heap_at(default_state(), 0);
+    *start = (*heap).buffer_start as *mut c_void;
+    *size = (*heap).buffer_size;
+}
+
+pub(super) unsafe fn fm_lm_state_contains(lm: *mut LmState, ptr: *const c_void) -> c_int {
+    let lm = state_of(lm);
+    let p = ptr as usize;
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        let start = (*heap).buffer_start as usize;
+        if !(*heap).buffer_start.is_null()
+            && p >= start + FM_PAGE_SIZE
+            && p < start + (*heap).buffer_size
+        {
+            return 1;
+        }
+    }
+    0
+}
+
+pub unsafe fn fm_lm_contains(ptr: *const c_void) -> c_int {
+    fm_lm_state_contains(ptr::null_mut(), ptr)
+}
+
+static DETERMINISTIC: Global<bool> = Global::new(false);
+
+pub(super) unsafe fn fm_lm_state_free(lm: *mut LmState, ptr: *mut c_void) {
+    #[cfg(feature = "test-support")]
+    if !is_page_aligned(ptr) {
+        fm_report!(
+            FM_ERR_BAD_POINTER,
+            "Pointer passed to free is not aligned, which might be tampered with!"
+        );
+        abort();
+    }
+    let lm = state_of(lm);
+    let heap = heap_of(lm, ptr);
+    let first_page = ptr_to_page(heap, ptr);
+    let pages = fetch_alloced_pages(heap, first_page);
+    #[cfg(feature = "fill-on-free")]
+    fill(heap, ptr);
+    // Checked before the pages are merged into the free ones
+    #[cfg(all(feature = "fill-on-free", feature = "test-support"))]
+    if heap_check_fill(heap, ptr) {
+        fm_report!(FM_ERR_CORRUPTED, "Memory is not filled after being freed!");
+        abort();
+    }
+    let region = ptr as *mut Region;
+    (*region).start_page = first_page;
+    (*region).pages = pages;
+    if DETERMINISTIC.get() {
+        // Merging right away leaves no trace of the order blocks are freed in
+        restore_freed_region(heap, region);
+    } else {
+        list_link_before(
+            addr_of_mut!((*heap).freed_memories),
+            addr_of_mut!((*region).link),
+        );
+    }
+    (*lm).live_blocks -= 1;
+    (*lm).used_pages -= pages;
+}
+
+pub unsafe fn fm_lm_free(ptr: *mut c_void) {
+    fm_lm_state_free(ptr::null_mut(), ptr);
+}
+
+unsafe fn alloc_designated_free_pages(
+    heap: *mut Heap,
+    start_page: usize,
+    requested_pages: usize,
+) -> usize {
+    let head = addr_of_mut!((*heap).free_regions);
+    let mut iter = (*head).next;
+    while iter != head {
+        let region = iter as *mut Region;
+        if (*region).start_page == start_page && (*region).pages >= requested_pages {
+            return take_front_pages(heap, region, requested_pages);
+        }
+        iter = (*iter).next;
+    }
+    0
+}
+
+unsafe fn take_front_pages(
+    heap: *const Heap,
+    region: *mut Region,
+    requested_pages: usize,
+) -> usize {
+    let result = (*region).start_page;
+    (*region).start_page += requested_pages;
+    (*region).pages -= requested_pages;
+    if (*region).pages == 0 {
+        list_unlink(addr_of_mut!((*region).link));
+    } else {
+        // we need to move region to a new location, since the old location
+        // has been allocated
+        move_region(heap, region);
+    }
+    result
+}
+
+unsafe fn take_back_pages(region: *mut Region, requested_pages: usize) -> usize {
+    // The first page is untouched, there is no need to move region struct.
+    let result = (*region).start_page + (*region).pages - requested_pages;
+    (*region).pages -= requested_pages;
+    if (*region).pages == 0 {
+        list_unlink(addr_of_mut!((*region).link));
+    }
+    result
+}
+
+#[cfg(feature = "hardening")]
+static RANDOM_ENABLED: Global<bool> = Global::new(false);
+#[cfg(feature = "hardening")]
+static RANDOM_STATE: Global<u64> = Global::new(0);
+
+#[cfg(feature = "hardening")]
+pub unsafe fn fm_lm_set_random_seed(seed: u64) {
+    RANDOM_ENABLED.set(true);
+    RANDOM_STATE.set(seed);
+}
+
+#[cfg(feature = "hardening")]
+unsafe fn random_placement() -> bool {
+    RANDOM_ENABLED.get() && !DETERMINISTIC.get()
+}
+
+// Reservoir sampling picks one of all fitting regions with equal possibility
+// in a single pass.
+#[cfg(feature = "hardening")]
+unsafe fn pick_random_region(heap: *mut Heap, requested_pages: usize) -> *mut Region {
+    let mut picked = ptr::null_mut();
+    let mut fitting = 0u64;
+    let head = addr_of_mut!((*heap).free_regions);
+    let mut iter = (*head).next;
+    while iter != head {
+        let region = iter as *mut Region;
+        if (*region).pages >= requested_pages {
+            fitting += 1;
+            if random_next(&mut *RANDOM_STATE.ptr()).is_multiple_of(fitting) {
+                picked = region;
+            }
+        }
+        iter = (*iter).next;
+    }
+    picked
+}
+
+unsafe fn alloc_free_pages(heap: *mut Heap, requested_pages: usize) -> usize {
+    #[cfg(feature = "hardening")]
+    if random_placement() {
+        let region = pick_random_region(heap, requested_pages);
+        return if region.is_null() {
+            0
+        } else {
+            take_front_pages(heap, region, requested_pages)
+        };
+    }
+    let head = addr_of_mut!((*heap).free_regions);
+    let mut iter = (*head).next;
+    while iter != head {
+        let region = iter as *mut Region;
+        if (*region).pages >= requested_pages {
+            return take_front_pages(heap, region, requested_pages);
+        }
+        iter = (*iter).next;
+    }
+    0
+}
+
+pub(super) unsafe fn fm_lm_state_realloc_in_place(
+    lm: *mut LmState,
+    ptr: *mut c_void,
+    size: usize,
+) -> *mut c_void {
+    #[cfg(feature = "test-support")]
+    if !is_page_aligned(ptr) {
+        fm_report!(
+            FM_ERR_BAD_POINTER,
+            "Pointer passed to free is not aligned, which might be tampered with!"
+        );
+        abort();
+    }
+    let lm = state_of(lm);
+    let heap = heap_of(lm, ptr);
+    // Rounding huge sizes up would wrap around to 0 pages
+    if size > (*heap).buffer_size.wrapping_sub(FM_PAGE_SIZE) {
+        return ptr::null_mut();
+    }
+    let new_pages = roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+    let first_page = ptr_to_page(heap, ptr);
+    let pages = fetch_alloced_pages(heap, first_page);
+    if new_pages <= pages {
+        return ptr;
+    }
+    let succeeding_pages = alloc_designated_free_pages(heap, first_page + pages, new_pages - pages);
+    if succeeding_pages != 0 {
+        // If there are enough free pages that are immediately after current
+        // allocated memory, we won't need to move the pages elsewhere
+        mark_alloced_pages(heap, first_page, new_pages);
+        (*lm).used_pages += new_pages - pages;
+        return ptr;
+    }
+    ptr::null_mut()
+}
+
+pub unsafe fn fm_lm_realloc_in_place(ptr: *mut c_void, size: usize) -> *mut c_void {
+    fm_lm_state_realloc_in_place(ptr::null_mut(), ptr, size)
+}
+
+pub(super) unsafe fn fm_lm_state_shrink_release(
+    lm: *mut LmState,
+    ptr: *mut c_void,
+    size: usize,
+) -> *mut c_void {
+    #[cfg(feature = "test-support")]
+    if !is_page_aligned(ptr) {
+        fm_report!(
+            FM_ERR_BAD_POINTER,
+            "Pointer passed to free is not aligned, which might be tampered with!"
+        );
+        abort();
+    }
+    let lm = state_of(lm);
+    let heap = heap_of(lm, ptr);
+    let first_page = ptr_to_page(heap, ptr);
+    let pages = fetch_alloced_pages(heap, first_page);
+    if size > pages * FM_PAGE_SIZE {
+        return ptr::null_mut();
+    }
+    // At least one page is kept
+    let new_pages = (roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE).max(1);
+    if new_pages < pages {
+        // Split the tail off as a block of its own, which is then freed like
+        // any other block
+        mark_alloced_pages(heap, first_page, new_pages);
+        mark_alloced_pages(heap, first_page + new_pages, pages - new_pages);
+        (*lm).live_blocks += 1;
+        fm_lm_state_free(lm, page_to_ptr(heap, first_page + new_pages) as *mut c_void);
+    }
+    ptr
+}
+
+pub(super) unsafe fn fm_lm_state_realloc(
+    lm: *mut LmState,
+    ptr: *mut c_void,
+    size: usize,
+    t: c_int,
+) -> *mut c_void {
+    if ptr.is_null() {
+        return fm_lm_state_malloc(lm, size, t);
+    }
+    if !fm_lm_state_realloc_in_place(lm, ptr, size).is_null() {
+        return ptr;
+    }
+    let heap = heap_of(state_of(lm), ptr);
+    let pages = fetch_alloced_pages(heap, ptr_to_page(heap, ptr));
+    let p = fm_lm_state_malloc(lm, size, t);
+    if !p.is_null() {
+        ptr::copy_nonoverlapping(ptr as *const u8, p as *mut u8, pages * FM_PAGE_SIZE);
+        fm_lm_state_free(lm, ptr);
+    }
+    p
+}
+
+pub unsafe fn fm_lm_realloc(ptr: *mut c_void, size: usize, t: c_int) -> *mut c_void {
+    fm_lm_state_realloc(ptr::null_mut(), ptr, size, t)
+}
+
+unsafe fn alloc_free_pages_reverse(heap: *mut Heap, requested_pages: usize) -> usize {
+    #[cfg(feature = "hardening")]
+    if random_placement() {
+        let region = pick_random_region(heap, requested_pages);
+        return if region.is_null() {
+            0
+        } else {
+            take_back_pages(region, requested_pages)
+        };
+    }
+    let head = addr_of_mut!((*heap).free_regions);
+    let mut iter = (*head).prev;
+    while iter != head {
+        let region = iter as *mut Region;
+        if (*region).pages >= requested_pages {
+            return take_back_pages(region, requested_pages);
+        }
+        iter = (*iter).prev;
+    }
+    0
+}
+
+unsafe fn merged_consecutive_pages(heap: *mut Heap) {
+    let head = addr_of_mut!((*heap).free_regions);
+    let mut prev_item = (*head).next;
+    let mut current_item = (*prev_item).next;
+    while prev_item != head && current_item != head {
+        let prev_region = prev_item as *mut Region;
+        let current_region = current_item as *mut Region;
+
+        if (*prev_region).start_page + (*prev_region).pages == (*current_region).start_page {
+            (*prev_region).pages += (*current_region).pages;
+            list_unlink(current_item);
+            current_item = (*prev_item).next;
+        } else {
+            prev_item = current_item;
+            current_item = (*current_item).next;
+        }
+    }
+}
+
+unsafe fn restore_freed_region(heap: *mut Heap, free_region: *mut Region) {
+    let head = addr_of_mut!((*heap).free_regions);
+    let mut prev_item = head;
+    let mut iter = (*head).next;
+    while iter != head {
+        let region = iter as *mut Region;
+        if (*free_region).start_page < (*region).start_page {
+            // Insert pages between prev_item and iter
+            let mut inserted = false;
+            if prev_item != head {
+                let prev_region = prev_item as *mut Region;
+                if (*prev_region).start_page + (*prev_region).pages == (*free_region).start_page {
+                    // Attach free pages to the previous item
+                    (*prev_region).pages += (*free_region).pages;
+                    inserted = true;
+                }
+            }
+            if !inserted && (*free_region).start_page + (*free_region).pages == (*region).start_page
+            {
+                (*region).start_page = (*free_region).start_page;
+                (*region).pages += (*free_region).pages;
+                // Attach free pages to current item, we need to move region
+                // here
+                move_region(heap, region);
+                inserted = true;
+            }
+            if inserted {
+                merged_consecutive_pages(heap);
+            } else {
+                // Attach free pages as a new region
+                list_link_before(iter, addr_of_mut!((*free_region).link));
+            }
+            return;
+        }
+        prev_item = iter;
+        iter = (*iter).next;
+    }
+    // Insert pages at the very end of the page. Notice at this stage,
+    // prev_item contains the last item(if available)
+    list_link_before(head, addr_of_mut!((*free_region).link));
+    merged_consecutive_pages(heap);
+}
+
+unsafe fn restore_all_freed_memories(heap: *mut Heap) {
+    let head = addr_of_mut!((*heap).freed_memories);
+    let mut iter = (*head).next;
+    while iter != head {
+        let region = iter as *mut Region;
+        iter = (*iter).next;
+        restore_freed_region(heap, region);
+    }
+    list_init(head);
+}
+
+pub(super) unsafe fn fm_lm_set_deterministic(enabled: c_int) {
+    DETERMINISTIC.set(enabled != 0);
+    if enabled != 0 {
+        let lm = default_state();
+        for i in 0..(*lm).heap_count {
+            restore_all_freed_memories(heap_at(lm, i));
+        }
+    }
+}
+
+// Find the first(or last when reverse is set) page in region, that is aligned
+// on align bytes and followed by enough pages. 0 is returned if none exists.
+unsafe fn find_aligned_page(
+    heap: *const Heap,
+    region: *const Region,
+    pages: usize,
+    align: usize,
+    reverse: bool,
+) -> usize {
+    if (*region).pages < pages {
+        return 0;
+    }
+    let first = page_to_ptr(heap, (*region).start_page) as usize;
+    let last = page_to_ptr(heap, (*region).start_page + (*region).pages - pages) as usize;
+    let p = if reverse {
+        rounddown(last, align)
+    } else {
+        roundup(first, align)
+    };
+    if p < first || p > last {
+        return 0;
+    }
+    ptr_to_page(heap, p as *const u8)
+}
+
+// Take pages from the middle of a region, the region might be split into two.
+unsafe fn take_middle_pages(
+    heap: *const Heap,
+    region: *mut Region,
+    page: usize,
+    requested_pages: usize,
+) -> usize {
+    if page == (*region).start_page {
+        return take_front_pages(heap, region, requested_pages);
+    }
+    let tail_pages = (*region).start_page + (*region).pages - page - requested_pages;
+    (*region).pages = page - (*region).start_page;
+    if tail_pages > 0 {
+        let tail = page_to_ptr(heap, page + requested_pages) as *mut Region;
+        (*tail).start_page = page + requested_pages;
+        (*tail).pages = tail_pages;
+        list_link_after(addr_of_mut!((*region).link), addr_of_mut!((*tail).link));
+    }
+    page
+}
+
+unsafe fn alloc_aligned(heap: *mut Heap, pages: usize, align: usize, t: c_int) -> usize {
+    let head = addr_of_mut!((*heap).free_regions);
+    let reverse = t != FM_LM_T_TRANSIENT;
+    let mut iter = if reverse { (*head).prev } else { (*head).next };
+    while iter != head {
+        let region = iter as *mut Region;
+        let page = find_aligned_page(heap, region, pages, align, reverse);
+        if page != 0 {
+            return take_middle_pages(heap, region, page, pages);
+        }
+        iter = if reverse { (*iter).prev } else { (*iter).next };
+    }
+    0
+}
+
+unsafe fn alloc(heap: *mut Heap, pages: usize, t: c_int) -> usize {
+    if t == FM_LM_T_TRANSIENT {
+        alloc_free_pages(heap, pages)
+    } else {
+        alloc_free_pages_reverse(heap, pages)
+    }
+}
+
+// Allocate from regions [first, end), which are tried in the order they are
+// added
+unsafe fn malloc_in(
+    lm: *mut LmState,
+    first: usize,
+    end: usize,
+    size: usize,
+    t: c_int,
+) -> *mut c_void {
+    if (*heap_at(lm, 0)).buffer_start.is_null() {
+        set_error(FM_ERR_NOT_INITIALIZED);
+        return ptr::null_mut();
+    }
+    let mut too_large = true;
+    for i in first..end {
+        let heap = heap_at(lm, i);
+        // This also prevents overflows when rounding up
+        if size > (*heap).buffer_size - FM_PAGE_SIZE {
+            continue;
+        }
+        too_large = false;
+        let pages = roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE;
+
+        let mut page = alloc(heap, pages, t);
+        if page == 0 {
+            restore_all_freed_memories(heap);
+            page = alloc(heap, pages, t);
+        }
+        if page != 0 {
+            mark_alloced_pages(heap, page, pages);
+            (*lm).live_blocks += 1;
+            (*lm).used_pages += pages;
+            return page_to_ptr(heap, page) as *mut c_void;
+        }
+    }
+    set_error(if too_large {
+        FM_ERR_TOO_LARGE
+    } else {
+        FM_ERR_NO_MEMORY
+    });
+    ptr::null_mut()
+}
+
+pub(super) unsafe fn fm_lm_state_malloc(lm: *mut LmState, size: usize, t: c_int) -> *mut c_void {
+    let lm = state_of(lm);
+    malloc_in(lm, (*lm).first_region, (*lm).heap_count, size, t)
+}
+
+pub(super) unsafe fn fm_lm_state_malloc_reserved(
+    lm: *mut LmState,
+    size: usize,
+    t: c_int,
+) -> *mut c_void {
+    let lm = state_of(lm);
+    if (*lm).first_region == 0 {
+        return fm_lm_state_malloc(lm, size, t);
+    }
+    malloc_in(lm, 0, 1, size, t)
+}
+
+pub unsafe fn fm_lm_malloc(size: usize, t: c_int) -> *mut c_void {
+    fm_lm_state_malloc(ptr::null_mut(), size, t)
+}
+
+pub unsafe fn fm_lm_malloc_reserved(size: usize, t: c_int) -> *mut c_void {
+    fm_lm_state_malloc_reserved(ptr::null_mut(), size, t)
+}
+
+unsafe fn max_alloc_size_in(lm: *mut LmState, first: usize, end: usize) -> usize {
+    if (*heap_at(lm, 0)).buffer_start.is_null() {
+        return 0;
+    }
+    let mut max_pages = 0;
+    for i in first..end {
+        let heap = heap_at(lm, i);
+        // A failing allocation would merge freed memory before giving up
+        restore_all_freed_memories(heap);
+        let head = addr_of_mut!((*heap).free_regions);
+        let mut iter = (*head).next;
+        while iter != head {
+            max_pages = max_pages.max((*(iter as *mut Region)).pages);
+            iter = (*iter).next;
+        }
+    }
+    max_pages * FM_PAGE_SIZE
+}
+
+pub unsafe fn fm_lm_max_alloc_size() -> usize {
+    let lm = default_state();
+    max_alloc_size_in(lm, (*lm).first_region, (*lm).heap_count)
+}
+
+pub unsafe fn fm_lm_max_alloc_size_reserved() -> usize {
+    let lm = default_state();
+    if (*lm).first_region == 0 {
+        return fm_lm_max_alloc_size();
+    }
+    max_alloc_size_in(lm, 0, 1)
+}
+
+pub unsafe fn fm_lm_aligned_malloc(size: usize, align: usize, t: c_int) -> *mut c_void {
+    if !align.is_power_of_two() {
+        set_error(FM_ERR_BAD_ALIGNMENT);
+        return ptr::null_mut();
+    }
+    // All pages are aligned on page boundary already
+    if align <= FM_PAGE_SIZE {
+        return fm_lm_malloc(size, t);
+    }
+    let lm = default_state();
+    if (*heap_at(lm, 0)).buffer_start.is_null() {
+        set_error(FM_ERR_NOT_INITIALIZED);
+        return ptr::null_mut();
+    }
+    let mut too_large = true;
+    for i in (*lm).first_region..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        let buffer_size = (*heap).buffer_size;
+        if size > buffer_size - FM_PAGE_SIZE || align > buffer_size {
+            continue;
+        }
+        too_large = false;
+        let pages = (roundup(size, FM_PAGE_SIZE) / FM_PAGE_SIZE).max(1);
+
+        let mut page = alloc_aligned(heap, pages, align, t);
+        if page == 0 {
+            restore_all_freed_memories(heap);
+            page = alloc_aligned(heap, pages, align, t);
+        }
+        if page != 0 {
+            mark_alloced_pages(heap, page, pages);
+            (*lm).live_blocks += 1;
+            (*lm).used_pages += pages;
+            return page_to_ptr(heap, page) as *mut c_void;
+        }
+    }
+    set_error(if too_large {
+        FM_ERR_TOO_LARGE
+    } else {
+        FM_ERR_NO_MEMORY
+    });
+    ptr::null_mut()
+}
+
+pub unsafe fn fm_lm_calloc(n: usize, size: usize, t: c_int) -> *mut c_void {
+    let Some(total) = n.checked_mul(size) else {
+        set_error(FM_ERR_TOO_LARGE);
+        return ptr::null_mut();
+    };
+    let p = fm_lm_malloc(total, t);
+    if !p.is_null() {
+        // Freed pages are never cleared, the whole block is zeroed here
+        ptr::write_bytes(p as *mut u8, 0, roundup(total, FM_PAGE_SIZE));
+    }
+    p
+}
+
+// Pages between old_size and new_size become a new free region
+unsafe fn grow(heap: *mut Heap, old_size: usize, new_size: usize) {
+    (*heap).buffer_size = new_size;
+    if new_size > old_size {
+        let old_pages = old_size / FM_PAGE_SIZE;
+        let region = page_to_ptr(heap, old_pages) as *mut Region;
+        (*region).start_page = old_pages;
+        (*region).pages = new_size / FM_PAGE_SIZE - old_pages;
+        restore_freed_region(heap, region);
+    }
+}
+
+pub unsafe fn fm_lm_migrate(
+    new_buffer: *mut c_void,
+    new_size: usize,
+    old_buffer: *mut *mut c_void,
+    old_size: *mut usize,
+) -> c_int {
+    // Only the first region is migrated
+    let lm = default_state();
+    let heap = heap_at(lm, 0);
+    if (*heap).buffer_start.is_null() {
+        return FM_ERR_NOT_INITIALIZED;
+    }
+    let ret = fm_lm_check_buffer(new_buffer, new_size);
+    if ret != 0 {
+        return ret;
+    }
+    if new_size < (*heap).buffer_size {
+        fm_report!(
+            FM_ERR_BUFFER_TOO_SMALL,
+            "New memory buffer must be larger than the current one!"
+        );
+        return FM_ERR_BUFFER_TOO_SMALL;
+    }
+    let start = (*heap).buffer_start as usize;
+    let end = start + (*heap).buffer_size;
+    if overlaps_heaps(lm, new_buffer as usize, new_size, ptr::null()) {
+        fm_report!(
+            FM_ERR_BUFFER_OVERLAP,
+            "New memory buffer must not overlap existing regions!"
+        );
+        return FM_ERR_BUFFER_OVERLAP;
+    }
+
+    *old_buffer = (*heap).buffer_start as *mut c_void;
+    *old_size = (*heap).buffer_size;
+    // Page indices are relative to the buffer start, only pointers linking
+    // regions need to be adjusted.
+    let new_start = new_buffer as *mut u8;
+    ptr::copy_nonoverlapping((*heap).buffer_start, new_start, (*heap).buffer_size);
+    relocate_list(addr_of_mut!((*heap).free_regions), start, end, new_start);
+    relocate_list(addr_of_mut!((*heap).freed_memories), start, end, new_start);
+    (*heap).buffer_start = new_start;
+    (*heap).meta = new_start;
+    grow(heap, *old_size, new_size);
+    0
+}
+
+pub unsafe fn fm_lm_extend(additional_bytes: usize) -> c_int {
+    let lm = default_state();
+    let heap = heap_at(lm, 0);
+    if (*heap).buffer_start.is_null() {
+        return FM_ERR_NOT_INITIALIZED;
+    }
+    if !additional_bytes.is_multiple_of(FM_PAGE_SIZE) {
+        fm_report!(
+            FM_ERR_UNALIGNED_SIZE,
+            "Extended size must be aligned to pages!"
+        );
+        return FM_ERR_UNALIGNED_SIZE;
+    }
+    if additional_bytes > FM_MAX_MEMORY_SIZE - (*heap).buffer_size {
+        fm_report!(
+            FM_ERR_BUFFER_TOO_LARGE,
+            "Memory size must be less than FM_MAX_MEMORY_SIZE!"
+        );
+        return FM_ERR_BUFFER_TOO_LARGE;
+    }
+    let end = (*heap).buffer_start as usize + (*heap).buffer_size;
+    if overlaps_heaps(lm, end, additional_bytes, heap) {
+        fm_report!(
+            FM_ERR_BUFFER_OVERLAP,
+            "Extended memory must not overlap other regions!"
+        );
+        return FM_ERR_BUFFER_OVERLAP;
+    }
+    grow(
+        heap,
+        (*heap).buffer_size,
+        (*heap).buffer_size + additional_bytes,
+    );
+    0
+}
+
+unsafe fn count_regions(list: *mut CList) -> usize {
+    let mut count = 0;
+    let mut iter = (*list).next;
+    while iter != list {
+        count += 1;
+        iter = (*iter).next;
+    }
+    count
+}
+
+unsafe fn put_regions(out: *mut u8, len: usize, pos: &mut usize, list: *mut CList) {
+    let mut iter = (*list).next;
+    while iter != list {
+        let region = iter as *mut Region;
+        snapshot_put(out, len, pos, bytes_of(&region), size_of::<*mut Region>());
+        snapshot_put(out, len, pos, bytes_of(region), size_of::<Region>());
+        iter = (*iter).next;
+    }
+}
+
+// Snapshot layout: address and copy of the state, bookkeeping pages of all
+// regions, then the number of free region headers followed by their
+// addresses and contents.
+pub(super) unsafe fn fm_lm_snapshot(out: *mut c_void, out_len: usize) -> isize {
+    let out = out as *mut u8;
+    let mut pos = 0;
+    let lm = default_state();
+    snapshot_put(
+        out,
+        out_len,
+        &mut pos,
+        bytes_of(&lm),
+        size_of::<*mut LmState>(),
+    );
+    snapshot_put(out, out_len, &mut pos, bytes_of(lm), size_of::<LmState>());
+    let mut count = 0usize;
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        snapshot_put(out, out_len, &mut pos, (*heap).meta, FM_PAGE_SIZE);
+        count += count_regions(addr_of_mut!((*heap).free_regions));
+        count += count_regions(addr_of_mut!((*heap).freed_memories));
+    }
+    snapshot_put(out, out_len, &mut pos, bytes_of(&count), size_of::<usize>());
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        put_regions(out, out_len, &mut pos, addr_of_mut!((*heap).free_regions));
+        put_regions(out, out_len, &mut pos, addr_of_mut!((*heap).freed_memories));
+    }
+    if pos <= out_len {
+        pos as isize
+    } else {
+        -(pos as isize)
+    }
+}
+
+unsafe fn valid_region_address(region: *const Region) -> bool {
+    is_page_aligned(region) && fm_lm_contains(region as *const c_void) != 0
+}
+
+pub(super) unsafe fn fm_lm_restore(input: *const c_void, len: usize) -> c_int {
+    let lm = default_state();
+    // Validate everything first, so nothing is written on errors
+    let input = input as *const u8;
+    let mut pos = 0;
+    let mut state: *mut LmState = ptr::null_mut();
+    let mut copy = LmState::EMPTY;
+    if snapshot_get(
+        input,
+        len,
+        &mut pos,
+        addr_of_mut!(state) as *mut u8,
+        size_of::<*mut LmState>(),
+    ) || snapshot_get(
+        input,
+        len,
+        &mut pos,
+        addr_of_mut!(copy) as *mut u8,
+        size_of::<LmState>(),
+    ) || state != lm
+        || copy.heap_count != (*lm).heap_count
+    {
+        return FM_ERR_BAD_SNAPSHOT;
+    }
+    for i in 0..(*lm).heap_count {
+        let heap = heap_at(lm, i);
+        if copy.heaps[i].buffer_start != (*heap).buffer_start
+            || copy.heaps[i].buffer_size != (*heap).buffer_size
+            || snapshot_get(input, len, &mut pos, ptr::null_mut(), FM_PAGE_SIZE)
+        {
+            return FM_ERR_BAD_SNAPSHOT;
+        }
+    }
+    let mut count = 0usize;
+    if snapshot_get(
+        input,
+        len,
+        &mut pos,
+        addr_of_mut!(count) as *mut u8,
+        size_of::<usize>(),
+    ) {
+        return FM_ERR_BAD_SNAPSHOT;
+    }
+    let regions_start = pos;
+    for _ in 0..count {
+        let mut region: *mut Region = ptr::null_mut();
+        if snapshot_get(
+            input,
+            len,
+            &mut pos,
+            addr_of_mut!(region) as *mut u8,
+            size_of::<*mut Region>(),
+        ) || !valid_region_address(region)
+            || snapshot_get(input, len, &mut pos, ptr::null_mut(), size_of::<Region>())
+        {
+            return FM_ERR_BAD_SNAPSHOT;
+        }
+    }
+    if pos != len {
+        return FM_ERR_BAD_SNAPSHOT;
+    }
+
+    pos = size_of::<*mut LmState>() + size_of::<LmState>();
+    for i in 0..(*lm).heap_count {
+        snapshot_get(input, len, &mut pos, (*heap_at(lm, i)).meta, FM_PAGE_SIZE);
+    }
+    pos = regions_start;
+    for _ in 0..count {
+        let mut region: *mut Region = ptr::null_mut();
+        snapshot_get(
+            input,
+            len,
+            &mut pos,
+            addr_of_mut!(region) as *mut u8,
+            size_of::<*mut Region>(),
+        );
+        snapshot_get(input, len, &mut pos, region as *mut u8, size_of::<Region>());
+    }
+    ptr::copy_nonoverlapping(&copy, lm, 1);
+    0
+}
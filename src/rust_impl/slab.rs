@@ -0,0 +1,2059 @@
+// Port of slab-malloc.c
+use super::linear::*;
+use super::*;
+use crate::ffi::*;
+// ffi declares the same functions of the C allocator when both are built
+// with the differential feature, names imported explicitly take precedence
+use super::fm_lm_set_error_sink;
+#[cfg(feature = "fill-on-free")]
+use super::linear::fm_lm_check_fill;
+#[cfg(all(feature = "test-support", feature = "fill-on-free"))]
+use super::linear::fm_lm_fill;
+#[cfg(feature = "hardening")]
+use super::linear::fm_lm_set_random_seed;
+#[cfg(feature = "test-support")]
+use super::linear::fm_lm_test_buffer_pointer;
+use super::linear::{
+    fm_lm_add_region, fm_lm_contains, fm_lm_deinit, fm_lm_extend, fm_lm_free, fm_lm_live_blocks,
+    fm_lm_max_alloc_size, fm_lm_max_alloc_size_reserved, fm_lm_migrate, fm_lm_regions,
+    fm_lm_reinit, fm_lm_reinit_split, fm_lm_reinit_swap, fm_lm_stats, fm_lm_used_pages,
+    fm_lm_verify, fm_lm_walk,
+};
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr::addr_of_mut;
+
+const INVALID_SLAB: usize = 0xFFFFFFFF;
+
+// Smaller classes are only added below the default ones. The two largest
+// classes fit 8 and 4 objects in a page, which are 512 and 1024 bytes with
+// 4KB pages.
+const SLAB_CLASSES: usize = match FM_SLAB_MIN_SIZE {
+    16 => 6,
+    32 => 5,
+    _ => 4,
+};
+
+const SLAB_SIZES: [usize; SLAB_CLASSES] = {
+    let mut sizes = [0; SLAB_CLASSES];
+    let mut i = 0;
+    while i < SLAB_CLASSES - 2 {
+        sizes[i] = FM_SLAB_MIN_SIZE << i;
+        i += 1;
+    }
+    sizes[SLAB_CLASSES - 2] = FM_PAGE_SIZE / 8;
+    sizes[SLAB_CLASSES - 1] = FM_PAGE_SIZE / 4;
+    sizes
+};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SmHeap {
+    slab_lists: [CList; SLAB_CLASSES],
+    // Fully used slabs are never picked for allocations, they are only tracked
+    // so the heap can be walked.
+    full_slabs: CList,
+    // Number of pages used as slabs, and total size of live slab objects
+    slab_pages: usize,
+    slab_used_bytes: usize,
+    // Number of slabs, and used slots in those slabs for each size class
+    class_slabs: [usize; SLAB_CLASSES],
+    class_used_slots: [usize; SLAB_CLASSES],
+    // NULL for the default linear malloc state
+    lm: *mut LmState,
+    // Set while a hook or callback runs for this heap, see callback_begin.
+    // An int in C, which is padded to the same size, so copies of the heap in
+    // snapshots carry no uninitialized bytes.
+    in_callback: usize,
+}
+
+impl SmHeap {
+    const EMPTY: SmHeap = SmHeap {
+        slab_lists: [CList::UNLINKED; SLAB_CLASSES],
+        full_slabs: CList::UNLINKED,
+        slab_pages: 0,
+        slab_used_bytes: 0,
+        class_slabs: [0; SLAB_CLASSES],
+        class_used_slots: [0; SLAB_CLASSES],
+        lm: ptr::null_mut(),
+        in_callback: 0,
+    };
+}
+
+// Instance used by all global functions, its lists are initialized on first
+// use
+static DEFAULT_HEAP: Global<SmHeap> = Global::new(SmHeap::EMPTY);
+static DEFAULT_HEAP_READY: Global<bool> = Global::new(false);
+
+unsafe fn default_heap() -> *mut SmHeap {
+    let heap = DEFAULT_HEAP.ptr();
+    if !DEFAULT_HEAP_READY.get() {
+        DEFAULT_HEAP_READY.set(true);
+        for i in 0..SLAB_CLASSES {
+            list_init(addr_of_mut!((*heap).slab_lists[i]));
+        }
+        list_init(addr_of_mut!((*heap).full_slabs));
+    }
+    heap
+}
+
+unsafe fn slab_list(heap: *mut SmHeap, i: usize) -> *mut CList {
+    addr_of_mut!((*heap).slab_lists[i])
+}
+
+unsafe fn full_slabs(heap: *mut SmHeap) -> *mut CList {
+    addr_of_mut!((*heap).full_slabs)
+}
+
+#[cfg(feature = "test-support")]
+const MAX_QUARANTINE: usize = 64;
+
+// Ring buffer of freed pointers that are not yet returned to allocators
+#[cfg(feature = "test-support")]
+static QUARANTINE: Global<[*mut c_void; MAX_QUARANTINE]> =
+    Global::new([ptr::null_mut(); MAX_QUARANTINE]);
+#[cfg(feature = "test-support")]
+static QUARANTINE_LIMIT: Global<usize> = Global::new(0);
+#[cfg(feature = "test-support")]
+static QUARANTINE_START: Global<usize> = Global::new(0);
+#[cfg(feature = "test-support")]
+static QUARANTINE_COUNT: Global<usize> = Global::new(0);
+
+#[cfg(feature = "test-support")]
+unsafe fn quarantine_slot(index: usize) -> *mut *mut c_void {
+    (QUARANTINE.ptr() as *mut *mut c_void).add(index)
+}
+
+// Set via fm_sm_set_realloc_fill, see fm_sm_test_realloc_old_size
+#[cfg(feature = "test-support")]
+static REALLOC_FILL: Global<bool> = Global::new(false);
+#[cfg(feature = "test-support")]
+static REALLOC_OLD_SIZE: Global<usize> = Global::new(0);
+
+#[cfg(feature = "test-support")]
+const MAX_TAGS: usize = 4096;
+#[cfg(feature = "test-support")]
+const TAG_EMPTY: usize = 0;
+#[cfg(feature = "test-support")]
+const TAG_TOMBSTONE: usize = usize::MAX;
+
+// Open addressing hash table keeping tags of live allocations, allocations
+// without an entry have tag 0. Allocations are keyed by their offsets in the
+// buffer, so the table stays valid when the heap is migrated. Offset 0 is the
+// accounting page, which can never be an allocation.
+#[cfg(feature = "test-support")]
+#[derive(Clone, Copy)]
+struct TagEntry {
+    offset: usize,
+    tag: u32,
+}
+
+#[cfg(feature = "test-support")]
+static TAGS: Global<[TagEntry; MAX_TAGS]> = Global::new(
+    [TagEntry {
+        offset: TAG_EMPTY,
+        tag: 0,
+    }; MAX_TAGS],
+);
+
+#[cfg(feature = "test-support")]
+unsafe fn tag_offset(ptr: *const c_void) -> usize {
+    (ptr as usize).wrapping_sub(fm_lm_test_buffer_pointer() as usize)
+}
+
+#[cfg(feature = "test-support")]
+fn tag_slot(offset: usize) -> usize {
+    (((offset as u64) >> 4).wrapping_mul(0x9E3779B97F4A7C15) % MAX_TAGS as u64) as usize
+}
+
+#[cfg(feature = "test-support")]
+unsafe fn tag_entry(index: usize) -> *mut TagEntry {
+    (TAGS.ptr() as *mut TagEntry).add(index % MAX_TAGS)
+}
+
+#[cfg(feature = "test-support")]
+unsafe fn tag_find(ptr: *const c_void) -> *mut TagEntry {
+    let offset = tag_offset(ptr);
+    let start = tag_slot(offset);
+    for i in 0..MAX_TAGS {
+        let entry = tag_entry(start + i);
+        if (*entry).offset == offset {
+            return entry;
+        }
+        if (*entry).offset == TAG_EMPTY {
+            return ptr::null_mut();
+        }
+    }
+    ptr::null_mut()
+}
+
+// When the table is full the tag is simply dropped
+#[cfg(feature = "test-support")]
+unsafe fn tag_set(ptr: *const c_void, tag: u32) {
+    if tag == 0 {
+        return;
+    }
+    let offset = tag_offset(ptr);
+    let start = tag_slot(offset);
+    for i in 0..MAX_TAGS {
+        let entry = tag_entry(start + i);
+        if (*entry).offset == TAG_EMPTY || (*entry).offset == TAG_TOMBSTONE {
+            (*entry).offset = offset;
+            (*entry).tag = tag;
+            return;
+        }
+    }
+}
+
+#[cfg(feature = "test-support")]
+unsafe fn tag_remove(ptr: *const c_void) -> u32 {
+    let entry = tag_find(ptr);
+    if entry.is_null() {
+        return 0;
+    }
+    (*entry).offset = TAG_TOMBSTONE;
+    (*entry).tag
+}
+
+static LOCK: Global<FmLockCallback> = Global::new(None);
+static UNLOCK: Global<FmLockCallback> = Global::new(None);
+static LOCK_CTX: Global<*mut c_void> = Global::new(ptr::null_mut());
+
+pub unsafe fn fm_set_lock_callbacks(
+    lock: FmLockCallback,
+    unlock: FmLockCallback,
+    ctx: *mut c_void,
+) {
+    LOCK.set(lock);
+    UNLOCK.set(unlock);
+    LOCK_CTX.set(ctx);
+}
+
+unsafe fn lock() {
+    if let Some(lock) = LOCK.get() {
+        lock(LOCK_CTX.get());
+    }
+}
+
+unsafe fn unlock() {
+    if let Some(unlock) = UNLOCK.get() {
+        unlock(LOCK_CTX.get());
+    }
+}
+
+// Mark heap as running a hook or callback, so allocations made from there
+// are rejected instead of corrupting the heap. With lock callbacks installed
+// the lock already serializes calls, other threads entering the heap
+// meanwhile are legitimate, hence nothing is marked. Returns the previous
+// mark, which is put back by callback_end.
+unsafe fn callback_begin(heap: *mut SmHeap) -> usize {
+    let previous = (*heap).in_callback;
+    if LOCK.get().is_none() {
+        (*heap).in_callback = 1;
+    }
+    previous
+}
+
+unsafe fn callback_end(heap: *mut SmHeap, previous: usize) {
+    (*heap).in_callback = previous;
+}
+
+// Check for an allocation made from a hook or callback of heap, which fails
+// with FM_ERR_REENTRANT, or aborts with trap-reentrant.
+unsafe fn reentered(heap: *const SmHeap) -> bool {
+    if (*heap).in_callback != 0 {
+        #[cfg(feature = "trap-reentrant")]
+        abort();
+        #[cfg(not(feature = "trap-reentrant"))]
+        {
+            set_error(FM_ERR_REENTRANT);
+            return true;
+        }
+    }
+    false
+}
+
+static OOM_HOOK: Global<FmOomHook> = Global::new(None);
+static OOM_HOOK_CTX: Global<*mut c_void> = Global::new(ptr::null_mut());
+
+pub unsafe fn fm_sm_set_oom_hook(hook: FmOomHook, ctx: *mut c_void) {
+    lock();
+    OOM_HOOK.set(hook);
+    OOM_HOOK_CTX.set(ctx);
+    unlock();
+}
+
+// Called with the lock held, which is released while the hook runs so the
+// hook can still inspect the heap
+unsafe fn notify_oom(heap: *mut SmHeap, requested: usize) {
+    if let Some(hook) = OOM_HOOK.get() {
+        let ctx = OOM_HOOK_CTX.get();
+        let previous = callback_begin(heap);
+        unlock();
+        hook(requested, ctx);
+        lock();
+        callback_end(heap, previous);
+    }
+}
+
+unsafe fn init_slabs(heap: *mut SmHeap) {
+    list_init(full_slabs(heap));
+    for i in 0..SLAB_CLASSES {
+        list_init(slab_list(heap, i));
+        (*heap).class_slabs[i] = 0;
+        (*heap).class_used_slots[i] = 0;
+    }
+    (*heap).slab_pages = 0;
+    (*heap).slab_used_bytes = 0;
+}
+
+unsafe fn reset_slabs() {
+    #[cfg(feature = "test-support")]
+    {
+        // Quarantined pointers belong to the previous buffer
+        QUARANTINE_START.set(0);
+        QUARANTINE_COUNT.set(0);
+        ptr::write_bytes(TAGS.ptr(), 0, 1);
+    }
+    init_slabs(default_heap());
+}
+
+unsafe fn live_allocations() -> usize {
+    let heap = default_heap();
+    let mut live = fm_lm_live_blocks() - (*heap).slab_pages;
+    for i in 0..SLAB_CLASSES {
+        live += (*heap).class_used_slots[i];
+    }
+    // Quarantined blocks are already freed by the caller
+    #[cfg(feature = "test-support")]
+    {
+        live -= QUARANTINE_COUNT.get();
+    }
+    live
+}
+
+pub unsafe fn fm_sm_live_allocations() -> usize {
+    lock();
+    let result = live_allocations();
+    unlock();
+    result
+}
+
+unsafe fn allocated_bytes() -> usize {
+    let heap = default_heap();
+    (fm_lm_used_pages() - (*heap).slab_pages) * FM_PAGE_SIZE + (*heap).slab_used_bytes
+}
+
+pub unsafe fn fm_sm_allocated_bytes() -> usize {
+    lock();
+    let result = allocated_bytes();
+    unlock();
+    result
+}
+
+pub unsafe fn fm_sm_default_memory_size() -> usize {
+    FM_MEMORY_SIZE
+}
+
+pub unsafe fn fm_sm_min_buffer_size() -> usize {
+    FM_MIN_MEMORY_SIZE
+}
+
+unsafe fn reinit_forced(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    let ret = fm_lm_reinit(buffer, size, zero_filled);
+    if ret != 0 {
+        return ret;
+    }
+    reset_slabs();
+    0
+}
+
+unsafe fn deinit() {
+    fm_lm_deinit();
+    reset_slabs();
+}
+
+pub unsafe fn fm_sm_deinit() {
+    lock();
+    deinit();
+    unlock();
+}
+
+unsafe fn reinit(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    if live_allocations() > 0 {
+        return FM_ERR_LIVE_ALLOCATIONS;
+    }
+    reinit_forced(buffer, size, zero_filled)
+}
+
+pub unsafe fn fm_sm_reinit(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    lock();
+    let result = reinit(buffer, size, zero_filled);
+    unlock();
+    result
+}
+
+pub unsafe fn fm_sm_reinit_forced(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    lock();
+    let result = reinit_forced(buffer, size, zero_filled);
+    unlock();
+    result
+}
+
+unsafe fn reinit_split(
+    slab_buffer: *mut c_void,
+    slab_size: usize,
+    linear_buffer: *mut c_void,
+    linear_size: usize,
+    zero_filled: c_int,
+) -> c_int {
+    if live_allocations() > 0 {
+        return FM_ERR_LIVE_ALLOCATIONS;
+    }
+    let ret = fm_lm_reinit_split(
+        slab_buffer,
+        slab_size,
+        linear_buffer,
+        linear_size,
+        zero_filled,
+    );
+    if ret != 0 {
+        return ret;
+    }
+    reset_slabs();
+    0
+}
+
+pub unsafe fn fm_sm_reinit_split(
+    slab_buffer: *mut c_void,
+    slab_size: usize,
+    linear_buffer: *mut c_void,
+    linear_size: usize,
+    zero_filled: c_int,
+) -> c_int {
+    lock();
+    let result = reinit_split(
+        slab_buffer,
+        slab_size,
+        linear_buffer,
+        linear_size,
+        zero_filled,
+    );
+    unlock();
+    result
+}
+
+unsafe fn add_region(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    fm_lm_add_region(buffer, size, zero_filled)
+}
+
+pub unsafe fn fm_sm_add_region(buffer: *mut c_void, size: usize, zero_filled: c_int) -> c_int {
+    lock();
+    let result = add_region(buffer, size, zero_filled);
+    unlock();
+    result
+}
+
+unsafe fn adopt(ptr: *mut c_void, size: usize) -> c_int {
+    let p = ptr as usize;
+    if p & 15 != 0 {
+        return FM_ERR_BAD_ALIGNMENT;
+    }
+    if size > usize::MAX - p {
+        return FM_ERR_BUFFER_TOO_LARGE;
+    }
+    // Only whole pages within the block can be managed
+    let start = roundup(p, FM_PAGE_SIZE);
+    let end = rounddown(p + size, FM_PAGE_SIZE);
+    if end < start + FM_MIN_MEMORY_SIZE {
+        return FM_ERR_BUFFER_TOO_SMALL;
+    }
+    add_region(
+        (ptr as *mut u8).wrapping_add(start - p) as *mut c_void,
+        end - start,
+        0,
+    )
+}
+
+pub unsafe fn fm_sm_adopt(ptr: *mut c_void, size: usize) -> c_int {
+    lock();
+    let result = adopt(ptr, size);
+    unlock();
+    result
+}
+
+unsafe fn reinit_swap(
+    new_buffer: *mut c_void,
+    new_size: usize,
+    zero_filled: c_int,
+    old_buffer: *mut *mut c_void,
+    old_size: *mut usize,
+) -> c_int {
+    if live_allocations() > 0 {
+        return FM_ERR_LIVE_ALLOCATIONS;
+    }
+    let ret = fm_lm_reinit_swap(new_buffer, new_size, zero_filled, old_buffer, old_size);
+    if ret != 0 {
+        return ret;
+    }
+    reset_slabs();
+    0
+}
+
+pub unsafe fn fm_sm_reinit_swap(
+    new_buffer: *mut c_void,
+    new_size: usize,
+    zero_filled: c_int,
+    old_buffer: *mut *mut c_void,
+    old_size: *mut usize,
+) -> c_int {
+    lock();
+    let result = reinit_swap(new_buffer, new_size, zero_filled, old_buffer, old_size);
+    unlock();
+    result
+}
+
+fn slab_index(size: usize) -> usize {
+    // Right now we have at most 6 slabs, a linear search shall be enough,
+    // a binary search might be needed once we have more slabs.
+    SLAB_SIZES
+        .iter()
+        .position(|slab_size| size <= *slab_size)
+        .unwrap_or(INVALID_SLAB)
+}
+
+// Enough bits for all slots of the smallest size class
+const BITMAP_WORDS: usize = (FM_PAGE_SIZE / FM_SLAB_MIN_SIZE).div_ceil(64);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PageMeta {
+    link: CList,
+    bitmap: [u64; BITMAP_WORDS],
+    size: usize,
+    count: usize,
+    slab_index: usize,
+    _padding: usize,
+}
+
+// Slots start after the page meta, rounded up to 64 bytes
+const PAGE_META_RESERVED_SIZE: usize = size_of::<PageMeta>().div_ceil(64) * 64;
+
+// Page meta of the slab holding ptr
+fn meta_of<T>(ptr: *const T) -> *mut PageMeta {
+    align_down(ptr as *mut T, FM_PAGE_SIZE) as *mut PageMeta
+}
+
+unsafe fn ptr_to_index(meta: *const PageMeta, ptr: *const c_void) -> usize {
+    let p = ptr as usize;
+    let base = meta as usize + PAGE_META_RESERVED_SIZE;
+    #[cfg(feature = "test-support")]
+    {
+        if !(p.wrapping_sub(base)).is_multiple_of((*meta).size) {
+            fm_report!(
+                FM_ERR_BAD_POINTER,
+                "Pointer does not lie on the boundary of slab allocated value!"
+            );
+            abort();
+        }
+        if p.wrapping_sub(base) / (*meta).size >= (*meta).count {
+            fm_report!(FM_ERR_BAD_POINTER, "Pointer exceeds slab count!");
+            abort();
+        }
+    }
+    p.wrapping_sub(base) / (*meta).size
+}
+
+unsafe fn index_to_ptr(meta: *mut PageMeta, index: usize) -> *mut c_void {
+    #[cfg(feature = "test-support")]
+    if index >= (*meta).count {
+        fm_report!(FM_ERR_CORRUPTED, "Invalid index in slab!");
+        abort();
+    }
+    (meta as *mut u8).wrapping_add(PAGE_META_RESERVED_SIZE + index * (*meta).size) as *mut c_void
+}
+
+unsafe fn bitmap_all_cleared(meta: *const PageMeta) -> bool {
+    (*meta).bitmap.iter().all(|word| *word == 0)
+}
+
+unsafe fn used_slots(meta: *const PageMeta) -> usize {
+    (*meta)
+        .bitmap
+        .iter()
+        .map(|word| word.count_ones() as usize)
+        .sum()
+}
+
+unsafe fn bitmap_all_used(meta: *const PageMeta) -> bool {
+    used_slots(meta) == (*meta).count
+}
+
+unsafe fn bitmap_next_free(meta: *const PageMeta) -> usize {
+    let mut zeros = INVALID_SLAB;
+    for (i, word) in (*meta).bitmap.iter().enumerate() {
+        if *word != u64::MAX {
+            zeros = i * 64 + (!*word).trailing_zeros() as usize;
+            break;
+        }
+    }
+    if zeros >= (*meta).count {
+        return INVALID_SLAB;
+    }
+    zeros
+}
+
+unsafe fn bitmap_is_set(meta: *const PageMeta, index: usize) -> bool {
+    ((*meta).bitmap[index / 64] >> (index % 64)) & 1 != 0
+}
+
+static SLAB_DETERMINISTIC: Global<bool> = Global::new(false);
+
+#[cfg(feature = "hardening")]
+static SLAB_RANDOM_ENABLED: Global<bool> = Global::new(false);
+#[cfg(feature = "hardening")]
+static SLAB_RANDOM_STATE: Global<u64> = Global::new(0);
+
+#[cfg(feature = "hardening")]
+unsafe fn slab_random_next() -> u64 {
+    random_next(&mut *SLAB_RANDOM_STATE.ptr())
+}
+
+#[cfg(feature = "hardening")]
+unsafe fn random_placement() -> bool {
+    SLAB_RANDOM_ENABLED.get() && !SLAB_DETERMINISTIC.get()
+}
+
+#[cfg(feature = "hardening")]
+pub unsafe fn fm_sm_set_random_seed(seed: u64) {
+    lock();
+    SLAB_RANDOM_ENABLED.set(true);
+    SLAB_RANDOM_STATE.set(seed);
+    fm_lm_set_random_seed(slab_random_next());
+    unlock();
+}
+
+// Start from a random slot, then pick the first free slot, wrapping around
+// at the end of the page.
+#[cfg(feature = "hardening")]
+unsafe fn bitmap_random_free(meta: *const PageMeta) -> usize {
+    let count = (*meta).count;
+    let start = (slab_random_next() % count as u64) as usize;
+    for i in 0..count {
+        let index = (start + i) % count;
+        if !bitmap_is_set(meta, index) {
+            return index;
+        }
+    }
+    INVALID_SLAB
+}
+
+unsafe fn pick_free(meta: *const PageMeta) -> usize {
+    #[cfg(feature = "hardening")]
+    if random_placement() {
+        return bitmap_random_free(meta);
+    }
+    bitmap_next_free(meta)
+}
+
+unsafe fn bitmap_set(meta: *mut PageMeta, index: usize) {
+    (*meta).bitmap[index / 64] |= 1 << (index % 64);
+}
+
+unsafe fn bitmap_clear(meta: *mut PageMeta, index: usize) {
+    (*meta).bitmap[index / 64] &= !(1 << (index % 64));
+}
+
+unsafe fn release(heap: *mut SmHeap, ptr: *mut c_void) {
+    if is_page_aligned(ptr) {
+        fm_lm_state_free((*heap).lm, ptr);
+        return;
+    }
+    let meta = meta_of(ptr);
+    let element_index = ptr_to_index(meta, ptr);
+    // Freeing a slot twice would break the counts of the slab
+    if !bitmap_is_set(meta, element_index) {
+        fm_report!(FM_ERR_BAD_POINTER, "Slab object is freed twice!");
+        set_error(FM_ERR_BAD_POINTER);
+        return;
+    }
+    let all_used = bitmap_all_used(meta);
+    #[cfg(feature = "fill-on-free")]
+    ptr::write_bytes(ptr as *mut u8, FM_FILL_PATTERN, (*meta).size);
+    // Checked while the slot is still allocated, clearing it might release
+    // the whole slab
+    #[cfg(all(feature = "fill-on-free", feature = "test-support"))]
+    if check_fill(ptr as *const u8, (*meta).size) {
+        fm_report!(FM_ERR_CORRUPTED, "Memory is not filled after being freed!");
+        abort();
+    }
+    bitmap_clear(meta, element_index);
+    (*heap).slab_used_bytes -= (*meta).size;
+    (*heap).class_used_slots[(*meta).slab_index] -= 1;
+    if all_used {
+        list_unlink(addr_of_mut!((*meta).link));
+        list_link_before(
+            slab_list(heap, (*meta).slab_index),
+            addr_of_mut!((*meta).link),
+        );
+        fm_debug!("Retrieving previously fully used slab: %p %zu\n");
+    }
+    if SLAB_DETERMINISTIC.get() && bitmap_all_cleared(meta) {
+        // Empty slabs are not kept around, which would depend on past frees
+        (*heap).slab_pages -= 1;
+        (*heap).class_slabs[(*meta).slab_index] -= 1;
+        list_unlink(addr_of_mut!((*meta).link));
+        fm_lm_state_free((*heap).lm, meta as *mut c_void);
+    }
+}
+
+#[cfg(feature = "fill-on-free")]
+pub unsafe fn fm_sm_check_fill(ptr: *mut c_void) -> c_int {
+    if is_page_aligned(ptr) {
+        return fm_lm_check_fill(ptr);
+    }
+    check_fill(ptr as *const u8, (*meta_of(ptr)).size) as c_int
+}
+
+#[cfg(all(feature = "test-support", feature = "fill-on-free"))]
+unsafe fn fill_block(ptr: *mut c_void) {
+    if is_page_aligned(ptr) {
+        fm_lm_fill(ptr);
+        return;
+    }
+    ptr::write_bytes(ptr as *mut u8, FM_FILL_PATTERN, (*meta_of(ptr)).size);
+}
+
+#[cfg(feature = "test-support")]
+unsafe fn evict_quarantine(limit: usize) {
+    while QUARANTINE_COUNT.get() > limit {
+        let start = QUARANTINE_START.get();
+        let oldest = *quarantine_slot(start);
+        QUARANTINE_START.set((start + 1) % MAX_QUARANTINE);
+        QUARANTINE_COUNT.set(QUARANTINE_COUNT.get() - 1);
+        #[cfg(feature = "fill-on-free")]
+        if fm_sm_check_fill(oldest) != 0 {
+            fm_report!(
+                FM_ERR_CORRUPTED,
+                "Quarantined memory is modified after being freed!"
+            );
+            abort();
+        }
+        release(default_heap(), oldest);
+    }
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_set_quarantine(n: usize) {
+    lock();
+    let n = n.min(MAX_QUARANTINE);
+    QUARANTINE_LIMIT.set(n);
+    evict_quarantine(n);
+    unlock();
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_set_realloc_fill(enabled: c_int) {
+    lock();
+    REALLOC_FILL.set(enabled != 0);
+    unlock();
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_test_realloc_old_size() -> usize {
+    lock();
+    let result = REALLOC_OLD_SIZE.get();
+    unlock();
+    result
+}
+
+#[cfg(feature = "hardening")]
+unsafe fn valid_pointer(heap: *mut SmHeap, ptr: *mut c_void) -> bool {
+    if fm_lm_state_contains((*heap).lm, ptr) == 0 {
+        return false;
+    }
+    if is_page_aligned(ptr) {
+        return true;
+    }
+    let meta = meta_of(ptr);
+    if (*meta).slab_index >= SLAB_CLASSES || (*meta).size != SLAB_SIZES[(*meta).slab_index] {
+        return false;
+    }
+    let p = ptr as usize;
+    let base = meta as usize + PAGE_META_RESERVED_SIZE;
+    if p < base
+        || !(p - base).is_multiple_of((*meta).size)
+        || (p - base) / (*meta).size >= (*meta).count
+    {
+        return false;
+    }
+    // Double free is also rejected
+    bitmap_is_set(meta, (p - base) / (*meta).size)
+}
+
+unsafe fn sm_free(heap: *mut SmHeap, ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    #[cfg(feature = "hardening")]
+    if !valid_pointer(heap, ptr) {
+        fm_report!(
+            FM_ERR_BAD_POINTER,
+            "Pointer passed to free is not allocated!"
+        );
+        set_error(FM_ERR_BAD_POINTER);
+        return;
+    }
+    #[cfg(feature = "test-support")]
+    {
+        // Tags and quarantine are only kept for the default instance
+        let is_default = heap == DEFAULT_HEAP.ptr();
+        if is_default {
+            tag_remove(ptr);
+        }
+        let limit = QUARANTINE_LIMIT.get();
+        if limit > 0 && is_default {
+            // Quarantined memory shall also be poisoned
+            #[cfg(feature = "fill-on-free")]
+            fill_block(ptr);
+            // Make room first so the ring buffer never overflows
+            evict_quarantine(limit - 1);
+            let end = (QUARANTINE_START.get() + QUARANTINE_COUNT.get()) % MAX_QUARANTINE;
+            *quarantine_slot(end) = ptr;
+            QUARANTINE_COUNT.set(QUARANTINE_COUNT.get() + 1);
+            return;
+        }
+    }
+    release(heap, ptr);
+}
+
+pub unsafe fn fm_sm_free(ptr: *mut c_void) {
+    fm_sm_heap_free(default_heap() as *mut FmHeap, ptr);
+}
+
+pub unsafe fn fm_sm_heap_free(heap: *mut FmHeap, ptr: *mut c_void) {
+    let heap = heap as *mut SmHeap;
+    if reentered(heap) {
+        return;
+    }
+    lock();
+    sm_free(heap, ptr);
+    unlock();
+}
+
+unsafe fn free_many(heap: *mut SmHeap, ptrs: *const *mut c_void, count: usize) {
+    for i in 0..count {
+        sm_free(heap, *ptrs.add(i));
+    }
+}
+
+pub unsafe fn fm_sm_free_many(ptrs: *const *mut c_void, count: usize) {
+    let heap = default_heap();
+    if reentered(heap) {
+        return;
+    }
+    lock();
+    free_many(heap, ptrs, count);
+    unlock();
+}
+
+// Requests larger than any buffer are rejected before size classes or pages
+// are computed, so no rounding can wrap around to a small block.
+unsafe fn too_large(size: usize) -> bool {
+    if size > FM_MAX_MEMORY_SIZE {
+        set_error(FM_ERR_TOO_LARGE);
+        return true;
+    }
+    false
+}
+
+unsafe fn sm_realloc(heap: *mut SmHeap, ptr: *mut c_void, size: usize) -> *mut c_void {
+    if too_large(size) {
+        notify_oom(heap, size);
+        return ptr::null_mut();
+    }
+    if is_page_aligned(ptr) {
+        let p = fm_lm_state_realloc((*heap).lm, ptr, size, FM_LM_T_TRANSIENT);
+        if p.is_null() {
+            notify_oom(heap, size);
+        }
+        return p;
+    }
+    let meta = meta_of(ptr);
+    if size <= (*meta).size {
+        return ptr;
+    }
+    let p = sm_malloc(heap, size);
+    if !p.is_null() {
+        ptr::copy_nonoverlapping(ptr as *const u8, p as *mut u8, (*meta).size);
+        sm_free(heap, ptr);
+    } else {
+        notify_oom(heap, size);
+    }
+    p
+}
+
+unsafe fn shrink_release(heap: *mut SmHeap, ptr: *mut c_void, size: usize) -> *mut c_void {
+    if too_large(size) {
+        return ptr::null_mut();
+    }
+    if is_page_aligned(ptr) {
+        return fm_lm_state_shrink_release((*heap).lm, ptr, size);
+    }
+    if size <= (*meta_of(ptr)).size {
+        ptr
+    } else {
+        ptr::null_mut()
+    }
+}
+
+pub unsafe fn fm_sm_shrink_release(ptr: *mut c_void, size: usize) -> *mut c_void {
+    let heap = default_heap();
+    if reentered(heap) {
+        return ptr::null_mut();
+    }
+    lock();
+    let result = shrink_release(heap, ptr, size);
+    unlock();
+    result
+}
+
+// Record the size ptr had before being reallocated to p, and fill the bytes
+// p gained beyond it when enabled
+#[cfg(feature = "test-support")]
+unsafe fn track_realloc(heap: *mut SmHeap, p: *mut c_void, old_size: usize) {
+    REALLOC_OLD_SIZE.set(old_size);
+    let new_size = usable_size(heap, p);
+    if REALLOC_FILL.get() && new_size > old_size {
+        ptr::write_bytes(
+            (p as *mut u8).add(old_size),
+            FM_REALLOC_FILL_PATTERN,
+            new_size - old_size,
+        );
+    }
+}
+
+unsafe fn heap_realloc(heap: *mut SmHeap, ptr: *mut c_void, size: usize) -> *mut c_void {
+    #[cfg(feature = "test-support")]
+    let old_size = if ptr.is_null() {
+        0
+    } else {
+        usable_size(heap, ptr)
+    };
+    let p = sm_realloc(heap, ptr, size);
+    #[cfg(feature = "test-support")]
+    if !p.is_null() {
+        track_realloc(heap, p, old_size);
+    }
+    p
+}
+
+pub unsafe fn fm_sm_realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    let heap = default_heap();
+    if reentered(heap) {
+        return ptr::null_mut();
+    }
+    lock();
+    #[cfg(feature = "test-support")]
+    let p = {
+        let entry = if ptr.is_null() {
+            ptr::null_mut()
+        } else {
+            tag_find(ptr)
+        };
+        let tag = if entry.is_null() { 0 } else { (*entry).tag };
+        let p = heap_realloc(heap, ptr, size);
+        if !p.is_null() && p != ptr {
+            // Tags move together with reallocated blocks
+            tag_remove(ptr);
+            tag_set(p, tag);
+        }
+        p
+    };
+    #[cfg(not(feature = "test-support"))]
+    let p = heap_realloc(heap, ptr, size);
+    unlock();
+    p
+}
+
+unsafe fn resize_in_place(heap: *mut SmHeap, ptr: *mut c_void, size: usize) -> *mut c_void {
+    if too_large(size) {
+        return ptr::null_mut();
+    }
+    if is_page_aligned(ptr) {
+        return fm_lm_state_realloc_in_place((*heap).lm, ptr, size);
+    }
+    if size <= (*meta_of(ptr)).size {
+        ptr
+    } else {
+        ptr::null_mut()
+    }
+}
+
+pub unsafe fn fm_sm_realloc_flags(ptr: *mut c_void, size: usize, flags: c_int) -> *mut c_void {
+    if ptr.is_null() || flags & FM_REALLOC_NO_MOVE == 0 {
+        return fm_sm_realloc(ptr, size);
+    }
+    let heap = default_heap();
+    if reentered(heap) {
+        return ptr::null_mut();
+    }
+    lock();
+    let result = resize_in_place(heap, ptr, size);
+    unlock();
+    result
+}
+
+pub unsafe fn fm_sm_heap_realloc(heap: *mut FmHeap, ptr: *mut c_void, size: usize) -> *mut c_void {
+    let heap = heap as *mut SmHeap;
+    if reentered(heap) {
+        return ptr::null_mut();
+    }
+    lock();
+    let result = heap_realloc(heap, ptr, size);
+    unlock();
+    result
+}
+
+unsafe fn free_empty_slabs(heap: *mut SmHeap) -> usize {
+    let mut freed = 0;
+    for i in 0..SLAB_CLASSES {
+        let head = slab_list(heap, i);
+        let mut iter = (*head).next;
+        while iter != head {
+            let meta = iter as *mut PageMeta;
+            let old = iter;
+            iter = (*iter).next;
+            if bitmap_all_cleared(meta) {
+                list_unlink(old);
+                fm_lm_state_free((*heap).lm, meta as *mut c_void);
+                (*heap).slab_pages -= 1;
+                (*heap).class_slabs[i] -= 1;
+                freed += 1;
+            }
+        }
+    }
+    freed
+}
+
+pub unsafe fn fm_sm_set_error_sink(sink: FmErrorSink, ctx: *mut c_void) {
+    lock();
+    fm_lm_set_error_sink(sink, ctx);
+    unlock();
+}
+
+pub unsafe fn fm_sm_set_deterministic(enabled: c_int) {
+    lock();
+    SLAB_DETERMINISTIC.set(enabled != 0);
+    if enabled != 0 {
+        free_empty_slabs(default_heap());
+    }
+    fm_lm_set_deterministic(enabled);
+    unlock();
+}
+
+pub unsafe fn fm_sm_trim() -> usize {
+    let heap = default_heap();
+    if reentered(heap) {
+        return 0;
+    }
+    lock();
+    let result = free_empty_slabs(heap) * FM_PAGE_SIZE;
+    unlock();
+    result
+}
+
+pub unsafe fn fm_sm_max_alloc_size() -> usize {
+    let heap = default_heap();
+    if reentered(heap) {
+        return 0;
+    }
+    lock();
+    free_empty_slabs(heap);
+    let mut result = fm_lm_max_alloc_size();
+    if result < SLAB_SIZES[SLAB_CLASSES - 1] && fm_lm_max_alloc_size_reserved() >= FM_PAGE_SIZE {
+        // A new slab can still be created for any class
+        result = SLAB_SIZES[SLAB_CLASSES - 1];
+    }
+    for i in (0..SLAB_CLASSES).rev() {
+        if result >= SLAB_SIZES[i] {
+            break;
+        }
+        if list_is_linked(slab_list(heap, i)) {
+            result = SLAB_SIZES[i];
+        }
+    }
+    unlock();
+    result
+}
+
+unsafe fn lm_malloc(heap: *mut SmHeap, size: usize, t: c_int) -> *mut c_void {
+    let mut p = fm_lm_state_malloc((*heap).lm, size, t);
+    if p.is_null() {
+        // When previous attempt fails, try freeing empty slabs, then retry
+        free_empty_slabs(heap);
+        p = fm_lm_state_malloc((*heap).lm, size, t);
+    }
+    p
+}
+
+unsafe fn sm_malloc(heap: *mut SmHeap, size: usize) -> *mut c_void {
+    if too_large(size) {
+        return ptr::null_mut();
+    }
+    let i = slab_index(size);
+    if i == INVALID_SLAB {
+        return lm_malloc(heap, size, FM_LM_T_TRANSIENT);
+    }
+    let head = slab_list(heap, i);
+    let mut iter = (*head).next;
+    while iter != head {
+        let meta = iter as *mut PageMeta;
+        let index = pick_free(meta);
+        if index != INVALID_SLAB {
+            bitmap_set(meta, index);
+            (*heap).slab_used_bytes += (*meta).size;
+            (*heap).class_used_slots[i] += 1;
+            if bitmap_all_used(meta) {
+                list_unlink(iter);
+                list_link_before(full_slabs(heap), addr_of_mut!((*meta).link));
+                fm_debug!("Unlinking fully utilized slab: %p %zu\n");
+            }
+            return index_to_ptr(meta, index);
+        }
+        iter = (*iter).next;
+    }
+    // Create a new slab here
+    let slab = fm_lm_state_malloc_reserved((*heap).lm, FM_PAGE_SIZE, FM_LM_T_PERSISTENT);
+    if slab.is_null() {
+        return ptr::null_mut();
+    }
+    let meta = slab as *mut PageMeta;
+    // Free slots always hold the fill pattern, so fm_sm_verify can check them
+    #[cfg(feature = "fill-on-free")]
+    ptr::write_bytes(
+        (slab as *mut u8).add(PAGE_META_RESERVED_SIZE),
+        FM_FILL_PATTERN,
+        FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE,
+    );
+    (*meta).bitmap = [0; BITMAP_WORDS];
+    (*meta).size = SLAB_SIZES[i];
+    (*meta).slab_index = i;
+    (*meta).count = (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / (*meta).size;
+    list_link_after(head, addr_of_mut!((*meta).link));
+    fm_debug!("Creating new slab: %p %zu\n");
+    (*heap).slab_pages += 1;
+    (*heap).slab_used_bytes += (*meta).size;
+    (*heap).class_slabs[i] += 1;
+    (*heap).class_used_slots[i] += 1;
+
+    #[allow(unused_mut)]
+    let mut element_index = 0;
+    #[cfg(feature = "hardening")]
+    if random_placement() {
+        // All slots are free in a new slab
+        element_index = (slab_random_next() % (*meta).count as u64) as usize;
+    }
+    bitmap_set(meta, element_index);
+    index_to_ptr(meta, element_index)
+}
+
+pub unsafe fn fm_sm_malloc(size: usize) -> *mut c_void {
+    fm_sm_heap_malloc(default_heap() as *mut FmHeap, size)
+}
+
+unsafe fn heap_malloc(heap: *mut SmHeap, size: usize) -> *mut c_void {
+    let p = sm_malloc(heap, size);
+    if p.is_null() {
+        notify_oom(heap, size);
+    }
+    p
+}
+
+pub unsafe fn fm_sm_heap_malloc(heap: *mut FmHeap, size: usize) -> *mut c_void {
+    let heap = heap as *mut SmHeap;
+    if reentered(heap) {
+        return ptr::null_mut();
+    }
+    lock();
+    let result = heap_malloc(heap, size);
+    unlock();
+    result
+}
+
+// The control block lives at the end of the bookkeeping page
+fn control_size() -> usize {
+    roundup(size_of::<SmHeap>() + fm_lm_state_size(), 16)
+}
+
+unsafe fn create(buffer: *mut c_void, size: usize, zero_filled: c_int) -> *mut SmHeap {
+    let mut ret = fm_lm_check_buffer(buffer, size);
+    // Enough room must be left for page counts in the bookkeeping page
+    if ret == 0 && size / FM_PAGE_SIZE + 8 > FM_PAGE_SIZE - control_size() {
+        fm_report!(
+            FM_ERR_BUFFER_TOO_LARGE,
+            "Memory size is too large to keep the control block!"
+        );
+        ret = FM_ERR_BUFFER_TOO_LARGE;
+    }
+    if ret != 0 {
+        set_error(ret);
+        return ptr::null_mut();
+    }
+    if zero_filled == 0 {
+        ptr::write_bytes(buffer as *mut u8, 0, FM_PAGE_SIZE);
+    }
+    let heap = (buffer as *mut u8).add(FM_PAGE_SIZE - control_size()) as *mut SmHeap;
+    (*heap).lm = heap.add(1) as *mut LmState;
+    fm_lm_state_reinit((*heap).lm, buffer, size, 1);
+    init_slabs(heap);
+    heap
+}
+
+pub unsafe fn fm_sm_create(buffer: *mut c_void, size: usize, zero_filled: c_int) -> *mut FmHeap {
+    lock();
+    let result = create(buffer, size, zero_filled);
+    unlock();
+    result as *mut FmHeap
+}
+
+pub unsafe fn fm_sm_destroy(heap: *mut FmHeap) {
+    ptr::write_bytes(heap as *mut u8, 0, control_size());
+}
+
+pub unsafe fn fm_sm_calloc(n: usize, size: usize) -> *mut c_void {
+    let heap = default_heap();
+    if reentered(heap) {
+        return ptr::null_mut();
+    }
+    let Some(total) = n.checked_mul(size) else {
+        lock();
+        set_error(FM_ERR_TOO_LARGE);
+        // The actual size cannot be represented, use the largest value instead
+        notify_oom(heap, usize::MAX);
+        unlock();
+        return ptr::null_mut();
+    };
+    let p = fm_sm_malloc(total);
+    if !p.is_null() {
+        ptr::write_bytes(p as *mut u8, 0, total);
+    }
+    p
+}
+
+unsafe fn page_alloc(pages: usize) -> *mut c_void {
+    if pages == 0 {
+        return ptr::null_mut();
+    }
+    let heap = default_heap();
+    let Some(size) = pages.checked_mul(FM_PAGE_SIZE) else {
+        set_error(FM_ERR_TOO_LARGE);
+        notify_oom(heap, usize::MAX);
+        return ptr::null_mut();
+    };
+    // Blocks served by linear malloc always start on a page boundary
+    let p = lm_malloc(heap, size, FM_LM_T_TRANSIENT);
+    if p.is_null() {
+        notify_oom(heap, size);
+    }
+    p
+}
+
+pub unsafe fn fm_sm_page_alloc(pages: usize) -> *mut c_void {
+    if reentered(default_heap()) {
+        return ptr::null_mut();
+    }
+    lock();
+    let p = page_alloc(pages);
+    unlock();
+    p
+}
+
+pub unsafe fn fm_sm_page_free(ptr: *mut c_void) {
+    if ptr.is_null() || reentered(default_heap()) {
+        return;
+    }
+    lock();
+    if !is_page_aligned(ptr) {
+        set_error(FM_ERR_BAD_POINTER);
+    } else {
+        sm_free(default_heap(), ptr);
+    }
+    unlock();
+}
+
+unsafe fn collect_stats(stats: *mut FmStats) {
+    let heap = default_heap();
+    fm_lm_stats(
+        addr_of_mut!((*stats).total_pages),
+        addr_of_mut!((*stats).free_pages),
+    );
+    (*stats).used_pages = (*stats).total_pages - (*stats).free_pages;
+    // Each region has its own bookkeeping page
+    let pages = (*stats).total_pages + fm_lm_regions();
+    (*stats).total_bytes = if (*stats).total_pages > 0 {
+        pages * FM_PAGE_SIZE
+    } else {
+        0
+    };
+    (*stats).used_bytes =
+        ((*stats).used_pages - (*heap).slab_pages) * FM_PAGE_SIZE + (*heap).slab_used_bytes;
+    (*stats).free_bytes = (*stats).free_pages * FM_PAGE_SIZE;
+}
+
+pub unsafe fn fm_sm_stats(stats: *mut FmStats) {
+    lock();
+    collect_stats(stats);
+    unlock();
+}
+
+unsafe fn list_contains(head: *mut CList, page: *const c_void) -> bool {
+    let mut iter = (*head).next;
+    while iter != head {
+        if iter as *const c_void == page {
+            return true;
+        }
+        iter = (*iter).next;
+    }
+    false
+}
+
+unsafe fn is_slab(heap: *mut SmHeap, page: *const c_void) -> bool {
+    (0..SLAB_CLASSES).any(|i| list_contains(slab_list(heap, i), page))
+        || list_contains(full_slabs(heap), page)
+}
+
+unsafe fn usable_size(heap: *mut SmHeap, ptr: *const c_void) -> usize {
+    if fm_lm_state_contains((*heap).lm, ptr) == 0 {
+        return 0;
+    }
+    // Quarantined blocks are already freed by the caller
+    #[cfg(feature = "test-support")]
+    for i in 0..QUARANTINE_COUNT.get() {
+        if ptr::eq(
+            *quarantine_slot((QUARANTINE_START.get() + i) % MAX_QUARANTINE),
+            ptr,
+        ) {
+            return 0;
+        }
+    }
+    if is_page_aligned(ptr) {
+        return if is_slab(heap, ptr) {
+            0
+        } else {
+            fm_lm_state_block_size((*heap).lm, ptr)
+        };
+    }
+    let meta = meta_of(ptr);
+    if !is_slab(heap, meta as *const c_void) {
+        return 0;
+    }
+    let p = ptr as usize;
+    let base = meta as usize + PAGE_META_RESERVED_SIZE;
+    if p < base
+        || !(p - base).is_multiple_of((*meta).size)
+        || (p - base) / (*meta).size >= (*meta).count
+    {
+        return 0;
+    }
+    if bitmap_is_set(meta, (p - base) / (*meta).size) {
+        (*meta).size
+    } else {
+        0
+    }
+}
+
+pub unsafe fn fm_sm_usable_size(ptr: *const c_void) -> usize {
+    lock();
+    let result = usable_size(default_heap(), ptr);
+    unlock();
+    result
+}
+
+struct WalkCtx {
+    callback: FmWalkCallback,
+    user: *mut c_void,
+}
+
+unsafe extern "C" fn walk_block(ptr: *mut c_void, size: usize, user: *mut c_void) {
+    let ctx = user as *mut WalkCtx;
+    if !is_slab(default_heap(), ptr) {
+        ((*ctx).callback)(ptr, size, (*ctx).user);
+        return;
+    }
+    let meta = ptr as *mut PageMeta;
+    for i in 0..(*meta).count {
+        if bitmap_is_set(meta, i) {
+            ((*ctx).callback)(index_to_ptr(meta, i), (*meta).size, (*ctx).user);
+        }
+    }
+}
+
+// Invoke callback for each live allocation in address order, slab pages
+// themselves are not reported but the slab objects in them are.
+unsafe fn walk_allocations(callback: FmWalkCallback, user: *mut c_void) {
+    let mut ctx = WalkCtx { callback, user };
+    fm_lm_walk(walk_block, addr_of_mut!(ctx) as *mut c_void);
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_class_stats(callback: FmClassStatsCallback, user: *mut c_void) {
+    lock();
+    let heap = default_heap();
+    let previous = callback_begin(heap);
+    for (i, class_size) in SLAB_SIZES.iter().enumerate() {
+        let count = (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / class_size;
+        let slabs = (*heap).class_slabs[i];
+        let used = (*heap).class_used_slots[i];
+        callback(*class_size, slabs, used, slabs * count - used, user);
+    }
+    callback_end(heap, previous);
+    unlock();
+}
+
+// There is no output without a C library, the same as freestanding C builds
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_test_report_oom(_requested: usize) {
+    let mut stats = FmStats::default();
+    fm_sm_stats(&mut stats);
+}
+
+#[cfg(feature = "test-support")]
+struct TestWalkCtx {
+    callback: FmSmWalkCallback,
+    user: *mut c_void,
+}
+
+#[cfg(feature = "test-support")]
+unsafe fn tag_of(ptr: *const c_void) -> u32 {
+    let entry = tag_find(ptr);
+    if entry.is_null() {
+        0
+    } else {
+        (*entry).tag
+    }
+}
+
+#[cfg(feature = "test-support")]
+unsafe extern "C" fn test_walk_block(ptr: *mut c_void, size: usize, user: *mut c_void) {
+    let ctx = user as *mut TestWalkCtx;
+    ((*ctx).callback)(ptr, size, tag_of(ptr), (*ctx).user);
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_malloc_tagged(size: usize, tag: u32) -> *mut c_void {
+    let heap = default_heap();
+    if reentered(heap) {
+        return ptr::null_mut();
+    }
+    lock();
+    let p = heap_malloc(heap, size);
+    if !p.is_null() {
+        tag_set(p, tag);
+    }
+    unlock();
+    p
+}
+
+#[cfg(feature = "test-support")]
+unsafe fn free_sized_checked(ptr: *mut c_void, size: usize) -> c_int {
+    if ptr.is_null() {
+        return 0;
+    }
+    let heap = default_heap();
+    let usable = usable_size(heap, ptr);
+    let mismatch = usable == 0 || size > usable;
+    if !mismatch {
+        sm_free(heap, ptr);
+    }
+    mismatch as c_int
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_free_sized_checked(ptr: *mut c_void, size: usize) -> c_int {
+    if reentered(default_heap()) {
+        return 0;
+    }
+    lock();
+    let result = free_sized_checked(ptr, size);
+    unlock();
+    result
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_test_walk(callback: FmSmWalkCallback, user: *mut c_void) {
+    lock();
+    let heap = default_heap();
+    let previous = callback_begin(heap);
+    let mut ctx = TestWalkCtx { callback, user };
+    walk_allocations(test_walk_block, addr_of_mut!(ctx) as *mut c_void);
+    callback_end(heap, previous);
+    unlock();
+}
+
+#[cfg(feature = "test-support")]
+unsafe extern "C" fn histogram_block(_ptr: *mut c_void, size: usize, user: *mut c_void) {
+    let buckets = user as *mut u64;
+    let mut i = 0;
+    while i + 1 < FM_SM_HISTOGRAM_BUCKETS && (size >> (i + 1)) != 0 {
+        i += 1;
+    }
+    *buckets.add(i) += 1;
+}
+
+#[cfg(feature = "test-support")]
+pub unsafe fn fm_sm_test_size_histogram(buckets: *mut u64) {
+    for i in 0..FM_SM_HISTOGRAM_BUCKETS {
+        *buckets.add(i) = 0;
+    }
+    lock();
+    walk_allocations(histogram_block, buckets as *mut c_void);
+    unlock();
+}
+
+struct MigrateCtx {
+    callback: FmRelocateCallback,
+    ctx: *mut c_void,
+    old_start: *mut u8,
+    // Range of the new buffer
+    start: usize,
+    end: usize,
+}
+
+unsafe extern "C" fn migrate_block(ptr: *mut c_void, size: usize, user: *mut c_void) {
+    let m = user as *mut MigrateCtx;
+    let p = ptr as usize;
+    if p >= (*m).start && p < (*m).end {
+        let old_ptr = (*m).old_start.wrapping_add(p - (*m).start);
+        ((*m).callback)(old_ptr as *mut c_void, ptr, size, (*m).ctx);
+    }
+}
+
+unsafe fn migrate(
+    new_buffer: *mut c_void,
+    new_size: usize,
+    callback: Option<FmRelocateCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    let mut old_buffer = ptr::null_mut();
+    let mut old_size = 0;
+    let heap = default_heap();
+    let ret = fm_lm_migrate(new_buffer, new_size, &mut old_buffer, &mut old_size);
+    if ret != 0 {
+        return ret;
+    }
+    let start = old_buffer as usize;
+    let end = start + old_size;
+    let new_start = new_buffer as *mut u8;
+    for i in 0..SLAB_CLASSES {
+        relocate_list(slab_list(heap, i), start, end, new_start);
+    }
+    relocate_list(full_slabs(heap), start, end, new_start);
+    #[cfg(feature = "test-support")]
+    for i in 0..QUARANTINE_COUNT.get() {
+        let slot = quarantine_slot((QUARANTINE_START.get() + i) % MAX_QUARANTINE);
+        let p = *slot as usize;
+        if p >= start && p < end {
+            *slot = new_start.wrapping_add(p - start) as *mut c_void;
+        }
+    }
+    if let Some(callback) = callback {
+        let mut m = MigrateCtx {
+            callback,
+            ctx,
+            old_start: old_buffer as *mut u8,
+            start: new_buffer as usize,
+            end: new_buffer as usize + new_size,
+        };
+        walk_allocations(migrate_block, addr_of_mut!(m) as *mut c_void);
+    }
+    0
+}
+
+pub unsafe fn fm_sm_migrate(
+    new_buffer: *mut c_void,
+    new_size: usize,
+    callback: Option<FmRelocateCallback>,
+    ctx: *mut c_void,
+) -> c_int {
+    lock();
+    let heap = default_heap();
+    let previous = callback_begin(heap);
+    let result = migrate(new_buffer, new_size, callback, ctx);
+    callback_end(heap, previous);
+    unlock();
+    result
+}
+
+unsafe fn extend(additional_bytes: usize) -> c_int {
+    fm_lm_extend(additional_bytes)
+}
+
+pub unsafe fn fm_sm_extend(additional_bytes: usize) -> c_int {
+    lock();
+    let result = extend(additional_bytes);
+    unlock();
+    result
+}
+
+// Partially used slab in class i with the most used slots, except skipped
+unsafe fn densest_slab(heap: *mut SmHeap, i: usize, skipped: *const PageMeta) -> *mut PageMeta {
+    let mut densest: *mut PageMeta = ptr::null_mut();
+    let head = slab_list(heap, i);
+    let mut iter = (*head).next;
+    while iter != head {
+        let meta = iter as *mut PageMeta;
+        if !ptr::eq(meta, skipped) && (densest.is_null() || used_slots(meta) > used_slots(densest))
+        {
+            densest = meta;
+        }
+        iter = (*iter).next;
+    }
+    densest
+}
+
+unsafe fn move_object(
+    heap: *mut SmHeap,
+    source: *mut PageMeta,
+    index: usize,
+    target: *mut PageMeta,
+    callback: Option<FmRelocateCallback>,
+    ctx: *mut c_void,
+) {
+    let old_ptr = index_to_ptr(source, index);
+    let slot = bitmap_next_free(target);
+    let new_ptr = index_to_ptr(target, slot);
+    ptr::copy_nonoverlapping(old_ptr as *const u8, new_ptr as *mut u8, (*source).size);
+    bitmap_set(target, slot);
+    bitmap_clear(source, index);
+    if bitmap_all_used(target) {
+        list_unlink(addr_of_mut!((*target).link));
+        list_link_before(full_slabs(heap), addr_of_mut!((*target).link));
+    }
+    #[cfg(feature = "test-support")]
+    {
+        let tag = tag_remove(old_ptr);
+        tag_set(new_ptr, tag);
+    }
+    if let Some(callback) = callback {
+        callback(old_ptr, new_ptr, (*source).size, ctx);
+    }
+}
+
+unsafe fn compact(callback: Option<FmRelocateCallback>, ctx: *mut c_void) -> usize {
+    let heap = default_heap();
+    // Quarantined blocks are already freed, they shall never be moved
+    #[cfg(feature = "test-support")]
+    evict_quarantine(0);
+    let mut reclaimed = 0;
+    for i in 0..SLAB_CLASSES {
+        loop {
+            // Empty the sparsest slab, as long as its objects fit in other
+            // slabs
+            let mut source: *mut PageMeta = ptr::null_mut();
+            let mut free_slots = 0;
+            let head = slab_list(heap, i);
+            let mut iter = (*head).next;
+            while iter != head {
+                let meta = iter as *mut PageMeta;
+                free_slots += (*meta).count - used_slots(meta);
+                if source.is_null() || used_slots(meta) < used_slots(source) {
+                    source = meta;
+                }
+                iter = (*iter).next;
+            }
+            if source.is_null() {
+                break;
+            }
+            let used = used_slots(source);
+            if free_slots - ((*source).count - used) < used {
+                break;
+            }
+            for index in 0..(*source).count {
+                if bitmap_is_set(source, index) {
+                    move_object(
+                        heap,
+                        source,
+                        index,
+                        densest_slab(heap, i, source),
+                        callback,
+                        ctx,
+                    );
+                }
+            }
+            list_unlink(addr_of_mut!((*source).link));
+            fm_lm_free(source as *mut c_void);
+            (*heap).slab_pages -= 1;
+            (*heap).class_slabs[i] -= 1;
+            reclaimed += 1;
+        }
+    }
+    reclaimed
+}
+
+pub unsafe fn fm_sm_compact(callback: Option<FmRelocateCallback>, ctx: *mut c_void) -> usize {
+    lock();
+    let heap = default_heap();
+    let previous = callback_begin(heap);
+    let result = compact(callback, ctx);
+    callback_end(heap, previous);
+    unlock();
+    result
+}
+
+unsafe fn verify_slab(meta: *mut PageMeta, error: *mut FmHeapError) -> c_int {
+    let i = (*meta).slab_index;
+    if i >= SLAB_CLASSES
+        || (*meta).size != SLAB_SIZES[i]
+        || (*meta).count != (FM_PAGE_SIZE - PAGE_META_RESERVED_SIZE) / (*meta).size
+    {
+        return report(error, FM_HEAP_CORRUPTED_HEADER, meta);
+    }
+    // Bits beyond the slot count are never set
+    for index in (*meta).count..BITMAP_WORDS * 64 {
+        if bitmap_is_set(meta, index) {
+            return report(error, FM_HEAP_CORRUPTED_HEADER, meta);
+        }
+    }
+    #[cfg(feature = "fill-on-free")]
+    for index in 0..(*meta).count {
+        if bitmap_is_set(meta, index) {
+            continue;
+        }
+        let p = index_to_ptr(meta, index) as *const u8;
+        for offset in 0..(*meta).size {
+            if *p.add(offset) != FM_FILL_PATTERN {
+                let kind = if offset < (*meta).size / 2 {
+                    FM_HEAP_OVERFLOW_GUARD
+                } else {
+                    FM_HEAP_UNDERFLOW_GUARD
+                };
+                return report(error, kind, p);
+            }
+        }
+    }
+    FM_HEAP_OK
+}
+
+// Walk a slab list, full is true for the list of fully used slabs, slabs
+// count the number of slabs seen so far.
+unsafe fn verify_slab_list(
+    heap: *mut SmHeap,
+    list: *mut CList,
+    class_index: usize,
+    full: bool,
+    slabs: &mut usize,
+    error: *mut FmHeapError,
+) -> c_int {
+    let mut iter = (*list).next;
+    while iter != list {
+        *slabs += 1;
+        if (*(*iter).next).prev != iter || *slabs > (*heap).slab_pages {
+            return report(error, FM_HEAP_FREE_LIST_CYCLE, iter);
+        }
+        let meta = iter as *mut PageMeta;
+        if !is_page_aligned(meta) || fm_lm_contains(meta as *const c_void) == 0 {
+            return report(error, FM_HEAP_CORRUPTED_HEADER, iter);
+        }
+        let ret = verify_slab(meta, error);
+        if ret != FM_HEAP_OK {
+            return ret;
+        }
+        if bitmap_all_used(meta) != full || (!full && (*meta).slab_index != class_index) {
+            return report(error, FM_HEAP_CORRUPTED_HEADER, meta);
+        }
+        iter = (*iter).next;
+    }
+    FM_HEAP_OK
+}
+
+unsafe fn verify(error: *mut FmHeapError) -> c_int {
+    let mut ret = fm_lm_verify(error);
+    if ret != FM_HEAP_OK {
+        return ret;
+    }
+    let heap = default_heap();
+    let mut slabs = 0;
+    for i in 0..SLAB_CLASSES {
+        ret = verify_slab_list(heap, slab_list(heap, i), i, false, &mut slabs, error);
+        if ret != FM_HEAP_OK {
+            return ret;
+        }
+    }
+    ret = verify_slab_list(heap, full_slabs(heap), 0, true, &mut slabs, error);
+    if ret != FM_HEAP_OK {
+        return ret;
+    }
+    if slabs != (*heap).slab_pages {
+        // Some slab is no longer linked in any list
+        return report(error, FM_HEAP_CORRUPTED_HEADER, ptr::null::<c_void>());
+    }
+    FM_HEAP_OK
+}
+
+pub unsafe fn fm_sm_verify(error: *mut FmHeapError) -> c_int {
+    lock();
+    let result = verify(error);
+    unlock();
+    result
+}
+
+// Pointers collected per heap walk, since blocks cannot be freed while the
+// heap is being walked
+const FREE_BATCH: usize = 64;
+
+struct FreeAllCtx {
+    ptrs: [*mut c_void; FREE_BATCH],
+    count: usize,
+}
+
+unsafe extern "C" fn collect_block(ptr: *mut c_void, _size: usize, user: *mut c_void) {
+    let ctx = user as *mut FreeAllCtx;
+    if (*ctx).count < FREE_BATCH {
+        (*ctx).ptrs[(*ctx).count] = ptr;
+        (*ctx).count += 1;
+    }
+}
+
+unsafe fn free_all() -> usize {
+    // Quarantined blocks would otherwise stay live forever
+    #[cfg(feature = "test-support")]
+    let quarantine_limit = QUARANTINE_LIMIT.get();
+    #[cfg(feature = "test-support")]
+    {
+        evict_quarantine(0);
+        QUARANTINE_LIMIT.set(0);
+    }
+    let mut freed = 0;
+    let mut ctx = FreeAllCtx {
+        ptrs: [ptr::null_mut(); FREE_BATCH],
+        count: 0,
+    };
+    loop {
+        ctx.count = 0;
+        walk_allocations(collect_block, addr_of_mut!(ctx) as *mut c_void);
+        for i in 0..ctx.count {
+            sm_free(default_heap(), ctx.ptrs[i]);
+        }
+        freed += ctx.count;
+        if ctx.count == 0 {
+            break;
+        }
+    }
+    #[cfg(feature = "test-support")]
+    QUARANTINE_LIMIT.set(quarantine_limit);
+    freed
+}
+
+pub unsafe fn fm_sm_free_all() -> usize {
+    lock();
+    let result = free_all();
+    unlock();
+    result
+}
+
+unsafe fn count_slabs(list: *mut CList) -> usize {
+    let mut count = 0;
+    let mut iter = (*list).next;
+    while iter != list {
+        count += 1;
+        iter = (*iter).next;
+    }
+    count
+}
+
+unsafe fn put_slabs(out: *mut u8, len: usize, pos: &mut usize, list: *mut CList) {
+    let mut iter = (*list).next;
+    while iter != list {
+        let meta = iter as *mut PageMeta;
+        snapshot_put(out, len, pos, bytes_of(&meta), size_of::<*mut PageMeta>());
+        snapshot_put(out, len, pos, bytes_of(meta), size_of::<PageMeta>());
+        iter = (*iter).next;
+    }
+}
+
+// Snapshot layout: size of the linear malloc snapshot followed by itself,
+// a copy of the heap, the number of slabs followed by their addresses and
+// headers, then the quarantine with test-support.
+unsafe fn snapshot(out: *mut c_void, out_len: usize) -> isize {
+    let heap = default_heap();
+    let out = out as *mut u8;
+    let mut pos = 0;
+    let fits = out_len >= size_of::<usize>();
+    let lm = fm_lm_snapshot(
+        if fits {
+            out.add(size_of::<usize>()) as *mut c_void
+        } else {
+            ptr::null_mut()
+        },
+        if fits {
+            out_len - size_of::<usize>()
+        } else {
+            0
+        },
+    );
+    let lm_size = lm.unsigned_abs();
+    snapshot_put(
+        out,
+        out_len,
+        &mut pos,
+        bytes_of(&lm_size),
+        size_of::<usize>(),
+    );
+    pos += lm_size;
+    snapshot_put(out, out_len, &mut pos, bytes_of(heap), size_of::<SmHeap>());
+    let mut count = count_slabs(full_slabs(heap));
+    for i in 0..SLAB_CLASSES {
+        count += count_slabs(slab_list(heap, i));
+    }
+    snapshot_put(out, out_len, &mut pos, bytes_of(&count), size_of::<usize>());
+    put_slabs(out, out_len, &mut pos, full_slabs(heap));
+    for i in 0..SLAB_CLASSES {
+        put_slabs(out, out_len, &mut pos, slab_list(heap, i));
+    }
+    #[cfg(feature = "test-support")]
+    {
+        snapshot_put(
+            out,
+            out_len,
+            &mut pos,
+            bytes_of(QUARANTINE.ptr()),
+            size_of::<[*mut c_void; MAX_QUARANTINE]>(),
+        );
+        for value in [&QUARANTINE_LIMIT, &QUARANTINE_START, &QUARANTINE_COUNT] {
+            snapshot_put(
+                out,
+                out_len,
+                &mut pos,
+                bytes_of(value.ptr()),
+                size_of::<usize>(),
+            );
+        }
+    }
+    if pos <= out_len {
+        pos as isize
+    } else {
+        -(pos as isize)
+    }
+}
+
+pub unsafe fn fm_sm_snapshot(out: *mut c_void, out_len: usize) -> isize {
+    lock();
+    let result = snapshot(out, out_len);
+    unlock();
+    result
+}
+
+unsafe fn restore(input: *const c_void, len: usize) -> c_int {
+    let heap = default_heap();
+    // Validate the slab part first, linear malloc validates its own part
+    let input = input as *const u8;
+    let mut pos = 0;
+    let mut lm_size = 0usize;
+    if snapshot_get(
+        input,
+        len,
+        &mut pos,
+        addr_of_mut!(lm_size) as *mut u8,
+        size_of::<usize>(),
+    ) || snapshot_get(input, len, &mut pos, ptr::null_mut(), lm_size)
+    {
+        return FM_ERR_BAD_SNAPSHOT;
+    }
+    let mut copy = SmHeap::EMPTY;
+    let mut count = 0usize;
+    if snapshot_get(
+        input,
+        len,
+        &mut pos,
+        addr_of_mut!(copy) as *mut u8,
+        size_of::<SmHeap>(),
+    ) || copy.lm != (*heap).lm
+        || snapshot_get(
+            input,
+            len,
+            &mut pos,
+            addr_of_mut!(count) as *mut u8,
+            size_of::<usize>(),
+        )
+    {
+        return FM_ERR_BAD_SNAPSHOT;
+    }
+    let slabs_start = pos;
+    for _ in 0..count {
+        let mut meta: *mut PageMeta = ptr::null_mut();
+        if snapshot_get(
+            input,
+            len,
+            &mut pos,
+            addr_of_mut!(meta) as *mut u8,
+            size_of::<*mut PageMeta>(),
+        ) || !is_page_aligned(meta)
+            || fm_lm_contains(meta as *const c_void) == 0
+            || snapshot_get(input, len, &mut pos, ptr::null_mut(), size_of::<PageMeta>())
+        {
+            return FM_ERR_BAD_SNAPSHOT;
+        }
+    }
+    #[cfg(feature = "test-support")]
+    let quarantine_start = pos;
+    #[cfg(feature = "test-support")]
+    if snapshot_get(
+        input,
+        len,
+        &mut pos,
+        ptr::null_mut(),
+        size_of::<[*mut c_void; MAX_QUARANTINE]>() + 3 * size_of::<usize>(),
+    ) {
+        return FM_ERR_BAD_SNAPSHOT;
+    }
+    if pos != len {
+        return FM_ERR_BAD_SNAPSHOT;
+    }
+    let ret = fm_lm_restore(input.add(size_of::<usize>()) as *const c_void, lm_size);
+    if ret != 0 {
+        return ret;
+    }
+
+    pos = slabs_start;
+    for _ in 0..count {
+        let mut meta: *mut PageMeta = ptr::null_mut();
+        snapshot_get(
+            input,
+            len,
+            &mut pos,
+            addr_of_mut!(meta) as *mut u8,
+            size_of::<*mut PageMeta>(),
+        );
+        snapshot_get(input, len, &mut pos, meta as *mut u8, size_of::<PageMeta>());
+    }
+    ptr::copy_nonoverlapping(&copy, heap, 1);
+    #[cfg(feature = "test-support")]
+    {
+        pos = quarantine_start;
+        snapshot_get(
+            input,
+            len,
+            &mut pos,
+            QUARANTINE.ptr() as *mut u8,
+            size_of::<[*mut c_void; MAX_QUARANTINE]>(),
+        );
+        for value in [&QUARANTINE_LIMIT, &QUARANTINE_START, &QUARANTINE_COUNT] {
+            snapshot_get(
+                input,
+                len,
+                &mut pos,
+                value.ptr() as *mut u8,
+                size_of::<usize>(),
+            );
+        }
+    }
+    0
+}
+
+pub unsafe fn fm_sm_restore(input: *const c_void, len: usize) -> c_int {
+    lock();
+    let result = restore(input, len);
+    unlock();
+    result
+}
@@ -0,0 +1,133 @@
+use crate::{malloc_size, FixedAlloc};
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut, Index, IndexMut};
+use core::ptr::{self, NonNull};
+
+/// Types for which all-zero bytes are a valid value, which
+/// `FixedAlloc::alloc_slice_zeroed` hands out without initializing them
+///
+/// # Safety
+///
+/// An all-zero bit pattern must be a valid value of the implementing type.
+pub unsafe trait Zeroable {}
+
+macro_rules! impl_zeroable {
+    ($($t:ty),*) => {
+        $(unsafe impl Zeroable for $t {})*
+    };
+}
+
+impl_zeroable!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char
+);
+
+unsafe impl<T> Zeroable for *const T {}
+unsafe impl<T> Zeroable for *mut T {}
+unsafe impl<T> Zeroable for MaybeUninit<T> {}
+unsafe impl<T: Zeroable, const N: usize> Zeroable for [T; N] {}
+
+// Fixed length array allocated from `FixedAlloc`, for programs without
+// `Vec`. Elements are dropped and the memory is freed when it goes out of
+// scope.
+pub struct FixedAllocSlice<'a, T> {
+    alloc: &'a FixedAlloc,
+    ptr: NonNull<T>,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T> FixedAllocSlice<'a, T> {
+    // Allocate memory for `len` values of `T` through `GlobalAlloc` of
+    // `alloc`, empty slices take no memory at all.
+    pub(crate) fn allocate(alloc: &'a FixedAlloc, len: usize, zeroed: bool) -> Option<Self> {
+        let layout = Layout::array::<T>(len).ok()?;
+        malloc_size(layout)?;
+        let ptr = if layout.size() == 0 {
+            NonNull::dangling()
+        } else if zeroed {
+            NonNull::new(unsafe { alloc.alloc_zeroed(layout) } as *mut T)?
+        } else {
+            NonNull::new(unsafe { alloc.alloc(layout) } as *mut T)?
+        };
+        Some(Self {
+            alloc,
+            ptr,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+}
+
+impl<'a, T> FixedAllocSlice<'a, MaybeUninit<T>> {
+    /// Treat the elements as initialized, e.g. after writing all of them
+    ///
+    /// # Safety
+    ///
+    /// Every element must be initialized.
+    pub unsafe fn assume_init(self) -> FixedAllocSlice<'a, T> {
+        let this = ManuallyDrop::new(self);
+        FixedAllocSlice {
+            alloc: this.alloc,
+            ptr: this.ptr.cast(),
+            len: this.len,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for FixedAllocSlice<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for FixedAllocSlice<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Index<usize> for FixedAllocSlice<'_, T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        &(**self)[index]
+    }
+}
+
+impl<T> IndexMut<usize> for FixedAllocSlice<'_, T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        &mut (**self)[index]
+    }
+}
+
+impl<T> Drop for FixedAllocSlice<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(&mut **self as *mut [T]);
+            let layout = Layout::array::<T>(self.len).unwrap();
+            if layout.size() != 0 {
+                self.alloc.dealloc(self.ptr.as_ptr() as *mut u8, layout);
+            }
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for FixedAllocSlice<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::FixedAlloc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::fmt;
+use core::mem::{align_of, size_of};
+use core::ptr::NonNull;
+
+// Short writes share one segment at least this large
+const MIN_SEGMENT_BYTES: usize = 64;
+
+// Header of a segment, its bytes directly follow
+struct Segment {
+    next: Option<NonNull<Segment>>,
+    len: usize,
+    cap: usize,
+}
+
+impl Segment {
+    fn layout(cap: usize) -> Option<Layout> {
+        Layout::from_size_align(
+            size_of::<Segment>().checked_add(cap)?,
+            align_of::<Segment>(),
+        )
+        .ok()
+    }
+
+    unsafe fn bytes(seg: NonNull<Segment>) -> *mut u8 {
+        (seg.as_ptr() as *mut u8).add(size_of::<Segment>())
+    }
+
+    unsafe fn contents<'s>(seg: NonNull<Segment>) -> &'s [u8] {
+        core::slice::from_raw_parts(Segment::bytes(seg), seg.as_ref().len)
+    }
+
+    unsafe fn room(seg: NonNull<Segment>) -> usize {
+        seg.as_ref().cap - seg.as_ref().len
+    }
+}
+
+// String built via `core::fmt::Write` in memory of a `FixedAlloc`, so text
+// can be formatted without `alloc::string::String`. Writes are appended to
+// the last segment while it has room, otherwise a new segment is linked in,
+// hence written text is never copied until `as_str` needs one slice.
+pub struct BumpString<'a> {
+    alloc: &'a FixedAlloc,
+    head: Cell<Option<NonNull<Segment>>>,
+    tail: Cell<Option<NonNull<Segment>>>,
+    len: usize,
+}
+
+impl<'a> BumpString<'a> {
+    pub fn new_in(alloc: &'a FixedAlloc) -> Self {
+        Self {
+            alloc,
+            head: Cell::new(None),
+            tail: Cell::new(None),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Contents as one contiguous slice. Segments are first merged into a new
+    // segment if there is more than one, the allocation error handler is
+    // invoked when the heap cannot hold the merged copy.
+    pub fn as_str(&self) -> &str {
+        let head = match self.head.get() {
+            Some(head) => head,
+            None => return "",
+        };
+        let seg = if unsafe { head.as_ref() }.next.is_none() {
+            head
+        } else {
+            let seg = match self.alloc_segment(self.len) {
+                Some(seg) => seg,
+                None => crate::handle_alloc_error(Segment::layout(self.len).unwrap()),
+            };
+            let mut iter = Some(head);
+            while let Some(s) = iter {
+                unsafe { self.append(seg, Segment::contents(s)) };
+                iter = unsafe { s.as_ref() }.next;
+            }
+            self.free_segments(head);
+            self.head.set(Some(seg));
+            self.tail.set(Some(seg));
+            seg
+        };
+        // Only complete `&str` chunks are ever written
+        unsafe { core::str::from_utf8_unchecked(Segment::contents(seg)) }
+    }
+
+    fn alloc_segment(&self, min_cap: usize) -> Option<NonNull<Segment>> {
+        let layout = Segment::layout(min_cap.max(MIN_SEGMENT_BYTES))?;
+        let ptr = unsafe { self.alloc.alloc(layout) };
+        let seg = NonNull::new(ptr as *mut Segment)?;
+        // The rest of the size class is used as well
+        let cap = self.alloc.shrink_to_fit_class(ptr, layout).size() - size_of::<Segment>();
+        unsafe {
+            seg.as_ptr().write(Segment {
+                next: None,
+                len: 0,
+                cap,
+            })
+        };
+        Some(seg)
+    }
+
+    // `seg` must have room for `bytes`
+    unsafe fn append(&self, seg: NonNull<Segment>, bytes: &[u8]) {
+        let s = &mut *seg.as_ptr();
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), Segment::bytes(seg).add(s.len), bytes.len());
+        s.len += bytes.len();
+    }
+
+    fn free_segments(&self, head: NonNull<Segment>) {
+        let mut iter = Some(head);
+        while let Some(seg) = iter {
+            unsafe {
+                let Segment { next, cap, .. } = seg.as_ptr().read();
+                self.alloc.dealloc(
+                    seg.as_ptr() as *mut u8,
+                    Layout::from_size_align_unchecked(
+                        size_of::<Segment>() + cap,
+                        align_of::<Segment>(),
+                    ),
+                );
+                iter = next;
+            }
+        }
+    }
+}
+
+impl fmt::Write for BumpString<'_> {
+    // `fmt::Error` is returned when the heap is exhausted
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.is_empty() {
+            return Ok(());
+        }
+        let tail = self.tail.get();
+        let seg = match tail {
+            Some(tail) if unsafe { Segment::room(tail) } >= s.len() => tail,
+            _ => {
+                let seg = self.alloc_segment(s.len()).ok_or(fmt::Error)?;
+                match tail {
+                    Some(tail) => unsafe { (*tail.as_ptr()).next = Some(seg) },
+                    None => self.head.set(Some(seg)),
+                }
+                self.tail.set(Some(seg));
+                seg
+            }
+        };
+        unsafe { self.append(seg, s.as_bytes()) };
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+impl Drop for BumpString<'_> {
+    fn drop(&mut self) {
+        if let Some(head) = self.head.get() {
+            self.free_segments(head);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use crate::lock::{forward_locked, with_heap_lock, Locked, Section};
+use crate::FixedAlloc;
+
+// Nothing but the spinlock shared by all wrappers
+pub(crate) struct Spin;
+
+impl Section for Spin {
+    fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        with_heap_lock(f)
+    }
+}
+
+// Wraps `FixedAlloc` with a spinlock, so the C allocator is only entered by
+// one thread at a time. The lock is shared with the other lock wrappers, as
+// they all use the same global heap.
+pub struct SyncAlloc {
+    inner: Locked<Spin>,
+}
+
+impl SyncAlloc {
+    pub const fn new(alloc: FixedAlloc) -> Self {
+        Self {
+            inner: Locked::new(alloc, Spin),
+        }
+    }
+}
+
+forward_locked!(SyncAlloc);
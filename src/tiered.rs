@@ -0,0 +1,118 @@
+use crate::error::FmError;
+use crate::{ffi, AllocType, FixedAlloc, LinearAlloc};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Range;
+use core::ptr::{self, NonNull};
+
+// Largest object served by slabs, which is the largest size class. Anything
+// larger ends up in linear malloc anyway, so it skips slab malloc entirely.
+pub const TIERED_SMALL_MAX: usize = ffi::FM_PAGE_SIZE / 4;
+
+// Serves objects of up to `TIERED_SMALL_MAX` bytes from slabs via `small`,
+// and larger ones as whole pages via `large`. Each tier has its own buffer as
+// set up by `FixedAlloc::new_split`, so pointers are routed back by address.
+// Like `FixedAlloc`, sharing it across threads requires a lock unless
+// `single-threaded` is enabled.
+pub struct TieredAlloc {
+    small: FixedAlloc,
+    large: LinearAlloc,
+    small_range: Range<usize>,
+}
+
+impl TieredAlloc {
+    /// # Safety
+    ///
+    /// See `FixedAlloc::new_split`.
+    pub unsafe fn new(
+        small_buffer: *mut u8,
+        small_len: usize,
+        large_buffer: *mut u8,
+        large_len: usize,
+        zero_filled: bool,
+    ) -> Result<Self, FmError> {
+        let small = FixedAlloc::new_split(
+            small_buffer,
+            small_len,
+            large_buffer,
+            large_len,
+            zero_filled,
+        )?;
+        let large = small.linear();
+        let start = small_buffer as usize;
+        Ok(Self {
+            small,
+            large,
+            small_range: start..start + small_len,
+        })
+    }
+
+    pub fn small(&self) -> &FixedAlloc {
+        &self.small
+    }
+
+    pub fn large(&self) -> &LinearAlloc {
+        &self.large
+    }
+
+    // Whether `ptr` lies in the buffer of small objects
+    pub fn contains_small(&self, ptr: *const u8) -> bool {
+        self.small_range.contains(&(ptr as usize))
+    }
+
+    fn is_small(layout: Layout) -> bool {
+        layout.size() <= TIERED_SMALL_MAX && layout.align() <= ffi::FM_MIN_ALIGN
+    }
+}
+
+unsafe impl GlobalAlloc for TieredAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if Self::is_small(layout) {
+            return self.small.alloc(layout);
+        }
+        self.large
+            .alloc_aligned(layout, AllocType::Transient)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if Self::is_small(layout) {
+            return self.small.alloc_zeroed(layout);
+        }
+        self.large
+            .alloc_zeroed(layout, AllocType::Transient)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.contains_small(ptr) {
+            self.small.dealloc(ptr, layout)
+        } else {
+            self.large.free(NonNull::new_unchecked(ptr))
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (self.contains_small(ptr), Self::is_small(new_layout)) {
+            (true, true) => self.small.realloc(ptr, layout, new_size),
+            (false, false) => self
+                .large
+                .realloc(
+                    NonNull::new_unchecked(ptr),
+                    layout,
+                    new_size,
+                    AllocType::Transient,
+                )
+                .map_or(ptr::null_mut(), NonNull::as_ptr),
+            _ => {
+                // Moving between tiers
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
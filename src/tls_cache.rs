@@ -0,0 +1,241 @@
+// Thread-local front-end keeping recently freed slab objects, so repeated
+// malloc and free of the same size on one thread never reach the C free
+// lists. Each size class has a bounded magazine, which is partly flushed back
+// to the inner allocator when it overflows, and fully when the thread exits.
+use crate::atomic::{AtomicUsize, Ordering};
+use crate::ffi;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::ptr;
+
+// Same as `slab_sizes` in slab-malloc.c, classes below `FM_SLAB_MIN_SIZE`
+// are never used
+const CLASS_SIZES: [usize; 6] = [
+    16,
+    32,
+    64,
+    128,
+    ffi::FM_PAGE_SIZE / 8,
+    ffi::FM_PAGE_SIZE / 4,
+];
+const MAGAZINE_SIZE: usize = 32;
+
+// Bumped whenever live allocations are dropped or moved, which invalidates
+// the blocks cached by all threads.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+fn class_of(layout: Layout) -> Option<usize> {
+    if layout.align() > ffi::FM_MIN_ALIGN {
+        return None;
+    }
+    let size = layout.size().max(ffi::FM_SLAB_MIN_SIZE);
+    CLASS_SIZES.iter().position(|class| size <= *class)
+}
+
+fn class_layout(class: usize) -> Layout {
+    unsafe { Layout::from_size_align_unchecked(CLASS_SIZES[class], ffi::FM_MIN_ALIGN) }
+}
+
+struct Magazine {
+    len: usize,
+    blocks: [*mut u8; MAGAZINE_SIZE],
+}
+
+struct Cache {
+    // Allocator all cached blocks are returned to
+    owner: Option<&'static dyn GlobalAlloc>,
+    generation: usize,
+    magazines: [Magazine; CLASS_SIZES.len()],
+}
+
+impl Cache {
+    const fn new() -> Self {
+        const EMPTY: Magazine = Magazine {
+            len: 0,
+            blocks: [ptr::null_mut(); MAGAZINE_SIZE],
+        };
+        Self {
+            owner: None,
+            generation: 0,
+            magazines: [EMPTY; CLASS_SIZES.len()],
+        }
+    }
+
+    // Make sure all cached blocks belong to `owner` and the current heap
+    fn claim(&mut self, owner: &'static dyn GlobalAlloc) {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if self.generation != generation {
+            // Blocks of a previous heap are simply dropped
+            for magazine in self.magazines.iter_mut() {
+                magazine.len = 0;
+            }
+            self.generation = generation;
+        }
+        match self.owner {
+            Some(current) if ptr::addr_eq(current, owner) => (),
+            _ => {
+                self.flush();
+                self.owner = Some(owner);
+            }
+        }
+    }
+
+    // Return the oldest blocks of `class` until only `keep` are left
+    fn release(&mut self, class: usize, keep: usize) {
+        let magazine = &mut self.magazines[class];
+        if let Some(owner) = self.owner {
+            let released = magazine.len.saturating_sub(keep);
+            for block in &magazine.blocks[..released] {
+                unsafe { owner.dealloc(*block, class_layout(class)) };
+            }
+            magazine.blocks.copy_within(released..magazine.len, 0);
+            magazine.len -= released;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.generation != GENERATION.load(Ordering::Relaxed) {
+            return;
+        }
+        for class in 0..CLASS_SIZES.len() {
+            self.release(class, 0);
+        }
+    }
+
+    fn pop(&mut self, class: usize) -> Option<*mut u8> {
+        let magazine = &mut self.magazines[class];
+        if magazine.len == 0 {
+            return None;
+        }
+        magazine.len -= 1;
+        Some(magazine.blocks[magazine.len])
+    }
+
+    fn push(&mut self, class: usize, block: *mut u8) {
+        if self.magazines[class].len == MAGAZINE_SIZE {
+            self.release(class, MAGAZINE_SIZE / 2);
+        }
+        let magazine = &mut self.magazines[class];
+        magazine.blocks[magazine.len] = block;
+        magazine.len += 1;
+    }
+
+    fn cached(&self) -> usize {
+        self.magazines.iter().map(|magazine| magazine.len).sum()
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+std::thread_local! {
+    static CACHE: RefCell<Cache> = const { RefCell::new(Cache::new()) };
+}
+
+// Run `f` on the cache of the current thread, `None` is returned when the
+// cache is unavailable, such as while it is being destroyed.
+fn with_cache<R, F: FnOnce(&mut Cache) -> R>(f: F) -> Option<R> {
+    CACHE
+        .try_with(|cache| cache.try_borrow_mut().ok().map(|mut cache| f(&mut cache)))
+        .ok()
+        .flatten()
+}
+
+// Wraps an allocator backed by the C heap, such as `SyncAlloc`, serving slab
+// sized allocations from the thread-local cache when possible. Blocks are
+// always requested from `alloc` with the full size of their size classes.
+//
+// Cached blocks still count as live allocations, call `flush` on the thread
+// before `try_reinitialize`. Reinitializing by any other means, restoring a
+// snapshot, `migrate` and `compact` discard the cached blocks of all threads,
+// the latter two without freeing them, so flush before those as well.
+pub struct TlsCacheAlloc<A> {
+    alloc: A,
+}
+
+impl<A: GlobalAlloc + 'static> TlsCacheAlloc<A> {
+    /// # Safety
+    ///
+    /// The result must live as long as any thread caches blocks from it,
+    /// which holds when it is kept in a `static`, e.g. as the global
+    /// allocator.
+    pub const unsafe fn new(alloc: A) -> Self {
+        Self { alloc }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.alloc
+    }
+
+    fn owner(&self) -> &'static dyn GlobalAlloc {
+        // Lifetime is extended as required by `new`
+        unsafe { &*(&self.alloc as *const A) }
+    }
+
+    // Hand all blocks cached by the current thread back to the inner
+    // allocator
+    pub fn flush(&self) {
+        with_cache(|cache| cache.flush());
+    }
+
+    // Number of blocks cached by the current thread
+    pub fn cached_blocks(&self) -> usize {
+        with_cache(|cache| {
+            cache.claim(self.owner());
+            cache.cached()
+        })
+        .unwrap_or(0)
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for TlsCacheAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = match class_of(layout) {
+            Some(class) => class,
+            None => return self.alloc.alloc(layout),
+        };
+        with_cache(|cache| {
+            cache.claim(self.owner());
+            cache.pop(class)
+        })
+        .flatten()
+        .unwrap_or_else(|| self.alloc.alloc(class_layout(class)))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let class = match class_of(layout) {
+            Some(class) => class,
+            None => return self.alloc.dealloc(ptr, layout),
+        };
+        let cached = with_cache(|cache| {
+            cache.claim(self.owner());
+            cache.push(class, ptr);
+        });
+        if cached.is_none() {
+            self.alloc.dealloc(ptr, class_layout(class));
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (class_of(layout), class_of(new_layout)) {
+            (Some(old), Some(new)) if old == new => ptr,
+            (None, None) => self.alloc.realloc(ptr, layout, new_size),
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::FixedAlloc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+// Handle to a `FixedAlloc`, which lets containers be generic over whether
+// they borrow the allocator or share ownership of it. Like `GlobalAlloc`,
+// `alloc` returns null when the heap is exhausted, and also for zero-sized
+// layouts, which `GlobalAlloc::alloc` leaves undefined.
+pub trait FixedAllocRef: Clone {
+    fn alloc(&self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    ///
+    /// `ptr` must be allocated from the same `FixedAlloc` with `layout`.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+}
+
+fn alloc_from(alloc: &FixedAlloc, layout: Layout) -> *mut u8 {
+    if layout.size() == 0 {
+        return core::ptr::null_mut();
+    }
+    unsafe { GlobalAlloc::alloc(alloc, layout) }
+}
+
+impl FixedAllocRef for &FixedAlloc {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc_from(self, layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        GlobalAlloc::dealloc(*self, ptr, layout)
+    }
+}
+
+// `GlobalAlloc` cannot be implemented for `Arc` outside of `alloc` itself, a
+// shared allocator can still be used through `FixedAllocRef`. Note that
+// `Arc<FixedAlloc>` is only `Send` with the `single-threaded` feature, `Rc`
+// serves the same purpose otherwise.
+#[cfg(feature = "alloc")]
+impl FixedAllocRef for alloc::sync::Arc<FixedAlloc> {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc_from(self, layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        GlobalAlloc::dealloc(&**self, ptr, layout)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl FixedAllocRef for alloc::rc::Rc<FixedAlloc> {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc_from(self, layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        GlobalAlloc::dealloc(&**self, ptr, layout)
+    }
+}
+
+// Owning pointer which always returns its value to the allocator it is
+// allocated from, so it can never be freed into the wrong heap.
+pub struct Tracked<T, A: FixedAllocRef> {
+    ptr: NonNull<T>,
+    alloc: A,
+}
+
+impl<T, A: FixedAllocRef> Tracked<T, A> {
+    // Move `val` into memory allocated from `alloc`, `None` is returned when
+    // the heap is exhausted or `T` is zero-sized.
+    pub fn new_in(val: T, alloc: A) -> Option<Self> {
+        let ptr = NonNull::new(alloc.alloc(Layout::new::<T>()) as *mut T)?;
+        unsafe { ptr.as_ptr().write(val) };
+        Some(Self { ptr, alloc })
+    }
+
+    pub fn as_ptr(this: &Self) -> *const T {
+        this.ptr.as_ptr()
+    }
+
+    pub fn allocator(this: &Self) -> &A {
+        &this.alloc
+    }
+}
+
+impl<T, A: FixedAllocRef> Deref for Tracked<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, A: FixedAllocRef> DerefMut for Tracked<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T, A: FixedAllocRef> Drop for Tracked<T, A> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            self.alloc
+                .dealloc(self.ptr.as_ptr() as *mut u8, Layout::new::<T>());
+        }
+    }
+}
+
+impl<T: fmt::Debug, A: FixedAllocRef> fmt::Debug for Tracked<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
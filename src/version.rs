@@ -0,0 +1,50 @@
+use crate::ffi;
+
+// Version of the linked allocator, unpacked from fm_version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+// Options the linked allocator is built with, unpacked from fm_features.
+// They follow the cargo features of the same names, guards come with
+// test-support and the debug callback with debug-hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub guards: bool,
+    pub test_support: bool,
+    pub manual_init: bool,
+    pub hardening: bool,
+    pub fill_on_free: bool,
+    pub debug_callback: bool,
+    pub trap_reentrant: bool,
+}
+
+impl Features {
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            guards: bits & ffi::FM_FEATURE_GUARDS != 0,
+            test_support: bits & ffi::FM_FEATURE_TEST_SUPPORT != 0,
+            manual_init: bits & ffi::FM_FEATURE_MANUAL_INIT != 0,
+            hardening: bits & ffi::FM_FEATURE_HARDENING != 0,
+            fill_on_free: bits & ffi::FM_FEATURE_FILL_ON_FREE != 0,
+            debug_callback: bits & ffi::FM_FEATURE_DEBUG_CALLBACK != 0,
+            trap_reentrant: bits & ffi::FM_FEATURE_TRAP_REENTRANT != 0,
+        }
+    }
+}
+
+pub fn version() -> Version {
+    let packed = unsafe { ffi::fm_version() };
+    Version {
+        major: packed >> 16,
+        minor: (packed >> 8) & 0xff,
+        patch: packed & 0xff,
+    }
+}
+
+pub fn features() -> Features {
+    Features::from_bits(unsafe { ffi::fm_features() })
+}
@@ -0,0 +1,52 @@
+use std::env;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_BUFFER_SECTION");
+
+    // Output directory of the C library built for fixed-malloc, which
+    // symbol tests inspect
+    let root = env::var("DEP_FIXED_MALLOC_ROOT").expect("DEP_FIXED_MALLOC_ROOT");
+    println!("cargo:rustc-env=FIXED_MALLOC_C_LIB_DIR={}", root);
+
+    // Bindings generated from the C header with ffi-check, which the drift
+    // test compares with src/ffi.rs
+    if let Ok(bindings) = env::var("DEP_FIXED_MALLOC_BINDINGS") {
+        println!("cargo:rustc-env=FIXED_MALLOC_BINDINGS={}", bindings);
+    }
+
+    // C code using the installed header, which header tests call into. The
+    // pure Rust allocator exports no C symbols to link against.
+    let include = env::var("DEP_FIXED_MALLOC_INCLUDE").expect("DEP_FIXED_MALLOC_INCLUDE");
+    println!("cargo:rustc-env=FIXED_MALLOC_INCLUDE_DIR={}", include);
+    println!("cargo:rustc-check-cfg=cfg(c_header_check)");
+    if env::var_os("CARGO_FEATURE_PURE_RUST").is_none() {
+        println!("cargo:rerun-if-changed=c/header_check.c");
+        cc::Build::new()
+            .file("c/header_check.c")
+            .include(&include)
+            .compile("header-check");
+        println!("cargo:rustc-cfg=c_header_check");
+    }
+
+    // C code calling plain malloc and friends, which libc-symbols provides.
+    // On hosts they must be renamed to stay clear of the C library there.
+    println!("cargo:rerun-if-env-changed=FIXED_MALLOC_LIBC_PREFIX");
+    if env::var_os("CARGO_FEATURE_LIBC_SYMBOLS").is_some() {
+        println!("cargo:rerun-if-changed=c/libc_symbols_check.c");
+        let mut build = cc::Build::new();
+        match env::var("FIXED_MALLOC_LIBC_PREFIX") {
+            Ok(prefix) => {
+                for name in ["malloc", "free", "calloc", "realloc", "malloc_usable_size"] {
+                    build.define(name, format!("{}{}", prefix, name).as_str());
+                }
+            }
+            Err(_) if env::var("TARGET") == env::var("HOST") => {
+                panic!("Testing libc-symbols on the host requires FIXED_MALLOC_LIBC_PREFIX")
+            }
+            Err(_) => (),
+        }
+        build
+            .file("c/libc_symbols_check.c")
+            .compile("libc-symbols-check");
+    }
+}
@@ -0,0 +1,28 @@
+// Linked against the libraries packaged by fixed-malloc-capi, either of them
+// must work on its own. The exit code tells which check failed.
+#include <fixed_malloc.h>
+
+static char buffer[32 * FM_PAGE_SIZE] __attribute__((aligned(FM_PAGE_SIZE)));
+
+int main(void) {
+  if (fm_abi_version() != FM_ABI_VERSION) {
+    return 1;
+  }
+  if (fm_sm_reinit(buffer, sizeof(buffer), 1) != FM_OK) {
+    return 2;
+  }
+  void *small = fm_sm_malloc(17);
+  void *large = fm_sm_malloc(3 * FM_PAGE_SIZE);
+  if (small == NULL || large == NULL) {
+    return 3;
+  }
+  if (fm_sm_live_allocations() != 2) {
+    return 4;
+  }
+  fm_sm_free(large);
+  fm_sm_free(small);
+  if (fm_sm_live_allocations() != 0) {
+    return 5;
+  }
+  return (fm_last_error() == FM_OK) ? 0 : 6;
+}
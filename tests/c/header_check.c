@@ -0,0 +1,18 @@
+// Compiled against the header installed by fixed-malloc, so declarations
+// drifting from the library fail to compile or to link.
+#include <fixed_malloc.h>
+
+int fm_header_check(void) {
+  fm_clear_error();
+  void *small = fm_sm_malloc(17);
+  void *large = fm_sm_malloc(3 * FM_PAGE_SIZE);
+  if (small == NULL || large == NULL) {
+    return 1;
+  }
+  if (fm_sm_usable_size(large) < 3 * FM_PAGE_SIZE) {
+    return 2;
+  }
+  fm_sm_free(large);
+  fm_sm_free(small);
+  return (fm_last_error() == FM_OK) ? 0 : 3;
+}
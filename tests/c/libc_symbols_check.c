@@ -0,0 +1,22 @@
+// Calls the standard allocation functions as any C library would. Hosts
+// build this with the names prefixed via FIXED_MALLOC_LIBC_PREFIX, so the
+// calls reach libc-symbols instead of the C library of the host.
+#include <stddef.h>
+
+void *malloc(size_t size);
+void free(void *ptr);
+void *calloc(size_t n, size_t size);
+void *realloc(void *ptr, size_t size);
+size_t malloc_usable_size(void *ptr);
+
+void *fm_libc_check_malloc(size_t size) { return malloc(size); }
+
+void fm_libc_check_free(void *ptr) { free(ptr); }
+
+void *fm_libc_check_calloc(size_t n, size_t size) { return calloc(n, size); }
+
+void *fm_libc_check_realloc(void *ptr, size_t size) {
+  return realloc(ptr, size);
+}
+
+size_t fm_libc_check_usable_size(void *ptr) { return malloc_usable_size(ptr); }
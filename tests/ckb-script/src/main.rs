@@ -0,0 +1,26 @@
+// Example of a CKB script using fixed-malloc as its heap. Build it with
+// `cargo build` from this directory, then run the binary in CKB-VM, e.g. via
+// ckb-debugger: its exit code is 0 when the allocations succeed.
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use fixed_malloc::ckb;
+
+fixed_malloc::ckb_global_allocator!();
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    ckb::init();
+    let mut values = Vec::new();
+    values.extend(0..1000u32);
+    let sum: u32 = values.iter().sum();
+    ckb::exit(if sum == 499500 { 0 } else { 1 })
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    ckb::exit(-1)
+}
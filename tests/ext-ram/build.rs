@@ -0,0 +1,10 @@
+use std::env;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR");
+    println!("cargo:rerun-if-changed=ext_ram.ld");
+    println!("cargo:rustc-link-arg=-T{}/ext_ram.ld", dir);
+    // Running on the host, startup code and memset come from the C library.
+    // Embedded targets get them from their runtime crate instead.
+    println!("cargo:rustc-link-lib=c");
+}
@@ -0,0 +1,45 @@
+// Example of a no_std program serving its heap from a buffer placed in a
+// dedicated linker section. Build and run it with `cargo run` from this
+// directory, the exit code is 0 when the heap lives in .ext_ram.bss.
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use fixed_malloc::FixedAlloc;
+
+#[global_allocator]
+static ALLOC: FixedAlloc = unsafe { FixedAlloc::new_static() };
+
+extern "C" {
+    static __ext_ram_start: u8;
+    static __ext_ram_end: u8;
+}
+
+#[no_mangle]
+pub extern "C" fn main(_argc: isize, _argv: *const *const u8) -> isize {
+    let mut values = Vec::new();
+    values.extend(0..1000u32);
+    let p = values.as_ptr() as usize;
+    let (start, end) = unsafe {
+        (
+            &__ext_ram_start as *const u8 as usize,
+            &__ext_ram_end as *const u8 as usize,
+        )
+    };
+    if start % fixed_malloc::ffi::FM_PAGE_SIZE != 0 || p < start || p >= end {
+        return 1;
+    }
+    0
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// The prebuilt alloc crate of the host still refers to the unwinding
+// personality, which is never called with panic = "abort"
+#[no_mangle]
+extern "C" fn rust_eh_personality() {}
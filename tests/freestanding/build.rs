@@ -0,0 +1,8 @@
+fn main() {
+    // Neither startup code nor the C library are linked in, and unlike on
+    // bare-metal targets, compiler-builtins leaves the mem functions to the
+    // C library here
+    println!("cargo:rustc-link-arg=-nostartfiles");
+    println!("cargo:rustc-link-arg=-nostdlib");
+    println!("cargo:rustc-link-arg=-static");
+}
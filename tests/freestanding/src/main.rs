@@ -0,0 +1,53 @@
+// Program linked without any C library, so memcpy, memset and memmove come
+// from the freestanding-mem feature, and linking fails without it. Build and
+// run it with `cargo run` from this directory on x86_64 Linux, the exit code
+// is 0 when all checks pass.
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use fixed_malloc::FixedAlloc;
+
+#[global_allocator]
+static ALLOC: FixedAlloc = unsafe { FixedAlloc::new_static() };
+
+fn exit(code: i32) -> ! {
+    unsafe {
+        core::arch::asm!("syscall", in("rax") 60, in("rdi") code, options(noreturn));
+    }
+}
+
+fn run() -> i32 {
+    // Growing goes through realloc, which copies via memcpy
+    let mut values: Vec<u32> = Vec::new();
+    values.extend(0..1000);
+    if !values.iter().copied().eq(0..1000) {
+        return 1;
+    }
+    values.copy_within(10..20, 0);
+    if !values[..10].iter().copied().eq(10..20) {
+        return 2;
+    }
+    let zeroed = alloc::vec![0u8; 5000];
+    if zeroed.iter().any(|b| *b != 0) {
+        return 3;
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    exit(run())
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    exit(101)
+}
+
+// The prebuilt alloc crate of the host still refers to the unwinding
+// personality, which is never called with panic = "abort"
+#[no_mangle]
+extern "C" fn rust_eh_personality() {}
@@ -0,0 +1,46 @@
+// Example of a no_std program serving its heap from a region defined by the
+// linker script. Build and run it with `cargo run` from this directory, the
+// exit code is 0 when allocations land between _heap_start and _heap_end.
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use fixed_malloc::FixedAlloc;
+
+#[global_allocator]
+static ALLOC: FixedAlloc = unsafe { FixedAlloc::new_static_uninit() };
+
+extern "C" {
+    static _heap_start: u8;
+    static _heap_end: u8;
+}
+
+#[no_mangle]
+pub extern "C" fn main(_argc: isize, _argv: *const *const u8) -> isize {
+    if unsafe { FixedAlloc::from_linker_symbols() }.is_err() {
+        return 1;
+    }
+    let mut values = Vec::new();
+    values.extend(0..1000u32);
+    let p = values.as_ptr() as usize;
+    let (start, end) = (
+        core::ptr::addr_of!(_heap_start) as usize,
+        core::ptr::addr_of!(_heap_end) as usize,
+    );
+    if p < start || p >= end {
+        return 2;
+    }
+    0
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// The prebuilt alloc crate of the host still refers to the unwinding
+// personality, which is never called with panic = "abort"
+#[no_mangle]
+extern "C" fn rust_eh_personality() {}
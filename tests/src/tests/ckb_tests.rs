@@ -0,0 +1,68 @@
+use fixed_malloc::ckb::{init_with, oom_exit_code, set_oom_exit_code, DEFAULT_OOM_EXIT_CODE};
+use fixed_malloc::ffi::*;
+use fixed_malloc::{try_reinitialize, ReinitError};
+use rusty_fork::{fork, rusty_fork_id, rusty_fork_test, rusty_fork_test_name};
+use std::ffi::CStr;
+use std::sync::Mutex;
+
+static MESSAGES: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+fn record(message: &CStr) {
+    MESSAGES
+        .lock()
+        .unwrap()
+        .push(message.to_string_lossy().into_owned());
+}
+
+// Stands in for the exit syscall, the exit code of the process is checked
+fn exit(code: i8) -> ! {
+    std::process::exit(code as u8 as i32)
+}
+
+rusty_fork_test! {
+
+#[test]
+fn test_oom_exit_code() {
+    assert_eq!(oom_exit_code(), DEFAULT_OOM_EXIT_CODE);
+    set_oom_exit_code(7);
+    assert_eq!(oom_exit_code(), 7);
+}
+
+#[test]
+fn test_debug_messages() {
+    init_with(record, exit);
+    let buffer = unsafe { fm_lm_test_static_buffer() } as *mut u8;
+    let unaligned = unsafe { buffer.add(16) };
+    assert_eq!(try_reinitialize(unaligned, 8192, true), Err(ReinitError::UnalignedBuffer));
+    assert_eq!(
+        *MESSAGES.lock().unwrap(),
+        ["Memory buffer must be aligned at page boundary!"]
+    );
+    // Regular allocations are not affected
+    let p = unsafe { fm_sm_malloc(100) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+}
+
+}
+
+#[test]
+fn test_oom_exit() {
+    fork(
+        rusty_fork_test_name!(test_oom_exit),
+        rusty_fork_id!(),
+        |_| {},
+        |child, _| {
+            let status = child.wait().expect("wait");
+            assert_eq!(status.code(), Some(42), "{}", status);
+        },
+        || {
+            init_with(record, exit);
+            set_oom_exit_code(42);
+            unsafe { fm_sm_malloc(FM_MAX_MEMORY_SIZE) };
+            // Not reached, the failed allocation exits right away
+            std::process::exit(0);
+        },
+    )
+    .expect("fork");
+}
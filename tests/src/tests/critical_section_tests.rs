@@ -0,0 +1,15 @@
+use super::*;
+use fixed_malloc::{CriticalSectionAlloc, FixedAlloc};
+use rusty_fork::rusty_fork_test;
+
+static ALLOC: CriticalSectionAlloc = CriticalSectionAlloc::new(unsafe { FixedAlloc::new_static() });
+
+rusty_fork_test! {
+
+#[test]
+fn test_concurrent_malloc_free() {
+    concurrent_malloc_free(&ALLOC);
+    ALLOC.with(|_| assert_heap_empty());
+}
+
+}
@@ -0,0 +1,129 @@
+use super::*;
+use core::ffi::c_int;
+use fixed_malloc::{ffi, rust_impl};
+use proptest::prelude::*;
+use std::ptr::null_mut;
+
+// Small enough that random sequences run out of memory now and then
+const MEMORY_SIZE: usize = 32 * FM_PAGE_SIZE;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Malloc(usize),
+    Calloc(usize, usize),
+    // Blocks are picked by an index reduced modulo the number of live blocks
+    Realloc(usize, usize),
+    Free(usize),
+}
+
+fn gen_size() -> impl Strategy<Value = usize> + Clone {
+    // Mostly slab objects, but also blocks of a few pages
+    prop_oneof![3 => 1usize..=1024, 1 => 1usize..=4 * FM_PAGE_SIZE]
+}
+
+fn gen_op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => gen_size().prop_map(Op::Malloc),
+        1 => (1usize..=16, 1usize..=256).prop_map(|(n, s)| Op::Calloc(n, s)),
+        2 => (any::<usize>(), gen_size()).prop_map(|(i, s)| Op::Realloc(i, s)),
+        3 => any::<usize>().prop_map(Op::Free),
+    ]
+}
+
+// What can be observed after one operation, addresses are kept as offsets
+// from the buffer, as both allocators use buffers of their own
+#[derive(Debug, PartialEq)]
+struct Step {
+    // Offset and usable size of the returned block
+    block: Option<(usize, usize)>,
+    stats: FmStats,
+    live_allocations: usize,
+}
+
+// Everything left after the last operation
+#[derive(Debug, PartialEq)]
+struct Summary {
+    steps: Vec<Step>,
+    blocks: Vec<(usize, usize)>,
+    verify: c_int,
+}
+
+unsafe extern "C" fn collect_offset(ptr: *mut c_void, size: usize, _tag: u32, user: *mut c_void) {
+    let blocks = &mut *(user as *mut Vec<(usize, usize)>);
+    blocks.push((ptr as usize, size));
+}
+
+// Run ops on a fresh heap of the allocator exported by module $m, which is
+// either the C library or its Rust port
+macro_rules! replay {
+    ($m:ident, $ops:expr) => {{
+        let layout = Layout::from_size_align(MEMORY_SIZE, FM_PAGE_SIZE).unwrap();
+        let buffer = unsafe { alloc_zeroed(layout) };
+        let ret = unsafe { $m::fm_sm_reinit_forced(buffer as *mut c_void, MEMORY_SIZE, 1) };
+        assert_eq!(ret, 0);
+        let base = buffer as usize;
+        let mut live: Vec<*mut c_void> = vec![];
+        let mut steps = vec![];
+        for op in $ops {
+            let p = match *op {
+                Op::Malloc(size) => unsafe { $m::fm_sm_malloc(size) },
+                Op::Calloc(n, size) => unsafe { $m::fm_sm_calloc(n, size) },
+                Op::Realloc(i, size) if !live.is_empty() => {
+                    let old = live.swap_remove(i % live.len());
+                    let p = unsafe { $m::fm_sm_realloc(old, size) };
+                    if p.is_null() {
+                        live.push(old);
+                    }
+                    p
+                }
+                Op::Free(i) if !live.is_empty() => {
+                    unsafe { $m::fm_sm_free(live.swap_remove(i % live.len())) };
+                    null_mut()
+                }
+                _ => null_mut(),
+            };
+            let block = if p.is_null() {
+                None
+            } else {
+                live.push(p);
+                Some((p as usize - base, unsafe { $m::fm_sm_usable_size(p) }))
+            };
+            let mut stats = FmStats::default();
+            unsafe { $m::fm_sm_stats(&mut stats) };
+            steps.push(Step {
+                block,
+                stats,
+                live_allocations: unsafe { $m::fm_sm_live_allocations() },
+            });
+        }
+        let mut blocks = vec![];
+        unsafe { $m::fm_sm_test_walk(collect_offset, &mut blocks as *mut _ as *mut c_void) };
+        let blocks = blocks.iter().map(|(a, s)| (a - base, *s)).collect();
+        let mut error = FmHeapError {
+            kind: FM_HEAP_OK,
+            address: null_mut(),
+        };
+        let verify = unsafe { $m::fm_sm_verify(&mut error) };
+        unsafe { dealloc(buffer, layout) };
+        Summary {
+            steps,
+            blocks,
+            verify,
+        }
+    }};
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        fork: true,
+        .. ProptestConfig::default()
+    })]
+
+    #[test]
+    fn test_same_as_c(ops in prop::collection::vec(gen_op(), 1..200)) {
+        let c = replay!(ffi, &ops);
+        let rust = replay!(rust_impl, &ops);
+        prop_assert_eq!(c.verify, FM_HEAP_OK);
+        prop_assert_eq!(c, rust);
+    }
+}
@@ -0,0 +1,84 @@
+use fixed_malloc::ffi::*;
+use fixed_malloc::{FixedAlloc, HeapErrorKind};
+use rusty_fork::rusty_fork_test;
+use std::alloc::{GlobalAlloc, Layout};
+
+rusty_fork_test! {
+
+#[test]
+fn test_fill_on_free() {
+    let a = unsafe { FixedAlloc::new_static() };
+    for size in [17, 1000, 5000] {
+        let layout = Layout::from_size_align(size, 8).expect("layout");
+        let p = unsafe { a.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { core::ptr::write_bytes(p, 0x11, size) };
+        unsafe { a.dealloc(p, layout) };
+        assert_eq!(unsafe { *p.add(size - 1) }, FM_FILL_PATTERN);
+    }
+}
+
+// Deterministic mode releases a slab as soon as its last object is freed,
+// which must not trip the fill check of GlobalAlloc::dealloc
+#[test]
+fn test_deterministic_fill_on_free() {
+    let a = unsafe { FixedAlloc::new_static() };
+    a.set_deterministic(true);
+    for size in [24, 1000, 5000] {
+        let layout = Layout::from_size_align(size, 8).expect("layout");
+        let p = unsafe { a.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { core::ptr::write_bytes(p, 0x11, size) };
+        unsafe { a.dealloc(p, layout) };
+        assert_eq!(a.stats().used_pages, 0);
+        // The same address is returned again from the empty heap
+        let q = unsafe { a.alloc(layout) };
+        assert_eq!(q, p);
+        unsafe { a.dealloc(q, layout) };
+    }
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    a.set_deterministic(false);
+}
+
+#[test]
+fn test_detect_write_after_free() {
+    let a = unsafe { FixedAlloc::new_static() };
+    a.set_quarantine(1);
+
+    let p = unsafe { fm_sm_malloc(64) } as *mut u8;
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p as *mut _) };
+    assert_eq!(unsafe { fm_sm_check_fill(p as *mut _) }, 0);
+
+    unsafe { *p.add(10) = 0 };
+    assert_ne!(unsafe { fm_sm_check_fill(p as *mut _) }, 0);
+}
+
+#[test]
+fn test_verify_freed_slots() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let p = unsafe { fm_sm_malloc(64) } as *mut u8;
+    let q = unsafe { fm_sm_malloc(64) } as *mut u8;
+    assert_eq!(q as usize, p as usize + 64);
+    unsafe { fm_sm_free(q as *mut _) };
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+
+    // Writing past the end of p overflows into the freed slot of q
+    unsafe { p.add(70).write(0) };
+    let e = a.verify_heap_integrity().unwrap_err();
+    assert_eq!(e.kind, HeapErrorKind::OverflowGuard);
+    assert_eq!(e.address, q as usize);
+    unsafe { p.add(70).write(FM_FILL_PATTERN) };
+
+    // Writing before the start of p underflows into the free slot before it
+    unsafe { fm_sm_free(p as *mut _) };
+    let p = unsafe { fm_sm_malloc(64) } as *mut u8;
+    let r = unsafe { fm_sm_malloc(64) } as *mut u8;
+    unsafe { fm_sm_free(p as *mut _) };
+    unsafe { r.sub(1).write(0) };
+    let e = a.verify_heap_integrity().unwrap_err();
+    assert_eq!(e.kind, HeapErrorKind::UnderflowGuard);
+    assert_eq!(e.address, p as usize);
+}
+
+}
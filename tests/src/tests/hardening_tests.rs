@@ -0,0 +1,125 @@
+use super::*;
+use fixed_malloc::ffi::*;
+use fixed_malloc::{FixedAlloc, FmError};
+use proptest::prelude::*;
+use rand::prelude::*;
+
+fn gen_size(rng: &mut StdRng) -> usize {
+    if rng.gen_ratio(2, 3) {
+        rng.gen_range(1..=1024)
+    } else {
+        rng.gen_range(1..=200000)
+    }
+}
+
+// Run a sequence of mallocs and frees, returning the offsets of allocated
+// pointers relative to the buffer start.
+fn run_sequence(seed: u64, ops: u64) -> Vec<usize> {
+    let m = init(655360);
+    unsafe { fm_sm_set_random_seed(seed) };
+
+    let mut rng = StdRng::seed_from_u64(ops);
+    let mut offsets = vec![];
+    let mut ptrs = vec![];
+    for _ in 0..200 {
+        if !ptrs.is_empty() && rng.gen_ratio(1, 3) {
+            let i = rng.gen_range(0..ptrs.len());
+            unsafe { fm_sm_free(ptrs.swap_remove(i)) };
+        } else {
+            let p = unsafe { fm_sm_malloc(gen_size(&mut rng)) };
+            if !p.is_null() {
+                ptrs.push(p);
+            }
+            offsets.push((p as usize).wrapping_sub(m.0 as usize));
+        }
+    }
+
+    deinit(m);
+    offsets
+}
+
+#[test]
+fn test_free_bad_pointer() {
+    let m = init(655360);
+    let a = unsafe { FixedAlloc::new_static() };
+
+    let p = unsafe { fm_sm_malloc(64) } as *mut u8;
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p.add(16) as *mut c_void) };
+    assert_eq!(a.last_error(), Some(FmError::BadPointer));
+    a.clear_error();
+
+    let mut foreign = 0u64;
+    unsafe { fm_sm_free(&mut foreign as *mut u64 as *mut c_void) };
+    assert_eq!(a.last_error(), Some(FmError::BadPointer));
+    a.clear_error();
+
+    unsafe { fm_sm_free(p as *mut c_void) };
+    assert_eq!(a.last_error(), None);
+    // Double free
+    unsafe { fm_sm_free(p as *mut c_void) };
+    assert_eq!(a.last_error(), Some(FmError::BadPointer));
+
+    deinit(m);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        fork: true,
+        .. ProptestConfig::default()
+    })]
+
+    #[test]
+    fn test_randomized_determinism(seed in 0..=u64::MAX, ops in 0..=u64::MAX) {
+        assert_eq!(run_sequence(seed, ops), run_sequence(seed, ops));
+    }
+
+    #[test]
+    fn test_randomized_small_malloc(seed in 0..=u64::MAX) {
+        let m = init(655360);
+        unsafe { fm_sm_set_random_seed(seed) };
+
+        let mut ptrs = vec![];
+        for _ in 0..200 {
+            let p = unsafe { fm_sm_malloc(32) };
+            assert!(!p.is_null());
+            ptrs.push((p, 32));
+        }
+        assert_valid_pointers(&ptrs);
+
+        // Randomized slot selection shall not always follow allocation order
+        let in_order = ptrs.windows(2).all(|w| (w[0].0 as usize) < (w[1].0 as usize));
+        assert!(!in_order);
+
+        deinit(m);
+    }
+
+    #[test]
+    fn test_randomized_continous_malloc(seed in 0..=u64::MAX) {
+        let m = init(655360);
+        unsafe { fm_sm_set_random_seed(seed) };
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let times = rng.gen_range(10..20);
+
+        let mut ptrs = vec![];
+        for _ in 0..times {
+            loop {
+                let s = gen_size(&mut rng);
+                let p = unsafe { fm_sm_malloc(s) };
+                if p.is_null() {
+                    break;
+                }
+                ptrs.push((p, s));
+            }
+            assert_valid_pointers(&ptrs);
+
+            for p in ptrs.drain(..) {
+                unsafe { fm_sm_free(p.0); }
+            }
+            assert_heap_empty();
+        }
+
+        deinit(m);
+    }
+}
@@ -0,0 +1,47 @@
+#[cfg(c_header_check)]
+use super::*;
+use rusty_fork::rusty_fork_test;
+use std::path::Path;
+
+#[cfg(c_header_check)]
+extern "C" {
+    fn fm_header_check() -> core::ffi::c_int;
+}
+
+// All functions of ffi.rs are declared in the installed header, so C and Rust
+// callers see the same interface
+#[test]
+fn test_header_declares_ffi() {
+    let header =
+        std::fs::read_to_string(Path::new(env!("FIXED_MALLOC_INCLUDE_DIR")).join("fixed_malloc.h"))
+            .expect("read installed header");
+    let ffi = std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("../src/ffi.rs"))
+        .expect("read ffi.rs");
+    let names: Vec<&str> = ffi
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("pub fn "))
+        .filter_map(|rest| rest.split('(').next())
+        .filter(|name| name.starts_with("fm_"))
+        .collect();
+    assert!(names.contains(&"fm_sm_malloc"));
+    for name in names {
+        assert!(
+            header.contains(&format!(" {}(", name)) || header.contains(&format!("*{}(", name)),
+            "{} is missing from fixed_malloc.h",
+            name
+        );
+    }
+}
+
+rusty_fork_test! {
+
+#[cfg(c_header_check)]
+#[test]
+fn test_header_links() {
+    let m = init(32 * 4096);
+    assert_eq!(unsafe { fm_header_check() }, 0);
+    assert_heap_empty();
+    deinit(m);
+}
+
+}
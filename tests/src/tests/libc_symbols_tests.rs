@@ -0,0 +1,44 @@
+use super::*;
+use fixed_malloc::FixedAlloc;
+use rusty_fork::rusty_fork_test;
+
+extern "C" {
+    fn fm_libc_check_malloc(size: usize) -> *mut c_void;
+    fn fm_libc_check_free(ptr: *mut c_void);
+    fn fm_libc_check_calloc(n: usize, size: usize) -> *mut c_void;
+    fn fm_libc_check_realloc(ptr: *mut c_void, size: usize) -> *mut c_void;
+    fn fm_libc_check_usable_size(ptr: *mut c_void) -> usize;
+}
+
+rusty_fork_test! {
+
+#[test]
+fn test_libc_symbols() {
+    let m = init(32 * 4096);
+    let a = unsafe { FixedAlloc::new_static() };
+
+    let p = unsafe { fm_libc_check_malloc(100) };
+    assert!(a.owns_and_size(p as *const u8).is_some());
+    assert!(unsafe { fm_libc_check_usable_size(p) } >= 100);
+    unsafe { (p as *mut u8).write_bytes(0x5A, 100) };
+    let p = unsafe { fm_libc_check_realloc(p, 10000) };
+    assert!(a.owns_and_size(p as *const u8).is_some());
+    assert!(unsafe { std::slice::from_raw_parts(p as *const u8, 100) }
+        .iter()
+        .all(|b| *b == 0x5A));
+
+    let q = unsafe { fm_libc_check_calloc(10, 100) };
+    assert!(a.owns_and_size(q as *const u8).is_some());
+    assert!(unsafe { std::slice::from_raw_parts(q as *const u8, 1000) }
+        .iter()
+        .all(|b| *b == 0));
+    assert!(unsafe { fm_libc_check_calloc(usize::MAX, 2) }.is_null());
+
+    unsafe { fm_libc_check_free(q) };
+    unsafe { fm_libc_check_free(p) };
+    unsafe { fm_libc_check_free(std::ptr::null_mut()) };
+    assert_heap_empty();
+    deinit(m);
+}
+
+}
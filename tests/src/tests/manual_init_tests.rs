@@ -0,0 +1,55 @@
+use fixed_malloc::ffi::*;
+use fixed_malloc::{init_static, FixedAlloc, FmError, ReinitError};
+use rusty_fork::rusty_fork_test;
+use std::alloc::{GlobalAlloc, Layout};
+
+rusty_fork_test! {
+
+#[test]
+fn test_init_static() {
+    assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, 0);
+    assert!(unsafe { fm_lm_test_static_buffer() }.is_null());
+
+    let a = unsafe { FixedAlloc::new_static_uninit() };
+    assert_eq!(init_static(), Ok(()));
+    assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, FM_MEMORY_SIZE);
+
+    let p = unsafe { a.alloc(Layout::from_size_align(100, 8).expect("layout")) };
+    assert!(!p.is_null());
+    unsafe { a.dealloc(p, Layout::from_size_align(100, 8).expect("layout")) };
+}
+
+#[test]
+fn test_init_static_twice() {
+    assert_eq!(init_static(), Ok(()));
+    assert_eq!(init_static(), Err(ReinitError::AlreadyInitialized));
+}
+
+#[test]
+fn test_malloc_before_init() {
+    let a = unsafe { FixedAlloc::new_static_uninit() };
+    let p = unsafe { fm_sm_malloc(32) };
+    assert!(p.is_null());
+    assert_eq!(a.last_error(), Some(FmError::NotInitialized));
+}
+
+#[test]
+fn test_alloc_functions_before_init() {
+    let a = unsafe { FixedAlloc::new_static_uninit() };
+    let layout = Layout::from_size_align(64, 8).expect("layout");
+    assert!(unsafe { a.alloc(layout) }.is_null());
+    assert!(unsafe { a.alloc_zeroed(layout) }.is_null());
+    assert!(unsafe { fm_sm_calloc(4, 16) }.is_null());
+    assert!(unsafe { fm_sm_realloc(std::ptr::null_mut(), 64) }.is_null());
+    assert!(unsafe { fm_sm_malloc(2 * FM_PAGE_SIZE) }.is_null());
+    assert!(unsafe { fm_sm_page_alloc(1) }.is_null());
+    assert_eq!(a.last_error(), Some(FmError::NotInitialized));
+
+    unsafe { fm_sm_free(std::ptr::null_mut()) };
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    assert_eq!(unsafe { fm_sm_live_allocations() }, 0);
+    assert_eq!(a.allocated_bytes(), 0);
+    assert_eq!(a.stats().total_bytes, 0);
+}
+
+}
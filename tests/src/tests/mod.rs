@@ -1,5 +1,41 @@
+#[cfg(all(feature = "ckb", not(feature = "manual-init")))]
+mod ckb_tests;
+#[cfg(all(feature = "critical-section", not(feature = "manual-init")))]
+mod critical_section_tests;
+#[cfg(feature = "differential")]
+mod differential_tests;
+#[cfg(feature = "fill-on-free")]
+mod fill_tests;
+#[cfg(feature = "hardening")]
+mod hardening_tests;
+mod header_tests;
+#[cfg(feature = "libc-symbols")]
+mod libc_symbols_tests;
+#[cfg(feature = "manual-init")]
+mod manual_init_tests;
+#[cfg(feature = "owned-buffer")]
+mod owned_buffer_tests;
+#[cfg(all(
+    feature = "portable-atomic",
+    feature = "sync",
+    feature = "spin",
+    not(feature = "manual-init")
+))]
+mod portable_atomic_tests;
 mod prop_tests;
 mod simple_tests;
+#[cfg(feature = "spin")]
+mod spin_tests;
+// Symbol sizes are only checked in ELF objects of the C library
+#[cfg(all(target_os = "linux", not(feature = "pure-rust")))]
+mod symbol_tests;
+#[cfg(all(feature = "sync", not(feature = "manual-init")))]
+mod sync_tests;
+#[cfg(all(feature = "tls-cache", not(feature = "manual-init")))]
+mod tls_cache_tests;
+mod version_tests;
+#[cfg(feature = "wasm")]
+mod wasm_tests;
 
 use core::ffi::c_void;
 use fixed_malloc::ffi::*;
@@ -8,23 +44,95 @@ use std::alloc::{alloc_zeroed, dealloc, Layout};
 pub fn init(memory_size: usize) -> (*mut c_void, Layout) {
     let layout = Layout::from_size_align(memory_size, FM_PAGE_SIZE).expect("layout");
     let buffer = unsafe { alloc_zeroed(layout) };
-    let ret = unsafe { fm_sm_reinit(buffer as *mut c_void, memory_size, 1) };
+    // Previous proptest cases in the same process might leave allocations behind
+    let ret = unsafe { fm_sm_reinit_forced(buffer as *mut c_void, memory_size, 1) };
     assert_eq!(ret, 0);
     (buffer as *mut c_void, layout)
 }
 
+// The static buffer has to be initialized first with manual-init
+pub fn init_static_buffer() {
+    #[cfg(feature = "manual-init")]
+    assert_eq!(fixed_malloc::init_static(), Ok(()));
+}
+
 pub fn deinit(meta: (*mut c_void, Layout)) {
     unsafe { dealloc(meta.0 as *mut u8, meta.1) };
 }
 
+// Handle of the global heap with or without manual-init, each test runs in
+// its own process so it is never used by two threads.
+pub fn global_heap() -> fixed_malloc::FixedAlloc {
+    #[cfg(not(feature = "manual-init"))]
+    let alloc = unsafe { fixed_malloc::FixedAlloc::new_static() };
+    #[cfg(feature = "manual-init")]
+    let alloc = unsafe { fixed_malloc::FixedAlloc::new_static_uninit() };
+    alloc
+}
+
+unsafe extern "C" fn collect_block(ptr: *mut c_void, size: usize, _tag: u32, user: *mut c_void) {
+    let blocks = &mut *(user as *mut Vec<(usize, usize)>);
+    blocks.push((ptr as usize, size));
+}
+
+// Live allocations in address order, slab objects are reported with the size
+// of their size classes.
+pub fn live_allocations() -> Vec<(usize, usize)> {
+    let mut blocks = vec![];
+    unsafe { fm_sm_test_walk(collect_block, &mut blocks as *mut _ as *mut c_void) };
+    blocks
+}
+
+pub fn assert_heap_empty() {
+    let mut stats = FmStats::default();
+    unsafe { fm_sm_stats(&mut stats) };
+    if stats.used_bytes != 0 {
+        let buffer_start = unsafe { fm_lm_test_buffer_pointer() } as usize;
+        let leaks: Vec<String> = live_allocations()
+            .iter()
+            .map(|(a, s)| format!("  {:x} (offset {:x}): {} bytes", a, a - buffer_start, s))
+            .collect();
+        panic!(
+            "{} bytes are leaked in {} allocations:\n{}",
+            stats.used_bytes,
+            leaks.len(),
+            leaks.join("\n")
+        );
+    }
+}
+
+// Run f on a freshly initialized heap, then assert everything is freed
+pub fn assert_no_leaks<F: FnOnce()>(memory_size: usize, f: F) {
+    let m = init(memory_size);
+    f();
+    assert_heap_empty();
+    deinit(m);
+}
+
+// Address ranges of all memory regions
+pub fn regions() -> Vec<(usize, usize)> {
+    (0..unsafe { fm_lm_regions() })
+        .map(|i| {
+            let mut buffer = std::ptr::null_mut();
+            let mut size = 0;
+            unsafe { fm_lm_test_region(i, &mut buffer, &mut size) };
+            (buffer as usize, buffer as usize + size)
+        })
+        .collect()
+}
+
+// Check that pointers are aligned, lie within the memory regions of the global
+// heap and do not overlap
 pub fn assert_valid_pointers(pointers: &[(*mut c_void, usize)]) {
+    assert_valid_pointers_in(pointers, &regions());
+}
+
+// Same as `assert_valid_pointers`, but pointers are checked against the
+// given address ranges instead of the regions of the global heap
+pub fn assert_valid_pointers_in(pointers: &[(*mut c_void, usize)], regions: &[(usize, usize)]) {
     let mut pointers: Vec<(usize, usize)> =
         pointers.iter().map(|(a, s)| (*a as usize, *s)).collect();
 
-    let buffer_start = unsafe { fm_lm_test_buffer_pointer() } as usize;
-    let buffer_size = unsafe { fm_lm_test_total_buffer_size() };
-    let buffer_end = buffer_start + buffer_size;
-
     for (a, s) in pointers.clone() {
         assert!(
             a % 16 == 0,
@@ -32,22 +140,78 @@ pub fn assert_valid_pointers(pointers: &[(*mut c_void, usize)]) {
             a
         );
         assert!(
-            a >= buffer_start && a + s <= buffer_end,
-            "Pointer {:x} exceeds buffer range {:x} - {:x}!",
+            regions
+                .iter()
+                .any(|(start, end)| a >= *start && a + s <= *end),
+            "Pointer {:x} exceeds buffer ranges {:x?}!",
             a,
-            buffer_start,
-            buffer_end,
+            regions,
         );
     }
 
     pointers.sort_by_key(|(a, _)| *a);
 
-    for i in 0..pointers.len() - 1 {
+    for pair in pointers.windows(2) {
         assert!(
-            pointers[i].0 + pointers[i].1 <= pointers[i + 1].0,
+            pair[0].0 + pair[0].1 <= pair[1].0,
             "Pointer {:x} and {:x} collides!",
-            pointers[i].0,
-            pointers[i + 1].0
+            pair[0].0,
+            pair[1].0
         );
     }
 }
+
+// Same as `assert_valid_pointers`, but for blocks served by linear malloc,
+// which must lie within its buffer after the bookkeeping page, and start on
+// page boundaries
+pub fn assert_valid_linear_pointers(pointers: &[(*mut c_void, usize)]) {
+    let mut buffer = std::ptr::null_mut();
+    let mut size = 0;
+    unsafe { fm_lm_buffer_range(&mut buffer, &mut size) };
+    for (a, _) in pointers {
+        assert!(
+            (*a as usize).is_multiple_of(FM_PAGE_SIZE),
+            "Pointer {:x} is not aligned on page boundary!",
+            *a as usize
+        );
+    }
+    let range = (buffer as usize + FM_PAGE_SIZE, buffer as usize + size);
+    assert_valid_pointers_in(pointers, &[range]);
+}
+
+// Allocate and free from 8 threads at once through a locked wrapper, while
+// checking that no block is handed to two threads. Each thread keeps a few
+// blocks alive to interleave with the others.
+#[cfg(all(
+    any(feature = "sync", feature = "critical-section"),
+    not(feature = "manual-init")
+))]
+pub fn concurrent_malloc_free<A: std::alloc::GlobalAlloc + Sync>(alloc: &'static A) {
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            std::thread::spawn(move || {
+                let mut live = vec![];
+                for i in 0..10000usize {
+                    let size = 1 + (i * 7 + t * 13) % 2048;
+                    let layout = Layout::from_size_align(size, 8).unwrap();
+                    let p = unsafe { alloc.alloc(layout) };
+                    assert!(!p.is_null());
+                    unsafe { p.write_bytes(t as u8, size) };
+                    live.push((p, layout));
+                    if live.len() > 4 {
+                        let (p, layout) = live.remove(0);
+                        let bytes = unsafe { std::slice::from_raw_parts(p, layout.size()) };
+                        assert!(bytes.iter().all(|b| *b == t as u8));
+                        unsafe { alloc.dealloc(p, layout) };
+                    }
+                }
+                for (p, layout) in live {
+                    unsafe { alloc.dealloc(p, layout) };
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
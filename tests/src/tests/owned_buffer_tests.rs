@@ -0,0 +1,63 @@
+use fixed_malloc::ffi::*;
+use fixed_malloc::{try_reinitialize, FixedAlloc, FmError, ReinitError};
+use rusty_fork::rusty_fork_test;
+use std::alloc::{GlobalAlloc, Layout};
+
+rusty_fork_test! {
+
+#[test]
+fn test_new_owned() {
+    let a = unsafe { FixedAlloc::new_owned(65536) }.expect("new_owned");
+    assert_eq!(a.stats().total_bytes, 65536);
+
+    let layout = Layout::from_size_align(1000, 8).expect("layout");
+    let p = unsafe { a.alloc(layout) };
+    assert!(!p.is_null());
+    unsafe { core::ptr::write_bytes(p, 0x11, 1000) };
+    unsafe { a.dealloc(p, layout) };
+
+    drop(a);
+    // The heap no longer refers to the released buffer
+    assert!(unsafe { fm_sm_malloc(100) }.is_null());
+    assert_eq!(unsafe { fm_last_error() }, FM_ERR_NOT_INITIALIZED);
+    assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, 0);
+}
+
+#[test]
+fn test_new_owned_errors() {
+    assert_eq!(
+        unsafe { FixedAlloc::new_owned(0) }.err(),
+        Some(ReinitError::Failed(FmError::BufferTooSmall))
+    );
+    assert_eq!(
+        unsafe { FixedAlloc::new_owned(65536 + 16) }.err(),
+        Some(ReinitError::UnalignedSize)
+    );
+
+    let a = unsafe { FixedAlloc::new_owned(65536) }.expect("new_owned");
+    let p = unsafe { fm_sm_malloc(100) };
+    assert!(!p.is_null());
+    assert_eq!(
+        unsafe { FixedAlloc::new_owned(65536) }.err(),
+        Some(ReinitError::Failed(FmError::LiveAllocations))
+    );
+    unsafe { fm_sm_free(p) };
+    drop(a);
+}
+
+#[test]
+fn test_drop_after_reinit() {
+    let a = unsafe { FixedAlloc::new_owned(65536) }.expect("new_owned");
+    let layout = Layout::from_size_align(131072, FM_PAGE_SIZE).expect("layout");
+    let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+    assert_eq!(try_reinitialize(buffer, 131072, true), Ok(()));
+
+    // The heap has moved on, dropping only releases the old buffer
+    drop(a);
+    let p = unsafe { fm_sm_malloc(100) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+    unsafe { std::alloc::dealloc(buffer, layout) };
+}
+
+}
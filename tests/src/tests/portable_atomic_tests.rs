@@ -0,0 +1,45 @@
+use fixed_malloc::{FixedAlloc, LockedFixedAlloc, SyncAlloc};
+use rusty_fork::rusty_fork_test;
+use std::alloc::{GlobalAlloc, Layout};
+use std::thread;
+
+// Lock wrappers can still be built in statics with portable atomics
+static SYNC: SyncAlloc = SyncAlloc::new(unsafe { FixedAlloc::new_static() });
+static LOCKED: LockedFixedAlloc = LockedFixedAlloc::new(unsafe { FixedAlloc::new_static() });
+
+fn hammer<A: GlobalAlloc + Sync>(alloc: &'static A) {
+    let threads: Vec<_> = (0..2u8)
+        .map(|t| {
+            thread::spawn(move || {
+                for i in 0..500usize {
+                    let layout = Layout::from_size_align(1 + (i * 13) % 3000, 8).unwrap();
+                    let p = unsafe { alloc.alloc(layout) };
+                    assert!(!p.is_null());
+                    unsafe { p.write_bytes(t, layout.size()) };
+                    let bytes = unsafe { std::slice::from_raw_parts(p, layout.size()) };
+                    assert!(bytes.iter().all(|b| *b == t));
+                    unsafe { alloc.dealloc(p, layout) };
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+rusty_fork_test! {
+
+#[test]
+fn test_portable_sync_alloc() {
+    hammer(&SYNC);
+    assert_eq!(SYNC.with(|a| a.live_allocations()), 0);
+}
+
+#[test]
+fn test_portable_locked_alloc() {
+    hammer(&LOCKED);
+    assert_eq!(LOCKED.with(|a| a.live_allocations()), 0);
+}
+
+}
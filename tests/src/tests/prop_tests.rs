@@ -1,17 +1,41 @@
 use super::*;
 use fixed_malloc::ffi::*;
+use fixed_malloc::{migrate, AllocType};
 use proptest::prelude::*;
 use rand::prelude::*;
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+// Heap used by the realloc tests, capped by the largest buffer supported with
+// small pages
+const REALLOC_MEMORY_SIZE: usize = if FM_MAX_MEMORY_SIZE < 12042240 {
+    FM_MAX_MEMORY_SIZE
+} else {
+    12042240
+};
+// Largest random allocation, 20 of them must fit in REALLOC_MEMORY_SIZE while
+// being reallocated
+const MAX_ALLOC_SIZE: usize = if REALLOC_MEMORY_SIZE < 12042240 {
+    50000
+} else {
+    200000
+};
 
 fn gen_size(rng: &mut StdRng) -> usize {
     // We want 67% of alloced data to be smaller ones.
     if rng.gen_ratio(2, 3) {
         rng.gen_range(1..=1024)
     } else {
-        rng.gen_range(1..=200000)
+        rng.gen_range(1..=MAX_ALLOC_SIZE)
     }
 }
 
+// Buffer sizes ranging from the minimal 2 pages(one for bookkeeping, one
+// for actual allocations) to 64 pages.
+fn valid_buffer_size() -> impl Strategy<Value = usize> {
+    (2usize..=64usize).prop_map(|pages| pages * FM_PAGE_SIZE)
+}
+
 proptest! {
     #![proptest_config(ProptestConfig {
         fork: true,
@@ -19,8 +43,11 @@ proptest! {
     })]
 
     #[test]
-    fn test_simple_malloc(s in 0usize..651264usize) {
-        let m = init(655360);
+    fn test_simple_malloc(
+        (memory_size, s) in valid_buffer_size()
+            .prop_flat_map(|m| (Just(m), 0usize..m - FM_PAGE_SIZE))
+    ) {
+        let m = init(memory_size);
 
         let p = unsafe { fm_sm_malloc(s) };
         assert!(!p.is_null());
@@ -29,7 +56,7 @@ proptest! {
     }
 
     #[test]
-    fn test_oversized_malloc(s in 651265usize..) {
+    fn test_oversized_malloc(s in (655360 - FM_PAGE_SIZE + 1)..) {
         let m = init(655360);
 
         let p = unsafe { fm_sm_malloc(s) };
@@ -39,21 +66,73 @@ proptest! {
     }
 
     #[test]
-    // ((655360 / 4096) - 1) * ((4096 - 64) / 32) == 20034
-    fn test_multiple_simple_malloc(times in 1..=20034) {
+    fn test_wrapping_malloc(s in (usize::MAX - 4096)..=usize::MAX) {
         let m = init(655360);
+        let small = unsafe { fm_sm_malloc(100) };
+        let large = unsafe { fm_sm_malloc(2 * FM_PAGE_SIZE) };
+
+        // Rounding any of these to size classes or pages would wrap around
+        assert!(unsafe { fm_sm_malloc(s) }.is_null());
+        assert!(unsafe { fm_sm_calloc(1, s) }.is_null());
+        assert!(unsafe { fm_sm_realloc(std::ptr::null_mut(), s) }.is_null());
+        assert!(unsafe { fm_sm_realloc(small, s) }.is_null());
+        assert!(unsafe { fm_sm_realloc(large, s) }.is_null());
+        assert!(unsafe { fm_sm_realloc_flags(small, s, FM_REALLOC_NO_MOVE) }.is_null());
+        assert!(unsafe { fm_sm_realloc_flags(large, s, FM_REALLOC_NO_MOVE) }.is_null());
+        assert!(unsafe { fm_lm_realloc_in_place(large, s) }.is_null());
+        assert_eq!(unsafe { fm_last_error() }, FM_ERR_TOO_LARGE);
+
+        unsafe { fm_sm_free(small) };
+        unsafe { fm_sm_free(large) };
+        deinit(m);
+    }
+
+    #[test]
+    // ((memory_size / page size) - 1) * ((page size - reserved) / min size)
+    // blocks of the smallest size class at most
+    fn test_multiple_simple_malloc(
+        (memory_size, times) in valid_buffer_size().prop_flat_map(|m| {
+            (Just(m), 1..=(m / FM_PAGE_SIZE - 1) * ((FM_PAGE_SIZE - FM_SLAB_RESERVED_SIZE) / FM_SLAB_MIN_SIZE))
+        })
+    ) {
+        let m = init(memory_size);
         let mut ptrs = vec![];
 
         for _ in 0..times {
-            let p = unsafe { fm_sm_malloc(32) };
+            let p = unsafe { fm_sm_malloc(FM_SLAB_MIN_SIZE) };
             assert!(!p.is_null());
-            ptrs.push((p, 32));
+            ptrs.push((p, FM_SLAB_MIN_SIZE));
         }
         assert_valid_pointers(&ptrs);
 
         deinit(m);
     }
 
+    #[test]
+    // Allocations up to the smallest size class share its slabs, so one page
+    // holds exactly as many of them as fit after the slab header
+    fn test_slab_min_size(sizes in prop::collection::vec(1..=FM_SLAB_MIN_SIZE, 1..=300)) {
+        let m = init(65536);
+        let per_slab = (FM_PAGE_SIZE - FM_SLAB_RESERVED_SIZE) / FM_SLAB_MIN_SIZE;
+        let mut ptrs = vec![];
+        for size in &sizes {
+            let p = unsafe { fm_sm_malloc(*size) };
+            assert_eq!(unsafe { fm_sm_usable_size(p) }, FM_SLAB_MIN_SIZE);
+            ptrs.push((p, FM_SLAB_MIN_SIZE));
+        }
+        assert_valid_pointers(&ptrs);
+        let mut stats = FmStats::default();
+        unsafe { fm_sm_stats(&mut stats) };
+        assert_eq!(stats.used_pages, sizes.len().div_ceil(per_slab));
+        assert_eq!(stats.used_bytes, sizes.len() * FM_SLAB_MIN_SIZE);
+
+        for (p, _) in ptrs {
+            unsafe { fm_sm_free(p) };
+        }
+        assert_heap_empty();
+        deinit(m);
+    }
+
     #[test]
     fn test_multiple_different_sized_malloc(
         small_allocs in prop::collection::vec(1usize..=1024usize, 1..=200),
@@ -78,51 +157,46 @@ proptest! {
 
     #[test]
     fn test_continous_malloc(seed in 0..=u64::MAX) {
-        let m = init(655360);
+        assert_no_leaks(655360, || {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let times = rng.gen_range(10..20);
 
-        let mut rng = StdRng::seed_from_u64(seed);
-        let times = rng.gen_range(10..20);
-
-        let mut i = 0;
-        let mut ptrs = vec![];
-        let mut last_size = gen_size(&mut rng);
-        while i < times {
-            let p = unsafe { fm_sm_malloc(last_size) };
-            assert!(!p.is_null());
-            ptrs.push((p, last_size));
-
-            loop {
-                last_size = gen_size(&mut rng);
+            let mut i = 0;
+            let mut ptrs = vec![];
+            let mut last_size = gen_size(&mut rng);
+            while i < times {
                 let p = unsafe { fm_sm_malloc(last_size) };
-                if p.is_null() {
-                    break;
-                }
+                assert!(!p.is_null());
                 ptrs.push((p, last_size));
-            }
-            assert_valid_pointers(&ptrs);
-
-            for p in ptrs.drain(..) {
-                unsafe { fm_sm_free(p.0); }
-            }
 
-            // See if all memories are freed by allocating the biggest
-            // page. Or we can leverage fixed-malloc's test support later.
-            let p = unsafe { fm_sm_malloc(651264) };
-            assert!(!p.is_null());
-            unsafe { fm_sm_free(p); }
-            i += 1;
-        }
+                loop {
+                    last_size = gen_size(&mut rng);
+                    let p = unsafe { fm_sm_malloc(last_size) };
+                    if p.is_null() {
+                        break;
+                    }
+                    ptrs.push((p, last_size));
+                }
+                assert_valid_pointers(&ptrs);
+                assert_eq!(live_allocations().len(), ptrs.len());
 
-        deinit(m);
+                for p in ptrs.drain(..) {
+                    unsafe { fm_sm_free(p.0); }
+                }
+                assert_heap_empty();
+                assert_eq!(unsafe { fm_sm_verify(std::ptr::null_mut()) }, FM_HEAP_OK);
+                i += 1;
+            }
+        });
     }
 
     #[test]
     fn test_realloc(
         seed in 0..=u64::MAX,
-        initial_allocs in prop::collection::vec(1usize..=200000usize, 15..=20),
+        initial_allocs in prop::collection::vec(1usize..=MAX_ALLOC_SIZE, 15..=20),
         times in 20..100,
     ) {
-        let m = init(12042240);
+        let m = init(REALLOC_MEMORY_SIZE);
 
         let mut rng = StdRng::seed_from_u64(seed);
         let mut ptrs = vec![];
@@ -144,8 +218,276 @@ proptest! {
             }
 
             assert_valid_pointers(&ptrs);
+            // Page aligned blocks are the ones served by linear malloc
+            let linear: Vec<_> = ptrs
+                .iter()
+                .copied()
+                .filter(|(p, _)| (*p as usize).is_multiple_of(FM_PAGE_SIZE))
+                .collect();
+            assert_valid_linear_pointers(&linear);
+            let mut stats = FmStats::default();
+            unsafe { fm_sm_stats(&mut stats) };
+            assert_eq!(unsafe { fm_sm_allocated_bytes() }, stats.used_bytes);
+        }
+
+        for p in ptrs {
+            unsafe { fm_sm_free(p.0); }
+        }
+        assert_heap_empty();
+        deinit(m);
+    }
+
+    // Shrinking a large block below the slab boundary and growing it again
+    // takes other paths than growing, the contents of the block and of other
+    // allocations must survive both
+    #[test]
+    fn test_realloc_shrink(
+        other_allocs in prop::collection::vec(1usize..=MAX_ALLOC_SIZE, 0..=10),
+        pages in 2usize..=10,
+        small_size in 1usize..=64,
+        final_size in (FM_PAGE_SIZE + 1)..=MAX_ALLOC_SIZE,
+    ) {
+        prop_assume!(final_size != pages * FM_PAGE_SIZE);
+        let m = init(REALLOC_MEMORY_SIZE);
+
+        let mut others = vec![];
+        for (i, s) in other_allocs.into_iter().enumerate() {
+            let p = unsafe { fm_sm_malloc(s) };
+            assert!(!p.is_null());
+            unsafe { std::ptr::write_bytes(p as *mut u8, i as u8 + 1, s) };
+            others.push((p, s));
+        }
+        let fill = |p: *mut c_void, s: usize| {
+            for i in 0..s {
+                unsafe { *(p as *mut u8).add(i) = (i % 251) as u8 };
+            }
+        };
+
+        let large = unsafe { fm_sm_malloc(pages * FM_PAGE_SIZE) };
+        assert!(!large.is_null());
+        fill(large, pages * FM_PAGE_SIZE);
+        let mut all = others.clone();
+        all.push((large, pages * FM_PAGE_SIZE));
+        assert_valid_pointers(&all);
+
+        let shrunk = unsafe { fm_sm_realloc(large, small_size) };
+        assert!(!shrunk.is_null());
+        for i in 0..small_size {
+            assert_eq!(unsafe { *(shrunk as *const u8).add(i) }, (i % 251) as u8);
+        }
+        *all.last_mut().unwrap() = (shrunk, small_size);
+        assert_valid_pointers(&all);
+
+        let grown = unsafe { fm_sm_realloc(shrunk, final_size) };
+        assert!(!grown.is_null());
+        for i in 0..small_size {
+            assert_eq!(unsafe { *(grown as *const u8).add(i) }, (i % 251) as u8);
+        }
+        fill(grown, final_size);
+        *all.last_mut().unwrap() = (grown, final_size);
+        assert_valid_pointers(&all);
+
+        // No other allocation is clobbered by the block moving around
+        for (i, (p, s)) in others.iter().enumerate() {
+            let bytes = unsafe { std::slice::from_raw_parts(*p as *const u8, *s) };
+            assert!(bytes.iter().all(|b| *b == i as u8 + 1));
         }
 
+        for (p, _) in all {
+            unsafe { fm_sm_free(p); }
+        }
+        assert_heap_empty();
+        deinit(m);
+    }
+
+    #[test]
+    fn test_linear_realloc(
+        seed in 0..=u64::MAX,
+        times in 20..100,
+    ) {
+        let m = init(REALLOC_MEMORY_SIZE);
+        let l = global_heap().linear();
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut ptrs: Vec<(NonNull<u8>, Layout, AllocType, u8)> = vec![];
+        for i in 0..16u8 {
+            let align = FM_PAGE_SIZE << rng.gen_range(0..3);
+            let layout = Layout::from_size_align(rng.gen_range(1..=MAX_ALLOC_SIZE), align).unwrap();
+            let kind = if rng.gen() { AllocType::Transient } else { AllocType::Persistent };
+            let p = l.alloc_aligned(layout, kind).unwrap();
+            unsafe { std::ptr::write_bytes(p.as_ptr(), i, layout.size()) };
+            ptrs.push((p, layout, kind, i));
+        }
+
+        for _ in 0..times {
+            let i = rng.gen_range(0..ptrs.len());
+            let (p, layout, kind, fill) = ptrs[i];
+            let new_size = gen_size(&mut rng);
+            let np = unsafe { l.realloc(p, layout, new_size, kind) }.unwrap();
+            assert_eq!(np.as_ptr() as usize % layout.align(), 0);
+            let kept = layout.size().min(new_size);
+            let data = unsafe { std::slice::from_raw_parts(np.as_ptr(), kept) };
+            assert!(data.iter().all(|b| *b == fill));
+            unsafe { std::ptr::write_bytes(np.as_ptr(), fill, new_size) };
+            ptrs[i] = (np, Layout::from_size_align(new_size, layout.align()).unwrap(), kind, fill);
+
+            let pointers: Vec<_> = ptrs
+                .iter()
+                .map(|(p, layout, _, _)| (p.as_ptr() as *mut c_void, layout.size()))
+                .collect();
+            assert_valid_linear_pointers(&pointers);
+        }
+
+        for (p, _, _, _) in ptrs {
+            unsafe { l.free(p) };
+        }
+        assert_heap_empty();
+        deinit(m);
+    }
+
+    #[test]
+    fn test_migrate(seed in 0..=u64::MAX) {
+        let m = init(262144);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut blocks = vec![];
+        for i in 0..rng.gen_range(10..200) {
+            let size = if rng.gen_ratio(4, 5) {
+                rng.gen_range(1..=1024)
+            } else {
+                rng.gen_range(1025..=20000)
+            };
+            let p = unsafe { fm_sm_malloc(size) } as *mut u8;
+            if p.is_null() {
+                break;
+            }
+            unsafe { p.write_bytes(i as u8, size) };
+            blocks.push((p, size, i as u8));
+        }
+        blocks.shuffle(&mut rng);
+        for (p, _, _) in blocks.drain(blocks.len() / 2..) {
+            unsafe { fm_sm_free(p as *mut c_void) };
+        }
+
+        let layout = Layout::from_size_align(524288, FM_PAGE_SIZE).unwrap();
+        let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+        let mut moved = 0;
+        migrate(buffer, 524288, |old, new, _| {
+            moved += 1;
+            for block in blocks.iter_mut() {
+                if block.0 == old {
+                    block.0 = new;
+                }
+            }
+        })
+        .unwrap();
+        assert_eq!(moved, blocks.len());
+        // The old buffer is no longer needed
+        deinit(m);
+
+        let start = buffer as usize;
+        for (p, size, byte) in &blocks {
+            assert!(*p as usize >= start + FM_PAGE_SIZE && *p as usize + size <= start + 524288);
+            let content = unsafe { std::slice::from_raw_parts(*p, *size) };
+            assert!(content.iter().all(|b| b == byte));
+        }
+
+        // Pages beyond the old buffer are available now
+        let p = unsafe { fm_sm_malloc(262144) };
+        assert!(!p.is_null());
+        unsafe { fm_sm_free(p) };
+        for (p, _, _) in blocks {
+            unsafe { fm_sm_free(p as *mut c_void) };
+        }
+        assert_heap_empty();
+        unsafe { std::alloc::dealloc(buffer, layout) };
+    }
+
+    #[test]
+    fn test_multiple_heaps(seed in 0..=u64::MAX, times in 100..400) {
+        let m = init(262144);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut buffers = vec![];
+        let mut heaps = vec![];
+        for size in [131072, 393216] {
+            let layout = Layout::from_size_align(size, FM_PAGE_SIZE).unwrap();
+            let buffer = unsafe { std::alloc::alloc(layout) };
+            let heap = unsafe { fm_sm_create(buffer as *mut c_void, size, 0) };
+            assert!(!heap.is_null());
+            buffers.push((buffer, layout));
+            heaps.push(heap);
+        }
+        let ranges: Vec<(usize, usize)> = buffers
+            .iter()
+            .map(|(b, l)| (*b as usize, *b as usize + l.size()))
+            .collect();
+
+        // Index 2 stands for the global heap
+        let mut ptrs: Vec<Vec<(*mut c_void, usize)>> = vec![vec![], vec![], vec![]];
+        for _ in 0..times {
+            let h = rng.gen_range(0..3);
+            let size = if rng.gen_ratio(4, 5) {
+                rng.gen_range(1..=1024)
+            } else {
+                rng.gen_range(1025..=20000)
+            };
+            match rng.gen_range(0..3) {
+                0 | 1 if ptrs[h].is_empty() || rng.gen_ratio(2, 3) => {
+                    let p = if h < 2 {
+                        unsafe { fm_sm_heap_malloc(heaps[h], size) }
+                    } else {
+                        unsafe { fm_sm_malloc(size) }
+                    };
+                    if !p.is_null() {
+                        ptrs[h].push((p, size));
+                    }
+                }
+                0 | 1 => {
+                    let i = rng.gen_range(0..ptrs[h].len());
+                    let (p, _) = ptrs[h].swap_remove(i);
+                    if h < 2 {
+                        unsafe { fm_sm_heap_free(heaps[h], p) };
+                    } else {
+                        unsafe { fm_sm_free(p) };
+                    }
+                }
+                _ if !ptrs[h].is_empty() => {
+                    let i = rng.gen_range(0..ptrs[h].len());
+                    let np = if h < 2 {
+                        unsafe { fm_sm_heap_realloc(heaps[h], ptrs[h][i].0, size) }
+                    } else {
+                        unsafe { fm_sm_realloc(ptrs[h][i].0, size) }
+                    };
+                    if !np.is_null() {
+                        ptrs[h][i] = (np, size);
+                    }
+                }
+                _ => (),
+            }
+            for (h, range) in ranges.iter().enumerate() {
+                if !ptrs[h].is_empty() {
+                    assert_valid_pointers_in(&ptrs[h], &[*range]);
+                }
+            }
+            if !ptrs[2].is_empty() {
+                assert_valid_pointers(&ptrs[2]);
+            }
+        }
+
+        for (p, _) in ptrs[2].drain(..) {
+            unsafe { fm_sm_free(p) };
+        }
+        assert_heap_empty();
+        for (h, heap) in heaps.into_iter().enumerate() {
+            for (p, _) in ptrs[h].drain(..) {
+                unsafe { fm_sm_heap_free(heap, p) };
+            }
+            unsafe { fm_sm_destroy(heap) };
+        }
+        for (buffer, layout) in buffers {
+            unsafe { std::alloc::dealloc(buffer, layout) };
+        }
         deinit(m);
     }
 }
@@ -1,12 +1,47 @@
+// Tests needing FixedAlloc::new_static are left out with manual-init, along
+// with the helpers they use
+#![cfg_attr(feature = "manual-init", allow(dead_code, unused_imports))]
+
 use super::*;
 use fixed_malloc::ffi::*;
-use rusty_fork::rusty_fork_test;
+use fixed_malloc::{
+    default_static_size, format_alloc_stats, handle_alloc_error, migrate, min_buffer_size,
+    reinitialize, reinitialize_swap, try_reinitialize, AdoptError, AllocType, BumpString,
+    FixedAlloc, FmError, Heap, HeapErrorKind, ReinitError, TieredAlloc, Tracked, Zeroable,
+    STATIC_MEMORY_SIZE, TIERED_SMALL_MAX,
+};
+use rusty_fork::{fork, rusty_fork_id, rusty_fork_test, rusty_fork_test_name, ExitStatusWrapper};
+use std::alloc::{GlobalAlloc, Layout};
+use std::ffi::c_int;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Slab size classes, which follow FM_SLAB_MIN_SIZE and FM_PAGE_SIZE as in
+// slab-malloc.c
+const SLAB_SIZES: &[usize] = match FM_SLAB_MIN_SIZE {
+    16 => &[16, 32, 64, 128, FM_PAGE_SIZE / 8, FM_PAGE_SIZE / 4],
+    32 => &[32, 64, 128, FM_PAGE_SIZE / 8, FM_PAGE_SIZE / 4],
+    _ => &[64, 128, FM_PAGE_SIZE / 8, FM_PAGE_SIZE / 4],
+};
+
+// Size class serving an allocation of size bytes
+fn slab_class(size: usize) -> usize {
+    *SLAB_SIZES.iter().find(|s| **s >= size).expect("slab size")
+}
+
+static OOM_CALLS: AtomicUsize = AtomicUsize::new(0);
+static OOM_REQUESTED: AtomicUsize = AtomicUsize::new(0);
+
+fn record_oom(requested: usize) {
+    OOM_CALLS.fetch_add(1, Ordering::SeqCst);
+    OOM_REQUESTED.store(requested, Ordering::SeqCst);
+}
 
 rusty_fork_test! {
 
 #[test]
 fn test_reinit() {
-    assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, 655360);
+    init_static_buffer();
+    assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, FM_MEMORY_SIZE);
 
     let m = init(32 * 4096);
     assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, 32 * 4096);
@@ -16,6 +51,7 @@ fn test_reinit() {
 
 #[test]
 fn test_simple_malloc_free() {
+    init_static_buffer();
     let p1 = unsafe { fm_sm_malloc(17) };
     assert!(!p1.is_null());
     let p2 = unsafe { fm_sm_malloc(32) };
@@ -30,6 +66,7 @@ fn test_simple_malloc_free() {
 
 #[test]
 fn test_repeated_malloc() {
+    init_static_buffer();
     for _ in 0..5000 {
         let p = unsafe { fm_sm_malloc(32) };
         assert!(!p.is_null());
@@ -38,27 +75,2068 @@ fn test_repeated_malloc() {
 
 #[test]
 fn test_malloc_biggest() {
-    let p = unsafe { fm_sm_malloc(651264) };
+    init_static_buffer();
+    let p = unsafe { fm_sm_malloc(FM_MEMORY_SIZE - FM_PAGE_SIZE) };
     assert!(!p.is_null());
 }
 
 #[test]
 fn test_malloc_free_then_malloc_biggest() {
+    init_static_buffer();
     let p1 = unsafe { fm_sm_malloc(17) };
     assert!(!p1.is_null());
     let p2 = unsafe { fm_sm_malloc(32) };
     assert!(!p2.is_null());
     let p3 = unsafe { fm_sm_malloc(5000) };
     assert!(!p3.is_null());
-    let p4 = unsafe { fm_sm_malloc(651264) };
+    let p4 = unsafe { fm_sm_malloc(FM_MEMORY_SIZE - FM_PAGE_SIZE) };
     assert!(p4.is_null());
 
     unsafe { fm_sm_free(p1); }
     unsafe { fm_sm_free(p2); }
     unsafe { fm_sm_free(p3); }
 
-    let p4 = unsafe { fm_sm_malloc(651264) };
+    let p4 = unsafe { fm_sm_malloc(FM_MEMORY_SIZE - FM_PAGE_SIZE) };
     assert!(!p4.is_null());
 }
 
 }
+
+// FixedAlloc::new_static is not available with manual-init
+#[cfg(not(feature = "manual-init"))]
+rusty_fork_test! {
+
+#[test]
+fn test_alloc_array() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let p = a.alloc_array::<u64>(100).expect("alloc");
+    assert_eq!(p.as_ptr() as usize % core::mem::align_of::<u64>(), 0);
+
+    let values = unsafe { core::slice::from_raw_parts_mut(p.as_ptr(), 100) };
+    for (i, v) in values.iter_mut().enumerate() {
+        *v = i as u64 * 3;
+    }
+    for (i, v) in values.iter().enumerate() {
+        assert_eq!(*v, i as u64 * 3);
+    }
+    assert_valid_pointers(&[(p.as_ptr() as *mut c_void, 800)]);
+
+    unsafe { a.dealloc_array(p, 100) };
+    assert!(a.alloc_array::<u64>(usize::MAX).is_none());
+}
+
+#[test]
+fn test_alloc_slice() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let mut uninit = a.alloc_slice::<u64>(100).expect("alloc");
+    for (i, v) in uninit.iter_mut().enumerate() {
+        v.write(i as u64 * 3);
+    }
+    let mut values = unsafe { uninit.assume_init() };
+    assert_eq!(values.len(), 100);
+    values[99] += 1;
+    assert_eq!(values[10], 30);
+    assert_eq!(values[99], 298);
+    assert_valid_pointers(&[(values.as_ptr() as *mut c_void, 800)]);
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(C, align(64))]
+    struct Line([u8; 64]);
+    unsafe impl Zeroable for Line {}
+    let mut lines = a.alloc_slice_zeroed::<Line>(3).expect("alloc");
+    assert_eq!(lines.as_ptr() as usize % 64, 0);
+    assert!(lines.iter().all(|line| *line == Line([0; 64])));
+    lines[1].0[5] = 7;
+    assert_eq!(lines[1].0[5], 7);
+
+    // Elements are dropped along with the slice
+    let drops = std::rc::Rc::new(());
+    let mut shared = a.alloc_slice(4).expect("alloc");
+    for v in shared.iter_mut() {
+        v.write(drops.clone());
+    }
+    let shared = unsafe { shared.assume_init() };
+    assert_eq!(std::rc::Rc::strong_count(&drops), 5);
+    drop(shared);
+    assert_eq!(std::rc::Rc::strong_count(&drops), 1);
+
+    assert_eq!(a.alloc_slice_zeroed::<u32>(0).expect("alloc").len(), 0);
+    assert!(a.alloc_slice::<u64>(usize::MAX).is_none());
+    assert!(a.alloc_slice_zeroed::<u64>(FM_MEMORY_SIZE).is_none());
+    a.clear_error();
+    drop(values);
+    drop(lines);
+    assert_heap_empty();
+}
+
+#[test]
+fn test_alloc_nonnull() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let small = Layout::from_size_align(64, 16).unwrap();
+    let grown = || -> Option<std::ptr::NonNull<u8>> {
+        let p = a.alloc_nonnull(small)?;
+        unsafe { core::ptr::write_bytes(p.as_ptr(), 0x5A, 64) };
+        unsafe { a.realloc_nonnull(p, small, 3 * FM_PAGE_SIZE) }
+    };
+    let p = grown().expect("alloc");
+    assert_eq!(unsafe { *p.as_ptr().add(63) }, 0x5A);
+    assert_valid_pointers(&[(p.as_ptr() as *mut c_void, 3 * FM_PAGE_SIZE)]);
+
+    let too_large = Layout::from_size_align(FM_MEMORY_SIZE + FM_PAGE_SIZE, 16).unwrap();
+    assert!(a.alloc_nonnull(too_large).is_none());
+    let large = Layout::from_size_align(3 * FM_PAGE_SIZE, 16).unwrap();
+    assert!(unsafe { a.realloc_nonnull(p, large, too_large.size()) }.is_none());
+    assert_eq!(unsafe { *p.as_ptr().add(63) }, 0x5A);
+    a.clear_error();
+
+    unsafe { a.dealloc(p.as_ptr(), large) };
+    assert_heap_empty();
+}
+
+#[test]
+fn test_alloc_page_aligned() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let p = a.alloc_page_aligned(3).expect("alloc");
+    assert_eq!(p.as_ptr() as usize % FM_PAGE_SIZE, 0);
+    assert_eq!(a.usable_size(p.as_ptr()), 3 * FM_PAGE_SIZE);
+    unsafe { core::ptr::write_bytes(p.as_ptr(), 0x5A, 3 * FM_PAGE_SIZE) };
+    assert_valid_pointers(&[(p.as_ptr() as *mut c_void, 3 * FM_PAGE_SIZE)]);
+
+    unsafe { a.dealloc_page_aligned(p, 3) };
+    assert!(a.alloc_page_aligned(0).is_none());
+    assert!(a.alloc_page_aligned(usize::MAX).is_none());
+    assert!(a.alloc_page_aligned(FM_MEMORY_SIZE / FM_PAGE_SIZE + 1).is_none());
+    a.clear_error();
+}
+
+#[test]
+fn test_alloc_overaligned_array() {
+    #[repr(align(128))]
+    struct Aligned([u8; 128]);
+
+    let a = unsafe { FixedAlloc::new_static() };
+    let p = a.alloc_array::<Aligned>(3).expect("alloc");
+    assert_eq!(p.as_ptr() as usize % 128, 0);
+    unsafe {
+        p.as_ptr().write(Aligned([7; 128]));
+        assert_eq!((*p.as_ptr()).0[127], 7);
+        a.dealloc_array(p, 3);
+    }
+}
+
+#[test]
+fn test_realloc_overaligned() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let layout = Layout::from_size_align(200, 128).expect("layout");
+    let p = unsafe { a.alloc(layout) };
+    assert_eq!(p as usize % 128, 0);
+    unsafe { p.write_bytes(0x42, 200) };
+    // The block stays aligned whether it grows or shrinks
+    let p = unsafe { a.realloc(p, layout, 3 * FM_PAGE_SIZE) };
+    assert_eq!(p as usize % 128, 0);
+    assert!(unsafe { std::slice::from_raw_parts(p, 200) }.iter().all(|b| *b == 0x42));
+    let grown = Layout::from_size_align(3 * FM_PAGE_SIZE, 128).expect("layout");
+    let p = unsafe { a.realloc(p, grown, 100) };
+    assert_eq!(p as usize % 128, 0);
+    unsafe { a.dealloc(p, Layout::from_size_align(100, 128).expect("layout")) };
+    assert_eq!(a.live_allocations(), 0);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+}
+
+#[test]
+fn test_realloc_fill() {
+    let a = unsafe { FixedAlloc::new_static() };
+    a.set_realloc_fill(true);
+    let mut p = unsafe { fm_sm_malloc(100) } as *mut u8;
+    let mut old_size = unsafe { fm_sm_usable_size(p as *const c_void) };
+    let ramp: Vec<u8> = (0..old_size).map(|i| i as u8).collect();
+    unsafe { core::ptr::copy_nonoverlapping(ramp.as_ptr(), p, old_size) };
+
+    // Into a larger slab, a page block, then a larger page block
+    for size in [1000, 2 * FM_PAGE_SIZE, 3 * FM_PAGE_SIZE] {
+        p = unsafe { fm_sm_realloc(p as *mut c_void, size) } as *mut u8;
+        assert!(!p.is_null());
+        assert_eq!(unsafe { fm_sm_test_realloc_old_size() }, old_size);
+        let usable = unsafe { fm_sm_usable_size(p as *const c_void) };
+        let block = unsafe { core::slice::from_raw_parts(p, usable) };
+        assert_eq!(&block[..ramp.len()], &ramp[..]);
+        assert!(block[old_size..]
+            .iter()
+            .all(|b| *b == FM_REALLOC_FILL_PATTERN));
+        old_size = usable;
+    }
+
+    // Shrinking keeps the block and gains no bytes
+    let q = unsafe { fm_sm_realloc(p as *mut c_void, 100) } as *mut u8;
+    assert_eq!(unsafe { fm_sm_test_realloc_old_size() }, old_size);
+    assert_eq!(unsafe { core::slice::from_raw_parts(q, 100) }, &ramp[..100]);
+    unsafe { fm_sm_free(q as *mut c_void) };
+    assert_eq!(a.live_allocations(), 0);
+}
+
+#[test]
+fn test_calloc() {
+    let a = unsafe { FixedAlloc::new_static() };
+
+    // Dirty the memory first so zeroing can be observed
+    let p = unsafe { fm_sm_malloc(1000) } as *mut u8;
+    unsafe { core::ptr::write_bytes(p, 0xFF, 1000) };
+    unsafe { fm_sm_free(p as *mut c_void) };
+
+    let p = a.calloc(10, 100).expect("calloc");
+    let values = unsafe { core::slice::from_raw_parts(p.as_ptr(), 1000) };
+    assert!(values.iter().all(|v| *v == 0));
+
+    assert!(a.calloc(usize::MAX, 2).is_none());
+    assert!(unsafe { fm_sm_calloc(2, usize::MAX / 2 + 1) }.is_null());
+}
+
+#[test]
+fn test_alloc_zeroed() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let layout = Layout::from_size_align(5000, 8).expect("layout");
+    let p = unsafe { a.alloc_zeroed(layout) };
+    assert!(!p.is_null());
+    let values = unsafe { core::slice::from_raw_parts(p, 5000) };
+    assert!(values.iter().all(|v| *v == 0));
+    unsafe { a.dealloc(p, layout) };
+}
+
+#[test]
+fn test_quarantine() {
+    let a = unsafe { FixedAlloc::new_static() };
+    a.set_quarantine(4);
+
+    let ptrs: Vec<_> = (0..5).map(|_| unsafe { fm_sm_malloc(32) }).collect();
+    for p in &ptrs[0..4] {
+        unsafe { fm_sm_free(*p) };
+    }
+
+    let p = unsafe { fm_sm_malloc(32) };
+    assert!(!p.is_null());
+    assert!(!ptrs[0..4].contains(&p));
+
+    // The 5th free evicts the oldest quarantined block
+    unsafe { fm_sm_free(ptrs[4]) };
+    let p = unsafe { fm_sm_malloc(32) };
+    assert_eq!(p, ptrs[0]);
+}
+
+#[test]
+fn test_oom_hook() {
+    let a = unsafe { FixedAlloc::new_static() };
+    a.set_oom_hook(record_oom);
+
+    let p = unsafe { fm_sm_malloc(FM_MEMORY_SIZE + FM_PAGE_SIZE) };
+    assert!(p.is_null());
+    assert_eq!(OOM_CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(OOM_REQUESTED.load(Ordering::SeqCst), FM_MEMORY_SIZE + FM_PAGE_SIZE);
+
+    let p = unsafe { fm_sm_malloc(17) };
+    assert!(!p.is_null());
+    let np = unsafe { fm_sm_realloc(p, FM_MEMORY_SIZE - FM_PAGE_SIZE) };
+    assert!(np.is_null());
+    assert_eq!(OOM_CALLS.load(Ordering::SeqCst), 2);
+    assert_eq!(OOM_REQUESTED.load(Ordering::SeqCst), FM_MEMORY_SIZE - FM_PAGE_SIZE);
+
+    a.clear_oom_hook();
+    let p = unsafe { fm_sm_malloc(FM_MEMORY_SIZE + FM_PAGE_SIZE) };
+    assert!(p.is_null());
+    assert_eq!(OOM_CALLS.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_error_sink() {
+    static ERRORS: std::sync::Mutex<Vec<(FmError, String)>> = std::sync::Mutex::new(vec![]);
+    fn record(error: FmError, message: &std::ffi::CStr) {
+        ERRORS.lock().unwrap().push((error, message.to_string_lossy().into_owned()));
+    }
+    let a = unsafe { FixedAlloc::new_static() };
+    a.set_error_sink(record);
+
+    let p = unsafe { fm_sm_malloc(32) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+    assert!(ERRORS.lock().unwrap().is_empty());
+    unsafe { fm_sm_free(p) };
+    {
+        let errors = ERRORS.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_ne!(errors[0].0.code(), FM_OK);
+        assert_eq!(errors[0].0, FmError::BadPointer);
+    }
+    assert_eq!(a.last_error(), Some(FmError::BadPointer));
+    a.clear_error();
+
+    // The heap stays usable after the rejected free
+    let p2 = unsafe { fm_sm_malloc(32) };
+    assert_eq!(p2, p);
+    unsafe { fm_sm_free(p2) };
+
+    a.clear_error_sink();
+    unsafe { fm_sm_free(p2) };
+    assert_eq!(ERRORS.lock().unwrap().len(), 1);
+    a.clear_error();
+}
+
+#[test]
+fn test_debug_stats() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let s = format!("{:?}", a);
+    assert!(s.contains(&format!("total_bytes: {}", FM_MEMORY_SIZE)), "{}", s);
+    assert!(s.contains("used_bytes: 0,"), "{}", s);
+
+    let p = unsafe { fm_sm_malloc(17) };
+    assert!(!p.is_null());
+    let p = unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) };
+    assert!(!p.is_null());
+    let s = format!("{:?}", a);
+    let class = slab_class(17);
+    assert!(s.contains(&format!("used_bytes: {},", 2 * FM_PAGE_SIZE + class)), "{}", s);
+    assert!(s.contains("used_pages: 3,"), "{}", s);
+    let free_pages = FM_MEMORY_SIZE / FM_PAGE_SIZE - 1 - 3;
+    assert!(s.contains(&format!("free_pages: {}", free_pages)), "{}", s);
+
+    unsafe { fm_sm_free(p) };
+    assert_eq!(a.stats().used_bytes, class);
+}
+
+#[test]
+fn test_dealloc_layout_check() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let layout = Layout::from_size_align(100, 8).expect("layout");
+    let p = unsafe { a.alloc(layout) };
+    assert!(!p.is_null());
+
+    let p = unsafe { a.realloc(p, layout, 200) };
+    assert!(!p.is_null());
+    let wrong = Layout::from_size_align(300, 8).expect("layout");
+    let result = std::panic::catch_unwind(|| unsafe { a.dealloc(p, wrong) });
+    assert!(result.is_err());
+
+    let p = unsafe { a.alloc(layout) };
+    unsafe { a.dealloc(p, layout) };
+}
+
+#[test]
+fn test_free_sized_checked() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let class = slab_class(100);
+    let p = unsafe { fm_sm_malloc(100) };
+    assert_ne!(unsafe { fm_sm_free_sized_checked(p, class + 1) }, 0);
+    // The block is kept when the size does not fit
+    assert_eq!(a.usable_size(p as *const u8), class);
+    assert_eq!(unsafe { fm_sm_free_sized_checked(p, class) }, 0);
+    assert_ne!(unsafe { fm_sm_free_sized_checked(p, class) }, 0);
+
+    // Blocks allocated via the C API are not in the layout table, dealloc
+    // still catches a wrong layout
+    let p = unsafe { fm_sm_malloc(100) } as *mut u8;
+    let wrong = Layout::from_size_align(class + 1, 8).expect("layout");
+    let result = std::panic::catch_unwind(|| unsafe { a.dealloc(p, wrong) });
+    assert!(result.is_err());
+    unsafe { a.dealloc(p, Layout::from_size_align(100, 8).expect("layout")) };
+    assert_eq!(a.live_allocations(), 0);
+}
+
+#[test]
+fn test_last_error() {
+    let a = unsafe { FixedAlloc::new_static() };
+    assert_eq!(a.last_error(), None);
+
+    let p = unsafe { fm_sm_malloc(FM_MEMORY_SIZE - FM_PAGE_SIZE + 1) };
+    assert!(p.is_null());
+    assert_eq!(a.last_error(), Some(FmError::TooLarge));
+    a.clear_error();
+    assert_eq!(a.last_error(), None);
+
+    let p = unsafe { fm_sm_malloc(usize::MAX) };
+    assert!(p.is_null());
+    assert_eq!(a.last_error(), Some(FmError::TooLarge));
+    a.clear_error();
+
+    loop {
+        let p = unsafe { fm_sm_malloc(32) };
+        if p.is_null() {
+            break;
+        }
+        assert_eq!(a.last_error(), None);
+    }
+    assert_eq!(a.last_error(), Some(FmError::NoMemory));
+}
+
+#[test]
+fn test_class_stats() {
+    let a = unsafe { FixedAlloc::new_static() };
+    for _ in 0..10 {
+        assert!(!unsafe { fm_sm_malloc(FM_SLAB_MIN_SIZE) }.is_null());
+    }
+    let p = unsafe { fm_sm_malloc(100) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+
+    let mut stats = Vec::new();
+    a.class_stats(|s| stats.push(s));
+    assert_eq!(stats.iter().map(|s| s.class_size).collect::<Vec<_>>(), SLAB_SIZES);
+    let i = SLAB_SIZES.iter().position(|s| *s == 128).unwrap();
+    assert_eq!(stats[0].slabs, 1);
+    assert!(stats[0].used_slots >= 10);
+    assert_eq!(
+        stats[0].used_slots + stats[0].free_slots,
+        (FM_PAGE_SIZE - FM_SLAB_RESERVED_SIZE) / FM_SLAB_MIN_SIZE
+    );
+    for (j, s) in stats.iter().enumerate() {
+        if j != 0 && j != i {
+            assert_eq!(s.slabs, 0);
+        }
+    }
+    assert_eq!(stats[i].slabs, 1);
+    assert_eq!(stats[i].used_slots, 0);
+    assert_eq!(stats[i].free_slots, (FM_PAGE_SIZE - FM_SLAB_RESERVED_SIZE) / 128);
+}
+
+#[test]
+fn test_size_histogram() {
+    let m = init(32 * 4096);
+    let a = unsafe { FixedAlloc::new_static() };
+    assert_eq!(a.size_histogram(), [0; FM_SM_HISTOGRAM_BUCKETS]);
+
+    for _ in 0..3 {
+        assert!(!unsafe { fm_sm_malloc(17) }.is_null());
+    }
+    // Rounded up to 2 pages
+    assert!(!unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) }.is_null());
+    assert!(!unsafe { fm_sm_malloc(70000) }.is_null());
+
+    let histogram = a.size_histogram();
+    assert_eq!(histogram[slab_class(17).ilog2() as usize], 3);
+    assert_eq!(histogram[(2 * FM_PAGE_SIZE).ilog2() as usize], 1);
+    // Blocks of 64KiB and beyond are all counted in the last bucket
+    assert_eq!(histogram[FM_SM_HISTOGRAM_BUCKETS - 1], 1);
+    assert_eq!(histogram.iter().sum::<u64>(), 5);
+    deinit(m);
+}
+
+#[test]
+fn test_format_alloc_stats() {
+    let m = init(32 * 4096);
+    let a = unsafe { FixedAlloc::new_static() };
+    // Rounded up to 2 pages, the slab object takes a third page
+    assert!(!unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) }.is_null());
+    assert!(!unsafe { fm_sm_malloc(17) }.is_null());
+
+    let used = 2 * FM_PAGE_SIZE + slab_class(17);
+    let frag = (3 * FM_PAGE_SIZE - used) * 1000 / (3 * FM_PAGE_SIZE);
+    let expected = format!(
+        "FixedAlloc: {}/131072 bytes used ({}.{}%), 2 live allocs, frag={}.{}%",
+        used,
+        used * 1000 / 131072 / 10,
+        used * 1000 / 131072 % 10,
+        frag / 10,
+        frag % 10
+    );
+    let mut out = [0u8; 128];
+    let n = format_alloc_stats(&a, &mut out);
+    assert_eq!(std::str::from_utf8(&out[..n]).unwrap(), expected);
+
+    // Cut off at the end of a short buffer
+    let mut out = [0u8; 10];
+    assert_eq!(format_alloc_stats(&a, &mut out), 10);
+    assert_eq!(&out, b"FixedAlloc");
+    deinit(m);
+}
+
+#[test]
+fn test_linear_alloc_aligned() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let l = a.linear();
+    let start = unsafe { fm_lm_test_buffer_pointer() } as usize;
+    let end = start + unsafe { fm_lm_test_total_buffer_size() };
+
+    let layout = Layout::from_size_align(10000, 4096).unwrap();
+    let p = l.alloc_aligned(layout, AllocType::Transient).unwrap();
+    let addr = p.as_ptr() as usize;
+    assert_eq!(addr % 4096, 0);
+    assert!(addr >= start + FM_PAGE_SIZE && addr + 10000 <= end);
+
+    for (align, kind) in [(65536, AllocType::Transient), (32768, AllocType::Persistent)] {
+        let layout = Layout::from_size_align(5000, align).unwrap();
+        let q = l.alloc_aligned(layout, kind).unwrap();
+        let addr = q.as_ptr() as usize;
+        assert_eq!(addr % align, 0);
+        assert!(addr >= start + FM_PAGE_SIZE && addr + 5000 <= end);
+        unsafe { q.as_ptr().write_bytes(0x5A, 5000) };
+        unsafe { l.free(q) };
+    }
+    unsafe { l.free(p) };
+
+    // Freed pages, including those split around aligned blocks, are reusable
+    let stats = a.stats();
+    let all = unsafe { fm_lm_malloc((stats.total_pages) * FM_PAGE_SIZE, FM_LM_T_TRANSIENT) };
+    assert!(!all.is_null());
+
+    assert_eq!(unsafe { fm_lm_aligned_malloc(4096, 3 * 4096, FM_LM_T_TRANSIENT) }, std::ptr::null_mut());
+    assert_eq!(a.last_error(), Some(FmError::BadAlignment));
+}
+
+#[test]
+fn test_alloc_type() {
+    assert_eq!(c_int::from(AllocType::Transient), FM_LM_T_TRANSIENT);
+    assert_eq!(c_int::from(AllocType::Persistent), FM_LM_T_PERSISTENT);
+
+    let p = unsafe { fm_lm_malloc(FM_PAGE_SIZE, AllocType::Transient.into()) } as usize;
+    let q = unsafe { fm_lm_malloc(FM_PAGE_SIZE, AllocType::Persistent.into()) } as usize;
+    let start = unsafe { fm_lm_test_buffer_pointer() } as usize;
+    let end = start + unsafe { fm_lm_test_total_buffer_size() };
+    assert_eq!(p, start + FM_PAGE_SIZE);
+    assert_eq!(q, end - FM_PAGE_SIZE);
+}
+
+#[test]
+fn test_linear_realloc_with() {
+    let l = unsafe { FixedAlloc::new_static() }.linear();
+    let start = unsafe { fm_lm_test_buffer_pointer() } as usize;
+    let end = start + unsafe { fm_lm_test_total_buffer_size() };
+
+    let p = l.alloc_pages(1, AllocType::Persistent).unwrap().cast::<u8>();
+    assert_eq!(p.as_ptr() as usize, end - FM_PAGE_SIZE);
+    unsafe { p.as_ptr().write_bytes(0x5A, FM_PAGE_SIZE) };
+
+    // Nothing follows the last page, so the block moves within persistent
+    // pages
+    let p = unsafe { l.realloc_with(p, 2 * FM_PAGE_SIZE, AllocType::Persistent) }.unwrap();
+    assert_eq!(p.as_ptr() as usize, end - 3 * FM_PAGE_SIZE);
+    assert!(unsafe { std::slice::from_raw_parts(p.as_ptr(), FM_PAGE_SIZE) }
+        .iter()
+        .all(|b| *b == 0x5A));
+
+    // A block resized in place keeps its place regardless of the type
+    let q = unsafe { l.realloc_with(p, 2 * FM_PAGE_SIZE, AllocType::Transient) }.unwrap();
+    assert_eq!(q, p);
+
+    // A block that has to move is migrated to transient pages
+    let q = unsafe { l.realloc_with(q, 4 * FM_PAGE_SIZE, AllocType::Transient) }.unwrap();
+    assert_eq!(q.as_ptr() as usize, start + FM_PAGE_SIZE);
+    assert!(unsafe { std::slice::from_raw_parts(q.as_ptr(), FM_PAGE_SIZE) }
+        .iter()
+        .all(|b| *b == 0x5A));
+    unsafe { l.free(q) };
+}
+
+#[test]
+fn test_error_codes() {
+    let errors = [
+        (FmError::TooLarge, FM_ERR_TOO_LARGE),
+        (FmError::NoMemory, FM_ERR_NO_MEMORY),
+        (FmError::NotInitialized, FM_ERR_NOT_INITIALIZED),
+        (FmError::BadPointer, FM_ERR_BAD_POINTER),
+        (FmError::BadAlignment, FM_ERR_BAD_ALIGNMENT),
+        (FmError::NullBuffer, FM_ERR_NULL_BUFFER),
+        (FmError::UnalignedBuffer, FM_ERR_UNALIGNED_BUFFER),
+        (FmError::UnalignedSize, FM_ERR_UNALIGNED_SIZE),
+        (FmError::BufferTooSmall, FM_ERR_BUFFER_TOO_SMALL),
+        (FmError::BufferTooLarge, FM_ERR_BUFFER_TOO_LARGE),
+        (FmError::LiveAllocations, FM_ERR_LIVE_ALLOCATIONS),
+        (FmError::BufferOverlap, FM_ERR_BUFFER_OVERLAP),
+        (FmError::TooManyRegions, FM_ERR_TOO_MANY_REGIONS),
+        (FmError::BadSnapshot, FM_ERR_BAD_SNAPSHOT),
+        (FmError::Reentrant, FM_ERR_REENTRANT),
+        (FmError::Corrupted, FM_ERR_CORRUPTED),
+        (FmError::Unknown(42), 42),
+    ];
+    for (e, code) in errors {
+        assert_eq!(c_int::from(e), code);
+        assert_eq!(FmError::try_from(code), Ok(e));
+        assert_eq!(FmError::check(code), Err(e));
+    }
+    assert_eq!(FmError::try_from(FM_OK), Err(()));
+    assert_eq!(FmError::check(FM_OK), Ok(()));
+}
+
+#[cfg(feature = "fmt")]
+#[test]
+fn test_error_display() {
+    assert_eq!(FmError::NoMemory.to_string(), "out of memory");
+    assert_eq!(FmError::Unknown(42).to_string(), "unknown error code 42");
+}
+
+#[test]
+fn test_leak_detected() {
+    let result = std::panic::catch_unwind(|| {
+        assert_no_leaks(655360, || {
+            unsafe { fm_sm_malloc(100) };
+            let p = unsafe { fm_sm_malloc(20) };
+            unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) };
+            unsafe { fm_sm_free(p) };
+        })
+    });
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    let leaked = format!("{} bytes are leaked in 2 allocations", 2 * FM_PAGE_SIZE + 128);
+    assert!(message.starts_with(&leaked), "{}", message);
+    let large = format!("(offset {:x}): {} bytes", FM_PAGE_SIZE, 2 * FM_PAGE_SIZE);
+    assert!(message.contains(&large), "{}", message);
+    assert!(message.contains("128 bytes"), "{}", message);
+}
+
+#[test]
+fn test_reinit_validation() {
+    let layout = Layout::from_size_align(FM_MAX_MEMORY_SIZE + FM_PAGE_SIZE, FM_PAGE_SIZE).unwrap();
+    let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+    let cases = [
+        (std::ptr::null_mut(), 655360, FmError::NullBuffer),
+        (unsafe { buffer.add(16) }, 655360, FmError::UnalignedBuffer),
+        (buffer, 655360 + 16, FmError::UnalignedSize),
+        (buffer, FM_MIN_MEMORY_SIZE - FM_PAGE_SIZE, FmError::BufferTooSmall),
+        (buffer, 0, FmError::BufferTooSmall),
+        (buffer, FM_MAX_MEMORY_SIZE + FM_PAGE_SIZE, FmError::BufferTooLarge),
+    ];
+    for (b, len, e) in cases {
+        assert_eq!(try_reinitialize(b, len, true), Err(ReinitError::from(e)));
+        assert_eq!(unsafe { fm_sm_reinit(b as *mut c_void, len, 1) }, c_int::from(e));
+    }
+
+    // Static memory is still in use after failed attempts
+    assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, FM_MEMORY_SIZE);
+
+    assert_eq!(try_reinitialize(buffer, FM_MAX_MEMORY_SIZE, true), Ok(()));
+    let p = unsafe { fm_sm_malloc(FM_MAX_MEMORY_SIZE - FM_PAGE_SIZE) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+
+    assert_eq!(try_reinitialize(buffer, FM_MIN_MEMORY_SIZE, false), Ok(()));
+    let p = unsafe { fm_sm_malloc(FM_PAGE_SIZE) };
+    assert_eq!(p as usize, buffer as usize + FM_PAGE_SIZE);
+    assert!(unsafe { fm_sm_malloc(1) }.is_null());
+}
+
+#[test]
+fn test_reinit_misaligned() {
+    let layout = Layout::from_size_align(65536 + FM_PAGE_SIZE, FM_PAGE_SIZE).unwrap();
+    let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+    let misaligned = unsafe { buffer.add(8) };
+    assert_eq!(try_reinitialize(misaligned, 65536, true), Err(ReinitError::UnalignedBuffer));
+    assert_eq!(unsafe { fm_sm_reinit(misaligned as *mut c_void, 65536, 1) }, FM_ERR_UNALIGNED_BUFFER);
+    assert_eq!(
+        try_reinitialize(buffer, 65536 + 100, true),
+        Err(ReinitError::UnalignedSize)
+    );
+    assert_eq!(unsafe { fm_sm_reinit(buffer as *mut c_void, 65536 + 100, 1) }, FM_ERR_UNALIGNED_SIZE);
+    // Both are also refused when swapping buffers
+    assert_eq!(reinitialize_swap(misaligned, 65536, true), Err(ReinitError::UnalignedBuffer));
+
+    // Nothing is touched by the failed attempts
+    assert_eq!(unsafe { fm_lm_test_total_buffer_size() }, FM_MEMORY_SIZE);
+    assert!(unsafe { std::slice::from_raw_parts(buffer, 65536) }.iter().all(|b| *b == 0));
+    unsafe { std::alloc::dealloc(buffer, layout) };
+}
+
+#[test]
+fn test_reinit_swap() {
+    let a = init(65536);
+    let b = init(131072);
+    // b is installed now, swap back to a
+    let (old, size) = reinitialize_swap(a.0 as *mut u8, 65536, true).unwrap();
+    assert_eq!((old as *mut c_void, size), (b.0, 131072));
+    deinit(b);
+
+    let c = init(65536);
+    let (old, size) = reinitialize_swap(c.0 as *mut u8, 65536, true).unwrap();
+    assert_eq!((old as *mut c_void, size), (c.0, 65536));
+    let (old, size) = reinitialize_swap(a.0 as *mut u8, 65536, false).unwrap();
+    assert_eq!((old as *mut c_void, size), (c.0, 65536));
+    let p = unsafe { fm_sm_malloc(32) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+    deinit(c);
+
+    // Failed swaps keep using the current buffer
+    assert_eq!(
+        reinitialize_swap(std::ptr::null_mut(), 65536, true),
+        Err(ReinitError::Failed(FmError::NullBuffer))
+    );
+    assert_eq!(unsafe { fm_lm_test_buffer_pointer() }, a.0);
+    deinit(a);
+}
+
+#[test]
+fn test_reinit_with_live_allocations() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let m = init(65536);
+    let start = || unsafe { fm_lm_test_buffer_pointer() };
+
+    let p = unsafe { fm_sm_malloc(100) };
+    assert!(!p.is_null());
+    assert_eq!(a.live_allocations(), 1);
+    assert_eq!(
+        try_reinitialize(m.0 as *mut u8, 65536, true),
+        Err(ReinitError::Failed(FmError::LiveAllocations))
+    );
+    assert_eq!(
+        reinitialize_swap(m.0 as *mut u8, 65536, true),
+        Err(ReinitError::Failed(FmError::LiveAllocations))
+    );
+    // The block is still usable
+    unsafe { (p as *mut u8).write_bytes(0x11, 100) };
+
+    unsafe { fm_sm_free(p) };
+    assert_eq!(a.live_allocations(), 0);
+    assert_eq!(try_reinitialize(m.0 as *mut u8, 65536, false), Ok(()));
+
+    let q = unsafe { fm_sm_malloc(5000) };
+    let r = unsafe { fm_sm_malloc(32) };
+    assert!(!q.is_null() && !r.is_null());
+    assert_eq!(a.live_allocations(), 2);
+    assert_eq!(
+        unsafe { fm_sm_reinit(m.0, 65536, 0) },
+        FM_ERR_LIVE_ALLOCATIONS
+    );
+    reinitialize(m.0 as *mut u8, 65536, false);
+    assert_eq!(start(), m.0);
+    assert_eq!(a.live_allocations(), 0);
+    assert_eq!(a.stats().used_pages, 0);
+    // Allocation starts from scratch after forced reinit
+    assert_eq!(unsafe { fm_sm_malloc(5000) }, q);
+    deinit(m);
+}
+
+#[test]
+fn test_with_capacity_hint() {
+    let m = init(16 * FM_PAGE_SIZE);
+    let a = unsafe { FixedAlloc::with_capacity_hint(m.0 as *mut u8, 16 * FM_PAGE_SIZE, false, 3 * FM_PAGE_SIZE) };
+    let stats = a.stats();
+    assert_eq!(stats.used_bytes, 0);
+    assert_eq!(stats.free_pages, 15);
+    // The warmed up pages are freed memories now, which are reused after
+    // the rest of free pages.
+    let p = unsafe { fm_lm_malloc(FM_PAGE_SIZE, FM_LM_T_TRANSIENT) };
+    assert_eq!(p as usize, m.0 as usize + 4 * FM_PAGE_SIZE);
+
+    // Warm up size is clamped to the buffer
+    let a = unsafe { FixedAlloc::with_capacity_hint(m.0 as *mut u8, 16 * FM_PAGE_SIZE, false, usize::MAX) };
+    assert_eq!(a.stats().free_pages, 15);
+    let p = unsafe { fm_sm_malloc(15 * FM_PAGE_SIZE) };
+    assert_eq!(p as usize, m.0 as usize + FM_PAGE_SIZE);
+    deinit(m);
+}
+
+#[test]
+fn test_malloc_tagged() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let mut ptrs = vec![];
+    for (size, tag) in [
+        (32, 1),
+        (100, 2),
+        (FM_PAGE_SIZE + 1000, 1),
+        (20, 2),
+        (FM_PAGE_SIZE / 8 + 1, 2),
+    ] {
+        ptrs.push(a.malloc_tagged(size, tag).unwrap());
+    }
+    let untagged = unsafe { fm_sm_malloc(64) };
+    // Tags follow reallocated blocks
+    let moved = unsafe { fm_sm_realloc(ptrs[0].as_ptr() as *mut c_void, FM_PAGE_SIZE / 8) };
+    assert_ne!(moved, ptrs[0].as_ptr() as *mut c_void);
+    unsafe { fm_sm_free(ptrs[3].as_ptr() as *mut c_void) };
+
+    let mut bytes = std::collections::BTreeMap::new();
+    a.walk(|b| *bytes.entry(b.tag).or_insert(0) += b.size);
+    assert_eq!(bytes.get(&0), Some(&64));
+    assert_eq!(bytes.get(&1), Some(&(FM_PAGE_SIZE / 8 + 2 * FM_PAGE_SIZE)));
+    assert_eq!(bytes.get(&2), Some(&(128 + FM_PAGE_SIZE / 4)));
+
+    let mut tags = vec![];
+    a.walk(|b| tags.push((b.ptr, b.tag)));
+    assert!(tags.contains(&(untagged as *mut u8, 0)));
+    assert!(tags.contains(&(moved as *mut u8, 1)));
+}
+
+#[test]
+fn test_failed_migrate() {
+    let m = init(65536);
+    let p = unsafe { fm_sm_malloc(100) } as *mut u8;
+    let q = unsafe { fm_sm_malloc(10000) } as *mut u8;
+    unsafe { p.write_bytes(0x12, 100) };
+    unsafe { q.write_bytes(0x34, 10000) };
+
+    let layout = Layout::from_size_align(131072, FM_PAGE_SIZE).unwrap();
+    let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+    let cases = [
+        (buffer, 32768, FmError::BufferTooSmall),
+        (unsafe { buffer.add(16) }, 65536, FmError::UnalignedBuffer),
+        (m.0 as *mut u8, 131072, FmError::BufferOverlap),
+    ];
+    for (b, len, e) in cases {
+        assert_eq!(
+            migrate(b, len, |_, _, _| panic!("nothing shall be moved")),
+            Err(ReinitError::from(e))
+        );
+    }
+    assert!(unsafe { std::slice::from_raw_parts(buffer, 131072) }.iter().all(|b| *b == 0));
+
+    // The current heap keeps working
+    assert_eq!(unsafe { fm_lm_test_buffer_pointer() }, m.0);
+    assert!(unsafe { std::slice::from_raw_parts(p, 100) }.iter().all(|b| *b == 0x12));
+    assert!(unsafe { std::slice::from_raw_parts(q, 10000) }.iter().all(|b| *b == 0x34));
+    unsafe { fm_sm_free(p as *mut c_void) };
+    unsafe { fm_sm_free(q as *mut c_void) };
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_owns_and_size() {
+    let m = init(65536);
+    let alloc = unsafe { FixedAlloc::new_static() };
+    let small = unsafe { fm_sm_malloc(100) } as *const u8;
+    let large = unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) } as *const u8;
+    assert_eq!(alloc.owns_and_size(small), Some(128));
+    assert_eq!(alloc.owns_and_size(large), Some(2 * FM_PAGE_SIZE));
+
+    // Interior pointers
+    assert_eq!(alloc.owns_and_size(unsafe { small.add(16) }), None);
+    assert_eq!(alloc.owns_and_size(unsafe { small.add(128) }), None);
+    assert_eq!(alloc.owns_and_size(unsafe { large.add(FM_PAGE_SIZE) }), None);
+    // The slab page itself is not an allocation
+    assert_eq!(alloc.owns_and_size(((small as usize) & !(FM_PAGE_SIZE - 1)) as *const u8), None);
+
+    // Foreign pointers
+    let foreign = Box::new([0u8; 128]);
+    assert_eq!(alloc.owns_and_size(foreign.as_ptr()), None);
+    assert_eq!(alloc.owns_and_size(std::ptr::null()), None);
+    assert_eq!(alloc.owns_and_size(m.0 as *const u8), None);
+
+    unsafe { fm_sm_free(small as *mut c_void) };
+    unsafe { fm_sm_free(large as *mut c_void) };
+    assert_eq!(alloc.owns_and_size(small), None);
+    assert_eq!(alloc.owns_and_size(large), None);
+    deinit(m);
+}
+
+#[cfg(feature = "guard-pages")]
+#[test]
+fn test_guard_page() {
+    // Mapping permissions of the page containing addr
+    fn permissions(addr: usize) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next().unwrap().split_once('-').unwrap();
+            let start = usize::from_str_radix(start, 16).unwrap();
+            let end = usize::from_str_radix(end, 16).unwrap();
+            if addr >= start && addr < end {
+                return fields.next().unwrap().to_string();
+            }
+        }
+        panic!("{:x} is not mapped", addr);
+    }
+
+    // Aligned generously so the guard page matches any system page size
+    let layout = Layout::from_size_align(131072, 65536).unwrap();
+    let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+    let guard = buffer as usize + 65536;
+    let _a = unsafe { FixedAlloc::new_with_guard(buffer, 65536, true) };
+    assert!(permissions(guard).starts_with("---"));
+    assert!(permissions(guard - 1).starts_with("rw"));
+
+    let p = unsafe { fm_sm_malloc(60000) } as *mut u8;
+    assert!(!p.is_null());
+    unsafe { p.write_bytes(1, 60000) };
+    unsafe { fm_sm_free(p as *mut c_void) };
+
+    FixedAlloc::remove_guard(buffer, 65536);
+    assert!(permissions(guard).starts_with("rw"));
+    unsafe { std::alloc::dealloc(buffer, layout) };
+}
+
+#[test]
+fn test_extend() {
+    // Only the first half is handed to the allocator at the beginning
+    let layout = Layout::from_size_align(32 * FM_PAGE_SIZE, FM_PAGE_SIZE).unwrap();
+    let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+    let a = unsafe { FixedAlloc::new(buffer, 16 * FM_PAGE_SIZE, true) };
+
+    let mut blocks = vec![];
+    for i in 0..20 {
+        let size = if i % 2 == 0 { 100 } else { FM_PAGE_SIZE / 2 };
+        let p = unsafe { fm_sm_malloc(size) } as *mut u8;
+        assert!(!p.is_null());
+        unsafe { p.write_bytes(i as u8, size) };
+        blocks.push((p, size, i as u8));
+    }
+    let large = 10 * FM_PAGE_SIZE;
+    assert!(unsafe { fm_sm_malloc(large) }.is_null());
+
+    assert_eq!(unsafe { a.extend(100) }, Err(FmError::UnalignedSize));
+    assert_eq!(unsafe { a.extend(FM_MAX_MEMORY_SIZE) }, Err(FmError::BufferTooLarge));
+    assert_eq!(unsafe { a.extend(16 * FM_PAGE_SIZE) }, Ok(()));
+    assert_eq!(a.stats().total_bytes, 32 * FM_PAGE_SIZE);
+
+    let p = unsafe { fm_sm_malloc(large) } as *mut u8;
+    assert!(!p.is_null());
+    assert!(p as usize >= buffer as usize + 16 * FM_PAGE_SIZE);
+    unsafe { p.write_bytes(0xFF, large) };
+    for (q, size, byte) in &blocks {
+        let content = unsafe { std::slice::from_raw_parts(*q, *size) };
+        assert!(content.iter().all(|b| b == byte));
+        unsafe { fm_sm_free(*q as *mut c_void) };
+    }
+    unsafe { fm_sm_free(p as *mut c_void) };
+    assert_heap_empty();
+    assert!(!unsafe { fm_sm_malloc(29 * FM_PAGE_SIZE) }.is_null());
+    unsafe { std::alloc::dealloc(buffer, layout) };
+}
+
+#[test]
+fn test_compact() {
+    let m = init(655360);
+    let a = unsafe { FixedAlloc::new_static() };
+    // Fill 4 slabs for each of the 128 and FM_PAGE_SIZE / 8 bytes classes,
+    // then keep only one object in each slab
+    let mut kept: Vec<(*mut u8, u8)> = vec![];
+    for class in [128, FM_PAGE_SIZE / 8] {
+        let count = (FM_PAGE_SIZE - FM_SLAB_RESERVED_SIZE) / class;
+        for i in 0..4 * count {
+            let p = unsafe { fm_sm_malloc(class - 12) } as *mut u8;
+            if i % count == count / 2 {
+                kept.push((p, kept.len() as u8));
+            }
+        }
+    }
+    for (p, byte) in &kept {
+        unsafe { p.write_bytes(*byte, 100) };
+    }
+    let mut blocks = vec![];
+    a.walk(|block| blocks.push(block.ptr));
+    for p in blocks {
+        if !kept.iter().any(|(q, _)| *q == p) {
+            unsafe { fm_sm_free(p as *mut c_void) };
+        }
+    }
+    let used_pages = a.stats().used_pages;
+
+    let mut moved = 0;
+    let reclaimed = unsafe {
+        a.compact(|old, new, size| {
+            assert!(size == 128 || size == FM_PAGE_SIZE / 8);
+            moved += 1;
+            for block in kept.iter_mut() {
+                if block.0 == old {
+                    block.0 = new;
+                }
+            }
+        })
+    };
+    // Objects of each class are packed into a single slab
+    assert_eq!(reclaimed, 6);
+    assert_eq!(moved, 6);
+    assert_eq!(a.stats().used_pages, used_pages - 6);
+    assert_eq!(a.live_allocations(), 8);
+    for (p, byte) in &kept {
+        assert!(a.owns_and_size(*p).is_some());
+        assert!(unsafe { std::slice::from_raw_parts(*p, 100) }.iter().all(|b| b == byte));
+    }
+
+    // Nothing is left to compact
+    assert_eq!(unsafe { a.compact(|_, _, _| panic!("nothing shall be moved")) }, 0);
+    for (p, _) in kept {
+        unsafe { fm_sm_free(p as *mut c_void) };
+    }
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_trim() {
+    let m = init(655360);
+    let a = unsafe { FixedAlloc::new_static() };
+    // Fill 4 slabs of the 128 bytes class, then empty all but the last one
+    let slots = (FM_PAGE_SIZE - FM_SLAB_RESERVED_SIZE) / 128;
+    let ptrs: Vec<*mut u8> = (0..4 * slots)
+        .map(|_| unsafe { fm_sm_malloc(100) } as *mut u8)
+        .collect();
+    for p in &ptrs[..3 * slots] {
+        unsafe { fm_sm_free(*p as *mut c_void) };
+    }
+    let used_pages = a.stats().used_pages;
+    assert_eq!(a.trim(), 3 * FM_PAGE_SIZE);
+    assert_eq!(a.stats().used_pages, used_pages - 3);
+    assert_eq!(a.live_allocations(), slots);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    // Nothing is left to trim
+    assert_eq!(a.trim(), 0);
+    for p in &ptrs[3 * slots..] {
+        unsafe { fm_sm_free(*p as *mut c_void) };
+    }
+    assert_eq!(a.trim(), FM_PAGE_SIZE);
+    assert_eq!(a.stats().used_pages, 0);
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_max_alloc_size() {
+    let m = init(655360);
+    let a = unsafe { FixedAlloc::new_static() };
+    assert_eq!(a.max_alloc_size(), 655360 - FM_PAGE_SIZE);
+    // Split the free pages with a slab, then fill the heap with the largest
+    // blocks that fit until only the slab has room left
+    let first = unsafe { fm_sm_malloc(10 * FM_PAGE_SIZE) };
+    let small = unsafe { fm_sm_malloc(100) };
+    unsafe { fm_sm_free(first) };
+    let mut ptrs = vec![small];
+    loop {
+        let max = a.max_alloc_size();
+        let p = unsafe { fm_sm_malloc(max) };
+        assert!(!p.is_null());
+        unsafe { fm_sm_free(p) };
+        assert!(unsafe { fm_sm_malloc(max + 1) }.is_null());
+        a.clear_error();
+        if max < FM_PAGE_SIZE {
+            assert_eq!(max, slab_class(100));
+            break;
+        }
+        ptrs.push(unsafe { fm_sm_malloc(max) });
+    }
+    for p in ptrs {
+        unsafe { fm_sm_free(p) };
+    }
+    assert_eq!(a.max_alloc_size(), 655360 - FM_PAGE_SIZE);
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_deterministic() {
+    let m = init(655360);
+    let a = unsafe { FixedAlloc::new_static() };
+    // Mixed alloc/free sequence which empties the heap at the end
+    let replay = || {
+        let mut seed = 7u64;
+        let mut live = vec![];
+        let mut addresses = vec![];
+        for _ in 0..200 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let r = (seed >> 33) as usize;
+            if r.is_multiple_of(3) && !live.is_empty() {
+                let p: *mut c_void = live.swap_remove(r % live.len());
+                unsafe { fm_sm_free(p) };
+            } else {
+                let p = unsafe { fm_sm_malloc([24, 200, 1000, 5000, 9000][r % 5]) };
+                assert!(!p.is_null());
+                addresses.push(p as usize);
+                live.push(p);
+            }
+        }
+        for p in live {
+            unsafe { fm_sm_free(p) };
+        }
+        addresses
+    };
+    // Freed blocks are queued by default, so replays start from another state
+    let first = replay();
+    assert_ne!(replay(), first);
+
+    a.set_deterministic(true);
+    // Randomized placement is suspended as well
+    #[cfg(feature = "hardening")]
+    a.set_random_seed(42);
+    let first = replay();
+    assert_eq!(a.stats().used_pages, 0);
+    assert_eq!(replay(), first);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    a.set_deterministic(false);
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_add_region() {
+    let m = init(16 * FM_PAGE_SIZE);
+    let a = unsafe { FixedAlloc::new_static() };
+    let layout = Layout::from_size_align(16 * FM_PAGE_SIZE, FM_PAGE_SIZE).unwrap();
+    let buffer = unsafe { std::alloc::alloc_zeroed(layout) };
+    assert_eq!(a.add_region(m.0 as *mut u8, 16 * FM_PAGE_SIZE, true), Err(FmError::BufferOverlap));
+    assert_eq!(a.add_region(buffer, 16 * FM_PAGE_SIZE, true), Ok(()));
+    assert_eq!(a.stats().total_bytes, 32 * FM_PAGE_SIZE);
+    let second = (buffer as usize, buffer as usize + 16 * FM_PAGE_SIZE);
+    assert_eq!(regions()[1], second);
+
+    // The first region is used up before spilling into the second one
+    let mut blocks = vec![];
+    loop {
+        let p = unsafe { fm_sm_malloc(FM_PAGE_SIZE) };
+        if p.is_null() {
+            break;
+        }
+        blocks.push((p, FM_PAGE_SIZE));
+    }
+    assert_eq!(blocks.len(), 30);
+    assert_valid_pointers(&blocks);
+    let in_second = |p: *mut c_void| p as usize >= second.0 && (p as usize) < second.1;
+    assert!(blocks[..15].iter().all(|(p, _)| !in_second(*p)));
+    assert!(blocks[15..].iter().all(|(p, _)| in_second(*p)));
+    // A single allocation never spans regions
+    assert!(unsafe { fm_sm_malloc(16 * FM_PAGE_SIZE) }.is_null());
+    assert_eq!(a.last_error(), Some(FmError::TooLarge));
+
+    // Freed pages in both regions are reused
+    let freed = [blocks.remove(20).0, blocks.remove(3).0];
+    for p in freed {
+        unsafe { fm_sm_free(p) };
+    }
+    let mut reused = [unsafe { fm_sm_malloc(FM_PAGE_SIZE) }, unsafe { fm_sm_malloc(FM_PAGE_SIZE) }];
+    reused.sort();
+    let mut expected = freed;
+    expected.sort();
+    assert_eq!(reused, expected);
+    for p in reused {
+        assert_eq!(a.owns_and_size(p as *const u8), Some(FM_PAGE_SIZE));
+        unsafe { fm_sm_free(p) };
+    }
+    for (p, _) in blocks {
+        unsafe { fm_sm_free(p) };
+    }
+    assert_heap_empty();
+
+    let small = Layout::from_size_align(2 * FM_PAGE_SIZE, FM_PAGE_SIZE).unwrap();
+    let extras: Vec<*mut u8> = (0..3).map(|_| unsafe { std::alloc::alloc_zeroed(small) }).collect();
+    assert_eq!(a.add_region(unsafe { extras[0].add(16) }, FM_PAGE_SIZE, true), Err(FmError::UnalignedBuffer));
+    assert_eq!(a.add_region(extras[0], 2 * FM_PAGE_SIZE, true), Ok(()));
+    assert_eq!(a.add_region(extras[1], 2 * FM_PAGE_SIZE, true), Ok(()));
+    assert_eq!(a.add_region(extras[2], 2 * FM_PAGE_SIZE, true), Err(FmError::TooManyRegions));
+    assert_eq!(regions().len(), 4);
+}
+
+#[test]
+fn test_alloc_tracked() {
+    struct Counted<'a>(&'a std::cell::Cell<usize>, [u64; 7]);
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    let m = init(65536);
+    let a = unsafe { FixedAlloc::new_static() };
+    let drops = std::cell::Cell::new(0);
+    let mut t = a.alloc_tracked(Counted(&drops, [7; 7])).unwrap();
+    assert_eq!(t.1[3], 7);
+    t.1[3] = 9;
+    assert_eq!(t.1, [7, 7, 7, 9, 7, 7, 7]);
+    assert_eq!(a.owns_and_size(Tracked::as_ptr(&t) as *const u8), Some(64));
+    assert_eq!(a.live_allocations(), 1);
+    drop(t);
+    assert_eq!(drops.get(), 1);
+    assert_heap_empty();
+
+    assert!(a.alloc_tracked([0u8; 65536]).is_none());
+    deinit(m);
+}
+
+#[test]
+fn test_fixed_alloc_ref() {
+    use fixed_malloc::FixedAllocRef;
+
+    fn roundtrip<A: FixedAllocRef>(a: A) {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p = a.alloc(layout);
+        assert!(!p.is_null());
+        unsafe { a.dealloc(p, layout) };
+        assert!(a.alloc(Layout::from_size_align(0, 8).unwrap()).is_null());
+        // Each value keeps a handle of its own
+        let t = Tracked::new_in(5u32, a.clone()).unwrap();
+        let u = Tracked::new_in(6u32, a).unwrap();
+        assert_eq!(*t + *u, 11);
+    }
+
+    let m = init(65536);
+    let a = unsafe { FixedAlloc::new_static() };
+    roundtrip(&a);
+    #[cfg(feature = "alloc")]
+    {
+        roundtrip(std::rc::Rc::new(unsafe { FixedAlloc::new_static() }));
+        // Sharing across threads needs FixedAlloc to be Sync
+        #[cfg(feature = "single-threaded")]
+        roundtrip(std::sync::Arc::new(unsafe { FixedAlloc::new_static() }));
+    }
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_default_static_size() {
+    // Tests can be built with a different size
+    let expected = option_env!("FIXED_MALLOC_MEMORY_SIZE").map_or(655360, |s| s.parse().unwrap());
+    assert_eq!(STATIC_MEMORY_SIZE, expected);
+    assert_eq!(default_static_size(), STATIC_MEMORY_SIZE);
+    assert_eq!(FM_MEMORY_SIZE, STATIC_MEMORY_SIZE);
+    let a = unsafe { FixedAlloc::new_static() };
+    assert_eq!(a.stats().total_bytes, default_static_size());
+}
+
+#[test]
+fn test_heap_instance() {
+    let layout = Layout::from_size_align(65536, FM_PAGE_SIZE).expect("layout");
+    let buffer = unsafe { std::alloc::alloc(layout) };
+    assert_eq!(
+        unsafe { Heap::new(buffer, FM_PAGE_SIZE, false) }.err(),
+        Some(FmError::BufferTooSmall)
+    );
+
+    let heap = unsafe { Heap::new(buffer, 65536, false) }.unwrap();
+    let range = (buffer as usize, buffer as usize + 65536);
+    let p = heap.alloc(Layout::from_size_align(100, 8).unwrap()).unwrap();
+    let q = heap.alloc(Layout::from_size_align(100, 64).unwrap()).unwrap();
+    assert_eq!(q.as_ptr() as usize % 64, 0);
+    assert_valid_pointers_in(
+        &[(p.as_ptr() as *mut c_void, 100), (q.as_ptr() as *mut c_void, 100)],
+        &[range],
+    );
+    // The global heap is left alone
+    assert_eq!(unsafe { FixedAlloc::new_static() }.owns_and_size(p.as_ptr()), None);
+
+    let p = unsafe { heap.realloc(p, 3000) }.unwrap();
+    assert_valid_pointers_in(&[(p.as_ptr() as *mut c_void, 3000)], &[range]);
+    unsafe {
+        heap.dealloc(p);
+        heap.dealloc(q);
+    }
+    drop(heap);
+    unsafe { std::alloc::dealloc(buffer, layout) };
+}
+
+#[test]
+fn test_alloc_by_reference() {
+    fn roundtrip<A: GlobalAlloc>(a: A) {
+        let layout = Layout::from_size_align(200, 8).unwrap();
+        let p = unsafe { a.alloc_zeroed(layout) };
+        assert!(!p.is_null());
+        let p = unsafe { a.realloc(p, layout, 3000) };
+        assert!(!p.is_null());
+        unsafe { a.dealloc(p, Layout::from_size_align(3000, 8).unwrap()) };
+    }
+
+    let m = init(65536);
+    let a = unsafe { FixedAlloc::new_static() };
+    roundtrip(&a);
+    #[cfg(feature = "alloc")]
+    {
+        let shared = std::rc::Rc::new(unsafe { FixedAlloc::new_static() });
+        roundtrip(&*shared);
+        let t = Tracked::new_in([1u64; 4], shared.clone()).unwrap();
+        assert_eq!(t[2], 1);
+    }
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_free_all() {
+    let m = init(256 * FM_PAGE_SIZE);
+    let a = unsafe { FixedAlloc::new_static() };
+    assert_eq!(a.free_all(), 0);
+    unsafe { fm_sm_set_quarantine(4) };
+    // More than one batch of pointers collected per heap walk
+    for size in [16, 100, FM_PAGE_SIZE / 4, FM_PAGE_SIZE + 1000, 5 * FM_PAGE_SIZE].iter().cycle().take(150) {
+        assert!(!unsafe { fm_sm_malloc(*size) }.is_null());
+    }
+    // Quarantined blocks are not counted
+    unsafe { fm_sm_free(fm_sm_malloc(64)) };
+    assert_eq!(a.free_all(), 150);
+    assert_heap_empty();
+
+    // The heap is fully usable afterwards
+    let p = unsafe { fm_sm_malloc(50 * FM_PAGE_SIZE) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+    unsafe { fm_sm_set_quarantine(0) };
+    deinit(m);
+}
+
+#[test]
+fn test_free_many() {
+    let m = init(1024 * FM_PAGE_SIZE);
+    let a = unsafe { FixedAlloc::new_static() };
+    let mut ptrs: Vec<*mut u8> = [16, 100, 1000, FM_PAGE_SIZE + 1000]
+        .iter()
+        .cycle()
+        .take(1000)
+        .map(|size| unsafe { fm_sm_malloc(*size) } as *mut u8)
+        .collect();
+    assert!(ptrs.iter().all(|p| !p.is_null()));
+    ptrs.push(std::ptr::null_mut());
+    unsafe { a.free_many(&ptrs) };
+    assert_heap_empty();
+
+    // Empty slabs are returned as well, then all pages form one block
+    a.trim();
+    let total_pages = a.stats().total_pages;
+    let p = unsafe { fm_lm_malloc(total_pages * FM_PAGE_SIZE, FM_LM_T_TRANSIENT) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+    deinit(m);
+}
+
+#[test]
+fn test_split_tiers() {
+    let layout = Layout::from_size_align(8 * FM_PAGE_SIZE, FM_PAGE_SIZE).expect("layout");
+    let slab_buffer = unsafe { std::alloc::alloc(layout) };
+    let linear_buffer = unsafe { std::alloc::alloc(layout) };
+    assert_eq!(
+        unsafe { FixedAlloc::new_split(slab_buffer, 8 * FM_PAGE_SIZE, slab_buffer, 8 * FM_PAGE_SIZE, false) }.err(),
+        Some(FmError::BufferOverlap)
+    );
+    let a = unsafe { FixedAlloc::new_split(slab_buffer, 8 * FM_PAGE_SIZE, linear_buffer, 8 * FM_PAGE_SIZE, false) }.unwrap();
+    let slab_range = (slab_buffer as usize, slab_buffer as usize + 8 * FM_PAGE_SIZE);
+    let linear_range = (linear_buffer as usize, linear_buffer as usize + 8 * FM_PAGE_SIZE);
+
+    // Exhaust the slab buffer, large allocations still succeed
+    let mut small = vec![];
+    loop {
+        let p = unsafe { fm_sm_malloc(FM_PAGE_SIZE / 4) };
+        if p.is_null() {
+            break;
+        }
+        small.push((p, FM_PAGE_SIZE / 4));
+    }
+    assert_eq!(small.len(), 7 * 3);
+    assert_valid_pointers_in(&small, &[slab_range]);
+    let mut large = vec![];
+    loop {
+        let p = unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) };
+        if p.is_null() {
+            break;
+        }
+        large.push((p, FM_PAGE_SIZE + 1000));
+    }
+    assert_eq!(large.len(), 3);
+    assert_valid_pointers_in(&large, &[linear_range]);
+
+    // Freeing small objects makes room for small objects only
+    for (p, _) in small.drain(..) {
+        unsafe { fm_sm_free(p) };
+    }
+    assert!(unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) }.is_null());
+    let p = unsafe { fm_sm_malloc(100) };
+    assert_valid_pointers_in(&[(p, 100)], &[slab_range]);
+    unsafe { fm_sm_free(p) };
+
+    // Reallocs move objects between tiers
+    let p = unsafe { fm_sm_malloc(100) };
+    unsafe { fm_sm_free(large.pop().unwrap().0) };
+    let p = unsafe { fm_sm_realloc(p, FM_PAGE_SIZE + 1000) };
+    assert_valid_pointers_in(&[(p, FM_PAGE_SIZE + 1000)], &[linear_range]);
+    large.push((p, FM_PAGE_SIZE + 1000));
+    for (p, _) in large {
+        unsafe { fm_sm_free(p) };
+    }
+    assert_eq!(a.live_allocations(), 0);
+    unsafe {
+        std::alloc::dealloc(slab_buffer, layout);
+        std::alloc::dealloc(linear_buffer, layout);
+    }
+}
+
+#[test]
+fn test_linear_alloc_zeroed() {
+    let m = init(65536);
+    let l = global_heap().linear();
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    // Dirty all pages, so the zeroed block has to reuse one of them
+    let mut blocks = vec![];
+    while let Some(p) = l.alloc_aligned(layout, AllocType::Persistent) {
+        unsafe { p.as_ptr().write_bytes(0x5A, FM_PAGE_SIZE) };
+        blocks.push(p);
+    }
+    for p in &blocks {
+        unsafe { l.free(*p) };
+    }
+
+    let q = l.alloc_zeroed(layout, AllocType::Persistent).unwrap();
+    assert!(blocks.contains(&q));
+    let content = unsafe { std::slice::from_raw_parts(q.as_ptr(), FM_PAGE_SIZE) };
+    assert!(content.iter().all(|b| *b == 0));
+    unsafe { l.free(q) };
+
+    let q = l
+        .alloc_zeroed(Layout::from_size_align(1000, 8192).unwrap(), AllocType::Transient)
+        .unwrap();
+    assert_eq!(q.as_ptr() as usize % 8192, 0);
+    unsafe { l.free(q) };
+
+    assert!(unsafe { fm_lm_calloc(usize::MAX, 2, FM_LM_T_TRANSIENT) }.is_null());
+    assert_eq!(unsafe { FixedAlloc::new_static() }.last_error(), Some(FmError::TooLarge));
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_verify_heap_integrity() {
+    let m = init(65536);
+    let a = unsafe { FixedAlloc::new_static() };
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    let p = unsafe { fm_sm_malloc(100) } as *mut u8;
+    let q = unsafe { fm_sm_malloc(5000) } as *mut u8;
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+
+    // Size class stored in the slab header, after the list link and the
+    // slot bitmap
+    let slab = (p as usize & !(FM_PAGE_SIZE - 1)) as *mut usize;
+    let size_field = 2 + (FM_PAGE_SIZE / FM_SLAB_MIN_SIZE).div_ceil(64);
+    unsafe { *slab.add(size_field) = 100 };
+    let e = a.verify_heap_integrity().unwrap_err();
+    assert_eq!(e.kind, HeapErrorKind::CorruptedHeader);
+    assert_eq!(e.address, slab as usize);
+    unsafe { *slab.add(size_field) = 128 };
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+
+    // Point the freed block list back at the freed block itself
+    unsafe { fm_sm_free(q as *mut c_void) };
+    let link = q as *mut *mut u8;
+    let next = unsafe { *link };
+    unsafe { *link = q };
+    let e = a.verify_heap_integrity().unwrap_err();
+    assert_eq!(e.kind, HeapErrorKind::FreeListCycle);
+    unsafe { *link = next };
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+
+    unsafe { fm_sm_free(p as *mut c_void) };
+    deinit(m);
+}
+
+#[test]
+fn test_lock_callbacks() {
+    use std::sync::{Condvar, Mutex};
+
+    // Binary semaphore, which unlike a mutex guard can be released from
+    // another callback invocation
+    struct Lock {
+        locked: Mutex<bool>,
+        released: Condvar,
+    }
+
+    unsafe extern "C" fn lock(ctx: *mut c_void) {
+        let l = &*(ctx as *const Lock);
+        let mut locked = l.locked.lock().unwrap();
+        while *locked {
+            locked = l.released.wait(locked).unwrap();
+        }
+        *locked = true;
+    }
+
+    unsafe extern "C" fn unlock(ctx: *mut c_void) {
+        let l = &*(ctx as *const Lock);
+        *l.locked.lock().unwrap() = false;
+        l.released.notify_one();
+    }
+
+    static LOCK: Lock = Lock {
+        locked: Mutex::new(false),
+        released: Condvar::new(),
+    };
+
+    let a = unsafe { FixedAlloc::new_static() };
+    unsafe {
+        fm_set_lock_callbacks(Some(lock), Some(unlock), &LOCK as *const Lock as *mut c_void)
+    };
+    let threads: Vec<_> = (0..8u8)
+        .map(|t| {
+            std::thread::spawn(move || {
+                let mut pointers = vec![];
+                for i in 0..2000usize {
+                    let size = [16, 100, 500, 1000, 6000][(i + t as usize) % 5];
+                    let p = unsafe { fm_sm_malloc(size) } as *mut u8;
+                    if !p.is_null() {
+                        unsafe { std::ptr::write_bytes(p, t, size) };
+                        pointers.push((p, size));
+                    }
+                    if pointers.len() > 16 || (i % 3 == 0 && !pointers.is_empty()) {
+                        let (p, size) = pointers.swap_remove(i % pointers.len());
+                        let data = unsafe { std::slice::from_raw_parts(p, size) };
+                        assert!(data.iter().all(|b| *b == t));
+                        unsafe { fm_sm_free(p as *mut c_void) };
+                    }
+                }
+                for (p, size) in pointers {
+                    let data = unsafe { std::slice::from_raw_parts(p, size) };
+                    assert!(data.iter().all(|b| *b == t));
+                    unsafe { fm_sm_free(p as *mut c_void) };
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    assert_eq!(unsafe { fm_sm_live_allocations() }, 0);
+    unsafe { fm_set_lock_callbacks(None, None, std::ptr::null_mut()) };
+}
+
+#[test]
+fn test_min_buffer_size() {
+    let len = min_buffer_size();
+    assert_eq!(len, 2 * FM_PAGE_SIZE);
+    let m = init(len);
+    assert_eq!(try_reinitialize(m.0 as *mut u8, len, false), Ok(()));
+    let p = unsafe { fm_sm_malloc(16) };
+    assert!(!p.is_null());
+    unsafe { fm_sm_free(p) };
+    assert_eq!(
+        try_reinitialize(m.0 as *mut u8, len - 1, false),
+        Err(ReinitError::UnalignedSize)
+    );
+    deinit(m);
+}
+
+#[test]
+fn test_usable_size_covers_request() {
+    let a = unsafe { FixedAlloc::new_static() };
+    for size in 1..=4 * FM_PAGE_SIZE {
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        let p = unsafe { a.alloc(layout) };
+        assert!(!p.is_null());
+        // Slab objects take their size class, larger blocks whole pages
+        let expected = if size <= TIERED_SMALL_MAX {
+            slab_class(size)
+        } else {
+            size.next_multiple_of(FM_PAGE_SIZE)
+        };
+        assert_eq!(a.usable_size(p), expected, "size {}", size);
+        // All of it can be written
+        unsafe { p.write_bytes(0x5A, expected) };
+        unsafe { a.dealloc(p, layout) };
+    }
+    assert_eq!(a.live_allocations(), 0);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+}
+
+#[test]
+fn test_shrink_to_fit_class() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let layout = Layout::from_size_align(17, 1).unwrap();
+    let p = unsafe { a.alloc(layout) };
+    assert!(!p.is_null());
+    assert_eq!(a.usable_size(p), slab_class(17));
+    let fit = a.shrink_to_fit_class(p, layout);
+    assert_eq!(fit, Layout::from_size_align(slab_class(17), 1).unwrap());
+    // The whole size class can be used and freed with the new layout
+    unsafe { std::ptr::write_bytes(p, 0xAB, fit.size()) };
+    assert_valid_pointers(&[(p as *mut c_void, fit.size())]);
+    unsafe { a.dealloc(p, fit) };
+    assert_eq!(a.usable_size(p), 0);
+
+    // Page allocations are rounded up to whole pages
+    let layout = Layout::from_size_align(FM_PAGE_SIZE + 1000, 8).unwrap();
+    let p = unsafe { a.alloc(layout) };
+    assert_eq!(a.shrink_to_fit_class(p, layout).size(), 2 * FM_PAGE_SIZE);
+    unsafe { a.dealloc(p, layout) };
+    assert_eq!(a.shrink_to_fit_class(p, layout), layout);
+}
+
+#[test]
+fn test_bump_string() {
+    use std::fmt::Write;
+
+    let m = init(65536);
+    let a = unsafe { FixedAlloc::new_static() };
+    let mut s = BumpString::new_in(&a);
+    assert!(s.is_empty());
+    assert_eq!(s.as_str(), "");
+    assert_eq!(a.live_allocations(), 0);
+
+    let mut expected = String::new();
+    for code in 0..200 {
+        write!(s, "error code: {}, ", code).unwrap();
+        write!(expected, "error code: {}, ", code).unwrap();
+    }
+    assert_eq!(s.len(), expected.len());
+    // Writes are spread over many segments, which are merged into one
+    assert!(a.live_allocations() > 1);
+    assert_eq!(s.as_str(), expected);
+    assert_eq!(a.live_allocations(), 1);
+    assert_eq!(s.as_str(), expected);
+
+    // Writing fails once the heap is exhausted, the text so far is kept
+    let chunk = "x".repeat(20000);
+    while s.write_str(&chunk).is_ok() {
+        expected.push_str(&chunk);
+    }
+    assert_eq!(s.len(), expected.len());
+    drop(s);
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_linear_alloc_pages() {
+    let m = init(65536);
+    let l = global_heap().linear();
+    assert!(l.alloc_pages(0, AllocType::Transient).is_none());
+    assert!(l.alloc_pages(usize::MAX, AllocType::Transient).is_none());
+    let p = l.alloc_pages(3, AllocType::Persistent).unwrap();
+    assert_eq!(p.as_ptr() as usize % FM_PAGE_SIZE, 0);
+    assert_eq!(unsafe { FixedAlloc::new_static() }.usable_size(p.as_ptr() as *const u8), 3 * FM_PAGE_SIZE);
+    unsafe { p.as_ptr().write_bytes(0x5A, 3) };
+    assert_valid_linear_pointers(&[(p.as_ptr() as *mut c_void, 3 * FM_PAGE_SIZE)]);
+    // Pages are indexed as arrays
+    assert_eq!(unsafe { (*p.as_ptr().add(2))[FM_PAGE_SIZE - 1] }, 0x5A);
+    unsafe { l.free_pages(p, 3) };
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_snapshot_restore() {
+    fn snapshot() -> Vec<u8> {
+        let needed = unsafe { fm_sm_snapshot(std::ptr::null_mut(), 0) };
+        assert!(needed < 0);
+        let mut buf = vec![0u8; needed.unsigned_abs()];
+        let written = unsafe { fm_sm_snapshot(buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        assert_eq!(written, -needed);
+        buf
+    }
+
+    let m = init(262144);
+    let a = unsafe { FixedAlloc::new_static() };
+    let kept: Vec<_> = [16, 100, 1000, 5000, 20000]
+        .iter()
+        .map(|size| (unsafe { fm_sm_malloc(*size) }, *size))
+        .collect();
+    let freed = unsafe { fm_sm_malloc(300) };
+    let snap = snapshot();
+    let mut small = [0u8; 8];
+    assert_eq!(a.snapshot_into(&mut small), Err(snap.len()));
+    let mut buf = vec![0u8; snap.len()];
+    assert_eq!(a.snapshot_into(&mut buf), Ok(snap.len()));
+    assert_eq!(buf, snap);
+    #[cfg(feature = "alloc")]
+    let checkpoint = a.snapshot();
+    #[cfg(feature = "alloc")]
+    assert_eq!(checkpoint.as_bytes(), &snap[..]);
+    let stats = a.stats();
+
+    unsafe { fm_sm_free(freed) };
+    for size in [16, 64, 500, 3000, 9000, 40000] {
+        assert!(!unsafe { fm_sm_malloc(size) }.is_null());
+    }
+    unsafe { fm_sm_free(kept[2].0) };
+    assert_ne!(a.stats(), stats);
+
+    assert_eq!(unsafe { a.restore_from(&snap) }, Ok(()));
+    assert_eq!(a.stats(), stats);
+    assert_eq!(a.live_allocations(), 6);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    assert_eq!(snapshot(), snap);
+    assert_eq!(
+        unsafe { a.restore_from(&snap[..snap.len() - 1]) },
+        Err(FmError::BadSnapshot)
+    );
+
+    // Rolling back to a checkpoint can be repeated
+    #[cfg(feature = "alloc")]
+    {
+        for size in [32, 2000, 12000] {
+            assert!(!unsafe { fm_sm_malloc(size) }.is_null());
+        }
+        assert_eq!(unsafe { a.restore(checkpoint.clone()) }, Ok(()));
+        assert_eq!(a.stats(), stats);
+        assert_eq!(a.snapshot(), checkpoint);
+    }
+
+    for (p, _) in kept {
+        unsafe { fm_sm_free(p) };
+    }
+    unsafe { fm_sm_free(freed) };
+    assert_heap_empty();
+
+    // Snapshots of other buffers are refused
+    let n = init(262144);
+    assert_eq!(unsafe { a.restore_from(&snap) }, Err(FmError::BadSnapshot));
+    deinit(n);
+    deinit(m);
+}
+
+#[test]
+fn test_page_alloc() {
+    let m = init(65536);
+    let a = unsafe { FixedAlloc::new_static() };
+    let small = unsafe { fm_sm_malloc(100) };
+    let p = a.alloc_pages(3);
+    assert!(!p.is_null());
+    assert_eq!(p as usize % FM_PAGE_SIZE, 0);
+    // The pages are one block inside the buffer
+    assert_eq!(a.usable_size(p), 3 * FM_PAGE_SIZE);
+    assert_valid_pointers(&[(small, 100), (p as *mut c_void, 3 * FM_PAGE_SIZE)]);
+    unsafe { p.write_bytes(0xA5, 3 * FM_PAGE_SIZE) };
+    assert_eq!(unsafe { *p.add(3 * FM_PAGE_SIZE - 1) }, 0xA5);
+
+    assert!(a.alloc_pages(0).is_null());
+    assert!(a.alloc_pages(usize::MAX).is_null());
+    assert_eq!(a.last_error(), Some(FmError::TooLarge));
+    unsafe { a.free_pages(small as *mut u8) };
+    assert_eq!(a.last_error(), Some(FmError::BadPointer));
+    assert_eq!(a.live_allocations(), 2);
+
+    unsafe { a.free_pages(p) };
+    unsafe { fm_sm_free(small) };
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+#[cfg(not(feature = "trap-reentrant"))]
+fn test_reentrant_hook() {
+    static NESTED: AtomicUsize = AtomicUsize::new(usize::MAX);
+    static NESTED_ERROR: AtomicUsize = AtomicUsize::new(0);
+
+    // Misbehaving hook allocating from the heap it is called for
+    fn allocating_hook(_requested: usize) {
+        let p = unsafe { fm_sm_malloc(64) };
+        NESTED.store(p as usize, Ordering::SeqCst);
+        NESTED_ERROR.store(unsafe { fm_last_error() } as usize, Ordering::SeqCst);
+    }
+
+    let a = unsafe { FixedAlloc::new_static() };
+    let p = unsafe { fm_sm_malloc(100) };
+    let stats = a.stats();
+    a.set_oom_hook(allocating_hook);
+    assert!(unsafe { fm_sm_malloc(FM_MEMORY_SIZE + FM_PAGE_SIZE) }.is_null());
+    assert_eq!(NESTED.load(Ordering::SeqCst), 0);
+    assert_eq!(NESTED_ERROR.load(Ordering::SeqCst), FM_ERR_REENTRANT as usize);
+    assert_eq!(a.stats(), stats);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    a.clear_oom_hook();
+    a.clear_error();
+
+    // Callbacks invoked with the heap in the middle of a walk are the same
+    let mut nested = vec![];
+    a.class_stats(|_| {
+        nested.push(unsafe { fm_sm_malloc(16) });
+        unsafe { fm_sm_free(p) };
+    });
+    assert!(nested.iter().all(|p| p.is_null()));
+    assert_eq!(a.last_error(), Some(FmError::Reentrant));
+    assert_eq!(a.live_allocations(), 1);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+
+    // Calls outside of hooks are not affected
+    a.clear_error();
+    let q = unsafe { fm_sm_malloc(64) };
+    assert!(!q.is_null());
+    assert_eq!(a.last_error(), None);
+    unsafe { fm_sm_free(q) };
+    unsafe { fm_sm_free(p) };
+    assert_heap_empty();
+}
+
+#[test]
+fn test_realloc_no_move() {
+    let m = init(16 * FM_PAGE_SIZE);
+    let a = unsafe { FixedAlloc::new_static() };
+    let p = unsafe { fm_sm_malloc(20) } as *mut u8;
+    unsafe { p.write_bytes(0x3C, 20) };
+    let class = slab_class(20);
+    // Growing within the size class keeps the pointer
+    assert_eq!(unsafe { a.realloc_no_move(p, class) }.map(|q| q.as_ptr()), Some(p));
+    // Beyond it the block would move, so nothing happens
+    assert_eq!(unsafe { a.realloc_no_move(p, class + 1) }, None);
+    assert_eq!(a.usable_size(p), class);
+    assert_valid_pointers(&[(p as *mut c_void, class)]);
+    assert!(unsafe { std::slice::from_raw_parts(p, 20) }.iter().all(|b| *b == 0x3C));
+
+    // Large blocks grow into free pages directly following them
+    let q = unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) } as *mut u8;
+    assert_eq!(unsafe { a.realloc_no_move(q, 3 * FM_PAGE_SIZE) }.map(|r| r.as_ptr()), Some(q));
+    assert_eq!(a.usable_size(q), 3 * FM_PAGE_SIZE);
+    let r = unsafe { fm_sm_malloc(FM_PAGE_SIZE) } as *mut u8;
+    assert_eq!(r as usize, q as usize + 3 * FM_PAGE_SIZE);
+    assert_eq!(unsafe { a.realloc_no_move(q, 4 * FM_PAGE_SIZE) }, None);
+    assert_eq!(a.usable_size(q), 3 * FM_PAGE_SIZE);
+    // Without the flag the block moves as usual
+    let moved = unsafe { fm_sm_realloc_flags(q as *mut c_void, 4 * FM_PAGE_SIZE, 0) };
+    assert!(!moved.is_null() && moved != q as *mut c_void);
+
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    unsafe { fm_sm_free(moved) };
+    unsafe { fm_sm_free(r as *mut c_void) };
+    unsafe { fm_sm_free(p as *mut c_void) };
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_shrink_release() {
+    let m = init(65536);
+    let a = unsafe { FixedAlloc::new_static() };
+    let p = unsafe { fm_sm_malloc(5 * FM_PAGE_SIZE) } as *mut u8;
+    unsafe { p.write_bytes(0x5A, FM_PAGE_SIZE) };
+    let free_pages = a.stats().free_pages;
+    assert_eq!(unsafe { a.shrink_release(p, FM_PAGE_SIZE) }.map(|q| q.as_ptr()), Some(p));
+    assert_eq!(a.stats().free_pages, free_pages + 4);
+    assert_eq!(a.usable_size(p), FM_PAGE_SIZE);
+    assert!(unsafe { std::slice::from_raw_parts(p, FM_PAGE_SIZE) }.iter().all(|b| *b == 0x5A));
+    // Shrinking never grows a block
+    assert_eq!(unsafe { a.shrink_release(p, FM_PAGE_SIZE + 1) }, None);
+    assert_eq!(a.usable_size(p), FM_PAGE_SIZE);
+
+    // Slab objects stay within their size classes
+    let r = unsafe { fm_sm_malloc(20) } as *mut u8;
+    assert_eq!(unsafe { a.shrink_release(r, 10) }.map(|s| s.as_ptr()), Some(r));
+    assert_eq!(unsafe { a.shrink_release(r, slab_class(20) + 1) }, None);
+
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    unsafe { fm_sm_free(r as *mut c_void) };
+    unsafe { fm_sm_free(p as *mut c_void) };
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_adopt() {
+    let m = init(FM_MIN_MEMORY_SIZE);
+    let a = unsafe { FixedAlloc::new_static() };
+    assert!(unsafe { fm_sm_malloc(3 * FM_PAGE_SIZE) }.is_null());
+
+    // External block which does not start on a page boundary
+    let layout = Layout::from_size_align(6 * FM_PAGE_SIZE, FM_PAGE_SIZE).unwrap();
+    let external = unsafe { std::alloc::alloc(layout) };
+    let block = std::ptr::NonNull::new(unsafe { external.add(16) }).unwrap();
+    let size = 6 * FM_PAGE_SIZE - 16;
+    let unaligned = std::ptr::NonNull::new(unsafe { external.add(8) }).unwrap();
+    assert_eq!(unsafe { a.adopt(unaligned, size) }, Err(AdoptError::Unaligned));
+    assert_eq!(
+        unsafe { a.adopt(block, 2 * FM_PAGE_SIZE) },
+        Err(AdoptError::TooSmall)
+    );
+    let total = a.stats().total_bytes;
+    assert_eq!(unsafe { a.adopt(block, size) }, Ok(()));
+    // The 5 whole pages of the block are added, the first one of them keeps
+    // their bookkeeping data
+    assert_eq!(a.stats().total_bytes, total + 5 * FM_PAGE_SIZE);
+
+    let p = unsafe { fm_sm_malloc(3 * FM_PAGE_SIZE) } as usize;
+    assert!(p >= external as usize + 2 * FM_PAGE_SIZE);
+    assert!(p + 3 * FM_PAGE_SIZE <= external as usize + 6 * FM_PAGE_SIZE);
+    assert_valid_pointers(&[(p as *mut c_void, 3 * FM_PAGE_SIZE)]);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+    assert_eq!(
+        unsafe { a.adopt(block, size) },
+        Err(AdoptError::Failed(FmError::BufferOverlap))
+    );
+
+    unsafe { fm_sm_free(p as *mut c_void) };
+    assert_heap_empty();
+    // Drop the adopted region before releasing the external block
+    let n = init(FM_MIN_MEMORY_SIZE);
+    unsafe { std::alloc::dealloc(external, layout) };
+    deinit(n);
+    deinit(m);
+}
+
+#[test]
+fn test_allocated_bytes() {
+    let a = unsafe { FixedAlloc::new_static() };
+    assert_eq!(a.allocated_bytes(), 0);
+    let p = unsafe { fm_sm_malloc(17) };
+    // Counted with the size of its size class
+    assert_eq!(a.allocated_bytes(), slab_class(17));
+    let q = unsafe { fm_sm_malloc(FM_PAGE_SIZE + 1000) };
+    assert_eq!(a.allocated_bytes(), slab_class(17) + 2 * FM_PAGE_SIZE);
+    let q = unsafe { fm_sm_realloc(q, 3 * FM_PAGE_SIZE) };
+    assert_eq!(a.allocated_bytes(), slab_class(17) + 3 * FM_PAGE_SIZE);
+    assert_eq!(a.allocated_bytes(), a.stats().used_bytes);
+
+    unsafe { fm_sm_free(q) };
+    unsafe { fm_sm_free(p) };
+    assert_eq!(a.allocated_bytes(), 0);
+}
+
+
+#[test]
+fn test_max_align() {
+    let a = unsafe { FixedAlloc::new_static() };
+    let layout = Layout::from_size_align(100, FM_MIN_ALIGN).unwrap();
+    let p = unsafe { a.alloc(layout) };
+    assert_eq!(p as usize % FM_MIN_ALIGN, 0);
+    unsafe { a.dealloc(p, layout) };
+
+    let layout = Layout::from_size_align(100, FM_MAX_ALIGN).unwrap();
+    let p = unsafe { a.alloc(layout) };
+    assert_eq!(p as usize % FM_MAX_ALIGN, 0);
+    unsafe { a.dealloc(p, layout) };
+
+    let layout = Layout::from_size_align(100, 2 * FM_MAX_ALIGN).unwrap();
+    let result = std::panic::catch_unwind(|| unsafe { a.alloc(layout) });
+    if cfg!(debug_assertions) {
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.contains("exceeds FM_MAX_ALIGN"), "{}", message);
+    } else {
+        assert!(result.unwrap().is_null());
+    }
+    assert_heap_empty();
+}
+
+#[test]
+fn test_static_buffer_address() {
+    let buffer = unsafe { fm_lm_test_static_buffer() };
+    assert!(!buffer.is_null());
+    assert_eq!(buffer as usize % FM_PAGE_SIZE, 0);
+    assert_eq!(buffer, unsafe { fm_lm_test_buffer_pointer() });
+}
+
+#[test]
+fn test_tiered_alloc() {
+    let layout = Layout::from_size_align(32768, FM_PAGE_SIZE).expect("layout");
+    let small_buffer = unsafe { std::alloc::alloc(layout) };
+    let large_buffer = unsafe { std::alloc::alloc(layout) };
+    let a = unsafe { TieredAlloc::new(small_buffer, 32768, large_buffer, 32768, false) }.unwrap();
+    let small_range = (small_buffer as usize, small_buffer as usize + 32768);
+    let large_range = (large_buffer as usize, large_buffer as usize + 32768);
+
+    let small = Layout::from_size_align(TIERED_SMALL_MAX, 8).unwrap();
+    let large = Layout::from_size_align(TIERED_SMALL_MAX + 1, 8).unwrap();
+    let p = unsafe { a.alloc(small) };
+    let q = unsafe { a.alloc_zeroed(large) };
+    assert!(a.contains_small(p));
+    assert!(!a.contains_small(q));
+    assert_valid_pointers_in(&[(p as *mut c_void, TIERED_SMALL_MAX)], &[small_range]);
+    assert_valid_pointers_in(&[(q as *mut c_void, TIERED_SMALL_MAX + 1)], &[large_range]);
+    assert!(unsafe { std::slice::from_raw_parts(q, large.size()) }.iter().all(|b| *b == 0));
+    // Alignment beyond slab objects is served by the large tier
+    let aligned = Layout::from_size_align(100, 64).unwrap();
+    let r = unsafe { a.alloc(aligned) };
+    assert!(!a.contains_small(r));
+    assert_eq!(r as usize % 64, 0);
+    unsafe { a.dealloc(r, aligned) };
+
+    // Reallocs move blocks between tiers as their sizes cross over
+    unsafe { p.write_bytes(0x11, small.size()) };
+    let p = unsafe { a.realloc(p, small, 5000) };
+    assert_valid_pointers_in(&[(p as *mut c_void, 5000)], &[large_range]);
+    assert!(unsafe { std::slice::from_raw_parts(p, small.size()) }.iter().all(|b| *b == 0x11));
+    let grown = Layout::from_size_align(5000, 8).unwrap();
+    let p = unsafe { a.realloc(p, grown, 100) };
+    assert!(a.contains_small(p));
+    assert!(unsafe { std::slice::from_raw_parts(p, 100) }.iter().all(|b| *b == 0x11));
+    let q = unsafe { a.realloc(q, large, 9000) };
+    assert!(!a.contains_small(q));
+
+    unsafe {
+        a.dealloc(p, Layout::from_size_align(100, 8).unwrap());
+        a.dealloc(q, Layout::from_size_align(9000, 8).unwrap());
+    }
+    assert_eq!(a.small().live_allocations(), 0);
+    unsafe {
+        std::alloc::dealloc(small_buffer, layout);
+        std::alloc::dealloc(large_buffer, layout);
+    }
+}
+
+#[test]
+fn test_linear_contains() {
+    let m = init(65536);
+    let l = global_heap().linear();
+    let mut start = std::ptr::null_mut();
+    let mut size = 0;
+    unsafe { fm_lm_buffer_range(&mut start, &mut size) };
+    assert_eq!((start, size), (m.0, 65536));
+    assert_eq!(start, unsafe { fm_lm_test_buffer_pointer() });
+    assert_eq!(size, unsafe { fm_lm_test_total_buffer_size() });
+
+    let p = unsafe { fm_lm_malloc(5000, FM_LM_T_TRANSIENT) } as *mut u8;
+    assert!(l.contains(p));
+    assert!(l.contains(unsafe { p.add(4999) }));
+    // Neither the bookkeeping page nor memory past the buffer belong to it
+    let buffer = m.0 as *mut u8;
+    assert!(!l.contains(buffer));
+    assert!(!l.contains(unsafe { buffer.add(65536) }));
+    let foreign = [0u8; 100];
+    assert!(!l.contains(foreign.as_ptr()));
+    unsafe { fm_lm_free(p as *mut c_void) };
+    deinit(m);
+}
+
+#[test]
+#[cfg(feature = "debug-hook")]
+fn test_debug_hook() {
+    static MESSAGES: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(vec![]);
+    fn record(message: &std::ffi::CStr) {
+        MESSAGES.lock().unwrap().push(message.to_string_lossy().into_owned());
+    }
+    let a = unsafe { FixedAlloc::new_static() };
+    a.set_debug_hook(record);
+    let m = init(65536);
+    let unaligned = unsafe { (m.0 as *mut u8).add(16) };
+    assert_eq!(try_reinitialize(unaligned, 8192, true), Err(ReinitError::UnalignedBuffer));
+    assert_eq!(
+        *MESSAGES.lock().unwrap(),
+        ["Memory buffer must be aligned at page boundary!"]
+    );
+
+    a.clear_debug_hook();
+    assert_eq!(try_reinitialize(unaligned, 8192, true), Err(ReinitError::UnalignedBuffer));
+    assert_eq!(MESSAGES.lock().unwrap().len(), 1);
+    deinit(m);
+}
+}
+
+// Whether the child was stopped by a trap or abort instead of exiting. On
+// Windows these end the process with an exception code, or with the exit
+// code 3 of abort.
+#[cfg(any(not(feature = "manual-init"), feature = "trap-reentrant"))]
+fn crashed(status: &ExitStatusWrapper) -> bool {
+    if cfg!(windows) {
+        matches!(status.code(), Some(code) if code == 3 || code < 0)
+    } else {
+        status.code().is_none() && status.unix_signal().is_some()
+    }
+}
+
+#[cfg(not(feature = "manual-init"))]
+#[test]
+fn test_alloc_error_handler() {
+    fork(
+        rusty_fork_test_name!(test_alloc_error_handler),
+        rusty_fork_id!(),
+        |_| {},
+        |child, _| {
+            let status = child.wait().expect("wait");
+            // A generic panic would exit with a failure code instead
+            assert!(crashed(&status), "{}", status);
+        },
+        || {
+            let a = unsafe { FixedAlloc::new_static() };
+            let layout = Layout::from_size_align(FM_MEMORY_SIZE + FM_PAGE_SIZE, 8).expect("layout");
+            let p = unsafe { a.alloc(layout) };
+            assert!(p.is_null());
+            handle_alloc_error(layout);
+        },
+    )
+    .expect("fork");
+}
+
+#[test]
+#[cfg(feature = "trap-reentrant")]
+fn test_trap_reentrant() {
+    fork(
+        rusty_fork_test_name!(test_trap_reentrant),
+        rusty_fork_id!(),
+        |_| {},
+        |child, _| {
+            let status = child.wait().expect("wait");
+            assert!(crashed(&status), "{}", status);
+        },
+        || {
+            fn allocating_hook(_requested: usize) {
+                unsafe { fm_sm_malloc(64) };
+                // Not reached, the nested call aborts
+                std::process::exit(0);
+            }
+            let a = unsafe { FixedAlloc::new_static() };
+            a.set_oom_hook(allocating_hook);
+            unsafe { fm_sm_malloc(FM_MEMORY_SIZE + FM_PAGE_SIZE) };
+        },
+    )
+    .expect("fork");
+}
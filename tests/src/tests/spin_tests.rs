@@ -0,0 +1,95 @@
+use fixed_malloc::TicketLock;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Take,
+    Wait(usize),
+    Release,
+    Done,
+}
+
+struct Model {
+    lock: TicketLock,
+    steps: Vec<Step>,
+    rounds: Vec<usize>,
+    holder: Option<usize>,
+    order: Vec<usize>,
+}
+
+impl Model {
+    fn new(threads: usize, rounds: usize) -> Self {
+        Self {
+            lock: TicketLock::new(),
+            steps: vec![Step::Take; threads],
+            rounds: vec![rounds; threads],
+            holder: None,
+            order: vec![],
+        }
+    }
+
+    // Waiting threads only make progress once their ticket is served
+    fn runnable(&self, t: usize) -> bool {
+        match self.steps[t] {
+            Step::Wait(ticket) => self.lock.is_serving(ticket),
+            Step::Done => false,
+            _ => true,
+        }
+    }
+
+    fn run(&mut self, t: usize) {
+        self.steps[t] = match self.steps[t] {
+            Step::Take => Step::Wait(self.lock.take_ticket()),
+            Step::Wait(ticket) => {
+                assert_eq!(self.holder, None, "Two threads hold the lock!");
+                self.holder = Some(t);
+                self.order.push(ticket);
+                Step::Release
+            }
+            Step::Release => {
+                self.holder = None;
+                self.lock.unlock();
+                self.rounds[t] -= 1;
+                if self.rounds[t] == 0 {
+                    Step::Done
+                } else {
+                    Step::Take
+                }
+            }
+            Step::Done => unreachable!(),
+        };
+    }
+}
+
+// Visit every interleaving of lock operations, replaying the schedule from a
+// fresh lock each time since atomics cannot be rolled back.
+fn explore(threads: usize, rounds: usize, schedule: &mut Vec<usize>) -> usize {
+    let mut model = Model::new(threads, rounds);
+    for t in schedule.iter() {
+        model.run(*t);
+    }
+    let runnable: Vec<_> = (0..threads).filter(|t| model.runnable(*t)).collect();
+    if runnable.is_empty() {
+        assert!(
+            model.steps.iter().all(|s| *s == Step::Done),
+            "Deadlock after schedule {:?}",
+            schedule
+        );
+        // Tickets are served in the order they are taken
+        assert!(model.order.windows(2).all(|w| w[0] + 1 == w[1]));
+        return 1;
+    }
+    let mut count = 0;
+    for t in runnable {
+        schedule.push(t);
+        count += explore(threads, rounds, schedule);
+        schedule.pop();
+    }
+    count
+}
+
+#[test]
+fn test_ticket_lock_interleavings() {
+    for (threads, rounds) in [(2, 3), (3, 1), (4, 1)] {
+        assert!(explore(threads, rounds, &mut vec![]) > 0);
+    }
+}
@@ -0,0 +1,42 @@
+use fixed_malloc::ffi::FM_MEMORY_SIZE;
+use object::read::archive::ArchiveFile;
+use object::{Object, ObjectSection, ObjectSymbol};
+use std::path::Path;
+
+// Names and sections of all symbols in the C library taking FM_MEMORY_SIZE
+// bytes, which can only be the static buffer
+fn static_buffer_symbols() -> Vec<(String, String)> {
+    let path = Path::new(env!("FIXED_MALLOC_C_LIB_DIR")).join("libfixed-malloc.a");
+    let data = std::fs::read(&path).expect("read C library");
+    let archive = ArchiveFile::parse(&*data).expect("parse archive");
+    let mut names = Vec::new();
+    for member in archive.members() {
+        let member = member.expect("archive member");
+        let file = object::File::parse(member.data(&*data).expect("member data"))
+            .expect("parse object file");
+        for symbol in file.symbols() {
+            if symbol.size() == FM_MEMORY_SIZE as u64 {
+                let section = symbol
+                    .section_index()
+                    .and_then(|index| file.section_by_index(index).ok())
+                    .and_then(|section| section.name().ok().map(str::to_string))
+                    .unwrap_or_default();
+                names.push((symbol.name().unwrap_or_default().to_string(), section));
+            }
+        }
+    }
+    names
+}
+
+#[test]
+fn test_static_buffer_symbol() {
+    let names = static_buffer_symbols();
+    if cfg!(feature = "manual-init") {
+        assert!(names.is_empty(), "static buffer compiled in: {:?}", names);
+    } else {
+        // FIXED_MALLOC_BUFFER_SECTION is also seen here as it is set for the
+        // whole build
+        let section = option_env!("FIXED_MALLOC_BUFFER_SECTION").unwrap_or(".bss.__sbuffer");
+        assert_eq!(names, [("__sbuffer".to_string(), section.to_string())]);
+    }
+}
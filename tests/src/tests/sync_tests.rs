@@ -0,0 +1,45 @@
+use super::*;
+use fixed_malloc::{FixedAlloc, SyncAlloc};
+use rusty_fork::rusty_fork_test;
+use std::alloc::{GlobalAlloc, Layout};
+use std::thread;
+
+static ALLOC: SyncAlloc = SyncAlloc::new(unsafe { FixedAlloc::new_static() });
+// Another handle of the same global heap, which must take the same lock
+static OTHER: SyncAlloc = SyncAlloc::new(unsafe { FixedAlloc::new_static() });
+
+rusty_fork_test! {
+
+#[test]
+fn test_concurrent_malloc_free() {
+    concurrent_malloc_free(&ALLOC);
+    ALLOC.with(|_| assert_heap_empty());
+}
+
+#[test]
+fn test_wrappers_share_lock() {
+    let threads: Vec<_> = [&ALLOC, &OTHER]
+        .into_iter()
+        .enumerate()
+        .map(|(t, alloc)| {
+            thread::spawn(move || {
+                for i in 0..10000usize {
+                    let layout = Layout::from_size_align(1 + (i * 13) % 3000, 8).unwrap();
+                    let p = unsafe { alloc.alloc(layout) };
+                    assert!(!p.is_null());
+                    unsafe { p.write_bytes(t as u8, layout.size()) };
+                    let bytes = unsafe { std::slice::from_raw_parts(p, layout.size()) };
+                    assert!(bytes.iter().all(|b| *b == t as u8));
+                    unsafe { alloc.dealloc(p, layout) };
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    ALLOC.with(|_| assert_heap_empty());
+}
+
+}
@@ -0,0 +1,115 @@
+use super::*;
+use fixed_malloc::{FixedAlloc, SyncAlloc, TlsCacheAlloc};
+use rusty_fork::rusty_fork_test;
+use std::alloc::{GlobalAlloc, Layout};
+use std::thread;
+
+static ALLOC: TlsCacheAlloc<SyncAlloc> =
+    unsafe { TlsCacheAlloc::new(SyncAlloc::new(FixedAlloc::new_static())) };
+
+rusty_fork_test! {
+
+#[test]
+fn test_cached_blocks_are_valid() {
+    let m = init(1024 * 1024);
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let blocks: Vec<*mut u8> = (0..40).map(|_| unsafe { ALLOC.alloc(layout) }).collect();
+    for p in &blocks {
+        unsafe { ALLOC.dealloc(*p, layout) };
+    }
+    // Overflowing the magazine releases some blocks to the C heap, the rest
+    // stay allocated there
+    let cached = ALLOC.cached_blocks();
+    assert!(cached > 0 && cached < blocks.len());
+    assert_eq!(unsafe { fm_sm_live_allocations() }, cached);
+
+    let layout = Layout::from_size_align(32, 16).unwrap();
+    let reused: Vec<*mut u8> = (0..cached).map(|_| unsafe { ALLOC.alloc(layout) }).collect();
+    assert_eq!(ALLOC.cached_blocks(), 0);
+    let mut pointers = vec![];
+    for p in &reused {
+        assert!(blocks.contains(p));
+        ALLOC.inner().with(|a| assert!(a.owns_and_size(*p) >= Some(32)));
+        unsafe { p.write_bytes(0xAB, 32) };
+        pointers.push((*p as *mut c_void, 32));
+    }
+    assert_valid_pointers(&pointers);
+
+    for p in reused {
+        unsafe { ALLOC.dealloc(p, layout) };
+    }
+    ALLOC.flush();
+    assert_eq!(ALLOC.cached_blocks(), 0);
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_thread_exit_flushes_cache() {
+    let m = init(1024 * 1024);
+    thread::spawn(|| {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        for _ in 0..10 {
+            let p = unsafe { ALLOC.alloc(layout) };
+            assert!(!p.is_null());
+            unsafe { ALLOC.dealloc(p, layout) };
+        }
+        assert_eq!(ALLOC.cached_blocks(), 1);
+    })
+    .join()
+    .unwrap();
+    assert_heap_empty();
+    deinit(m);
+}
+
+#[test]
+fn test_reinit_invalidates_cache() {
+    let m = init(1024 * 1024);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let p = unsafe { ALLOC.alloc(layout) };
+    unsafe { ALLOC.dealloc(p, layout) };
+    assert_eq!(ALLOC.cached_blocks(), 1);
+    // Cached blocks are live, so they must be flushed for a regular reinit
+    let buffer = m.0 as *mut u8;
+    assert!(fixed_malloc::try_reinitialize(buffer, 1024 * 1024, false).is_err());
+    ALLOC.flush();
+    assert!(fixed_malloc::try_reinitialize(buffer, 1024 * 1024, false).is_ok());
+
+    let p = unsafe { ALLOC.alloc(layout) };
+    unsafe { ALLOC.dealloc(p, layout) };
+    let layout_n = Layout::from_size_align(512 * 1024, FM_PAGE_SIZE).unwrap();
+    let n = unsafe { std::alloc::alloc_zeroed(layout_n) };
+    fixed_malloc::reinitialize(n, 512 * 1024, true);
+    // The block cached before now belongs to a dropped heap
+    assert_eq!(ALLOC.cached_blocks(), 0);
+    let q = unsafe { ALLOC.alloc(layout) };
+    assert_valid_pointers(&[(q as *mut c_void, 64)]);
+    assert_eq!(unsafe { fm_sm_live_allocations() }, 1);
+    unsafe { ALLOC.dealloc(q, layout) };
+    ALLOC.flush();
+    assert_heap_empty();
+    unsafe { std::alloc::dealloc(n, layout_n) };
+    deinit(m);
+}
+
+#[test]
+fn test_realloc_within_class() {
+    let m = init(1024 * 1024);
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let p = unsafe { ALLOC.alloc(layout) };
+    let q = unsafe { ALLOC.realloc(p, layout, 60) };
+    assert_eq!(p, q);
+    let layout = Layout::from_size_align(60, 8).unwrap();
+    unsafe { q.write_bytes(7, 60) };
+    let r = unsafe { ALLOC.realloc(q, layout, 5000) };
+    assert!(!r.is_null());
+    let bytes = unsafe { std::slice::from_raw_parts(r, 60) };
+    assert!(bytes.iter().all(|b| *b == 7));
+    unsafe { ALLOC.dealloc(r, Layout::from_size_align(5000, 8).unwrap()) };
+    assert_eq!(ALLOC.cached_blocks(), 1);
+    ALLOC.flush();
+    assert_heap_empty();
+    deinit(m);
+}
+
+}
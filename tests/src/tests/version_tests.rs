@@ -0,0 +1,56 @@
+use fixed_malloc::ffi::*;
+use fixed_malloc::{features, version, Features, Version};
+use std::path::Path;
+
+// The version comes from Cargo.toml of fixed-malloc, not of this crate
+#[test]
+fn test_version() {
+    let manifest =
+        std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("../Cargo.toml"))
+            .expect("read Cargo.toml");
+    let expected = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("version = "))
+        .expect("version of fixed-malloc")
+        .trim_matches('"');
+    let v = version();
+    assert_eq!(format!("{}.{}.{}", v.major, v.minor, v.patch), expected);
+    assert_eq!(
+        v,
+        Version {
+            major: FM_VERSION >> 16,
+            minor: (FM_VERSION >> 8) & 0xff,
+            patch: FM_VERSION & 0xff
+        }
+    );
+}
+
+// Bits reported by the linked allocator follow the features of this build
+#[test]
+fn test_features() {
+    assert_eq!(
+        features(),
+        Features {
+            guards: true,
+            test_support: true,
+            manual_init: cfg!(feature = "manual-init"),
+            hardening: cfg!(feature = "hardening"),
+            fill_on_free: cfg!(feature = "fill-on-free"),
+            debug_callback: cfg!(feature = "debug-hook"),
+            trap_reentrant: cfg!(feature = "trap-reentrant"),
+        }
+    );
+    assert_eq!(Features::from_bits(unsafe { fm_features() }), features());
+    assert_eq!(
+        Features::from_bits(FM_FEATURE_HARDENING),
+        Features {
+            guards: false,
+            test_support: false,
+            manual_init: false,
+            hardening: true,
+            fill_on_free: false,
+            debug_callback: false,
+            trap_reentrant: false,
+        }
+    );
+}
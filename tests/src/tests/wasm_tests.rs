@@ -0,0 +1,38 @@
+use core::ffi::c_void;
+use fixed_malloc::ffi::*;
+use fixed_malloc::{FixedAlloc, FmError, ReinitError};
+use rusty_fork::rusty_fork_test;
+
+rusty_fork_test! {
+
+#[test]
+fn test_from_static_buffer() {
+    // Starts 100 bytes past a page boundary, which leaves 4 whole pages
+    let buffer = Box::leak(vec![0u8; 6 * FM_PAGE_SIZE].into_boxed_slice());
+    let skip = (buffer.as_ptr() as usize).wrapping_neg() % FM_PAGE_SIZE + 100;
+    let buffer = &mut buffer[skip..skip + 5 * FM_PAGE_SIZE];
+    let range = buffer.as_ptr_range();
+
+    let _alloc = unsafe { FixedAlloc::from_static_buffer(buffer) }.expect("init from buffer");
+    let mut start: *mut c_void = core::ptr::null_mut();
+    let mut size = 0;
+    unsafe { fm_lm_buffer_range(&mut start, &mut size) };
+    assert_eq!(start as usize, range.start as usize - 100 + FM_PAGE_SIZE);
+    assert_eq!(size, 4 * FM_PAGE_SIZE);
+
+    let p = unsafe { fm_sm_malloc(100) };
+    assert!((start as usize..range.end as usize).contains(&(p as usize)));
+    unsafe { fm_sm_free(p) };
+}
+
+#[test]
+fn test_from_static_buffer_too_small() {
+    // One whole page at most, which leaves no page for allocations
+    let buffer = Box::leak(vec![0u8; 2 * FM_PAGE_SIZE - 1].into_boxed_slice());
+    assert_eq!(
+        unsafe { FixedAlloc::from_static_buffer(buffer) }.err(),
+        Some(ReinitError::Failed(FmError::BufferTooSmall))
+    );
+}
+
+}
@@ -0,0 +1,63 @@
+// Compiles c/capi_check.c against the static and the shared library
+// packaged by fixed-malloc-capi and runs both programs.
+#![cfg(all(feature = "capi", target_os = "linux"))]
+
+use object::{Object, ObjectSymbol};
+use std::path::Path;
+use std::process::Command;
+
+fn compile_and_run(name: &str, link: &[&str]) {
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("c/capi_check.c");
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg("-I")
+        .arg(fixed_malloc_capi::INCLUDE_DIR)
+        .arg(&source)
+        .arg("-o")
+        .arg(&program)
+        .args(link)
+        .status()
+        .expect("run C compiler");
+    assert!(status.success(), "compiling {} failed", name);
+
+    let status = Command::new(&program).status().expect("run program");
+    assert_eq!(status.code(), Some(0), "{}: {}", name, status);
+}
+
+#[test]
+fn test_capi_static() {
+    let archive = Path::new(fixed_malloc_capi::LIB_DIR).join("libfixed_malloc.a");
+    compile_and_run("capi_static", &[archive.to_str().unwrap()]);
+}
+
+#[test]
+fn test_capi_shared() {
+    let lib_dir = fixed_malloc_capi::LIB_DIR;
+    compile_and_run(
+        "capi_shared",
+        &[
+            "-L",
+            lib_dir,
+            "-lfixed_malloc",
+            &format!("-Wl,-rpath,{}", lib_dir),
+        ],
+    );
+}
+
+// Only the functions marked with FM_API are exported
+#[test]
+fn test_capi_exports() {
+    let data = std::fs::read(Path::new(fixed_malloc_capi::LIB_DIR).join("libfixed_malloc.so"))
+        .expect("read shared library");
+    let file = object::File::parse(&*data).expect("parse shared library");
+    let exported: Vec<String> = file
+        .dynamic_symbols()
+        .filter(|symbol| symbol.is_definition())
+        .filter_map(|symbol| symbol.name().ok().map(str::to_string))
+        .collect();
+    assert!(exported.iter().any(|name| name == "fm_sm_malloc"));
+    assert!(exported.iter().any(|name| name == "fm_abi_version"));
+    for name in &exported {
+        assert!(name.starts_with("fm_"), "{} is exported", name);
+    }
+}
@@ -0,0 +1,224 @@
+// Compares the declarations in src/ffi.rs with the bindings bindgen generates
+// from fixed_malloc.h, so a C function added or changed without updating
+// src/ffi.rs is caught. Lines starting with - are what src/ffi.rs declares,
+// lines starting with + what the C header does.
+#![cfg(feature = "ffi-check")]
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// Functions of linear-malloc.h which only slab-malloc.c calls, src/ffi.rs
+// leaves them out on purpose
+const UNBOUND: &[&str] = &[
+    "fm_lm_check_buffer",
+    "fm_lm_restore",
+    "fm_lm_set_deterministic",
+    "fm_lm_shrink_release",
+    "fm_lm_snapshot",
+    "fm_lm_state_block_size",
+    "fm_lm_state_contains",
+    "fm_lm_state_free",
+    "fm_lm_state_malloc",
+    "fm_lm_state_malloc_reserved",
+    "fm_lm_state_realloc",
+    "fm_lm_state_realloc_in_place",
+    "fm_lm_state_reinit",
+    "fm_lm_state_shrink_release",
+    "fm_lm_state_size",
+];
+
+// Constants build.rs generates for src/ffi.rs from FIXED_MALLOC_PAGE_SHIFT
+const GENERATED: &[&str] = &["FM_PAGE_SHIFT", "FM_PAGE_SIZE"];
+
+// C types and the names src/ffi.rs uses for them. Callbacks which must not
+// be NULL are declared without Option there.
+const TYPES: &[(&str, &str)] = &[
+    ("fm_heap_error_t", "FmHeapError"),
+    ("fm_stats_t", "FmStats"),
+    ("fm_heap_t", "FmHeap"),
+    ("fm_error_sink_t", "FmErrorSink"),
+    ("fm_debug_cb_t", "FmDebugCallback"),
+    ("fm_oom_hook_t", "FmOomHook"),
+    ("fm_lock_cb_t", "FmLockCallback"),
+    ("fm_walk_cb_t", "FmWalkCallback"),
+    ("fm_sm_walk_cb_t", "FmSmWalkCallback"),
+    ("fm_relocate_cb_t", "Option<FmRelocateCallback>"),
+    ("fm_class_stats_cb_t", "FmClassStatsCallback"),
+    ("ptrdiff_t", "isize"),
+    ("uint32_t", "u32"),
+    ("uint64_t", "u64"),
+];
+
+// Type with paths removed, C type names replaced by the ones of src/ffi.rs
+// and whitespace only kept between words
+fn normalize(ty: &str) -> String {
+    let mut ty = ty.to_string();
+    for prefix in ["::core::ffi::", "core::ffi::", "::std::os::raw::"] {
+        ty = ty.replace(prefix, "");
+    }
+    let mut result = String::new();
+    let mut ident = String::new();
+    for c in ty.chars().chain(std::iter::once(' ')) {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            ident.push(c);
+            continue;
+        }
+        if !ident.is_empty() {
+            if result.ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+                result.push(' ');
+            }
+            match TYPES.iter().find(|(c_name, _)| *c_name == ident) {
+                Some((_, rust_name)) => result.push_str(rust_name),
+                None => result.push_str(&ident),
+            }
+            ident.clear();
+        }
+        if !c.is_whitespace() {
+            result.push(c);
+        }
+    }
+    result
+}
+
+// Split at commas which are not nested in brackets
+fn split_top_level(list: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' | '<' | '[' => depth += 1,
+            ')' | '>' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&list[start..]);
+    parts
+        .into_iter()
+        .filter(|part| !part.trim().is_empty())
+        .collect()
+}
+
+// Declared functions mapped to their parameter and return types, parameter
+// names are ignored
+fn functions(source: &str) -> BTreeMap<String, String> {
+    let mut result = BTreeMap::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("pub fn ") {
+        rest = &rest[start + "pub fn ".len()..];
+        let end = rest.find([';', '{']).expect("end of declaration");
+        let declaration = &rest[..end];
+        rest = &rest[end..];
+        if !rest.starts_with(';') {
+            continue;
+        }
+        let open = declaration.find('(').expect("parameters");
+        let mut depth = 0;
+        let close = declaration[open..]
+            .char_indices()
+            .find(|(_, c)| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+            })
+            .map(|(i, _)| open + i)
+            .expect("end of parameters");
+        let parameters: Vec<String> = split_top_level(&declaration[open + 1..close])
+            .into_iter()
+            .map(|parameter| normalize(parameter.split_once(':').expect("parameter type").1))
+            .collect();
+        let ret = match declaration[close + 1..].trim().strip_prefix("->") {
+            Some(ty) => normalize(ty),
+            None => "()".to_string(),
+        };
+        result.insert(
+            declaration[..open].trim().to_string(),
+            format!("({}) -> {}", parameters.join(", "), ret),
+        );
+    }
+    result
+}
+
+// Constants mapped to their values, None when the value is no integer
+// literal
+fn constants(source: &str) -> BTreeMap<String, Option<u64>> {
+    let mut result = BTreeMap::new();
+    for line in source.lines() {
+        let Some(declaration) = line.trim().strip_prefix("pub const ") else {
+            continue;
+        };
+        let Some((name, rest)) = declaration.split_once(':') else {
+            continue;
+        };
+        let value = rest
+            .split_once('=')
+            .map(|(_, value)| value.trim().trim_end_matches(';').trim())
+            .and_then(|value| match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => value.parse().ok(),
+            });
+        result.insert(name.trim().to_string(), value);
+    }
+    result
+}
+
+#[test]
+fn test_ffi_matches_header() {
+    let committed =
+        std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("../src/ffi.rs"))
+            .expect("read src/ffi.rs");
+    let generated = std::fs::read_to_string(env!("FIXED_MALLOC_BINDINGS")).expect("read bindings");
+    let mut diff = vec![];
+
+    let committed_functions = functions(&committed);
+    let generated_functions = functions(&generated);
+    assert!(generated_functions.contains_key("fm_sm_malloc"));
+    for (name, signature) in &generated_functions {
+        if UNBOUND.contains(&name.as_str()) {
+            continue;
+        }
+        match committed_functions.get(name) {
+            Some(committed) if committed == signature => (),
+            Some(committed) => {
+                diff.push(format!("- fn {}{}", name, committed));
+                diff.push(format!("+ fn {}{}", name, signature));
+            }
+            None => diff.push(format!("+ fn {}{}", name, signature)),
+        }
+    }
+    for (name, signature) in &committed_functions {
+        if !generated_functions.contains_key(name) {
+            diff.push(format!("- fn {}{}", name, signature));
+        }
+    }
+
+    let committed_constants = constants(&committed);
+    for (name, value) in constants(&generated) {
+        if GENERATED.contains(&name.as_str()) {
+            continue;
+        }
+        match committed_constants.get(&name) {
+            // Values computed on the Rust side are checked by its own tests
+            Some(None) => (),
+            Some(committed) if *committed == value => (),
+            Some(committed) => {
+                diff.push(format!("- const {} = {:?}", name, committed));
+                diff.push(format!("+ const {} = {:?}", name, value));
+            }
+            None => diff.push(format!("+ const {} = {:?}", name, value)),
+        }
+    }
+
+    assert!(
+        diff.is_empty(),
+        "src/ffi.rs differs from the C header:\n{}",
+        diff.join("\n")
+    );
+}
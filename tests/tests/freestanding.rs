@@ -0,0 +1,25 @@
+// Builds and runs the program in freestanding, which is linked without any C
+// library and so relies on the mem functions of the freestanding-mem feature.
+#![cfg(all(target_arch = "x86_64", target_os = "linux"))]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn test_freestanding_link() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("freestanding");
+    let target_dir = dir.join("target");
+    let status = Command::new(env!("CARGO"))
+        .arg("build")
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .current_dir(&dir)
+        .status()
+        .expect("run cargo");
+    assert!(status.success(), "building the freestanding program failed");
+
+    let status = Command::new(target_dir.join("debug/freestanding"))
+        .status()
+        .expect("run program");
+    assert_eq!(status.code(), Some(0), "{}", status);
+}
@@ -0,0 +1,45 @@
+// Lives in its own test binary, since the heap symbols are defined once per
+// program. They mark a region starting 100 bytes past a page boundary and
+// spanning 5 pages, which leaves 4 whole pages in between.
+#![cfg(all(feature = "linker-heap", target_os = "linux"))]
+
+use core::ffi::c_void;
+use fixed_malloc::ffi::{fm_lm_buffer_range, fm_sm_free, fm_sm_malloc, FM_PAGE_SIZE};
+use fixed_malloc::FixedAlloc;
+
+core::arch::global_asm!(
+    ".pushsection .data.fm_test_heap, \"aw\"",
+    ".balign {page}",
+    ".skip 100",
+    ".globl _heap_start",
+    "_heap_start:",
+    ".skip 5 * {page}",
+    ".globl _heap_end",
+    "_heap_end:",
+    ".popsection",
+    page = const FM_PAGE_SIZE,
+);
+
+extern "C" {
+    static _heap_start: u8;
+    static _heap_end: u8;
+}
+
+#[test]
+fn test_from_linker_symbols() {
+    let start = core::ptr::addr_of!(_heap_start) as usize;
+    let end = core::ptr::addr_of!(_heap_end) as usize;
+    assert_eq!(start % FM_PAGE_SIZE, 100);
+    assert_eq!(end - start, 5 * FM_PAGE_SIZE);
+
+    let _alloc = unsafe { FixedAlloc::from_linker_symbols() }.expect("init from symbols");
+    let mut buffer: *mut c_void = core::ptr::null_mut();
+    let mut size = 0;
+    unsafe { fm_lm_buffer_range(&mut buffer, &mut size) };
+    assert_eq!(buffer as usize, start - 100 + FM_PAGE_SIZE);
+    assert_eq!(size, 4 * FM_PAGE_SIZE);
+
+    let p = unsafe { fm_sm_malloc(100) } as usize;
+    assert!(p >= buffer as usize && p < end);
+    unsafe { fm_sm_free(p as *mut c_void) };
+}
@@ -0,0 +1,29 @@
+// Lives in its own test binary, since the heap symbols are defined once per
+// program. They span 2 pages, but start 16 bytes past a page boundary, so
+// only one whole page is left, which is less than the bookkeeping page plus
+// the page for allocations required.
+#![cfg(all(feature = "linker-heap", target_os = "linux"))]
+
+use fixed_malloc::ffi::FM_PAGE_SIZE;
+use fixed_malloc::{FixedAlloc, FmError, ReinitError};
+
+core::arch::global_asm!(
+    ".pushsection .data.fm_test_heap, \"aw\"",
+    ".balign {page}",
+    ".skip 16",
+    ".globl _heap_start",
+    "_heap_start:",
+    ".skip 2 * {page}",
+    ".globl _heap_end",
+    "_heap_end:",
+    ".popsection",
+    page = const FM_PAGE_SIZE,
+);
+
+#[test]
+fn test_from_linker_symbols_too_small() {
+    assert_eq!(
+        unsafe { FixedAlloc::from_linker_symbols() }.unwrap_err(),
+        ReinitError::Failed(FmError::BufferTooSmall)
+    );
+}
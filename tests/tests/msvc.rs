@@ -0,0 +1,39 @@
+// Smoke test for C sources built with MSVC, which use plain C versions of
+// the GCC builtins.
+#![cfg(all(target_env = "msvc", not(feature = "manual-init")))]
+
+use core::ffi::c_void;
+use fixed_malloc::ffi::*;
+use fixed_malloc::FixedAlloc;
+
+#[test]
+fn test_msvc_smoke() {
+    let a = unsafe { FixedAlloc::new_static() };
+    // Fill more than one bitmap word of a slab, then free every other slot
+    let small: Vec<*mut c_void> = (0..100).map(|_| unsafe { fm_sm_malloc(32) }).collect();
+    assert!(small.iter().all(|p| !p.is_null()));
+    for p in small.iter().step_by(2) {
+        unsafe { fm_sm_free(*p) };
+    }
+    let p = unsafe { fm_sm_malloc(32) };
+    assert!(small.contains(&p));
+    unsafe { fm_sm_free(p) };
+    for p in small.iter().skip(1).step_by(2) {
+        unsafe { fm_sm_free(*p) };
+    }
+
+    let p = unsafe { fm_sm_calloc(10, 100) } as *mut u8;
+    assert!(unsafe { core::slice::from_raw_parts(p, 1000) }
+        .iter()
+        .all(|b| *b == 0));
+    unsafe { p.write_bytes(0x5A, 1000) };
+    let p = unsafe { fm_sm_realloc(p as *mut c_void, 3 * FM_PAGE_SIZE) } as *mut u8;
+    assert!(unsafe { core::slice::from_raw_parts(p, 1000) }
+        .iter()
+        .all(|b| *b == 0x5A));
+    unsafe { fm_sm_free(p as *mut c_void) };
+    assert!(unsafe { fm_sm_calloc(usize::MAX, 2) }.is_null());
+
+    assert_eq!(a.live_allocations(), 0);
+    assert_eq!(a.verify_heap_integrity(), Ok(()));
+}
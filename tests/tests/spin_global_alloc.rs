@@ -0,0 +1,67 @@
+// Lives in its own test binary, since the global allocator applies to the
+// whole process.
+#![cfg(all(feature = "spin", not(feature = "manual-init")))]
+
+use fixed_malloc::{FixedAlloc, LockedFixedAlloc};
+use std::thread;
+
+#[global_allocator]
+static ALLOC: LockedFixedAlloc = LockedFixedAlloc::new(unsafe { FixedAlloc::new_static() });
+
+#[test]
+fn test_global_allocator_threads() {
+    // Waiting threads spin rather than yield, keep to one thread per core of
+    // a dual-core target so the test stays fast on small hosts
+    let threads: Vec<_> = (0..2u8)
+        .map(|t| {
+            thread::spawn(move || {
+                let mut live: Vec<Vec<u8>> = vec![];
+                for i in 0..300usize {
+                    let mut v = vec![t; 1 + (i * 7 + t as usize * 13) % 2048];
+                    // Growing goes through realloc
+                    v.extend_from_slice(&[t; 100]);
+                    live.push(v);
+                    if live.len() > 4 {
+                        let v = live.remove(0);
+                        assert!(v.iter().all(|b| *b == t));
+                    }
+                }
+                live.iter().all(|v| v.iter().all(|b| *b == t))
+            })
+        })
+        .collect();
+    for t in threads {
+        assert!(t.join().unwrap());
+    }
+
+    ALLOC.with(|a| assert_eq!(a.verify_heap_integrity(), Ok(())));
+}
+
+#[test]
+fn test_global_allocator_over_aligned_box() {
+    #[repr(align(128))]
+    struct Aligned {
+        id: u64,
+        bytes: [u8; 200],
+    }
+
+    let mut boxes: Vec<Box<Aligned>> = (0..40u8)
+        .map(|i| {
+            Box::new(Aligned {
+                id: i as u64,
+                bytes: [i; 200],
+            })
+        })
+        .collect();
+    for (i, b) in boxes.iter().enumerate() {
+        assert_eq!(&**b as *const Aligned as usize % 128, 0);
+        assert_eq!(b.id, i as u64);
+        assert!(b.bytes.iter().all(|v| *v == i as u8));
+    }
+    // Boxes are freed through raw pointers as well
+    let raw = Box::into_raw(boxes.pop().unwrap());
+    drop(unsafe { Box::from_raw(raw) });
+    drop(boxes);
+
+    ALLOC.with(|a| assert_eq!(a.verify_heap_integrity(), Ok(())));
+}
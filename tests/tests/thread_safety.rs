@@ -0,0 +1,36 @@
+// Without `single-threaded`, the unlocked allocator must not be shareable nor
+// movable to another thread
+#[cfg(not(feature = "single-threaded"))]
+#[test]
+fn test_unlocked_alloc_not_sync() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("ui/share_fixed_alloc.rs");
+    t.compile_fail("ui/send_fixed_alloc.rs");
+    t.compile_fail("ui/send_linear_alloc.rs");
+    t.compile_fail("ui/global_fixed_alloc.rs");
+}
+
+// Every handle refers to the same global heap, so creating one is unsafe
+#[test]
+fn test_constructors_unsafe() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("ui/new_fixed_alloc.rs");
+}
+
+#[cfg(feature = "single-threaded")]
+#[test]
+fn test_single_threaded_alloc_sync() {
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+    assert_send::<fixed_malloc::FixedAlloc>();
+    assert_sync::<fixed_malloc::FixedAlloc>();
+    assert_send::<fixed_malloc::LinearAlloc>();
+    assert_sync::<fixed_malloc::Heap>();
+}
+
+#[cfg(feature = "spin")]
+#[test]
+fn test_locked_alloc_sync() {
+    fn assert_sync<T: Send + Sync>() {}
+    assert_sync::<fixed_malloc::LockedFixedAlloc>();
+}
@@ -0,0 +1,33 @@
+// Builds the module in wasm-module for wasm32-unknown-unknown and runs it
+// under wasmtime, so the C sources are exercised on a 32-bit target.
+#![cfg(feature = "wasmtime")]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn test_wasm_module() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("wasm-module");
+    let target_dir = dir.join("target");
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--release", "--target", "wasm32-unknown-unknown"])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .current_dir(&dir)
+        .status()
+        .expect("run cargo");
+    assert!(status.success(), "building the wasm module failed");
+
+    let module = target_dir.join("wasm32-unknown-unknown/release/wasm_module.wasm");
+    let output = Command::new("wasmtime")
+        .args(["run", "--invoke", "run"])
+        .arg(&module)
+        .output()
+        .expect("run wasmtime");
+    assert!(
+        output.status.success(),
+        "wasmtime failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "0");
+}
@@ -0,0 +1,7 @@
+use fixed_malloc::FixedAlloc;
+
+fn assert_global_alloc<T: core::alloc::GlobalAlloc + Sync>() {}
+
+fn main() {
+    assert_global_alloc::<FixedAlloc>();
+}
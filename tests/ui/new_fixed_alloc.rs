@@ -0,0 +1,6 @@
+use fixed_malloc::FixedAlloc;
+
+fn main() {
+    let mut buffer = vec![0u8; 65536];
+    FixedAlloc::new(buffer.as_mut_ptr(), buffer.len(), true);
+}
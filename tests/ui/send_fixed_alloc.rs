@@ -0,0 +1,7 @@
+use fixed_malloc::FixedAlloc;
+
+fn send(alloc: FixedAlloc) {
+    std::thread::spawn(move || alloc.live_allocations());
+}
+
+fn main() {}
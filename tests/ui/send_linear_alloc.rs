@@ -0,0 +1,10 @@
+use fixed_malloc::{AllocType, FixedAlloc};
+
+fn send(alloc: &FixedAlloc) {
+    let linear = alloc.linear();
+    std::thread::spawn(move || {
+        linear.alloc_pages(1, AllocType::Transient);
+    });
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use fixed_malloc::FixedAlloc;
+
+fn share(alloc: &FixedAlloc) {
+    std::thread::scope(|s| {
+        s.spawn(|| alloc.live_allocations());
+    });
+}
+
+fn main() {}
@@ -0,0 +1,83 @@
+// WebAssembly module serving its heap from an exported static buffer, built
+// and run under wasmtime by tests/tests/wasm.rs. The exported run function
+// returns 0 when all checks pass, otherwise the number of the failed check.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use fixed_malloc::ffi::*;
+use fixed_malloc::FixedAlloc;
+
+#[global_allocator]
+static ALLOC: FixedAlloc = unsafe { FixedAlloc::new_static_uninit() };
+
+#[no_mangle]
+pub static mut HEAP: [u8; 64 * 1024] = [0; 64 * 1024];
+
+// Whether ptr lies in the pages handed to the heap
+fn in_heap(ptr: *const u8) -> bool {
+    let mut start: *mut c_void = core::ptr::null_mut();
+    let mut size = 0;
+    unsafe { fm_lm_buffer_range(&mut start, &mut size) };
+    (start as usize..start as usize + size).contains(&(ptr as usize))
+}
+
+fn heap_ok() -> bool {
+    unsafe { fm_sm_verify(core::ptr::null_mut()) == FM_HEAP_OK }
+}
+
+#[no_mangle]
+pub extern "C" fn run() -> i32 {
+    let heap = unsafe { &mut *core::ptr::addr_of_mut!(HEAP) };
+    if unsafe { FixedAlloc::from_static_buffer(heap) }.is_err() {
+        return 1;
+    }
+
+    // A slab object and a block of whole pages
+    let small = Box::new([7u8; 24]);
+    let mut values: Vec<u64> = (0..1000).collect();
+    if !in_heap(small.as_ptr()) || !in_heap(values.as_ptr().cast()) {
+        return 2;
+    }
+    if unsafe { fm_sm_usable_size(small.as_ptr().cast()) } < 24 {
+        return 3;
+    }
+    if unsafe { fm_sm_live_allocations() } != 2 || !heap_ok() {
+        return 4;
+    }
+
+    // Shrinking moves the block into a slab, growing a string moves it
+    // through several size classes into pages
+    values.truncate(10);
+    values.shrink_to_fit();
+    let mut text = String::new();
+    for _ in 0..2000 {
+        text.push_str("abc");
+    }
+    if !values.iter().copied().eq(0..10) || text.len() != 6000 {
+        return 5;
+    }
+    if !in_heap(values.as_ptr().cast()) || !in_heap(text.as_ptr()) {
+        return 6;
+    }
+    if unsafe { fm_sm_live_allocations() } != 3 || !heap_ok() {
+        return 7;
+    }
+
+    drop(small);
+    drop(values);
+    drop(text);
+    if unsafe { fm_sm_live_allocations() } != 0 || !heap_ok() {
+        return 8;
+    }
+    0
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    core::arch::wasm32::unreachable()
+}
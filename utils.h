@@ -2,6 +2,19 @@
 #define FIXED_MALLOC_UTILS_H_
 
 #include <stddef.h>
+#include <stdint.h>
+
+#if __STDC_HOSTED__
+#include <string.h>
+#else
+// Freestanding builds, such as for wasm32-unknown-unknown, come without C
+// library headers. The functions themselves are still needed, the Rust side
+// provides them.
+void *memcpy(void *dst, const void *src, size_t n);
+void *memset(void *dst, int c, size_t n);
+#endif
+
+#include "c-list.h"
 
 static inline size_t __fm_roundup(size_t x, size_t round) {
   return x + (((~x) + 1) & (round - 1));
@@ -11,19 +24,160 @@ static inline size_t __fm_rounddown(size_t x, size_t round) {
   return x & (~(round - 1));
 }
 
+// GCC and clang builtins, other compilers such as MSVC get plain C versions
+#if defined(__GNUC__) || defined(__clang__)
+#define __fm_likely(x) __builtin_expect(!!(x), 1)
+#define __fm_mul_overflow(a, b, out) __builtin_mul_overflow(a, b, out)
+#define __fm_popcount64(x) __builtin_popcountll(x)
+#define __fm_ctz64(x) __builtin_ctzll(x)
+#else
+#define __fm_likely(x) (x)
+
+static inline int __fm_mul_overflow(size_t a, size_t b, size_t *out) {
+  *out = a * b;
+  return a != 0 && *out / a != b;
+}
+
+static inline int __fm_popcount64(uint64_t x) {
+  int count = 0;
+  for (; x != 0; x &= x - 1) {
+    count++;
+  }
+  return count;
+}
+
+// x must not be 0
+static inline int __fm_ctz64(uint64_t x) {
+  int count = 0;
+  for (; (x & 1) == 0; x >>= 1) {
+    count++;
+  }
+  return count;
+}
+#endif
+
+// Shift pointers of list nodes lying within [start, end) by delta, used when
+// the memory holding the nodes is copied elsewhere. head itself must not be
+// moved.
+static inline void __fm_relocate_list(CList *head, size_t start, size_t end,
+                                      size_t delta) {
+  CList *node = head;
+  do {
+    if ((size_t)node->next >= start && (size_t)node->next < end) {
+      node->next = (CList *)((size_t)node->next + delta);
+    }
+    if ((size_t)node->prev >= start && (size_t)node->prev < end) {
+      node->prev = (CList *)((size_t)node->prev + delta);
+    }
+    node = node->next;
+  } while (node != head);
+}
+
+// Append n bytes to a snapshot, bytes are only copied while they fit, but pos
+// always advances so the needed size is known in the end.
+static inline void __fm_snapshot_put(void *out, size_t len, size_t *pos,
+                                     const void *src, size_t n) {
+  if (*pos <= len && n <= len - *pos) {
+    memcpy((uint8_t *)out + *pos, src, n);
+  }
+  *pos += n;
+}
+
+// Read n bytes from a snapshot, returns 1 when the snapshot is too short
+static inline int __fm_snapshot_get(const void *in, size_t len, size_t *pos,
+                                    void *dst, size_t n) {
+  if (*pos > len || n > len - *pos) {
+    return 1;
+  }
+  if (dst != NULL) {
+    memcpy(dst, (const uint8_t *)in + *pos, n);
+  }
+  *pos += n;
+  return 0;
+}
+
+// Latch an error code, which can be queried via fm_last_error
+void __fm_set_error(int code);
+// Pass an internal error to the sink installed via fm_lm_set_error_sink
+void __fm_report(int code, const char *message);
+
+#ifdef FM_HARDENING
+// splitmix64, used by hardening mode to pick pseudo random locations
+static inline uint64_t __fm_random_next(uint64_t *state) {
+  uint64_t z = (*state += 0x9E3779B97F4A7C15ULL);
+  z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9ULL;
+  z = (z ^ (z >> 27)) * 0x94D049BB133111EBULL;
+  return z ^ (z >> 31);
+}
+#endif
+
+#ifdef FM_FILL_ON_FREE
+#define FM_FILL_PATTERN 0xAB
+
+// Returns 0 if all bytes still hold the fill pattern
+static inline int __fm_check_fill(const void *ptr, size_t size) {
+  const uint8_t *p = (const uint8_t *)ptr;
+  for (size_t i = 0; i < size; i++) {
+    if (p[i] != FM_FILL_PATTERN) {
+      return 1;
+    }
+  }
+  return 0;
+}
+#endif
+
+#ifndef FM_MEMORY_SIZE
+#define FM_MEMORY_SIZE (640 * 1024)
+#endif
+
+#ifdef FM_DEBUG_CALLBACK
+// Pass the format string of a debug message to the callback installed via
+// fm_set_debug_callback, arguments are dropped since no formatting is
+// available without the C library
+void __fm_debug(const char *format, ...);
+#define FM_DEBUG(...) __fm_debug(__VA_ARGS__)
+#endif
+
+// Debug messages can be compiled out via FM_NO_DEBUG as well, for compilers
+// which cannot define function-like macros on the command line
+#if defined(FM_NO_DEBUG) && !defined(FM_DEBUG)
+#define FM_DEBUG(...)
+#endif
+
+// Without a C library, output is dropped and aborting traps
 #ifndef FM_DEBUG
+#if __STDC_HOSTED__
 #include <stdio.h>
 #define FM_DEBUG(...) fprintf(stderr, __VA_ARGS__)
+#else
+#define FM_DEBUG(...)
 #endif
+#endif
+
+// Internal errors are printed as debug messages, and always passed to the
+// error sink
+#define FM_REPORT(code, message) \
+  do {                           \
+    FM_DEBUG(message);           \
+    __fm_report(code, message);  \
+  } while (0)
 
 #ifndef FM_PRINT
+#if __STDC_HOSTED__
 #include <stdio.h>
 #define FM_PRINT(...) printf(__VA_ARGS__)
+#else
+#define FM_PRINT(...)
+#endif
 #endif
 
 #ifndef FM_ABORT
+#if __STDC_HOSTED__
 #include <stdlib.h>
 #define FM_ABORT abort
+#else
+#define FM_ABORT __builtin_trap
+#endif
 #endif
 
 #endif /* FIXED_MALLOC_UTILS_H_ */